@@ -4,6 +4,7 @@ use tempfile::TempDir;
 use alloy_primitives::B256;
 use ubt::{Stem, StemNode, TreeKey};
 use ubt_exex::persistence::UbtDatabase;
+use ubt_exex::stem_codec::{decode_stem, encode_stem, encode_stem_legacy};
 
 fn stem_from_u64(i: u64) -> Stem {
     let mut bytes = [0u8; 31];
@@ -93,5 +94,90 @@ fn bench_load_value(c: &mut Criterion) {
     group.finish();
 }
 
-criterion_group!(benches, bench_batch_update, bench_load_value);
+fn bench_stem_encoding(c: &mut Criterion) {
+    let mut group = c.benchmark_group("stem_encoding");
+    let stem_count = 10_000usize;
+
+    for values_per_stem in [2u8, 8, 64] {
+        let updates = make_updates(stem_count, values_per_stem);
+        let legacy: Vec<Vec<u8>> = updates
+            .iter()
+            .map(|(_, node)| encode_stem_legacy(node).expect("legacy encode"))
+            .collect();
+        let compact: Vec<Vec<u8>> = updates.iter().map(|(_, node)| encode_stem(node)).collect();
+
+        let legacy_bytes: usize = legacy.iter().map(Vec::len).sum();
+        let compact_bytes: usize = compact.iter().map(Vec::len).sum();
+        println!(
+            "stem_encoding/{values_per_stem} values: legacy {} B/stem, compact {} B/stem ({:.1}%)",
+            legacy_bytes / stem_count,
+            compact_bytes / stem_count,
+            100.0 * compact_bytes as f64 / legacy_bytes as f64,
+        );
+
+        group.throughput(criterion::Throughput::Elements(stem_count as u64));
+        group.bench_function(BenchmarkId::new("encode_legacy", values_per_stem), |b| {
+            b.iter(|| {
+                for (_, node) in &updates {
+                    black_box(encode_stem_legacy(node).expect("legacy encode"));
+                }
+            })
+        });
+        group.bench_function(BenchmarkId::new("encode_compact", values_per_stem), |b| {
+            b.iter(|| {
+                for (_, node) in &updates {
+                    black_box(encode_stem(node));
+                }
+            })
+        });
+        group.bench_function(BenchmarkId::new("decode_legacy", values_per_stem), |b| {
+            b.iter(|| {
+                for ((stem, _), bytes) in updates.iter().zip(&legacy) {
+                    black_box(decode_stem(*stem, bytes).expect("legacy decode"));
+                }
+            })
+        });
+        group.bench_function(BenchmarkId::new("decode_compact", values_per_stem), |b| {
+            b.iter(|| {
+                for ((stem, _), bytes) in updates.iter().zip(&compact) {
+                    black_box(decode_stem(*stem, bytes).expect("compact decode"));
+                }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn bench_migrate_stem_encoding(c: &mut Criterion) {
+    let mut group = c.benchmark_group("mdbx_migrate_stem_encoding");
+    let stem_count = 10_000usize;
+    let updates = make_updates(stem_count, 8);
+
+    group.throughput(criterion::Throughput::Elements(stem_count as u64));
+    group.bench_function("stems", |b| {
+        b.iter_batched(
+            || {
+                let dir = TempDir::new().expect("tempdir");
+                let db = UbtDatabase::open(dir.path()).expect("db open");
+                db.write_legacy_stems(&updates).expect("legacy write");
+                (db, dir)
+            },
+            |(db, _dir)| {
+                while !db.migrate_stem_encoding(1_000).expect("migrate").done {}
+            },
+            BatchSize::SmallInput,
+        );
+    });
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_batch_update,
+    bench_load_value,
+    bench_stem_encoding,
+    bench_migrate_stem_encoding
+);
 criterion_main!(benches);
@@ -0,0 +1,152 @@
+//! Hot backups of the UBT stores.
+//!
+//! A backup holds a copy of MDBX, NOMT and the key index taken at the same persisted
+//! head, laid out like a data directory so it can be used as one:
+//!
+//! ```text
+//! <dest>/
+//!   ubt/mdbx.dat     MDBX copy (`mdbx_env_copy`, optionally compacted)
+//!   nomt/            NOMT directory
+//!   key-index.redb   key index
+//!   manifest.json    `BackupManifest`, written last
+//! ```
+//!
+//! Backups are taken by `UbtExEx::backup` between blocks, after flushing the dirty
+//! overlay, so no store is ahead of the head named in the manifest. NOMT is closed
+//! while its directory is copied. The manifest is written only once the heads read
+//! back from the three copies agree. A directory without a manifest is an
+//! incomplete backup and must not be restored.
+
+use alloy_primitives::B256;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tokio::sync::oneshot;
+
+use crate::error::{Result, UbtError};
+use crate::hasher::TreeHasher;
+
+/// Manifest file written to the root of a backup once every store is copied.
+pub const BACKUP_MANIFEST_FILE: &str = "manifest.json";
+
+/// Head and provenance of a backup.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    #[serde(rename = "blockNumber")]
+    pub block_number: u64,
+    #[serde(rename = "blockHash")]
+    pub block_hash: B256,
+    pub root: B256,
+    #[serde(rename = "stemCount")]
+    pub stem_count: usize,
+    /// Head block read back from the NOMT copy.
+    #[serde(rename = "nomtBlockNumber")]
+    pub nomt_block_number: u64,
+    /// Head block read back from the key index copy.
+    #[serde(rename = "keyIndexBlockNumber")]
+    pub key_index_block_number: u64,
+    pub hasher: TreeHasher,
+    #[serde(rename = "schemaVersion")]
+    pub schema_version: u32,
+    /// Whether the MDBX copy was compacted.
+    pub compacted: bool,
+    /// Unix timestamp (seconds) at which the backup completed.
+    #[serde(rename = "createdAt")]
+    pub created_at: u64,
+}
+
+/// A backup requested over RPC, run by the ExEx task between notifications.
+#[derive(Debug)]
+pub struct BackupRequest {
+    pub dest: PathBuf,
+    pub compact: bool,
+    pub reply: oneshot::Sender<Result<BackupManifest>>,
+}
+
+/// Check that `dest` does not exist or is an empty directory.
+pub fn ensure_empty_dest(dest: &Path) -> Result<()> {
+    if dest.exists() && std::fs::read_dir(dest)?.next().is_some() {
+        return Err(UbtError::Backup {
+            message: format!("destination {} is not empty", dest.display()),
+        });
+    }
+    Ok(())
+}
+
+/// Recursively copy the directory `src` to `dest`, which must not exist.
+pub fn copy_dir(src: &Path, dest: &Path) -> Result<()> {
+    std::fs::create_dir(dest)?;
+    for entry in std::fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dest.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            std::fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write the manifest into `dest` through a temporary file, so a manifest is never
+/// seen half-written.
+pub fn write_manifest(dest: &Path, manifest: &BackupManifest) -> Result<()> {
+    let json = serde_json::to_vec_pretty(manifest).map_err(|e| UbtError::Backup {
+        message: format!("failed to encode manifest: {e}"),
+    })?;
+    let tmp = dest.join(format!("{BACKUP_MANIFEST_FILE}.tmp"));
+    std::fs::write(&tmp, json)?;
+    std::fs::rename(&tmp, dest.join(BACKUP_MANIFEST_FILE))?;
+    Ok(())
+}
+
+/// Read the manifest of a completed backup.
+pub fn read_manifest(dest: &Path) -> Result<BackupManifest> {
+    let json = std::fs::read(dest.join(BACKUP_MANIFEST_FILE))?;
+    serde_json::from_slice(&json).map_err(|e| UbtError::Backup {
+        message: format!("invalid manifest: {e}"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_manifest_roundtrip() {
+        let dir = TempDir::new().unwrap();
+        let manifest = BackupManifest {
+            block_number: 42,
+            block_hash: B256::repeat_byte(0x01),
+            root: B256::repeat_byte(0x02),
+            stem_count: 3,
+            nomt_block_number: 42,
+            key_index_block_number: 42,
+            hasher: TreeHasher::Sha256,
+            schema_version: 4,
+            compacted: true,
+            created_at: 1_700_000_000,
+        };
+
+        write_manifest(dir.path(), &manifest).unwrap();
+        assert_eq!(read_manifest(dir.path()).unwrap(), manifest);
+        assert!(!dir.path().join(format!("{BACKUP_MANIFEST_FILE}.tmp")).exists());
+    }
+
+    #[test]
+    fn test_copy_dir() {
+        let src = TempDir::new().unwrap();
+        std::fs::write(src.path().join("a"), b"a").unwrap();
+        std::fs::create_dir(src.path().join("sub")).unwrap();
+        std::fs::write(src.path().join("sub").join("b"), b"b").unwrap();
+
+        let dest = TempDir::new().unwrap();
+        let target = dest.path().join("copy");
+        copy_dir(src.path(), &target).unwrap();
+        assert_eq!(std::fs::read(target.join("a")).unwrap(), b"a");
+        assert_eq!(std::fs::read(target.join("sub").join("b")).unwrap(), b"b");
+
+        ensure_empty_dest(&dest.path().join("missing")).unwrap();
+        assert!(ensure_empty_dest(dest.path()).is_err());
+    }
+}
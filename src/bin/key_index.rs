@@ -6,6 +6,8 @@ use clap::Parser;
 use eyre::Result;
 use hex;
 use ubt_exex::key_index::{KeyIndex, KEY_INDEX_FILE};
+use ubt_exex::persistence::UbtDatabase;
+use ubt_exex::ubt_exex::UBT_DATA_DIR;
 
 #[derive(Parser, Debug)]
 #[command(about = "Inspect the UBT key index database")]
@@ -17,6 +19,10 @@ struct Args {
     /// Count stems by iterating the key index
     #[arg(long)]
     count_stems: bool,
+
+    /// Compare the key index head with the MDBX head (opened read-only)
+    #[arg(long)]
+    compare_mdbx: bool,
 }
 
 fn main() -> Result<()> {
@@ -52,6 +58,35 @@ fn main() -> Result<()> {
         }
     }
 
+    if args.compare_mdbx {
+        let ubt_dir = data_dir.join(UBT_DATA_DIR);
+        let db = UbtDatabase::open_read_only(&ubt_dir)?;
+        println!("MDBX: {} (schema version {})", ubt_dir.display(), db.schema_version()?);
+        // The key index follows the flushed head, including one whose root is pending.
+        let (mdbx_head, pending_root) = db.load_head_with_pending_root()?;
+        match &mdbx_head {
+            Some(head) => println!("MDBX head block: {}", head.block_number),
+            None => println!("No MDBX head found"),
+        }
+        if let Some(pending) = &pending_root {
+            println!(
+                "Root of block {} is still being computed; the key index root is not final",
+                pending.block_number
+            );
+        }
+        let index_head = key_index.load_head()?;
+        let in_sync = match (&mdbx_head, &index_head) {
+            (Some(mdbx), Some(index)) => {
+                mdbx.block_number == index.block_number && mdbx.block_hash == index.block_hash
+            }
+            (None, None) => true,
+            _ => false,
+        };
+        if !in_sync {
+            println!("Warning: key index head does not match MDBX head");
+        }
+    }
+
     Ok(())
 }
 
@@ -0,0 +1,351 @@
+//! Bootstrap of the UBT from reth's plain state.
+//!
+//! A node that is already synced has state that no ExEx notification will ever
+//! replay. Instead of requiring an external migration tool, the ExEx can read
+//! reth's `PlainAccountState`, `PlainStorageState` and `Bytecodes` tables directly
+//! and build the tree in bounded batches (see `UbtExEx::bootstrap_from_state`).
+//!
+//! # Consistency
+//!
+//! `ProviderStateSource` holds one read transaction for its lifetime, so every
+//! batch of a bootstrap run reads the state of the same block while the node keeps
+//! executing. The head is set to the block the bootstrap *started* at and reth
+//! backfills every later block.
+//!
+//! A bootstrap resumed after a restart reads its remaining batches at a later
+//! block, and those leaves already hold the post-state of the blocks in between
+//! without any deltas. Since each block rewrites the post-state of every account
+//! and slot it touches, backfill still converges to the exact state at the tip,
+//! but those blocks cannot be reverted: the highest block read is recorded as the
+//! bootstrap floor, and reverts below it are refused.
+
+use alloy_eips::BlockNumHash;
+use alloy_primitives::U256;
+use reth_db_api::{
+    cursor::{DbCursorRO, DbDupCursorRO},
+    tables,
+    transaction::DbTx,
+};
+use reth_ethereum::provider::{BlockHashReader, BlockNumReader, DBProvider, DatabaseProviderFactory};
+
+use crate::error::{Result, UbtError};
+use crate::persistence::BootstrapPosition;
+use crate::ubt_exex::{AccountChange, AccountState};
+
+/// Default number of leaves (accounts plus storage slots) read per batch.
+pub const DEFAULT_BOOTSTRAP_BATCH: usize = 100_000;
+
+/// One batch of plain state, in address then slot order.
+#[derive(Debug, Clone, Default)]
+pub struct BootstrapBatch {
+    /// Changes to apply. An account whose storage continues from an earlier batch
+    /// has `info: None`.
+    pub changes: Vec<AccountChange>,
+    /// Where the next batch starts, or `None` once the state is exhausted.
+    pub next: Option<BootstrapPosition>,
+}
+
+/// Source of plain account and storage state for a bootstrap.
+pub trait PlainStateSource {
+    /// Block the source currently reflects.
+    fn state_block(&self) -> Result<BlockNumHash>;
+
+    /// Read up to `limit` leaves starting at `from`.
+    fn read_batch(&self, from: &BootstrapPosition, limit: usize) -> Result<BootstrapBatch>;
+}
+
+/// Plain state source backed by one reth database provider, and so by one read
+/// transaction.
+pub struct ProviderStateSource<P> {
+    provider: P,
+}
+
+impl<P: DBProvider> ProviderStateSource<P> {
+    /// Pin the current state of `factory` for a bootstrap.
+    ///
+    /// reth aborts read transactions that stay open for long; this one is exempt,
+    /// so drop the source as soon as the bootstrap is done to let MDBX reuse pages.
+    pub fn open<F>(factory: &F) -> Result<Self>
+    where
+        F: DatabaseProviderFactory<Provider = P>,
+    {
+        let mut provider = factory.database_provider_ro().map_err(state_error)?;
+        provider.tx_mut().disable_long_read_transaction_safety();
+        Ok(Self { provider })
+    }
+}
+
+fn state_error(e: impl std::fmt::Display) -> UbtError {
+    UbtError::StateExtraction {
+        message: format!("failed to read plain state: {e}"),
+    }
+}
+
+impl<P> PlainStateSource for ProviderStateSource<P>
+where
+    P: DBProvider + BlockNumReader + BlockHashReader,
+{
+    fn state_block(&self) -> Result<BlockNumHash> {
+        let number = self.provider.best_block_number().map_err(state_error)?;
+        let hash = self
+            .provider
+            .block_hash(number)
+            .map_err(state_error)?
+            .ok_or_else(|| state_error(format!("missing hash for block {number}")))?;
+        Ok(BlockNumHash::new(number, hash))
+    }
+
+    fn read_batch(&self, from: &BootstrapPosition, limit: usize) -> Result<BootstrapBatch> {
+        let tx = self.provider.tx_ref();
+        let mut accounts = tx
+            .cursor_read::<tables::PlainAccountState>()
+            .map_err(state_error)?;
+        let mut storage = tx
+            .cursor_dup_read::<tables::PlainStorageState>()
+            .map_err(state_error)?;
+
+        let mut batch = BootstrapBatch::default();
+        let mut budget = limit.max(1);
+        let mut walker = accounts.walk(Some(from.address)).map_err(state_error)?;
+
+        while let Some(entry) = walker.next() {
+            let (address, account) = entry.map_err(state_error)?;
+            let resume_slot = if address == from.address { from.next_slot } else { None };
+
+            if budget == 0 {
+                batch.next = Some(BootstrapPosition {
+                    address,
+                    next_slot: resume_slot,
+                });
+                return Ok(batch);
+            }
+
+            let info = if resume_slot.is_some() {
+                None
+            } else {
+                budget -= 1;
+                let code = match account.bytecode_hash {
+                    Some(hash) => tx
+                        .get::<tables::Bytecodes>(hash)
+                        .map_err(state_error)?
+                        .map(|code| code.original_bytes()),
+                    None => None,
+                };
+                Some(AccountState {
+                    nonce: account.nonce,
+                    balance: account.balance,
+                    code_hash: account.get_bytecode_hash(),
+                    code,
+                })
+            };
+
+            let mut slots = Vec::new();
+            for slot in storage
+                .walk_dup(Some(address), resume_slot)
+                .map_err(state_error)?
+            {
+                let (_, entry) = slot.map_err(state_error)?;
+                if budget == 0 {
+                    batch.changes.push(AccountChange {
+                        address,
+                        info,
+                        storage_wiped: false,
+                        storage: slots,
+                    });
+                    batch.next = Some(BootstrapPosition {
+                        address,
+                        next_slot: Some(entry.key),
+                    });
+                    return Ok(batch);
+                }
+                budget -= 1;
+                slots.push((U256::from_be_bytes(entry.key.0), entry.value));
+            }
+
+            batch.changes.push(AccountChange {
+                address,
+                info,
+                storage_wiped: false,
+                storage: slots,
+            });
+        }
+
+        Ok(batch)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use alloy_primitives::{keccak256, Address, Bytes, B256, KECCAK256_EMPTY};
+    use reth_db_api::transaction::DbTxMut;
+    use reth_primitives_traits::{Account, Bytecode, StorageEntry};
+    use reth_provider::test_utils::{create_test_provider_factory, MockNodeTypesWithDB};
+    use reth_provider::ProviderFactory;
+    use std::collections::BTreeMap;
+
+    /// Plain state by address: the account and its storage by slot.
+    pub(crate) type PlainState = BTreeMap<Address, (AccountState, BTreeMap<B256, U256>)>;
+
+    /// A reth test database holding `state` in its plain state tables.
+    pub(crate) fn provider_factory_with(
+        state: &PlainState,
+    ) -> ProviderFactory<MockNodeTypesWithDB> {
+        let factory = create_test_provider_factory();
+        write_plain_state(&factory, state);
+        factory
+    }
+
+    fn write_plain_state(factory: &ProviderFactory<MockNodeTypesWithDB>, state: &PlainState) {
+        let provider = factory.provider_rw().unwrap();
+        let tx = provider.tx_ref();
+        for (address, (info, storage)) in state {
+            if let Some(code) = &info.code {
+                tx.put::<tables::Bytecodes>(info.code_hash, Bytecode::new_raw(code.clone()))
+                    .unwrap();
+            }
+            let account = Account {
+                nonce: info.nonce,
+                balance: info.balance,
+                bytecode_hash: info.code.as_ref().map(|_| info.code_hash),
+            };
+            tx.put::<tables::PlainAccountState>(*address, account).unwrap();
+            for (&key, &value) in storage {
+                tx.put::<tables::PlainStorageState>(*address, StorageEntry { key, value })
+                    .unwrap();
+            }
+        }
+        provider.commit().unwrap();
+    }
+
+    fn plain_state(accounts: u8, slots: u8) -> PlainState {
+        (1..=accounts)
+            .map(|i| {
+                let code = (i % 2 == 0).then(|| Bytes::from(vec![0x5b; i as usize]));
+                let info = AccountState {
+                    nonce: i as u64,
+                    balance: U256::from(i),
+                    code_hash: code.as_ref().map(keccak256).unwrap_or(KECCAK256_EMPTY),
+                    code,
+                };
+                let storage = (1..=slots)
+                    .map(|slot| (B256::with_last_byte(slot), U256::from(slot)))
+                    .collect();
+                (Address::repeat_byte(i), (info, storage))
+            })
+            .collect()
+    }
+
+    /// Read every batch from the start, returning the changes of each batch and
+    /// the positions the batches ended at.
+    fn read_all(
+        source: &impl PlainStateSource,
+        limit: usize,
+    ) -> (Vec<Vec<AccountChange>>, Vec<BootstrapPosition>) {
+        let mut from = BootstrapPosition::default();
+        let mut batches = Vec::new();
+        let mut positions = Vec::new();
+        loop {
+            let batch = source.read_batch(&from, limit).unwrap();
+            batches.push(batch.changes);
+            let Some(next) = batch.next else {
+                return (batches, positions);
+            };
+            positions.push(next);
+            from = next;
+        }
+    }
+
+    /// The state `changes` write, merging storage continued across batches.
+    fn merged(changes: impl IntoIterator<Item = AccountChange>) -> PlainState {
+        let mut state = PlainState::new();
+        for change in changes {
+            if let Some(info) = change.info {
+                state.insert(change.address, (info, BTreeMap::new()));
+            }
+            let (_, storage) = state
+                .get_mut(&change.address)
+                .expect("account read before its storage");
+            storage.extend(
+                change
+                    .storage
+                    .into_iter()
+                    .map(|(slot, value)| (B256::from(slot), value)),
+            );
+        }
+        state
+    }
+
+    fn assert_same_state(read: &PlainState, expected: &PlainState) {
+        let summary = |state: &PlainState| {
+            state
+                .iter()
+                .map(|(address, (info, storage))| {
+                    let account = (info.nonce, info.balance, info.code_hash, info.code.clone());
+                    (*address, account, storage.clone())
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(summary(read), summary(expected));
+    }
+
+    #[test]
+    fn test_provider_source_resumes_storage_across_batches() {
+        let expected = plain_state(4, 5);
+        let factory = provider_factory_with(&expected);
+        let source = ProviderStateSource::open(&factory).unwrap();
+
+        // 24 leaves in batches of 4: every account's storage is split between
+        // two batches.
+        let (batches, positions) = read_all(&source, 4);
+        assert_eq!(batches.len(), 6);
+        assert_eq!(
+            positions[0],
+            BootstrapPosition {
+                address: Address::repeat_byte(1),
+                next_slot: Some(B256::with_last_byte(4)),
+            }
+        );
+        // The account resumed mid-storage carries no account info.
+        let resumed = &batches[1][0];
+        assert_eq!(resumed.address, Address::repeat_byte(1));
+        assert!(resumed.info.is_none());
+        assert_eq!(
+            resumed.storage,
+            vec![
+                (U256::from(4), U256::from(4)),
+                (U256::from(5), U256::from(5))
+            ]
+        );
+        assert_same_state(&merged(batches.into_iter().flatten()), &expected);
+
+        // A batch ending at an account boundary resumes at the next account.
+        let (_, positions) = read_all(&source, 6);
+        assert_eq!(
+            positions[0],
+            BootstrapPosition {
+                address: Address::repeat_byte(2),
+                next_slot: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_provider_source_reads_one_snapshot() {
+        let expected = plain_state(3, 2);
+        let factory = provider_factory_with(&expected);
+        let source = ProviderStateSource::open(&factory).unwrap();
+        let first = source.read_batch(&BootstrapPosition::default(), 3).unwrap();
+
+        // State written while the bootstrap runs is not seen by its later batches.
+        write_plain_state(&factory, &plain_state(5, 4));
+        let rest = source.read_batch(&first.next.unwrap(), usize::MAX).unwrap();
+        assert!(rest.next.is_none());
+        let changes = first.changes.into_iter().chain(rest.changes);
+        assert_same_state(&merged(changes), &expected);
+
+        let reopened = ProviderStateSource::open(&factory).unwrap();
+        let (batches, _) = read_all(&reopened, usize::MAX);
+        assert_same_state(&merged(batches.into_iter().flatten()), &plain_state(5, 4));
+    }
+}
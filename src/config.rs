@@ -2,19 +2,84 @@
 //!
 //! Supports both CLI arguments and environment variable fallbacks.
 
-use clap::Args;
+use clap::{Args, ValueEnum};
 use std::path::PathBuf;
 
+use crate::hasher::TreeHasher;
+
 /// Default flush interval (blocks between MDBX writes)
 pub const DEFAULT_FLUSH_INTERVAL: u64 = 1;
 
 /// Default delta retention (blocks to keep deltas for reorgs)
 pub const DEFAULT_DELTA_RETENTION: u64 = 256;
+
+/// Default root retention (blocks to keep in the root history, 0 keeps all)
+pub const DEFAULT_ROOT_RETENTION: u64 = 100_000;
 /// Default HTTP RPC address
 pub const DEFAULT_RPC_HTTP_ADDR: &str = "127.0.0.1:9845";
 /// Default IPC socket path
 pub const DEFAULT_RPC_IPC_PATH: &str = "/tmp/ubt-exex.ipc";
 
+/// What to do when an account balance does not fit the 16-byte basic-data field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum BalanceOverflowPolicy {
+    /// Fail the block with `UbtError::BalanceOverflow`.
+    Error,
+    /// Write the saturated balance and record the account in `ubt_balance_overflows`.
+    #[default]
+    Record,
+}
+
+/// What to do when an MDBX write fails because the map reached its upper bound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum MapFullPolicy {
+    /// Double the map upper bound and retry the write.
+    #[default]
+    Grow,
+    /// Stop processing notifications and alert, leaving the node running. The
+    /// flush is retried from MDBX on restart, after raising `UBT_MDBX_MAX_SIZE`.
+    Pause,
+}
+
+/// How the root of a resumed head is verified at startup.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum VerifyMode {
+    /// Recompute the root from every entry before processing blocks.
+    #[default]
+    Full,
+    /// Rehash a random sample of stems against the stem hash cache and check the
+    /// stored root node, falling back to `full` on a mismatch.
+    Sampled,
+    /// Start processing and recompute the root from every entry on a separate
+    /// thread, marking the node unhealthy on a mismatch.
+    Background,
+    /// Trust the stored root.
+    Skip,
+}
+
+impl VerifyMode {
+    /// Name used in logs and metric labels.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Full => "full",
+            Self::Sampled => "sampled",
+            Self::Background => "background",
+            Self::Skip => "skip",
+        }
+    }
+}
+
+/// When the root of a flush is computed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum RootComputation {
+    /// Compute the root before the flush, blocking the notification loop.
+    #[default]
+    Sync,
+    /// Flush first and compute the root on a separate thread from a snapshot of
+    /// the flushed state. The next flush waits only while that root is pending.
+    Async,
+}
+
 /// UBT ExEx configuration arguments.
 #[derive(Debug, Clone, Args)]
 #[command(next_help_heading = "UBT ExEx")]
@@ -34,6 +99,31 @@ pub struct UbtConfig {
     #[arg(long = "ubt.delta-retention", value_name = "BLOCKS", default_value_t = DEFAULT_DELTA_RETENTION)]
     pub delta_retention: u64,
 
+    /// Number of blocks to keep in the root history (`ubt_roots`).
+    /// Older records are pruned; 0 keeps every record.
+    #[arg(long = "ubt.root-retention", value_name = "BLOCKS", default_value_t = DEFAULT_ROOT_RETENTION)]
+    pub root_retention: u64,
+
+    /// Handling of balances above u128::MAX (error or record).
+    #[arg(long = "ubt.balance-overflow", value_enum, default_value_t = BalanceOverflowPolicy::default())]
+    pub balance_overflow: BalanceOverflowPolicy,
+
+    /// Handling of a full MDBX map (grow or pause).
+    #[arg(long = "ubt.map-full", value_enum, default_value_t = MapFullPolicy::default())]
+    pub map_full: MapFullPolicy,
+
+    /// Tree hash function (blake3 or sha256). Fixed once the database is created.
+    #[arg(long = "ubt.hasher", value_enum, default_value_t = TreeHasher::default())]
+    pub hasher: TreeHasher,
+
+    /// Startup root verification (full, sampled, background or skip).
+    #[arg(long = "ubt.verify-mode", value_enum, default_value_t = VerifyMode::default())]
+    pub verify_mode: VerifyMode,
+
+    /// Flush root computation (sync or async).
+    #[arg(long = "ubt.root-computation", value_enum, default_value_t = RootComputation::default())]
+    pub root_computation: RootComputation,
+
     /// Disable UBT ExEx (useful for debugging).
     #[arg(long = "ubt.disable", default_value_t = false)]
     pub disabled: bool,
@@ -91,6 +181,102 @@ impl UbtConfig {
         }
     }
 
+    /// Get root retention, with env var fallback.
+    ///
+    /// Precedence: CLI arg (if not default) > UBT_ROOT_RETENTION env var > default
+    pub fn get_root_retention(&self) -> u64 {
+        if self.root_retention != DEFAULT_ROOT_RETENTION {
+            return self.root_retention;
+        }
+        match std::env::var("UBT_ROOT_RETENTION") {
+            Ok(s) => s.parse().unwrap_or_else(|_| {
+                tracing::warn!(value = %s, "Invalid UBT_ROOT_RETENTION, using default");
+                self.root_retention
+            }),
+            Err(_) => self.root_retention,
+        }
+    }
+
+    /// Get balance overflow policy, with env var fallback.
+    ///
+    /// Precedence: CLI arg (if not default) > UBT_BALANCE_OVERFLOW env var > default
+    pub fn get_balance_overflow_policy(&self) -> BalanceOverflowPolicy {
+        if self.balance_overflow != BalanceOverflowPolicy::default() {
+            return self.balance_overflow;
+        }
+        match std::env::var("UBT_BALANCE_OVERFLOW") {
+            Ok(s) => BalanceOverflowPolicy::from_str(&s, true).unwrap_or_else(|_| {
+                tracing::warn!(value = %s, "Invalid UBT_BALANCE_OVERFLOW, using default");
+                self.balance_overflow
+            }),
+            Err(_) => self.balance_overflow,
+        }
+    }
+
+    /// Get map-full policy, with env var fallback.
+    ///
+    /// Precedence: CLI arg (if not default) > UBT_MAP_FULL env var > default
+    pub fn get_map_full_policy(&self) -> MapFullPolicy {
+        if self.map_full != MapFullPolicy::default() {
+            return self.map_full;
+        }
+        match std::env::var("UBT_MAP_FULL") {
+            Ok(s) => MapFullPolicy::from_str(&s, true).unwrap_or_else(|_| {
+                tracing::warn!(value = %s, "Invalid UBT_MAP_FULL, using default");
+                self.map_full
+            }),
+            Err(_) => self.map_full,
+        }
+    }
+
+    /// Get tree hasher, with env var fallback.
+    ///
+    /// Precedence: CLI arg (if not default) > UBT_HASHER env var > default
+    pub fn get_tree_hasher(&self) -> TreeHasher {
+        if self.hasher != TreeHasher::default() {
+            return self.hasher;
+        }
+        match std::env::var("UBT_HASHER") {
+            Ok(s) => TreeHasher::from_str(&s, true).unwrap_or_else(|_| {
+                tracing::warn!(value = %s, "Invalid UBT_HASHER, using default");
+                self.hasher
+            }),
+            Err(_) => self.hasher,
+        }
+    }
+
+    /// Get startup root verification mode, with env var fallback.
+    ///
+    /// Precedence: CLI arg (if not default) > UBT_VERIFY_MODE env var > default
+    pub fn get_verify_mode(&self) -> VerifyMode {
+        if self.verify_mode != VerifyMode::default() {
+            return self.verify_mode;
+        }
+        match std::env::var("UBT_VERIFY_MODE") {
+            Ok(s) => VerifyMode::from_str(&s, true).unwrap_or_else(|_| {
+                tracing::warn!(value = %s, "Invalid UBT_VERIFY_MODE, using default");
+                self.verify_mode
+            }),
+            Err(_) => self.verify_mode,
+        }
+    }
+
+    /// Get flush root computation mode, with env var fallback.
+    ///
+    /// Precedence: CLI arg (if not default) > UBT_ROOT_COMPUTATION env var > default
+    pub fn get_root_computation(&self) -> RootComputation {
+        if self.root_computation != RootComputation::default() {
+            return self.root_computation;
+        }
+        match std::env::var("UBT_ROOT_COMPUTATION") {
+            Ok(s) => RootComputation::from_str(&s, true).unwrap_or_else(|_| {
+                tracing::warn!(value = %s, "Invalid UBT_ROOT_COMPUTATION, using default");
+                self.root_computation
+            }),
+            Err(_) => self.root_computation,
+        }
+    }
+
     /// Get HTTP RPC address with env var fallback.
     pub fn get_rpc_http_addr(&self) -> Option<String> {
         if let Some(addr) = &self.rpc_http_addr {
@@ -123,6 +309,12 @@ impl UbtConfig {
             data_dir: Some(data_dir),
             flush_interval: 1,
             delta_retention: 1024,
+            root_retention: DEFAULT_ROOT_RETENTION,
+            balance_overflow: BalanceOverflowPolicy::default(),
+            map_full: MapFullPolicy::default(),
+            hasher: TreeHasher::default(),
+            verify_mode: VerifyMode::default(),
+            root_computation: RootComputation::default(),
             disabled: false,
             rpc_http_addr: Some(DEFAULT_RPC_HTTP_ADDR.to_string()),
             rpc_ipc_path: Some(PathBuf::from(DEFAULT_RPC_IPC_PATH)),
@@ -136,6 +328,12 @@ impl Default for UbtConfig {
             data_dir: None,
             flush_interval: DEFAULT_FLUSH_INTERVAL,
             delta_retention: DEFAULT_DELTA_RETENTION,
+            root_retention: DEFAULT_ROOT_RETENTION,
+            balance_overflow: BalanceOverflowPolicy::default(),
+            map_full: MapFullPolicy::default(),
+            hasher: TreeHasher::default(),
+            verify_mode: VerifyMode::default(),
+            root_computation: RootComputation::default(),
             disabled: false,
             rpc_http_addr: None,
             rpc_ipc_path: None,
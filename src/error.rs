@@ -12,14 +12,35 @@ pub enum UbtError {
     Serialization(#[from] bincode::Error),
 
     #[error("State extraction error: {message}")]
-    #[allow(dead_code)]
     StateExtraction { message: String },
 
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
+    #[error("Balance of {address} at block {block_number} exceeds u128: {balance}")]
+    BalanceOverflow {
+        address: String,
+        block_number: u64,
+        balance: String,
+    },
+
+    #[error("Database was built with the {stored} hasher but {configured} is configured")]
+    HasherMismatch { stored: String, configured: String },
+
+    #[error("Database schema version {stored} is newer than the supported version {supported}")]
+    SchemaTooNew { stored: u32, supported: u32 },
+
+    #[error("Backup failed: {message}")]
+    Backup { message: String },
+
+    #[error("Cannot bring MDBX, NOMT and the key index to a common block: {message}")]
+    InconsistentStores { message: String },
+
     #[error("Root verification failed: expected {expected}, computed {computed}")]
     RootVerificationFailed { expected: String, computed: String },
+
+    #[error("Cannot revert to block {target}: the bootstrap read plain state at block {floor}")]
+    RevertBelowBootstrap { target: u64, floor: u64 },
 }
 
 #[derive(Error, Debug)]
@@ -32,6 +53,16 @@ pub enum DatabaseError {
 
     #[error("Transaction failed: {0}")]
     Transaction(String),
+
+    #[error("MDBX map is full; raise UBT_MDBX_MAX_SIZE or use the grow map-full policy")]
+    MapFull,
+}
+
+impl UbtError {
+    /// Whether this is an MDBX write that failed because the map reached its upper bound.
+    pub fn is_map_full(&self) -> bool {
+        matches!(self, UbtError::Database(DatabaseError::MapFull))
+    }
 }
 
 pub type Result<T> = std::result::Result<T, UbtError>;
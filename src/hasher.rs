@@ -0,0 +1,257 @@
+//! Tree hash function selection.
+//!
+//! EIP-7864 drafts have specified both BLAKE3 and SHA-256 for internal nodes. The
+//! hasher is chosen once per database (see `UbtConfig::get_tree_hasher`) and
+//! recorded in `ubt_meta`; root computation and NOMT are dispatched on it here so
+//! the rest of the crate only carries a `TreeHasher` value.
+
+use alloy_primitives::B256;
+use clap::ValueEnum;
+use nomt::hasher::{Blake3Hasher as NomtBlake3Hasher, HashAlgorithm, Sha2Hasher as NomtSha2Hasher};
+use nomt::trie::KeyPath;
+use nomt::{KeyReadWrite, Nomt, Options as NomtOptions};
+use rayon::prelude::*;
+use std::path::Path;
+use ubt::{Blake3Hasher, Hasher, Sha256Hasher, Stem, StemNode, StreamingTreeBuilder, TreeKey};
+
+use crate::error::{DatabaseError, Result, UbtError};
+
+/// Hash function used for tree nodes.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, serde::Serialize, serde::Deserialize,
+)]
+pub enum TreeHasher {
+    #[default]
+    Blake3,
+    Sha256,
+}
+
+impl TreeHasher {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TreeHasher::Blake3 => "blake3",
+            TreeHasher::Sha256 => "sha256",
+        }
+    }
+
+    /// Compute the root of a set of entries sorted by key.
+    pub fn root_from_sorted_entries(
+        self,
+        entries: impl IntoIterator<Item = (TreeKey, B256)>,
+    ) -> B256 {
+        match self {
+            TreeHasher::Blake3 => root_with::<Blake3Hasher>(entries),
+            TreeHasher::Sha256 => root_with::<Sha256Hasher>(entries),
+        }
+    }
+
+    /// Compute the root from a fallible stream of sorted entries, such as a
+    /// database iterator. Stops at and returns the first error.
+    pub fn try_root_from_sorted_entries(
+        self,
+        entries: impl IntoIterator<Item = Result<(TreeKey, B256)>>,
+    ) -> Result<B256> {
+        let mut error = None;
+        let root = self.root_from_sorted_entries(entries.into_iter().map_while(|entry| {
+            entry.map_err(|e| error = Some(e)).ok()
+        }));
+        match error {
+            Some(e) => Err(e),
+            None => Ok(root),
+        }
+    }
+
+    /// Hash of a stem node, which is also the root of a tree holding only that stem.
+    pub fn hash_stem(self, stem: Stem, node: &StemNode) -> B256 {
+        let mut entries: Vec<_> = node
+            .values
+            .iter()
+            .map(|(&subindex, &value)| (TreeKey::new(stem, subindex), value))
+            .collect();
+        entries.sort_unstable_by_key(|(key, _)| key.subindex);
+        self.root_from_sorted_entries(entries)
+    }
+
+    /// Hashes of stem nodes, computed in parallel; `None` for a stem without values,
+    /// which is deleted.
+    pub fn hash_stems(self, stems: &[(Stem, StemNode)]) -> Vec<(Stem, Option<B256>)> {
+        stems
+            .par_iter()
+            .map(|(stem, node)| {
+                let hash = (!node.values.is_empty()).then(|| self.hash_stem(*stem, node));
+                (*stem, hash)
+            })
+            .collect()
+    }
+
+    /// Hash of an internal node from its children; a node with two empty children
+    /// is itself empty.
+    pub fn hash_internal(self, left: B256, right: B256) -> B256 {
+        if left == B256::ZERO && right == B256::ZERO {
+            return B256::ZERO;
+        }
+        match self {
+            TreeHasher::Blake3 => Blake3Hasher::default().hash_64(&left, &right),
+            TreeHasher::Sha256 => Sha256Hasher::default().hash_64(&left, &right),
+        }
+    }
+}
+
+impl std::fmt::Display for TreeHasher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+fn root_with<H: Hasher>(entries: impl IntoIterator<Item = (TreeKey, B256)>) -> B256 {
+    StreamingTreeBuilder::<H>::new().build_root_hash_parallel(entries)
+}
+
+fn nomt_error(e: impl std::fmt::Display) -> UbtError {
+    UbtError::Database(DatabaseError::Mdbx(e.to_string()))
+}
+
+/// NOMT key holding the last block committed to NOMT, as big-endian u64.
+pub const NOMT_HEAD_KEY: KeyPath = [0xff; 32];
+
+/// NOMT instance opened with the hasher matching the tree.
+pub enum NomtDb {
+    Blake3(Nomt<NomtBlake3Hasher>),
+    Sha256(Nomt<NomtSha2Hasher>),
+    /// Closed by `close` until `reopen`; every access fails.
+    Closed(TreeHasher),
+}
+
+impl NomtDb {
+    pub fn open(path: &Path, hasher: TreeHasher) -> Result<Self> {
+        let mut opts = NomtOptions::new();
+        opts.path(path);
+        opts.rollback(true);
+        opts.commit_concurrency(1);
+
+        Ok(match hasher {
+            TreeHasher::Blake3 => NomtDb::Blake3(Nomt::open(opts).map_err(nomt_error)?),
+            TreeHasher::Sha256 => NomtDb::Sha256(Nomt::open(opts).map_err(nomt_error)?),
+        })
+    }
+
+    /// Close the instance, releasing its directory so it can be copied.
+    pub fn close(&mut self) {
+        *self = NomtDb::Closed(self.hasher());
+    }
+
+    /// Open `path` again with the same hasher after `close`.
+    pub fn reopen(&mut self, path: &Path) -> Result<()> {
+        *self = NomtDb::open(path, self.hasher())?;
+        Ok(())
+    }
+
+    pub fn hasher(&self) -> TreeHasher {
+        match self {
+            NomtDb::Blake3(_) => TreeHasher::Blake3,
+            NomtDb::Sha256(_) => TreeHasher::Sha256,
+            NomtDb::Closed(hasher) => *hasher,
+        }
+    }
+
+    pub fn read(&self, key: KeyPath) -> Result<Option<Vec<u8>>> {
+        match self {
+            NomtDb::Blake3(nomt) => nomt.read(key).map_err(nomt_error),
+            NomtDb::Sha256(nomt) => nomt.read(key).map_err(nomt_error),
+            NomtDb::Closed(_) => Err(nomt_error("NOMT is closed")),
+        }
+    }
+
+    /// Last block committed to NOMT, or `None` if no block has been.
+    pub fn head(&self) -> Result<Option<u64>> {
+        match self.read(NOMT_HEAD_KEY)? {
+            Some(value) => {
+                let bytes: [u8; 8] = value
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| nomt_error("Invalid NOMT head value"))?;
+                Ok(Some(u64::from_be_bytes(bytes)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn rollback(&self, n: usize) -> Result<()> {
+        match self {
+            NomtDb::Blake3(nomt) => nomt.rollback(n).map_err(nomt_error),
+            NomtDb::Sha256(nomt) => nomt.rollback(n).map_err(nomt_error),
+            NomtDb::Closed(_) => Err(nomt_error("NOMT is closed")),
+        }
+    }
+
+    /// Apply a batch of writes, which must be sorted by key path, in one session.
+    pub fn write(&self, updates: Vec<(KeyPath, KeyReadWrite)>) -> Result<()> {
+        match self {
+            NomtDb::Blake3(nomt) => write_with(nomt, updates),
+            NomtDb::Sha256(nomt) => write_with(nomt, updates),
+            NomtDb::Closed(_) => Err(nomt_error("NOMT is closed")),
+        }
+    }
+}
+
+fn write_with<T: HashAlgorithm>(nomt: &Nomt<T>, updates: Vec<(KeyPath, KeyReadWrite)>) -> Result<()> {
+    let session = nomt.begin_session(Default::default());
+    for (path, _) in &updates {
+        session.warm_up(*path);
+    }
+    let finished = session.finish(updates).map_err(nomt_error)?;
+    finished.commit(nomt).map_err(nomt_error)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ubt::Stem;
+
+    #[test]
+    fn test_hashers_produce_different_roots() {
+        let entries = vec![(
+            TreeKey {
+                stem: Stem::new([1u8; 31]),
+                subindex: 0,
+            },
+            B256::repeat_byte(0x42),
+        )];
+
+        let blake3 = TreeHasher::Blake3.root_from_sorted_entries(entries.clone());
+        let sha256 = TreeHasher::Sha256.root_from_sorted_entries(entries);
+        assert_ne!(blake3, B256::ZERO);
+        assert_ne!(sha256, B256::ZERO);
+        assert_ne!(blake3, sha256);
+    }
+
+    #[test]
+    fn test_root_is_built_from_stem_and_internal_hashes() {
+        let mut left = StemNode::new(Stem::new([0u8; 31]));
+        left.set_value(0, B256::repeat_byte(0x01));
+        left.set_value(7, B256::repeat_byte(0x02));
+        let mut right = StemNode::new(Stem::new([0x80; 31]));
+        right.set_value(3, B256::repeat_byte(0x03));
+
+        for hasher in [TreeHasher::Blake3, TreeHasher::Sha256] {
+            let left_hash = hasher.hash_stem(Stem::new([0u8; 31]), &left);
+            let right_hash = hasher.hash_stem(Stem::new([0x80; 31]), &right);
+
+            // The stems differ in their first bit, so they are the root's children.
+            let entries = vec![
+                (TreeKey::new(Stem::new([0u8; 31]), 0), B256::repeat_byte(0x01)),
+                (TreeKey::new(Stem::new([0u8; 31]), 7), B256::repeat_byte(0x02)),
+                (TreeKey::new(Stem::new([0x80; 31]), 3), B256::repeat_byte(0x03)),
+            ];
+            assert_eq!(
+                hasher.root_from_sorted_entries(entries),
+                hasher.hash_internal(left_hash, right_hash)
+            );
+        }
+        assert_eq!(
+            TreeHasher::Blake3.hash_internal(B256::ZERO, B256::ZERO),
+            B256::ZERO
+        );
+    }
+}
@@ -0,0 +1,573 @@
+//! Persisted internal node hashes for incremental root computation.
+//!
+//! An internal node exists at every bit path shared by at least two stems and
+//! hashes its two children. A subtree holding a single stem hashes to that stem's
+//! node, and an empty subtree to zero. Bits are taken from the stem most
+//! significant first, so stems under a path are a contiguous key range.
+//!
+//! `ubt_internal_nodes` stores every internal node under `node_key`. A flush
+//! rehashes only the paths above its dirty stems (`IncrementalRoot`) and reads all
+//! other children from the table, and stem hashes from `ubt_stem_hashes`; the node
+//! writes go into the flush's `WriteBatch`. The table is only trusted while
+//! `internal_nodes` is set in `ubt_meta`: stem writes without matching node writes
+//! clear it, and `UbtDatabase::rebuild_internal_nodes` rebuilds the table and sets
+//! it again.
+//!
+//! Without valid internal nodes, `StemHashRoot` combines the cached stem hashes of
+//! every stem in one pass, which avoids rehashing unchanged stems.
+
+use alloy_primitives::B256;
+use std::collections::BTreeMap;
+use ubt::{Stem, STEM_LEN};
+
+use crate::error::{DatabaseError, Result, UbtError};
+use crate::hasher::TreeHasher;
+use crate::mdbx::Cursor;
+
+/// Bits in a stem; internal nodes sit at depths `0..STEM_BITS`.
+pub const STEM_BITS: usize = STEM_LEN * 8;
+
+/// Depth of the subtrees `UbtDatabase::rebuild_internal_nodes` builds one write
+/// transaction at a time: one per first stem byte.
+pub const REBUILD_BUCKET_DEPTH: usize = 8;
+
+/// Key of an internal node: its bit path zero-padded to a stem, then its depth.
+/// A node sorts before every node below it, and the nodes below a path form one
+/// contiguous range (see `subtree_keys`).
+pub type NodeKey = [u8; STEM_LEN + 1];
+
+/// Internal node writes of one flush; `None` deletes the node.
+pub type NodeUpdates = BTreeMap<NodeKey, Option<B256>>;
+
+pub fn node_key(path: &[u8], depth: usize) -> NodeKey {
+    let mut key = [0u8; STEM_LEN + 1];
+    key[..STEM_LEN].copy_from_slice(&fill_path(path, depth, false));
+    key[STEM_LEN] = depth as u8;
+    key
+}
+
+/// First and last key of the nodes at `depth` or deeper under `path`.
+fn subtree_keys(path: &[u8], depth: usize) -> (NodeKey, NodeKey) {
+    let mut last = [0xffu8; STEM_LEN + 1];
+    last[..STEM_LEN].copy_from_slice(&fill_path(path, depth, true));
+    (node_key(path, depth), last)
+}
+
+/// Bit `depth` of a stem or path.
+pub fn bit(path: &[u8], depth: usize) -> u8 {
+    (path[depth / 8] >> (7 - depth % 8)) & 1
+}
+
+/// `path` with bit `depth` set.
+fn with_bit(path: &[u8; STEM_LEN], depth: usize) -> [u8; STEM_LEN] {
+    let mut out = *path;
+    out[depth / 8] |= 0x80 >> (depth % 8);
+    out
+}
+
+/// The first `depth` bits of `path`, with the remaining bits all zero or all one.
+/// Zero and one fill give the first and last stem under the path.
+fn fill_path(path: &[u8], depth: usize, ones: bool) -> [u8; STEM_LEN] {
+    let fill = if ones { 0xff } else { 0 };
+    let mut out = [fill; STEM_LEN];
+    let full = depth / 8;
+    out[..full].copy_from_slice(&path[..full]);
+    let rem = depth % 8;
+    if rem > 0 {
+        let keep = 0xffu8 << (8 - rem);
+        out[full] = (path[full] & keep) | (fill & !keep);
+    }
+    out
+}
+
+fn mdbx_error(e: crate::mdbx::Error) -> UbtError {
+    UbtError::Database(DatabaseError::Mdbx(e.to_string()))
+}
+
+/// Decode a stored stem or internal node hash.
+pub fn decode_hash(value: &[u8]) -> Result<B256> {
+    if value.len() != 32 {
+        return Err(UbtError::Database(DatabaseError::Mdbx(format!(
+            "Invalid stored hash length: expected 32, got {}",
+            value.len()
+        ))));
+    }
+    Ok(B256::from_slice(value))
+}
+
+/// Number of leading bits two different stems share, which is the depth of the
+/// internal node where they split.
+fn split_depth(a: &[u8], b: &[u8]) -> usize {
+    a.iter()
+        .zip(b)
+        .position(|(x, y)| x != y)
+        .map(|i| i * 8 + (a[i] ^ b[i]).leading_zeros() as usize)
+        .unwrap_or(STEM_BITS)
+}
+
+/// Hash of a subtree and how many stems it holds, counted up to two.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Subtree {
+    pub stems: usize,
+    pub hash: B256,
+}
+
+impl Subtree {
+    pub const EMPTY: Subtree = Subtree {
+        stems: 0,
+        hash: B256::ZERO,
+    };
+
+    /// Join two sibling subtrees at `path`/`depth`, recording the internal node
+    /// in `nodes` if the result holds more than one stem. Only a single stem
+    /// passes up unhashed; several stems beside an empty sibling still get a node.
+    fn join(
+        hasher: TreeHasher,
+        path: &[u8; STEM_LEN],
+        depth: usize,
+        left: Subtree,
+        right: Subtree,
+        nodes: &mut Vec<(NodeKey, B256)>,
+    ) -> Subtree {
+        match (left.stems, right.stems) {
+            (0, 0) => Subtree::EMPTY,
+            (0, 1) => right,
+            (1, 0) => left,
+            _ => {
+                let hash = hasher.hash_internal(left.hash, right.hash);
+                nodes.push((node_key(path, depth), hash));
+                Subtree { stems: 2, hash }
+            }
+        }
+    }
+}
+
+/// Build the subtree at `path`/`depth` from its stems (sorted, with their hashes),
+/// pushing every internal node to `nodes`.
+pub fn build_subtree(
+    hasher: TreeHasher,
+    path: &[u8; STEM_LEN],
+    depth: usize,
+    stems: &[(Stem, B256)],
+    nodes: &mut Vec<(NodeKey, B256)>,
+) -> Subtree {
+    match stems {
+        [] => Subtree::EMPTY,
+        [(_, hash)] => Subtree {
+            stems: 1,
+            hash: *hash,
+        },
+        _ => {
+            let split = stems.partition_point(|(stem, _)| bit(stem.as_bytes(), depth) == 0);
+            let left = build_subtree(hasher, path, depth + 1, &stems[..split], nodes);
+            let right = build_subtree(
+                hasher,
+                &with_bit(path, depth),
+                depth + 1,
+                &stems[split..],
+                nodes,
+            );
+            Subtree::join(hasher, path, depth, left, right, nodes)
+        }
+    }
+}
+
+/// Join the `2^(REBUILD_BUCKET_DEPTH - depth)` buckets under `path`/`depth` into
+/// one subtree, pushing the internal nodes above the buckets to `nodes`.
+pub fn build_top(
+    hasher: TreeHasher,
+    path: &[u8; STEM_LEN],
+    depth: usize,
+    buckets: &[Subtree],
+    nodes: &mut Vec<(NodeKey, B256)>,
+) -> Subtree {
+    if let [bucket] = buckets {
+        return *bucket;
+    }
+    let (left, right) = buckets.split_at(buckets.len() / 2);
+    let left = build_top(hasher, path, depth + 1, left, nodes);
+    let right = build_top(hasher, &with_bit(path, depth), depth + 1, right, nodes);
+    Subtree::join(hasher, path, depth, left, right, nodes)
+}
+
+/// Root of a tree from the hashes of its stems, pushed in stem order, in memory
+/// bounded by the stem length rather than the number of stems.
+pub struct StemHashRoot {
+    hasher: TreeHasher,
+    /// Depth and finished left child of each internal node whose right child holds
+    /// the latest stem, shallowest first.
+    splits: Vec<(usize, B256)>,
+    /// The subtree holding the latest stem.
+    open: Option<OpenSubtree>,
+}
+
+/// A subtree whose stems end with `stem`, rooted at `depth`.
+struct OpenSubtree {
+    stem: Stem,
+    depth: usize,
+    hash: B256,
+    single: bool,
+}
+
+impl OpenSubtree {
+    /// Move the root up to `depth`, hashing in each node on the way, whose other
+    /// child is empty. A single stem hashes to itself at any depth.
+    fn lift(&mut self, hasher: TreeHasher, depth: usize) {
+        if !self.single {
+            for d in (depth..self.depth).rev() {
+                self.hash = if bit(self.stem.as_bytes(), d) == 0 {
+                    hasher.hash_internal(self.hash, B256::ZERO)
+                } else {
+                    hasher.hash_internal(B256::ZERO, self.hash)
+                };
+            }
+        }
+        self.depth = depth;
+    }
+}
+
+impl StemHashRoot {
+    pub fn new(hasher: TreeHasher) -> Self {
+        Self {
+            hasher,
+            splits: Vec::new(),
+            open: None,
+        }
+    }
+
+    /// Add the next stem, which must sort after every stem pushed so far.
+    pub fn push(&mut self, stem: Stem, hash: B256) {
+        if let Some(mut open) = self.open.take() {
+            let split = split_depth(open.stem.as_bytes(), stem.as_bytes());
+            debug_assert!(
+                split < STEM_BITS && open.stem < stem,
+                "stems must be pushed in order"
+            );
+            self.close(&mut open, split + 1);
+            self.splits.push((split, open.hash));
+        }
+        self.open = Some(OpenSubtree {
+            stem,
+            depth: STEM_BITS,
+            hash,
+            single: true,
+        });
+    }
+
+    pub fn finish(mut self) -> B256 {
+        match self.open.take() {
+            Some(mut open) => {
+                self.close(&mut open, 0);
+                open.hash
+            }
+            None => B256::ZERO,
+        }
+    }
+
+    /// Join `open` with the finished left children of every split at `depth` or
+    /// deeper, leaving it rooted at `depth`.
+    fn close(&mut self, open: &mut OpenSubtree, depth: usize) {
+        while let Some(&(split, left)) = self.splits.last() {
+            if split < depth {
+                break;
+            }
+            self.splits.pop();
+            open.lift(self.hasher, split + 1);
+            open.hash = self.hasher.hash_internal(left, open.hash);
+            open.depth = split;
+            open.single = false;
+        }
+        open.lift(self.hasher, depth);
+    }
+}
+
+/// Root computation over MDBX plus a sorted overlay of stem hashes (see
+/// `TreeHasher::hash_stems`), rehashing only the paths above overlay stems and
+/// reading every other subtree from `ubt_internal_nodes`. Stored stems are found,
+/// with their hashes, in `ubt_stem_hashes`.
+///
+/// Both cursors belong to one read transaction, so the result describes a single
+/// snapshot of MDBX with the overlay applied. The node writes that bring the table
+/// in line with the overlay are collected for the flush that writes it.
+pub struct IncrementalRoot<'txn, 'a> {
+    hasher: TreeHasher,
+    overlay: &'a [(Stem, Option<B256>)],
+    stem_hashes: Cursor<'txn>,
+    nodes: Cursor<'txn>,
+    updates: NodeUpdates,
+}
+
+impl<'txn, 'a> IncrementalRoot<'txn, 'a> {
+    pub fn new(
+        hasher: TreeHasher,
+        overlay: &'a [(Stem, Option<B256>)],
+        stem_hashes: Cursor<'txn>,
+        nodes: Cursor<'txn>,
+    ) -> Self {
+        Self {
+            hasher,
+            overlay,
+            stem_hashes,
+            nodes,
+            updates: NodeUpdates::new(),
+        }
+    }
+
+    /// Compute the root and the node writes for the overlay.
+    ///
+    /// Fails if a subtree without overlay stems has no stored node although it
+    /// holds several stems, which means the table does not match `ubt_stems`.
+    pub fn compute(mut self) -> Result<(B256, NodeUpdates)> {
+        let overlay = self.overlay;
+        let root = self.subtree([0u8; STEM_LEN], 0, overlay)?;
+        Ok((root, self.updates))
+    }
+
+    fn subtree(
+        &mut self,
+        path: [u8; STEM_LEN],
+        depth: usize,
+        dirty: &'a [(Stem, Option<B256>)],
+    ) -> Result<B256> {
+        if dirty.is_empty() {
+            if let Some(hash) = self.stored_node(&path, depth)? {
+                return Ok(hash);
+            }
+        }
+
+        let found = self.stems_under(&path, depth)?;
+        if found.len() < 2 {
+            if !dirty.is_empty() {
+                self.delete_nodes_under(&path, depth)?;
+            }
+            return Ok(found.first().map_or(B256::ZERO, |(_, hash)| *hash));
+        }
+        if dirty.is_empty() {
+            return Err(UbtError::Database(DatabaseError::Mdbx(format!(
+                "Missing internal node at depth {depth} under path {}",
+                hex::encode(path)
+            ))));
+        }
+
+        let split = dirty.partition_point(|(stem, _)| bit(stem.as_bytes(), depth) == 0);
+        let left = self.subtree(path, depth + 1, &dirty[..split])?;
+        let right = self.subtree(with_bit(&path, depth), depth + 1, &dirty[split..])?;
+        let hash = self.hasher.hash_internal(left, right);
+        self.updates.insert(node_key(&path, depth), Some(hash));
+        Ok(hash)
+    }
+
+    fn stored_node(&mut self, path: &[u8; STEM_LEN], depth: usize) -> Result<Option<B256>> {
+        let key = node_key(path, depth);
+        match self
+            .nodes
+            .set_range::<Vec<u8>, Vec<u8>>(&key)
+            .map_err(mdbx_error)?
+        {
+            Some((found, value)) if found == key => Ok(Some(decode_hash(&value)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Up to two stems under `path`/`depth` with their hashes, the overlay replacing
+    /// stored stems.
+    fn stems_under(&mut self, path: &[u8; STEM_LEN], depth: usize) -> Result<Vec<(Stem, B256)>> {
+        let first = fill_path(path, depth, false);
+        let last = fill_path(path, depth, true);
+        let overlay = self.overlay;
+        let mut found = Vec::with_capacity(2);
+
+        let start = overlay.partition_point(|(stem, _)| stem.as_bytes()[..] < first[..]);
+        for (stem, hash) in &overlay[start..] {
+            if found.len() == 2 || stem.as_bytes()[..] > last[..] {
+                break;
+            }
+            if let Some(hash) = hash {
+                found.push((*stem, *hash));
+            }
+        }
+
+        let mut entry = self
+            .stem_hashes
+            .set_range::<Vec<u8>, Vec<u8>>(&first)
+            .map_err(mdbx_error)?;
+        while found.len() < 2 {
+            let Some((key, value)) = entry else { break };
+            if key.as_slice() > &last[..] {
+                break;
+            }
+            if let Ok(bytes) = <[u8; STEM_LEN]>::try_from(key.as_slice()) {
+                let stem = Stem::new(bytes);
+                if overlay
+                    .binary_search_by(|(dirty, _)| dirty.cmp(&stem))
+                    .is_err()
+                {
+                    found.push((stem, decode_hash(&value)?));
+                }
+            }
+            entry = self.stem_hashes.next().map_err(mdbx_error)?;
+        }
+        Ok(found)
+    }
+
+    /// Queue deletes for every stored node at `depth` or deeper under `path`, once
+    /// the subtree holds at most one stem.
+    fn delete_nodes_under(&mut self, path: &[u8; STEM_LEN], depth: usize) -> Result<()> {
+        let (first, last) = subtree_keys(path, depth);
+        let mut entry = self
+            .nodes
+            .set_range::<Vec<u8>, Vec<u8>>(&first)
+            .map_err(mdbx_error)?;
+        while let Some((key, _)) = entry {
+            if key.as_slice() > &last[..] {
+                break;
+            }
+            if let Ok(key) = NodeKey::try_from(key.as_slice()) {
+                self.updates.insert(key, None);
+            }
+            entry = self.nodes.next().map_err(mdbx_error)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stem_with(first: u8, second: u8) -> Stem {
+        let mut bytes = [0u8; STEM_LEN];
+        bytes[0] = first;
+        bytes[1] = second;
+        Stem::new(bytes)
+    }
+
+    #[test]
+    fn test_node_keys_group_subtrees() {
+        let path = stem_with(0b1010_0000, 0);
+        let (first, last) = subtree_keys(path.as_bytes(), 3);
+
+        // The node itself and every node below it fall in the range.
+        assert_eq!(first, node_key(path.as_bytes(), 3));
+        for (stem, depth) in [
+            (stem_with(0b1010_0000, 0), 4),
+            (stem_with(0b1011_1111, 0xff), 200),
+            (stem_with(0b1010_0000, 0), 100),
+        ] {
+            let key = node_key(stem.as_bytes(), depth);
+            assert!(first <= key && key <= last, "depth {depth}");
+        }
+
+        // Ancestors and nodes of other subtrees do not.
+        for (stem, depth) in [
+            (stem_with(0b1010_0000, 0), 2),
+            (stem_with(0b1000_0000, 0), 3),
+            (stem_with(0b1100_0000, 0), 8),
+        ] {
+            let key = node_key(stem.as_bytes(), depth);
+            assert!(key < first || key > last, "depth {depth}");
+        }
+    }
+
+    #[test]
+    fn test_fill_path() {
+        let path = [0xabu8; STEM_LEN];
+        let zeros = fill_path(&path, 12, false);
+        let ones = fill_path(&path, 12, true);
+        assert_eq!(&zeros[..2], &[0xab, 0xa0]);
+        assert!(zeros[2..].iter().all(|&b| b == 0));
+        assert_eq!(&ones[..2], &[0xab, 0xaf]);
+        assert!(ones[2..].iter().all(|&b| b == 0xff));
+        assert_eq!(fill_path(&path, STEM_BITS, false), path);
+    }
+
+    #[test]
+    fn test_build_subtree_places_nodes_at_divergence() {
+        let hasher = TreeHasher::Blake3;
+        let a = (stem_with(0b0000_0000, 0), B256::repeat_byte(1));
+        let b = (stem_with(0b0000_0001, 0), B256::repeat_byte(2));
+        let c = (stem_with(0b1000_0000, 0), B256::repeat_byte(3));
+
+        let mut nodes = Vec::new();
+        let root = build_subtree(hasher, &[0u8; STEM_LEN], 0, &[a, b, c], &mut nodes);
+
+        // a and b diverge at bit 7, so a chain of nodes with empty siblings leads
+        // down to them; c sits alone in the right subtree of the root.
+        let mut expected = hasher.hash_internal(a.1, b.1);
+        for _ in 1..7 {
+            expected = hasher.hash_internal(expected, B256::ZERO);
+        }
+        let expected_root = hasher.hash_internal(expected, c.1);
+        assert_eq!(root.hash, expected_root);
+        assert_eq!(nodes.len(), 8);
+        assert!(nodes.contains(&(node_key(&[0u8; STEM_LEN], 0), expected_root)));
+    }
+
+    #[test]
+    fn test_build_top_matches_build_subtree() {
+        let hasher = TreeHasher::Sha256;
+        let stems: Vec<_> = [(0x00, 1), (0x00, 2), (0x40, 0), (0xff, 9)]
+            .into_iter()
+            .map(|(first, second)| (stem_with(first, second), B256::repeat_byte(second + 1)))
+            .collect();
+
+        let mut direct = Vec::new();
+        let expected = build_subtree(hasher, &[0u8; STEM_LEN], 0, &stems, &mut direct);
+
+        let mut bucketed = Vec::new();
+        let buckets: Vec<_> = (0..=u8::MAX)
+            .map(|bucket| {
+                let mut path = [0u8; STEM_LEN];
+                path[0] = bucket;
+                let start = stems.partition_point(|(stem, _)| stem.as_bytes()[0] < bucket);
+                let end = stems.partition_point(|(stem, _)| stem.as_bytes()[0] <= bucket);
+                build_subtree(
+                    hasher,
+                    &path,
+                    REBUILD_BUCKET_DEPTH,
+                    &stems[start..end],
+                    &mut bucketed,
+                )
+            })
+            .collect();
+        let root = build_top(hasher, &[0u8; STEM_LEN], 0, &buckets, &mut bucketed);
+
+        assert_eq!(root, expected);
+        direct.sort();
+        bucketed.sort();
+        assert_eq!(bucketed, direct);
+    }
+
+    #[test]
+    fn test_stem_hash_root_matches_build_subtree() {
+        let hasher = TreeHasher::Blake3;
+        let cases: Vec<Vec<(u8, u8)>> = vec![
+            vec![],
+            vec![(0x42, 0)],
+            vec![(0x00, 0), (0x00, 1)],
+            vec![
+                (0x00, 0),
+                (0x00, 1),
+                (0x00, 0x80),
+                (0x7f, 0),
+                (0x80, 0),
+                (0xff, 0xff),
+            ],
+            (0..=255u8).map(|i| (i, i.wrapping_mul(7))).collect(),
+        ];
+
+        for stems in cases {
+            let stems: Vec<_> = stems
+                .into_iter()
+                .map(|(first, second)| (stem_with(first, second), B256::repeat_byte(second | 1)))
+                .collect();
+            let expected = build_subtree(hasher, &[0u8; STEM_LEN], 0, &stems, &mut Vec::new());
+
+            let mut builder = StemHashRoot::new(hasher);
+            for (stem, hash) in &stems {
+                builder.push(*stem, *hash);
+            }
+            assert_eq!(builder.finish(), expected.hash, "{} stems", stems.len());
+        }
+    }
+}
@@ -1,18 +1,23 @@
 //! Key index for NOMT-only exports.
 //!
 //! Stores per-stem address + subindex bitmap so we can enumerate keys without MDBX.
+//! A reverse `address || stem` table lists the stems owned by each account, which is
+//! needed to clear every leaf of a self-destructed or storage-wiped account.
 
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 
 use alloy_primitives::{Address, B256};
-use redb::{Database, ReadableTable, TableDefinition, TableError};
+use redb::{Database, ReadableTable, ReadableTableMetadata, TableDefinition, TableError};
+use tracing::info;
 use ubt::Stem;
 
 use crate::error::{Result, UbtError};
 
 pub const KEY_INDEX_FILE: &str = "key-index.redb";
 const STEM_TABLE: TableDefinition<&[u8; 31], &[u8; 52]> = TableDefinition::new("ubt_stems");
+const ADDRESS_STEM_TABLE: TableDefinition<&[u8; 51], ()> =
+    TableDefinition::new("ubt_address_stems");
 const META_TABLE: TableDefinition<&str, &[u8; 80]> = TableDefinition::new("ubt_meta");
 const META_HEAD_KEY: &str = "head";
 
@@ -22,6 +27,20 @@ pub struct StemRecord {
     pub bitmap: [u8; 32],
 }
 
+impl StemRecord {
+    /// Iterate the subindices present in this stem, in ascending order.
+    pub fn subindices(&self) -> impl Iterator<Item = u8> + '_ {
+        (0u16..256).filter_map(move |idx| {
+            let byte = self.bitmap[(idx / 8) as usize];
+            if (byte & (1u8 << (idx % 8))) != 0 {
+                Some(idx as u8)
+            } else {
+                None
+            }
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct HeadRecord {
     pub block_number: u64,
@@ -49,7 +68,66 @@ impl KeyIndex {
         }
         .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
 
-        Ok(Self { db, path })
+        let index = Self { db, path };
+        index.ensure_address_index()?;
+        Ok(index)
+    }
+
+    /// Build the reverse address -> stem table for indexes created before it existed.
+    fn ensure_address_index(&self) -> Result<()> {
+        let write_txn = self
+            .db
+            .begin_write()
+            .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+
+        let rebuilt = {
+            let stems = write_txn
+                .open_table(STEM_TABLE)
+                .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+            let mut reverse = write_txn
+                .open_table(ADDRESS_STEM_TABLE)
+                .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+
+            let reverse_empty = reverse
+                .is_empty()
+                .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+            let stems_empty = stems
+                .is_empty()
+                .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+
+            if !reverse_empty || stems_empty {
+                0
+            } else {
+                info!("Building key index address table; this may take a while on large state");
+                let mut count = 0usize;
+                let iter = stems
+                    .iter()
+                    .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+                for entry in iter {
+                    let (key, value) = entry.map_err(|e| {
+                        UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string()))
+                    })?;
+                    let stem = Stem::new(*key.value());
+                    let (address, _) = split_value(value.value())?;
+                    reverse
+                        .insert(&address_stem_key(address, &stem), ())
+                        .map_err(|e| {
+                            UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string()))
+                        })?;
+                    count += 1;
+                }
+                count
+            }
+        };
+
+        write_txn
+            .commit()
+            .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+
+        if rebuilt > 0 {
+            info!(stems = rebuilt, "Key index address table built");
+        }
+        Ok(())
     }
 
     pub fn path(&self) -> &Path {
@@ -60,6 +138,23 @@ impl KeyIndex {
         &self.db
     }
 
+    /// Copy the index file to `dest`, which must not exist.
+    ///
+    /// Every redb write transaction is durable when it commits, so the file is
+    /// consistent as long as no write is in progress; callers copy between commits.
+    pub fn backup(&self, dest: &Path) -> Result<()> {
+        if dest.exists() {
+            return Err(UbtError::Backup {
+                message: format!("destination {} already exists", dest.display()),
+            });
+        }
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::copy(&self.path, dest)?;
+        Ok(())
+    }
+
     /// Update the index for a batch of (stem, subindex, address) entries.
     /// Returns the number of new stems added.
     pub fn apply_updates(
@@ -90,6 +185,9 @@ impl KeyIndex {
             let mut table = write_txn
                 .open_table(STEM_TABLE)
                 .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+            let mut reverse = write_txn
+                .open_table(ADDRESS_STEM_TABLE)
+                .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
 
             let mut new_stems = 0usize;
 
@@ -113,6 +211,11 @@ impl KeyIndex {
                     }
                 } else {
                     new_stems += 1;
+                    reverse
+                        .insert(&address_stem_key(address, &stem), ())
+                        .map_err(|e| {
+                            UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string()))
+                        })?;
                 }
 
                 let value = pack_value(address, merged_bitmap);
@@ -133,6 +236,186 @@ impl KeyIndex {
         Ok(new_stems)
     }
 
+    /// Clear the presence bits for a batch of deleted (stem, subindex) entries.
+    ///
+    /// Stems without a record are ignored. Stems whose bitmap becomes empty are
+    /// removed. Returns the number of stems removed.
+    pub fn clear_entries(&self, deletions: impl IntoIterator<Item = (Stem, u8)>) -> Result<usize> {
+        let mut per_stem: HashMap<Stem, [u8; 32]> = HashMap::new();
+        for (stem, subindex) in deletions {
+            set_bit(per_stem.entry(stem).or_insert([0u8; 32]), subindex);
+        }
+
+        if per_stem.is_empty() {
+            return Ok(0);
+        }
+
+        let write_txn = self
+            .db
+            .begin_write()
+            .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+
+        let removed_stems = {
+            let mut table = write_txn
+                .open_table(STEM_TABLE)
+                .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+            let mut reverse = write_txn
+                .open_table(ADDRESS_STEM_TABLE)
+                .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+
+            let mut removed_stems = 0usize;
+
+            for (stem, cleared) in per_stem {
+                let key = stem.as_bytes();
+                let existing = table
+                    .get(key)
+                    .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?
+                    .map(|value| split_value(value.value()))
+                    .transpose()?;
+
+                let Some((address, mut bitmap)) = existing else {
+                    continue;
+                };
+                for i in 0..32 {
+                    bitmap[i] &= !cleared[i];
+                }
+
+                if bitmap == [0u8; 32] {
+                    table.remove(key).map_err(|e| {
+                        UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string()))
+                    })?;
+                    reverse
+                        .remove(&address_stem_key(address, &stem))
+                        .map_err(|e| {
+                            UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string()))
+                        })?;
+                    removed_stems += 1;
+                    continue;
+                }
+
+                let value = pack_value(address, bitmap);
+                table
+                    .insert(key, &value)
+                    .map_err(|e| {
+                        UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string()))
+                    })?;
+            }
+
+            removed_stems
+        };
+
+        write_txn
+            .commit()
+            .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+
+        Ok(removed_stems)
+    }
+
+    /// Replace every stem record with `stems`, given as (stem, owning address,
+    /// present subindices). Used to bring the index back in line with MDBX after
+    /// an interrupted commit. Returns the number of stems written.
+    pub fn rebuild(
+        &self,
+        stems: impl IntoIterator<Item = Result<(Stem, Address, Vec<u8>)>>,
+    ) -> Result<u64> {
+        let write_txn = self
+            .db
+            .begin_write()
+            .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+
+        let count = {
+            write_txn
+                .delete_table(STEM_TABLE)
+                .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+            write_txn
+                .delete_table(ADDRESS_STEM_TABLE)
+                .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+            let mut table = write_txn
+                .open_table(STEM_TABLE)
+                .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+            let mut reverse = write_txn
+                .open_table(ADDRESS_STEM_TABLE)
+                .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+
+            let mut count = 0u64;
+            for entry in stems {
+                let (stem, address, subindices) = entry?;
+                let mut bitmap = [0u8; 32];
+                for subindex in subindices {
+                    set_bit(&mut bitmap, subindex);
+                }
+                if bitmap == [0u8; 32] {
+                    continue;
+                }
+
+                table
+                    .insert(stem.as_bytes(), &pack_value(address, bitmap))
+                    .map_err(|e| {
+                        UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string()))
+                    })?;
+                reverse
+                    .insert(&address_stem_key(address, &stem), ())
+                    .map_err(|e| {
+                        UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string()))
+                    })?;
+                count += 1;
+            }
+            count
+        };
+
+        write_txn
+            .commit()
+            .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+
+        Ok(count)
+    }
+
+    /// Load the stem records owned by an account, in stem order.
+    pub fn stems_for_address(&self, address: Address) -> Result<Vec<(Stem, StemRecord)>> {
+        let read_txn = self
+            .db
+            .begin_read()
+            .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+
+        let reverse = match read_txn.open_table(ADDRESS_STEM_TABLE) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(_)) => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(UbtError::Database(crate::error::DatabaseError::Mdbx(
+                    e.to_string(),
+                )))
+            }
+        };
+        let stems = read_txn
+            .open_table(STEM_TABLE)
+            .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+
+        let start = address_stem_key(address, &Stem::new([0x00; 31]));
+        let end = address_stem_key(address, &Stem::new([0xff; 31]));
+        let range = reverse
+            .range(&start..=&end)
+            .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+
+        let mut records = Vec::new();
+        for entry in range {
+            let (key, _) = entry
+                .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+            let mut stem_bytes = [0u8; 31];
+            stem_bytes.copy_from_slice(&key.value()[20..]);
+            let stem = Stem::new(stem_bytes);
+
+            if let Some(value) = stems
+                .get(&stem_bytes)
+                .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?
+            {
+                let (address, bitmap) = split_value(value.value())?;
+                records.push((stem, StemRecord { address, bitmap }));
+            }
+        }
+
+        Ok(records)
+    }
+
     pub fn save_head(
         &self,
         block_number: u64,
@@ -191,6 +474,26 @@ impl KeyIndex {
         Ok(Some(unpack_head(value.value())?))
     }
 
+    /// Number of stems currently in the index.
+    pub fn stem_count(&self) -> Result<u64> {
+        let read_txn = self
+            .db
+            .begin_read()
+            .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+        let table = match read_txn.open_table(STEM_TABLE) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(_)) => return Ok(0),
+            Err(e) => {
+                return Err(UbtError::Database(crate::error::DatabaseError::Mdbx(
+                    e.to_string(),
+                )))
+            }
+        };
+        table
+            .len()
+            .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))
+    }
+
     /// Iterate all stem records in sorted order, invoking the provided callback.
     pub fn for_each_stem<F>(&self, mut f: F) -> Result<()>
     where
@@ -231,6 +534,13 @@ fn set_bit(bitmap: &mut [u8; 32], subindex: u8) {
     bitmap[byte] |= 1u8 << bit;
 }
 
+fn address_stem_key(address: Address, stem: &Stem) -> [u8; 51] {
+    let mut key = [0u8; 51];
+    key[..20].copy_from_slice(address.as_slice());
+    key[20..].copy_from_slice(stem.as_bytes());
+    key
+}
+
 fn pack_value(address: Address, bitmap: [u8; 32]) -> [u8; 52] {
     let mut value = [0u8; 52];
     let addr_bytes: [u8; 20] = address.into_array();
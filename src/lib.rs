@@ -2,8 +2,12 @@
 //!
 //! This exposes internal modules for reuse in benchmarks and integrations.
 
+pub mod backup;
+pub mod bootstrap;
 pub mod config;
 pub mod error;
+pub mod hasher;
+pub mod internal_nodes;
 pub mod key_index;
 pub mod mdbx;
 pub mod metrics;
@@ -11,6 +15,7 @@ pub mod persistence;
 pub mod pir_export;
 pub mod rpc;
 pub mod rpc_server;
+pub mod stem_codec;
 pub mod ubt_exex;
 
 pub use ubt_exex::ubt_exex;
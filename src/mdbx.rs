@@ -21,11 +21,13 @@ use std::ptr;
 use std::rc::Rc;
 
 use mdbx_rs::{
-    mdbx_cursor_close, mdbx_cursor_get, mdbx_cursor_open, mdbx_dbi_open, mdbx_del, mdbx_env_close,
-    mdbx_env_create, mdbx_env_open, mdbx_env_set_geometry, mdbx_env_set_maxdbs, mdbx_env_sync_ex,
-    mdbx_get, mdbx_put, mdbx_strerror, mdbx_txn_abort, mdbx_txn_begin, mdbx_txn_commit,
-    MDBX_cursor, MDBX_cursor_op, MDBX_dbi, MDBX_env, MDBX_txn, MDBX_val, MDBX_CREATE,
-    MDBX_NOTFOUND, MDBX_RDONLY, MDBX_SUCCESS,
+    mdbx_cursor_close, mdbx_cursor_del, mdbx_cursor_get, mdbx_cursor_open, mdbx_dbi_open,
+    mdbx_dbi_stat, mdbx_del, mdbx_drop, mdbx_env_close, mdbx_env_copy, mdbx_env_create,
+    mdbx_env_info_ex, mdbx_env_open, mdbx_env_set_geometry, mdbx_env_set_maxdbs, mdbx_env_stat_ex,
+    mdbx_env_sync_ex, mdbx_get, mdbx_put, mdbx_strerror, mdbx_txn_abort, mdbx_txn_begin,
+    mdbx_txn_commit, MDBX_cursor, MDBX_cursor_op, MDBX_dbi, MDBX_env, MDBX_envinfo, MDBX_stat,
+    MDBX_txn, MDBX_val, MDBX_CP_COMPACT, MDBX_CREATE, MDBX_MAP_FULL, MDBX_NOTFOUND, MDBX_RDONLY,
+    MDBX_SUCCESS,
 };
 
 /// Error type for MDBX operations.
@@ -62,11 +64,19 @@ impl Error {
     pub fn is_not_found(&self) -> bool {
         self.code == MDBX_NOTFOUND
     }
+
+    /// Returns true if a write failed because the map reached its upper bound.
+    pub fn is_map_full(&self) -> bool {
+        self.code == MDBX_MAP_FULL
+    }
 }
 
 /// Result type for MDBX operations.
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Name of the data file MDBX creates inside an environment directory.
+pub const DATA_FILE: &str = "mdbx.dat";
+
 /// Check MDBX return code and convert to Result.
 fn check_rc(rc: i32) -> Result<()> {
     if rc == MDBX_SUCCESS {
@@ -106,6 +116,78 @@ impl Default for Geometry {
     }
 }
 
+/// Page statistics of an environment or a single database (`MDBX_stat`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stat {
+    /// Page size in bytes.
+    pub page_size: u32,
+    /// Depth of the B-tree.
+    pub depth: u32,
+    pub branch_pages: u64,
+    pub leaf_pages: u64,
+    /// Pages holding values too large for a leaf page.
+    pub overflow_pages: u64,
+    pub entries: u64,
+}
+
+impl Stat {
+    fn from_raw(stat: &MDBX_stat) -> Self {
+        Self {
+            page_size: stat.ms_psize,
+            depth: stat.ms_depth,
+            branch_pages: stat.ms_branch_pages,
+            leaf_pages: stat.ms_leaf_pages,
+            overflow_pages: stat.ms_overflow_pages,
+            entries: stat.ms_entries,
+        }
+    }
+
+    /// Pages used by the tree.
+    pub fn pages(&self) -> u64 {
+        self.branch_pages + self.leaf_pages + self.overflow_pages
+    }
+
+    /// Bytes used by the tree.
+    pub fn size_bytes(&self) -> u64 {
+        self.pages() * self.page_size as u64
+    }
+}
+
+/// Map geometry and usage of an environment (`MDBX_envinfo`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EnvInfo {
+    /// Page size of the data file in bytes.
+    pub page_size: u32,
+    /// Lower bound of the map size.
+    pub geo_lower: u64,
+    /// Upper bound of the map size; writes fail with `MDBX_MAP_FULL` beyond it.
+    pub geo_upper: u64,
+    /// Current size of the data file.
+    pub geo_current: u64,
+    /// Last used page number, so `last_pgno + 1` pages are allocated.
+    pub last_pgno: u64,
+    /// ID of the last committed transaction.
+    pub recent_txnid: u64,
+}
+
+impl EnvInfo {
+    fn from_raw(info: &MDBX_envinfo) -> Self {
+        Self {
+            page_size: info.mi_dxb_pagesize,
+            geo_lower: info.mi_geo.lower,
+            geo_upper: info.mi_geo.upper,
+            geo_current: info.mi_geo.current,
+            last_pgno: info.mi_last_pgno,
+            recent_txnid: info.mi_recent_txnid,
+        }
+    }
+
+    /// Bytes of the map in use, including free pages not yet reclaimed.
+    pub fn used_bytes(&self) -> u64 {
+        (self.last_pgno + 1) * self.page_size as u64
+    }
+}
+
 /// Database flags for opening/creating databases.
 #[derive(Debug, Clone, Copy, Default)]
 pub struct DatabaseFlags(u32);
@@ -176,6 +258,77 @@ impl Environment {
         check_rc(rc)
     }
 
+    /// Copy the environment to a new data file while it stays open.
+    ///
+    /// The copy is taken from a single read transaction, so it is a consistent
+    /// snapshot even with concurrent writers. `dest` is the data file to create
+    /// (it must not exist). Open it as an environment by placing it in an empty
+    /// directory as [`DATA_FILE`]. With `compact`, free pages are omitted and the
+    /// copy is renumbered, which is slower but can be much smaller.
+    pub fn copy(&self, dest: &Path, compact: bool) -> Result<()> {
+        let dest_cstr = path_to_cstring(dest)?;
+        let flags = if compact { MDBX_CP_COMPACT as u32 } else { 0 };
+        // SAFETY: env pointer is valid, dest_cstr is a valid C string.
+        let rc = unsafe { mdbx_env_copy(self.env, dest_cstr.as_ptr(), flags) };
+        check_rc(rc)
+    }
+
+    /// Change the geometry of the open environment.
+    ///
+    /// Fields set to -1 keep their current value, so raising only `size_upper`
+    /// lets writes that failed with `MDBX_MAP_FULL` be retried. Must not be called
+    /// while this process has a write transaction open.
+    pub fn set_geometry(&self, geometry: &Geometry) -> Result<()> {
+        // SAFETY: env pointer is valid, geometry values are within valid ranges.
+        let rc = unsafe {
+            mdbx_env_set_geometry(
+                self.env,
+                geometry.size_lower as isize,
+                geometry.size_now as isize,
+                geometry.size_upper as isize,
+                geometry.growth_step as isize,
+                geometry.shrink_threshold as isize,
+                geometry.page_size as isize,
+            )
+        };
+        check_rc(rc)
+    }
+
+    /// Page statistics of the whole environment.
+    pub fn stat(&self) -> Result<Stat> {
+        // SAFETY: MDBX_stat is plain data; all-zero is a valid value.
+        let mut stat: MDBX_stat = unsafe { std::mem::zeroed() };
+        // SAFETY: env pointer is valid, stat is a valid output buffer of the given size.
+        // A null txn reads the stats of the last committed transaction.
+        let rc = unsafe {
+            mdbx_env_stat_ex(
+                self.env,
+                ptr::null(),
+                &mut stat,
+                std::mem::size_of::<MDBX_stat>(),
+            )
+        };
+        check_rc(rc)?;
+        Ok(Stat::from_raw(&stat))
+    }
+
+    /// Map geometry and usage of the environment.
+    pub fn info(&self) -> Result<EnvInfo> {
+        // SAFETY: MDBX_envinfo is plain data; all-zero is a valid value.
+        let mut info: MDBX_envinfo = unsafe { std::mem::zeroed() };
+        // SAFETY: env pointer is valid, info is a valid output buffer of the given size.
+        let rc = unsafe {
+            mdbx_env_info_ex(
+                self.env,
+                ptr::null(),
+                &mut info,
+                std::mem::size_of::<MDBX_envinfo>(),
+            )
+        };
+        check_rc(rc)?;
+        Ok(EnvInfo::from_raw(&info))
+    }
+
     /// Begin a read-only transaction.
     pub fn begin_ro_txn(&self) -> Result<RoTransaction<'_>> {
         let mut txn: *mut MDBX_txn = ptr::null_mut();
@@ -222,6 +375,7 @@ impl Drop for Environment {
 pub struct EnvironmentBuilder {
     max_dbs: u32,
     geometry: Geometry,
+    read_only: bool,
 }
 
 impl EnvironmentBuilder {
@@ -229,6 +383,7 @@ impl EnvironmentBuilder {
         Self {
             max_dbs: 10,
             geometry: Geometry::default(),
+            read_only: false,
         }
     }
 
@@ -238,12 +393,22 @@ impl EnvironmentBuilder {
         self
     }
 
-    /// Set the database geometry.
+    /// Set the database geometry. Ignored for read-only environments, which use
+    /// the geometry stored in the database.
     pub fn set_geometry(mut self, geometry: Geometry) -> Self {
         self.geometry = geometry;
         self
     }
 
+    /// Open the environment read-only (`MDBX_RDONLY`).
+    ///
+    /// The database must already exist. Only read-only transactions can be begun;
+    /// write transactions fail.
+    pub fn read_only(mut self) -> Self {
+        self.read_only = true;
+        self
+    }
+
     /// Open the environment at the given path.
     pub fn open(self, path: &Path) -> Result<Environment> {
         let mut env: *mut MDBX_env = ptr::null_mut();
@@ -259,35 +424,42 @@ impl EnvironmentBuilder {
             return Err(Error::from_code(rc));
         }
 
-        // SAFETY: env pointer is valid, geometry values are within valid ranges.
-        let rc = unsafe {
-            mdbx_env_set_geometry(
-                env,
-                self.geometry.size_lower as isize,
-                self.geometry.size_now as isize,
-                self.geometry.size_upper as isize,
-                self.geometry.growth_step as isize,
-                self.geometry.shrink_threshold as isize,
-                self.geometry.page_size as isize,
-            )
-        };
-        if rc != MDBX_SUCCESS {
-            unsafe { mdbx_env_close(env) };
-            return Err(Error::from_code(rc));
+        if !self.read_only {
+            // SAFETY: env pointer is valid, geometry values are within valid ranges.
+            let rc = unsafe {
+                mdbx_env_set_geometry(
+                    env,
+                    self.geometry.size_lower as isize,
+                    self.geometry.size_now as isize,
+                    self.geometry.size_upper as isize,
+                    self.geometry.growth_step as isize,
+                    self.geometry.shrink_threshold as isize,
+                    self.geometry.page_size as isize,
+                )
+            };
+            if rc != MDBX_SUCCESS {
+                unsafe { mdbx_env_close(env) };
+                return Err(Error::from_code(rc));
+            }
         }
 
-        let path_str = path.to_str().ok_or_else(|| Error {
-            code: -22,
-            message: "Invalid path: not valid UTF-8".to_string(),
-        })?;
-        let path_cstr = CString::new(path_str).map_err(|_| Error {
-            code: -22,
-            message: "Invalid path: contains null byte".to_string(),
-        })?;
+        let path_cstr = match path_to_cstring(path) {
+            Ok(path_cstr) => path_cstr,
+            Err(e) => {
+                unsafe { mdbx_env_close(env) };
+                return Err(e);
+            }
+        };
+
+        let flags = if self.read_only {
+            MDBX_RDONLY as u32
+        } else {
+            0
+        };
 
         // SAFETY: env pointer is valid, path_cstr is a valid C string.
         // Mode 0o644 is standard file permissions.
-        let rc = unsafe { mdbx_env_open(env, path_cstr.as_ptr(), 0, 0o644) };
+        let rc = unsafe { mdbx_env_open(env, path_cstr.as_ptr(), flags, 0o644) };
         if rc != MDBX_SUCCESS {
             unsafe { mdbx_env_close(env) };
             return Err(Error::from_code(rc));
@@ -297,6 +469,17 @@ impl EnvironmentBuilder {
     }
 }
 
+fn path_to_cstring(path: &Path) -> Result<CString> {
+    let path_str = path.to_str().ok_or_else(|| Error {
+        code: -22,
+        message: "Invalid path: not valid UTF-8".to_string(),
+    })?;
+    CString::new(path_str).map_err(|_| Error {
+        code: -22,
+        message: "Invalid path: contains null byte".to_string(),
+    })
+}
+
 impl Default for EnvironmentBuilder {
     fn default() -> Self {
         Self::new()
@@ -391,6 +574,44 @@ impl<'env> RoTransaction<'env> {
             _not_send: PhantomData,
         })
     }
+
+    /// Page statistics of a database as of this transaction.
+    pub fn db_stat(&self, db: Database) -> Result<Stat> {
+        // SAFETY: MDBX_stat is plain data; all-zero is a valid value.
+        let mut stat: MDBX_stat = unsafe { std::mem::zeroed() };
+        // SAFETY: txn and dbi are valid, stat is a valid output buffer of the given size.
+        let rc = unsafe {
+            mdbx_dbi_stat(
+                self.txn,
+                db.dbi,
+                &mut stat,
+                std::mem::size_of::<MDBX_stat>(),
+            )
+        };
+        check_rc(rc)?;
+        Ok(Stat::from_raw(&stat))
+    }
+}
+
+impl<'env> RoTransaction<'env> {
+    /// Open a cursor that takes ownership of this transaction.
+    ///
+    /// Used for lazy iterators that must outlive the scope that began the
+    /// transaction. The transaction stays open until the cursor is dropped.
+    pub fn into_cursor(self, db: &Database) -> Result<OwnedCursor<'env>> {
+        let mut cursor: *mut MDBX_cursor = ptr::null_mut();
+        // SAFETY: txn and dbi are valid, cursor is a valid output pointer.
+        let rc = unsafe { mdbx_cursor_open(self.txn, db.dbi, &mut cursor) };
+        check_rc(rc)?;
+        Ok(OwnedCursor {
+            cursor: Cursor {
+                cursor,
+                _marker: PhantomData,
+                _not_send: PhantomData,
+            },
+            txn: self,
+        })
+    }
 }
 
 impl Drop for RoTransaction<'_> {
@@ -537,6 +758,14 @@ impl<'env> RwTransaction<'env> {
         }
     }
 
+    /// Delete every entry of a database, keeping the database itself.
+    pub fn clear_db(&self, db: Database) -> Result<()> {
+        // SAFETY: txn and dbi are valid; `false` empties the database instead of
+        // deleting it.
+        let rc = unsafe { mdbx_drop(self.txn, db.dbi, false) };
+        check_rc(rc)
+    }
+
     /// Create a cursor for iterating over the database.
     pub fn cursor(&self, db: &Database) -> Result<Cursor<'_>> {
         let mut cursor: *mut MDBX_cursor = ptr::null_mut();
@@ -572,6 +801,10 @@ impl Drop for RwTransaction<'_> {
 
 /// Cursor for iterating over database entries.
 ///
+/// Positioning methods return the entry the cursor lands on, or None if there is
+/// none (an empty database, or moving past either end). Cursors of write
+/// transactions can also delete the entry they are positioned at.
+///
 /// # Thread Safety
 ///
 /// Cursors are NOT thread-safe and must not be moved between threads.
@@ -590,21 +823,75 @@ impl<'txn> Cursor<'txn> {
     /// Returns None when there are no more entries.
     pub fn next<K: FromMdbxValue, V: FromMdbxValue>(&mut self) -> Result<Option<(K, V)>> {
         let mut key_val = MDBX_val::default();
-        let mut data_val = MDBX_val::default();
+        self.get(&mut key_val, MDBX_cursor_op::MDBX_NEXT)
+    }
 
-        // SAFETY: cursor is valid, key_val and data_val are valid output pointers.
-        let rc = unsafe {
-            mdbx_cursor_get(
-                self.cursor,
-                &mut key_val,
-                &mut data_val,
-                MDBX_cursor_op::MDBX_NEXT,
-            )
+    /// Move to the previous entry and return the key-value pair.
+    ///
+    /// Returns None when there are no more entries.
+    pub fn prev<K: FromMdbxValue, V: FromMdbxValue>(&mut self) -> Result<Option<(K, V)>> {
+        let mut key_val = MDBX_val::default();
+        self.get(&mut key_val, MDBX_cursor_op::MDBX_PREV)
+    }
+
+    /// Position at the first entry.
+    pub fn first<K: FromMdbxValue, V: FromMdbxValue>(&mut self) -> Result<Option<(K, V)>> {
+        let mut key_val = MDBX_val::default();
+        self.get(&mut key_val, MDBX_cursor_op::MDBX_FIRST)
+    }
+
+    /// Position at the last entry.
+    pub fn last<K: FromMdbxValue, V: FromMdbxValue>(&mut self) -> Result<Option<(K, V)>> {
+        let mut key_val = MDBX_val::default();
+        self.get(&mut key_val, MDBX_cursor_op::MDBX_LAST)
+    }
+
+    /// Return the entry at the cursor's position without moving it.
+    ///
+    /// Fails if the cursor has not been positioned yet.
+    pub fn get_current<K: FromMdbxValue, V: FromMdbxValue>(&mut self) -> Result<Option<(K, V)>> {
+        let mut key_val = MDBX_val::default();
+        self.get(&mut key_val, MDBX_cursor_op::MDBX_GET_CURRENT)
+    }
+
+    /// Position at the first entry whose key is greater than or equal to `key`.
+    ///
+    /// Returns None when there is no such entry.
+    pub fn set_range<K: FromMdbxValue, V: FromMdbxValue>(
+        &mut self,
+        key: &[u8],
+    ) -> Result<Option<(K, V)>> {
+        let mut key_val = MDBX_val {
+            iov_base: key.as_ptr() as *mut c_void,
+            iov_len: key.len(),
         };
+        self.get(&mut key_val, MDBX_cursor_op::MDBX_SET_RANGE)
+    }
+
+    /// Delete the entry at the cursor's position.
+    ///
+    /// The cursor stays in place, so the following `next` returns the entry after
+    /// the deleted one. Fails on cursors of read-only transactions.
+    pub fn del(&mut self) -> Result<()> {
+        // SAFETY: cursor is valid.
+        let rc = unsafe { mdbx_cursor_del(self.cursor, WriteFlags::DEFAULT.bits()) };
+        check_rc(rc)
+    }
+
+    fn get<K: FromMdbxValue, V: FromMdbxValue>(
+        &mut self,
+        key_val: &mut MDBX_val,
+        op: MDBX_cursor_op,
+    ) -> Result<Option<(K, V)>> {
+        let mut data_val = MDBX_val::default();
+
+        // SAFETY: cursor is valid, key_val and data_val are valid pointers; key_val
+        // is only read for positioning ops and points to a live slice.
+        let rc = unsafe { mdbx_cursor_get(self.cursor, key_val, &mut data_val, op) };
 
         if rc == MDBX_SUCCESS {
             // SAFETY: key_val and data_val point to valid data within the cursor's transaction.
-            let key = unsafe { K::from_mdbx_val(&key_val) };
+            let key = unsafe { K::from_mdbx_val(key_val) };
             let value = unsafe { V::from_mdbx_val(&data_val) };
             Ok(Some((key, value)))
         } else if rc == MDBX_NOTFOUND {
@@ -626,6 +913,55 @@ impl Drop for Cursor<'_> {
     }
 }
 
+/// Cursor that owns its read-only transaction.
+///
+/// Fields drop in declaration order, so the cursor is closed before the
+/// transaction is aborted.
+pub struct OwnedCursor<'env> {
+    cursor: Cursor<'env>,
+    txn: RoTransaction<'env>,
+}
+
+impl<'env> OwnedCursor<'env> {
+    /// Move to the next entry and return the key-value pair.
+    pub fn next<K: FromMdbxValue, V: FromMdbxValue>(&mut self) -> Result<Option<(K, V)>> {
+        self.cursor.next()
+    }
+
+    /// Move to the previous entry and return the key-value pair.
+    pub fn prev<K: FromMdbxValue, V: FromMdbxValue>(&mut self) -> Result<Option<(K, V)>> {
+        self.cursor.prev()
+    }
+
+    /// Position at the first entry.
+    pub fn first<K: FromMdbxValue, V: FromMdbxValue>(&mut self) -> Result<Option<(K, V)>> {
+        self.cursor.first()
+    }
+
+    /// Position at the last entry.
+    pub fn last<K: FromMdbxValue, V: FromMdbxValue>(&mut self) -> Result<Option<(K, V)>> {
+        self.cursor.last()
+    }
+
+    /// Return the entry at the cursor's position without moving it.
+    pub fn get_current<K: FromMdbxValue, V: FromMdbxValue>(&mut self) -> Result<Option<(K, V)>> {
+        self.cursor.get_current()
+    }
+
+    /// Position at the first entry whose key is greater than or equal to `key`.
+    pub fn set_range<K: FromMdbxValue, V: FromMdbxValue>(
+        &mut self,
+        key: &[u8],
+    ) -> Result<Option<(K, V)>> {
+        self.cursor.set_range(key)
+    }
+
+    /// The transaction the cursor reads from, for point lookups in the same snapshot.
+    pub fn txn(&self) -> &RoTransaction<'env> {
+        &self.txn
+    }
+}
+
 /// Trait for types that can be constructed from MDBX_val.
 ///
 /// # Safety
@@ -733,6 +1069,40 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_clear_db() {
+        let (_dir, env) = create_test_env();
+
+        {
+            let txn = env.begin_rw_txn().expect("Failed to begin transaction");
+            let db = txn
+                .create_db(Some("test"), DatabaseFlags::CREATE)
+                .expect("Failed to create database");
+            txn.put(db, b"key1", b"value1", WriteFlags::DEFAULT)
+                .expect("Failed to put");
+            txn.put(db, b"key2", b"value2", WriteFlags::DEFAULT)
+                .expect("Failed to put");
+            txn.commit().expect("Failed to commit");
+        }
+
+        {
+            let txn = env.begin_rw_txn().expect("Failed to begin transaction");
+            let db = txn.open_db(Some("test")).expect("Failed to open database");
+            txn.clear_db(db).expect("Failed to clear");
+            txn.commit().expect("Failed to commit");
+        }
+
+        {
+            let txn = env.begin_ro_txn().expect("Failed to begin transaction");
+            let db = txn
+                .open_db(Some("test"))
+                .expect("Database should still exist");
+            let mut cursor = txn.cursor(&db).expect("Failed to open cursor");
+            let first: Option<(Vec<u8>, Vec<u8>)> = cursor.first().expect("Failed to read");
+            assert!(first.is_none());
+        }
+    }
+
     #[test]
     fn test_cursor_iteration() {
         let (_dir, env) = create_test_env();
@@ -770,4 +1140,297 @@ mod tests {
             assert_eq!(entries[2].0, b"ccc".to_vec());
         }
     }
+
+    #[test]
+    fn test_cursor_set_range() {
+        let (_dir, env) = create_test_env();
+
+        let txn = env.begin_rw_txn().expect("Failed to begin transaction");
+        let db = txn
+            .create_db(Some("test"), DatabaseFlags::CREATE)
+            .expect("Failed to create database");
+        txn.put(db, b"aaa", b"111", WriteFlags::DEFAULT)
+            .expect("Failed to put");
+        txn.put(db, b"ccc", b"333", WriteFlags::DEFAULT)
+            .expect("Failed to put");
+
+        let mut cursor = txn.cursor(&db).expect("Failed to create cursor");
+        let entry = cursor
+            .set_range::<Vec<u8>, Vec<u8>>(b"bbb")
+            .expect("Failed to seek");
+        assert_eq!(entry, Some((b"ccc".to_vec(), b"333".to_vec())));
+        let entry = cursor
+            .set_range::<Vec<u8>, Vec<u8>>(b"aaa")
+            .expect("Failed to seek");
+        assert_eq!(entry, Some((b"aaa".to_vec(), b"111".to_vec())));
+        let next = cursor
+            .next::<Vec<u8>, Vec<u8>>()
+            .expect("Cursor iteration failed");
+        assert_eq!(next.map(|(key, _)| key), Some(b"ccc".to_vec()));
+        let entry = cursor
+            .set_range::<Vec<u8>, Vec<u8>>(b"ddd")
+            .expect("Failed to seek");
+        assert_eq!(entry, None);
+    }
+
+    #[test]
+    fn test_read_only_environment() {
+        let dir = TempDir::new().expect("Failed to create temp dir");
+        {
+            let env = Environment::builder()
+                .open(dir.path())
+                .expect("Failed to open environment");
+            let txn = env.begin_rw_txn().expect("Failed to begin transaction");
+            let db = txn
+                .create_db(Some("test"), DatabaseFlags::CREATE)
+                .expect("Failed to create database");
+            txn.put(db, b"key", b"value", WriteFlags::DEFAULT)
+                .expect("Failed to put");
+            txn.commit().expect("Failed to commit");
+        }
+
+        let env = Environment::builder()
+            .read_only()
+            .open(dir.path())
+            .expect("Failed to open read-only environment");
+        let txn = env.begin_ro_txn().expect("Failed to begin transaction");
+        let db = txn.open_db(Some("test")).expect("Failed to open database");
+        let value: Option<Vec<u8>> = txn.get(db, b"key").expect("Failed to get");
+        assert_eq!(value, Some(b"value".to_vec()));
+        assert!(txn.open_db(Some("missing")).is_err());
+        drop(txn);
+
+        assert!(env.begin_rw_txn().is_err());
+    }
+
+    #[test]
+    fn test_read_only_environment_requires_existing_database() {
+        let dir = TempDir::new().expect("Failed to create temp dir");
+        let path = dir.path().join("missing");
+        std::fs::create_dir(&path).expect("Failed to create dir");
+
+        assert!(Environment::builder().read_only().open(&path).is_err());
+    }
+
+    #[test]
+    fn test_map_full_and_growth() {
+        let dir = TempDir::new().expect("Failed to create temp dir");
+        let env = Environment::builder()
+            .set_geometry(Geometry {
+                size_lower: -1,
+                size_now: 64 * 1024,
+                size_upper: 1024 * 1024,
+                growth_step: 64 * 1024,
+                shrink_threshold: -1,
+                page_size: 4096,
+            })
+            .open(dir.path())
+            .expect("Failed to open environment");
+
+        let write = |count: u32| -> Result<()> {
+            let txn = env.begin_rw_txn()?;
+            let db = txn.create_db(Some("test"), DatabaseFlags::CREATE)?;
+            for i in 0..count {
+                txn.put(db, &i.to_be_bytes(), &[0u8; 8192], WriteFlags::DEFAULT)?;
+            }
+            txn.commit()
+        };
+
+        let err = write(512).expect_err("Write should exceed the map");
+        assert!(err.is_map_full());
+
+        env.set_geometry(&Geometry {
+            size_lower: -1,
+            size_now: -1,
+            size_upper: 16 * 1024 * 1024,
+            growth_step: -1,
+            shrink_threshold: -1,
+            page_size: -1,
+        })
+        .expect("Failed to raise the upper bound");
+        write(512).expect("Write should fit after growth");
+        assert_eq!(
+            env.info().expect("Failed to get info").geo_upper,
+            16 * 1024 * 1024
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let (_dir, env) = create_test_env();
+        let txn = env.begin_rw_txn().expect("Failed to begin transaction");
+        let db = txn
+            .create_db(Some("test"), DatabaseFlags::CREATE)
+            .expect("Failed to create database");
+        for i in 0..100u32 {
+            txn.put(db, &i.to_be_bytes(), &[0u8; 64], WriteFlags::DEFAULT)
+                .expect("Failed to put");
+        }
+        txn.commit().expect("Failed to commit");
+
+        let txn = env.begin_ro_txn().expect("Failed to begin transaction");
+        let db = txn.open_db(Some("test")).expect("Failed to open database");
+        let stat = txn.db_stat(db).expect("Failed to stat database");
+        assert_eq!(stat.entries, 100);
+        assert!(stat.leaf_pages > 0);
+        assert_eq!(stat.size_bytes(), stat.pages() * stat.page_size as u64);
+        drop(txn);
+
+        assert!(env.stat().expect("Failed to stat environment").page_size > 0);
+        let info = env.info().expect("Failed to get environment info");
+        assert!(info.geo_upper >= info.geo_current);
+        assert!(info.used_bytes() > 0);
+        assert!(info.used_bytes() <= info.geo_current);
+    }
+
+    #[test]
+    fn test_copy_environment() {
+        let (_dir, env) = create_test_env();
+        let txn = env.begin_rw_txn().expect("Failed to begin transaction");
+        let db = txn
+            .create_db(Some("test"), DatabaseFlags::CREATE)
+            .expect("Failed to create database");
+        txn.put(db, b"key", b"value", WriteFlags::DEFAULT)
+            .expect("Failed to put");
+        txn.commit().expect("Failed to commit");
+
+        let backup = TempDir::new().expect("Failed to create temp dir");
+        for (name, compact) in [("plain", false), ("compact", true)] {
+            let dest = backup.path().join(name);
+            std::fs::create_dir(&dest).expect("Failed to create dir");
+            env.copy(&dest.join(DATA_FILE), compact)
+                .expect("Failed to copy environment");
+
+            let copy = Environment::builder()
+                .read_only()
+                .open(&dest)
+                .expect("Failed to open copy");
+            let txn = copy.begin_ro_txn().expect("Failed to begin transaction");
+            let db = txn.open_db(Some("test")).expect("Failed to open database");
+            let value: Option<Vec<u8>> = txn.get(db, b"key").expect("Failed to get");
+            assert_eq!(value, Some(b"value".to_vec()));
+        }
+    }
+
+    #[test]
+    fn test_cursor_navigation() {
+        let (_dir, env) = create_test_env();
+
+        let txn = env.begin_rw_txn().expect("Failed to begin transaction");
+        let db = txn
+            .create_db(Some("test"), DatabaseFlags::CREATE)
+            .expect("Failed to create database");
+        let mut cursor = txn.cursor(&db).expect("Failed to create cursor");
+        assert_eq!(
+            cursor.first::<Vec<u8>, Vec<u8>>().expect("Failed to seek"),
+            None
+        );
+        assert_eq!(
+            cursor.last::<Vec<u8>, Vec<u8>>().expect("Failed to seek"),
+            None
+        );
+        drop(cursor);
+
+        for (key, value) in [(b"aaa", b"111"), (b"bbb", b"222"), (b"ccc", b"333")] {
+            txn.put(db, key, value, WriteFlags::DEFAULT)
+                .expect("Failed to put");
+        }
+        txn.commit().expect("Failed to commit");
+
+        let txn = env.begin_ro_txn().expect("Failed to begin transaction");
+        let db = txn.open_db(Some("test")).expect("Failed to open database");
+        let mut cursor = txn.cursor(&db).expect("Failed to create cursor");
+        let key = |entry: Option<(Vec<u8>, Vec<u8>)>| entry.map(|(key, _)| key);
+
+        assert_eq!(
+            key(cursor.last().expect("Failed to seek")),
+            Some(b"ccc".to_vec())
+        );
+        assert_eq!(
+            key(cursor.prev().expect("Failed to move")),
+            Some(b"bbb".to_vec())
+        );
+        assert_eq!(
+            cursor
+                .get_current::<Vec<u8>, Vec<u8>>()
+                .expect("Failed to read"),
+            Some((b"bbb".to_vec(), b"222".to_vec()))
+        );
+        assert_eq!(
+            key(cursor.first().expect("Failed to seek")),
+            Some(b"aaa".to_vec())
+        );
+        assert_eq!(key(cursor.prev().expect("Failed to move")), None);
+    }
+
+    #[test]
+    fn test_cursor_del() {
+        let (_dir, env) = create_test_env();
+
+        let txn = env.begin_rw_txn().expect("Failed to begin transaction");
+        let db = txn
+            .create_db(Some("test"), DatabaseFlags::CREATE)
+            .expect("Failed to create database");
+        for (key, value) in [(b"aaa", b"111"), (b"bbb", b"222"), (b"ccc", b"333")] {
+            txn.put(db, key, value, WriteFlags::DEFAULT)
+                .expect("Failed to put");
+        }
+
+        {
+            let mut cursor = txn.cursor(&db).expect("Failed to create cursor");
+            cursor
+                .set_range::<Vec<u8>, Vec<u8>>(b"bbb")
+                .expect("Failed to seek");
+            cursor.del().expect("Failed to delete");
+            let next = cursor
+                .next::<Vec<u8>, Vec<u8>>()
+                .expect("Cursor iteration failed");
+            assert_eq!(next.map(|(key, _)| key), Some(b"ccc".to_vec()));
+        }
+        txn.commit().expect("Failed to commit");
+
+        let txn = env.begin_ro_txn().expect("Failed to begin transaction");
+        let db = txn.open_db(Some("test")).expect("Failed to open database");
+        let value: Option<Vec<u8>> = txn.get(db, b"bbb").expect("Failed to get");
+        assert_eq!(value, None);
+        let mut cursor = txn.cursor(&db).expect("Failed to create cursor");
+        cursor.first::<Vec<u8>, Vec<u8>>().expect("Failed to seek");
+        assert!(cursor.del().is_err());
+    }
+
+    #[test]
+    fn test_owned_cursor_outlives_scope() {
+        let (_dir, env) = create_test_env();
+
+        {
+            let txn = env.begin_rw_txn().expect("Failed to begin transaction");
+            let db = txn
+                .create_db(Some("test"), DatabaseFlags::CREATE)
+                .expect("Failed to create database");
+            txn.put(db, b"aaa", b"111", WriteFlags::DEFAULT)
+                .expect("Failed to put");
+            txn.put(db, b"bbb", b"222", WriteFlags::DEFAULT)
+                .expect("Failed to put");
+            txn.commit().expect("Failed to commit");
+        }
+
+        let open = |env: &Environment| {
+            let txn = env.begin_ro_txn().expect("Failed to begin transaction");
+            let db = txn.open_db(Some("test")).expect("Failed to open database");
+            (txn.into_cursor(&db).expect("Failed to create cursor"), db)
+        };
+        let (mut cursor, db) = open(&env);
+
+        let value: Option<Vec<u8>> = cursor.txn().get(db, b"bbb").expect("Failed to get");
+        assert_eq!(value, Some(b"222".to_vec()));
+
+        let mut keys = Vec::new();
+        while let Some((key, _)) = cursor
+            .next::<Vec<u8>, Vec<u8>>()
+            .expect("Cursor iteration failed")
+        {
+            keys.push(key);
+        }
+        assert_eq!(keys, vec![b"aaa".to_vec(), b"bbb".to_vec()]);
+    }
 }
@@ -5,12 +5,17 @@
 
 use metrics::{counter, gauge, histogram};
 
+use crate::persistence::DbStats;
+
 const BLOCKS_PROCESSED_TOTAL: &str = "ubt_exex_blocks_processed_total";
 const ENTRIES_PER_BLOCK: &str = "ubt_exex_entries_per_block";
 const STEMS_TOTAL: &str = "ubt_exex_stems_total";
 const LAST_BLOCK_NUMBER: &str = "ubt_exex_last_block_number";
 
 const ROOT_COMPUTATION_SECONDS: &str = "ubt_exex_root_computation_seconds";
+const ROOT_COMPUTATIONS_TOTAL: &str = "ubt_exex_root_computations_total";
+const ROOT_PENDING: &str = "ubt_exex_root_pending";
+const ROOT_WAIT_SECONDS: &str = "ubt_exex_root_wait_seconds";
 const PERSISTENCE_SECONDS: &str = "ubt_exex_persistence_seconds";
 const STEMS_PERSISTED: &str = "ubt_exex_stems_persisted";
 
@@ -19,6 +24,24 @@ const REVERTS_TOTAL: &str = "ubt_exex_reverts_total";
 const REVERT_BLOCKS: &str = "ubt_exex_revert_blocks";
 const REVERT_ENTRIES: &str = "ubt_exex_revert_entries";
 
+const BALANCE_OVERFLOWS_TOTAL: &str = "ubt_exex_balance_overflows_total";
+
+const LEAF_WRITES_TOTAL: &str = "ubt_exex_leaf_writes_total";
+const LEAF_WRITES_SKIPPED_TOTAL: &str = "ubt_exex_leaf_writes_skipped_total";
+
+const MDBX_USED_BYTES: &str = "ubt_exex_mdbx_used_bytes";
+const MDBX_MAP_SIZE_BYTES: &str = "ubt_exex_mdbx_map_size_bytes";
+const MDBX_MAP_UPPER_BYTES: &str = "ubt_exex_mdbx_map_upper_bytes";
+const MDBX_MAP_UTILIZATION: &str = "ubt_exex_mdbx_map_utilization";
+const MDBX_TABLE_ENTRIES: &str = "ubt_exex_mdbx_table_entries";
+const MDBX_TABLE_SIZE_BYTES: &str = "ubt_exex_mdbx_table_size_bytes";
+const MDBX_MAP_FULL_TOTAL: &str = "ubt_exex_mdbx_map_full_total";
+const PAUSED: &str = "ubt_exex_paused";
+
+const STARTUP_VERIFICATIONS_TOTAL: &str = "ubt_exex_startup_verifications_total";
+const STARTUP_VERIFICATION_SECONDS: &str = "ubt_exex_startup_verification_seconds";
+const HEALTHY: &str = "ubt_exex_healthy";
+
 /// Record a block being processed.
 pub fn record_block_processed(block_number: u64, entries: usize, stems: usize) {
     counter!(BLOCKS_PROCESSED_TOTAL).increment(1);
@@ -32,6 +55,21 @@ pub fn record_root_computation(duration_secs: f64) {
     histogram!(ROOT_COMPUTATION_SECONDS).record(duration_secs);
 }
 
+/// Record how a flush root was computed: `incremental`, `stem_hashes` or `streaming`.
+pub fn record_root_mode(mode: &'static str) {
+    counter!(ROOT_COMPUTATIONS_TOTAL, "mode" => mode).increment(1);
+}
+
+/// Record whether the root of the last flush is still being computed.
+pub fn record_root_pending(pending: bool) {
+    gauge!(ROOT_PENDING).set(if pending { 1.0 } else { 0.0 });
+}
+
+/// Record time spent waiting for an outstanding root before flushing.
+pub fn record_root_wait(duration_secs: f64) {
+    histogram!(ROOT_WAIT_SECONDS).record(duration_secs);
+}
+
 /// Record persistence operation time.
 pub fn record_persistence(duration_secs: f64, stems_written: usize) {
     histogram!(PERSISTENCE_SECONDS).record(duration_secs);
@@ -49,3 +87,47 @@ pub fn record_revert(blocks_reverted: usize, entries_reverted: usize) {
     histogram!(REVERT_BLOCKS).record(blocks_reverted as f64);
     histogram!(REVERT_ENTRIES).record(entries_reverted as f64);
 }
+
+/// Record an account balance that did not fit the basic-data leaf.
+pub fn record_balance_overflow() {
+    counter!(BALANCE_OVERFLOWS_TOTAL).increment(1);
+}
+
+/// Record leaves of one kind written and skipped as unchanged in a block.
+pub fn record_leaf_writes(kind: &'static str, written: u64, skipped: u64) {
+    counter!(LEAF_WRITES_TOTAL, "kind" => kind).increment(written);
+    counter!(LEAF_WRITES_SKIPPED_TOTAL, "kind" => kind).increment(skipped);
+}
+
+/// Record MDBX map usage and per-table sizes.
+pub fn record_db_stats(stats: &DbStats) {
+    gauge!(MDBX_USED_BYTES).set(stats.used_bytes as f64);
+    gauge!(MDBX_MAP_SIZE_BYTES).set(stats.map_size_bytes as f64);
+    gauge!(MDBX_MAP_UPPER_BYTES).set(stats.map_upper_bytes as f64);
+    gauge!(MDBX_MAP_UTILIZATION).set(stats.map_utilization);
+    for table in &stats.tables {
+        gauge!(MDBX_TABLE_ENTRIES, "table" => table.name.clone()).set(table.entries as f64);
+        gauge!(MDBX_TABLE_SIZE_BYTES, "table" => table.name.clone()).set(table.size_bytes as f64);
+    }
+}
+
+/// Record an MDBX write that failed because the map was full.
+pub fn record_map_full() {
+    counter!(MDBX_MAP_FULL_TOTAL).increment(1);
+}
+
+/// Record whether block processing is paused.
+pub fn record_paused(paused: bool) {
+    gauge!(PAUSED).set(if paused { 1.0 } else { 0.0 });
+}
+
+/// Record a startup root verification by mode and result.
+pub fn record_startup_verification(mode: &'static str, result: &'static str, duration_secs: f64) {
+    counter!(STARTUP_VERIFICATIONS_TOTAL, "mode" => mode, "result" => result).increment(1);
+    histogram!(STARTUP_VERIFICATION_SECONDS, "mode" => mode).record(duration_secs);
+}
+
+/// Record whether the UBT state is believed to match its root.
+pub fn record_healthy(healthy: bool) {
+    gauge!(HEALTHY).set(if healthy { 1.0 } else { 0.0 });
+}
@@ -5,35 +5,188 @@
 //!
 //! # Database Layout
 //!
-//! Three tables are used:
-//! - `ubt_stems`: Maps 31-byte stem keys to serialized `StemNode` values
-//! - `ubt_meta`: Stores metadata including the current head block and root hash
+//! Nine tables are used:
+//! - `ubt_stems`: Maps 31-byte stem keys to encoded `StemNode` values
+//! - `ubt_stem_addresses`: Maps stems back to the owning account address
+//! - `ubt_meta`: Stores metadata including the current head block and root hash, the
+//!   tree hasher, the bootstrap checkpoint while a bootstrap is in progress, the
+//!   highest block a bootstrap read plain state at, the pending-commit marker while NOMT or the key index may be ahead of the head, the
+//!   stem encoding migration state and whether the stem and internal node hashes
+//!   are valid
 //! - `ubt_block_deltas`: Stores per-block state deltas for reorg handling
+//! - `ubt_balance_overflows`: Balances that exceeded u128 and were saturated, keyed by
+//!   address and block, with a cleared entry once an account's balance fits again
+//! - `ubt_stem_hashes`: Hash of every stem node, kept in step with `ubt_stems`
+//! - `ubt_internal_nodes`: Internal node hashes by bit path (see `internal_nodes`)
+//! - `ubt_roots`: Root and stem count of every flushed head, keyed by block number
+//!   and hash, unwound by reverts and pruned by retention
+//! - `ubt_root_hashes`: Maps block hashes in `ubt_roots` to their block number
+//!
+//! # Write Batches
+//!
+//! A flush writes stem addresses, block deltas, balance overflow records, stems,
+//! internal node hashes and the head, prunes deltas and root history past their
+//! retention and continues the stem encoding migration. `WriteBatch` collects
+//! these and `UbtDatabase::write_batch` applies them in one write transaction, so
+//! a crash never leaves stems ahead of the head or deltas without their stems. Stems
+//! written without their internal node hashes mark the stored hashes invalid until
+//! `UbtDatabase::rebuild_internal_nodes` runs.
+//!
+//! # Stem Hashes
+//!
+//! Every write to `ubt_stems` also writes the stem's hash to `ubt_stem_hashes`, with
+//! the recorded tree hasher, so root computations combine stored stem hashes
+//! instead of rehashing unchanged stems. Databases from before the table have no
+//! `stem_hashes` flag in `ubt_meta` until `UbtDatabase::rebuild_stem_hashes` runs.
+//! A flush whose root is pending leaves the hashes of its stems to the root
+//! computation, which writes them with `UbtDatabase::finalize_root`; until then the
+//! flag is `pending` and the table is only trusted together with those hashes.
+//!
+//! # Schema Versions
+//!
+//! `ubt_meta` records the layout version as `schema_version`. Databases without it
+//! predate versioning and are version 1. `UbtDatabase::open` runs the upgrades in
+//! `MIGRATIONS` in order, bumping the version after each, and refuses databases
+//! written by a newer build. New databases start at `SCHEMA_VERSION`.
+//!
+//! # Stem Encoding
+//!
+//! Stems are written in the compact encoding from `stem_codec` and read in either
+//! the compact or the legacy bincode encoding. Databases with legacy rows are
+//! migrated online: `UbtDatabase::migrate_stem_encoding` rewrites a bounded number of
+//! rows per call and records its position in `ubt_meta`. Once every row is compact,
+//! `stem_encoding` is set in `ubt_meta`; new databases start with it set.
 //!
 //! # Recovery
 //!
 //! On startup, the ExEx loads all stems from MDBX and reconstructs the in-memory tree.
 //! The stored root hash is verified against the computed root to detect corruption.
 
-use alloy_primitives::{Address, B256};
+use alloy_primitives::{Address, B256, U256};
+use rayon::prelude::*;
+use std::collections::BTreeMap;
 use std::path::Path;
-use tracing::warn;
+use tracing::{info, warn};
 use ubt::{Stem, StemNode, TreeKey, STEM_LEN};
 
 use crate::error::{DatabaseError, Result, UbtError};
-use crate::mdbx::{DatabaseFlags, Environment, Geometry, WriteFlags};
+use crate::hasher::TreeHasher;
+use crate::internal_nodes::{
+    build_subtree, build_top, decode_hash, IncrementalRoot, NodeKey, NodeUpdates, StemHashRoot,
+    Subtree, REBUILD_BUCKET_DEPTH,
+};
+use crate::mdbx::{
+    Database, DatabaseFlags, Environment, EnvironmentBuilder, Error as MdbxError, Geometry,
+    OwnedCursor, RoTransaction, RwTransaction, WriteFlags, DATA_FILE,
+};
+use crate::stem_codec::{decode_stem, encode_stem, encode_stem_legacy, is_compact};
 
 const STEMS_DB: &str = "ubt_stems";
 const STEM_ADDR_DB: &str = "ubt_stem_addresses";
 const META_DB: &str = "ubt_meta";
 const DELTAS_DB: &str = "ubt_block_deltas";
+const BALANCE_OVERFLOW_DB: &str = "ubt_balance_overflows";
+const STEM_HASHES_DB: &str = "ubt_stem_hashes";
+const INTERNAL_NODES_DB: &str = "ubt_internal_nodes";
+const ROOTS_DB: &str = "ubt_roots";
+const ROOT_HASHES_DB: &str = "ubt_root_hashes";
+/// Tables reported by `UbtDatabase::stats`.
+const TABLES: [&str; 9] = [
+    STEMS_DB,
+    STEM_ADDR_DB,
+    META_DB,
+    DELTAS_DB,
+    BALANCE_OVERFLOW_DB,
+    STEM_HASHES_DB,
+    INTERNAL_NODES_DB,
+    ROOTS_DB,
+    ROOT_HASHES_DB,
+];
 const META_KEY_HEAD: &[u8] = b"head";
+const META_KEY_BOOTSTRAP: &[u8] = b"bootstrap_checkpoint";
+const META_KEY_BOOTSTRAP_FLOOR: &[u8] = b"bootstrap_floor";
+const META_KEY_HASHER: &[u8] = b"tree_hasher";
+const META_KEY_PENDING_COMMIT: &[u8] = b"pending_commit";
+const META_KEY_PENDING_ROOT: &[u8] = b"pending_root";
+const META_KEY_STEM_ENCODING: &[u8] = b"stem_encoding";
+const META_KEY_STEM_MIGRATION: &[u8] = b"stem_migration_cursor";
+const STEM_ENCODING_COMPACT_V1: &[u8] = b"compact_v1";
+const META_KEY_SCHEMA_VERSION: &[u8] = b"schema_version";
+const META_KEY_MIGRATION_CURSOR: &[u8] = b"schema_migration_cursor";
+const META_KEY_MISSING_STEM_ADDRESSES: &[u8] = b"missing_stem_addresses";
+const META_KEY_STEM_HASHES: &[u8] = b"stem_hashes";
+const META_KEY_INTERNAL_NODES: &[u8] = b"internal_nodes";
+/// Value of the `stem_hashes` and `internal_nodes` flags while the table is valid.
+const META_FLAG_VALID: &[u8] = b"valid";
+/// Value of the `stem_hashes` flag while the hashes of the stems flushed with a
+/// pending root are still to be written.
+const META_FLAG_PENDING: &[u8] = b"pending";
+/// Stems checked per write transaction by the stem address audit.
+const STEM_ADDRESS_AUDIT_BATCH: usize = 100_000;
+
+/// On-disk layout version written by this build.
+pub const SCHEMA_VERSION: u32 = 8;
+
+/// An upgrade from `version - 1` to `version`.
+struct Migration {
+    version: u32,
+    description: &'static str,
+    run: fn(&UbtDatabase) -> Result<()>,
+}
+
+/// Upgrades applied in order by `UbtDatabase::open`. The version is bumped after
+/// each one completes, so an interrupted upgrade is re-run from its start (or from
+/// the position it records in `ubt_meta`) on the next open.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 2,
+        description: "record the tree hasher of existing state",
+        run: migrate_record_tree_hasher,
+    },
+    Migration {
+        version: 3,
+        description: "count stems without a stored address",
+        run: migrate_audit_stem_addresses,
+    },
+    Migration {
+        // Readers decode both encodings; legacy rows are rewritten online by
+        // `UbtDatabase::migrate_stem_encoding`.
+        version: 4,
+        description: "compact stem encoding",
+        run: |_| Ok(()),
+    },
+    Migration {
+        // The table is created empty and not marked valid; the ExEx builds it at
+        // startup with `UbtDatabase::rebuild_internal_nodes`.
+        version: 5,
+        description: "internal node hashes",
+        run: |_| Ok(()),
+    },
+    Migration {
+        // Likewise built at startup, with `UbtDatabase::rebuild_stem_hashes`.
+        version: 6,
+        description: "stem hash cache",
+        run: |_| Ok(()),
+    },
+    Migration {
+        version: 7,
+        description: "root history",
+        run: migrate_record_head_root,
+    },
+    Migration {
+        version: 8,
+        description: "balance overflows keyed by block",
+        run: migrate_balance_overflow_keys,
+    },
+];
+
+const _: () = assert!(MIGRATIONS[MIGRATIONS.len() - 1].version == SCHEMA_VERSION);
 
 pub struct UbtDatabase {
     env: Environment,
 }
 
-#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct UbtHead {
     pub block_number: u64,
     pub block_hash: B256,
@@ -41,49 +194,581 @@ pub struct UbtHead {
     pub stem_count: usize,
 }
 
+/// Lazy iterator over stored stems, in key order, within one read transaction.
+pub struct StemIter<'db> {
+    cursor: OwnedCursor<'db>,
+    stem_addr_db: Database,
+    /// Key to seek to before the first entry; `None` starts at the beginning.
+    start: Option<Stem>,
+}
+
+impl StemIter<'_> {
+    /// Look up a stem's owning address in the same snapshot as the iteration.
+    pub fn stem_address(&self, stem: &Stem) -> Result<Option<Address>> {
+        match self
+            .cursor
+            .txn()
+            .get::<Vec<u8>>(self.stem_addr_db, stem.as_bytes())
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+        {
+            Some(bytes) if bytes.len() == 20 => Ok(Some(Address::from_slice(&bytes))),
+            Some(bytes) => Err(UbtError::Database(DatabaseError::Mdbx(format!(
+                "Invalid address length: expected 20, got {}",
+                bytes.len()
+            )))),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Iterator for StemIter<'_> {
+    type Item = Result<(Stem, StemNode)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let entry = match self.start.take() {
+                Some(start) => self.cursor.set_range::<Vec<u8>, Vec<u8>>(start.as_bytes()),
+                None => self.cursor.next::<Vec<u8>, Vec<u8>>(),
+            };
+            let (key, value) = match entry {
+                Ok(Some(entry)) => entry,
+                Ok(None) => return None,
+                Err(e) => return Some(Err(UbtError::Database(DatabaseError::Mdbx(e.to_string())))),
+            };
+            if key.len() != STEM_LEN {
+                continue;
+            }
+            let mut stem_bytes = [0u8; STEM_LEN];
+            stem_bytes.copy_from_slice(&key);
+            let stem = Stem::new(stem_bytes);
+            return Some(decode_stem(stem, &value).map(|node| (stem, node)));
+        }
+    }
+}
+
+/// Lazy iterator over (TreeKey, value) pairs in key order, built on `StemIter`.
+///
+/// Stems from an overlay (see `UbtDatabase::iter_entries_with_overlay`) replace the
+/// stored stem with the same key; overlay stems without values are skipped.
+pub struct EntryIter<'db> {
+    stems: std::iter::Fuse<StemIter<'db>>,
+    stored: Option<(Stem, StemNode)>,
+    overlay: std::iter::Peekable<std::slice::Iter<'db, (Stem, StemNode)>>,
+    current: std::vec::IntoIter<(TreeKey, B256)>,
+}
+
+impl EntryIter<'_> {
+    /// Entries of the next stem that has values, merging stored and overlay stems.
+    fn next_stem_entries(&mut self) -> Option<Result<Vec<(TreeKey, B256)>>> {
+        loop {
+            if self.stored.is_none() {
+                match self.stems.next() {
+                    Some(Ok(stem)) => self.stored = Some(stem),
+                    Some(Err(e)) => return Some(Err(e)),
+                    None => {}
+                }
+            }
+
+            let from_overlay = match (&self.stored, self.overlay.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => false,
+                (None, Some(_)) => true,
+                (Some((stored, _)), Some((dirty, _))) => dirty <= stored,
+            };
+
+            let entries = if from_overlay {
+                let (stem, node) = self.overlay.next().expect("peeked");
+                if self.stored.as_ref().is_some_and(|(stored, _)| stored == stem) {
+                    self.stored = None;
+                }
+                sorted_stem_entries(*stem, node.values.iter().map(|(i, v)| (*i, *v)))
+            } else {
+                let (stem, node) = self.stored.take().expect("checked above");
+                sorted_stem_entries(stem, node.values)
+            };
+            if !entries.is_empty() {
+                return Some(Ok(entries));
+            }
+        }
+    }
+}
+
+impl Iterator for EntryIter<'_> {
+    type Item = Result<(TreeKey, B256)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.current.next() {
+                return Some(Ok(entry));
+            }
+            match self.next_stem_entries()? {
+                Ok(entries) => self.current = entries.into_iter(),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+fn sorted_stem_entries(
+    stem: Stem,
+    values: impl IntoIterator<Item = (u8, B256)>,
+) -> Vec<(TreeKey, B256)> {
+    let mut entries: Vec<_> = values
+        .into_iter()
+        .map(|(subindex, value)| (TreeKey::new(stem, subindex), value))
+        .collect();
+    entries.sort_unstable_by_key(|(key, _)| key.subindex);
+    entries
+}
+
+/// Position in reth's plain state from which a bootstrap continues.
+///
+/// `next_slot` is `None` when the account at `address` has not been started yet;
+/// otherwise its basic data is written and storage resumes at `next_slot`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, serde::Serialize, serde::Deserialize)]
+pub struct BootstrapPosition {
+    pub address: Address,
+    pub next_slot: Option<B256>,
+}
+
+/// Progress of an in-flight bootstrap from plain state.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct BootstrapCheckpoint {
+    /// Block of the plain state when the bootstrap started; becomes the head.
+    pub block_number: u64,
+    pub block_hash: B256,
+    pub next: BootstrapPosition,
+    pub accounts: u64,
+}
+
+/// An account whose balance did not fit the 16-byte basic-data field.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct BalanceOverflow {
+    pub address: Address,
+    /// Last block at which the overflowing balance was written.
+    pub block_number: u64,
+    pub balance: U256,
+}
+
+/// Marker written before NOMT or the key index are changed and cleared once the
+/// MDBX and key index heads have caught up. Its presence at startup means the
+/// stores may not agree (see `UbtExEx::new`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum PendingCommit {
+    /// Blocks after the MDBX head may have been applied to NOMT and the key index.
+    Commit,
+    /// A revert to this block may have been applied to NOMT and the key index but
+    /// not to MDBX.
+    Revert { block_number: u64, block_hash: B256 },
+}
+
+/// Marker written with a flush whose root is computed after it (see
+/// `RootComputation::Async`) and removed once the root is recorded in the head.
+///
+/// While it is stored, the stored head names the flushed block but its `root` is
+/// still the root of `finalized`, so `UbtDatabase::load_head` returns `finalized`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct PendingRoot {
+    /// Flushed block whose root is being computed.
+    pub block_number: u64,
+    pub block_hash: B256,
+    /// Head of the last flush whose root is known; `None` before the first one.
+    pub finalized: Option<UbtHead>,
+}
+
+/// Result of `UbtDatabase::sample_root_paths`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootPathSample {
+    /// Sampled stems whose stored hash is missing or differs from their values.
+    pub stale_stems: Vec<Stem>,
+    /// Stored internal nodes on the sampled paths that differ from the rehashed
+    /// paths, including nodes that should not exist.
+    pub stale_nodes: Vec<NodeKey>,
+    /// Root the rehashed paths lead to over the stored sibling subtrees.
+    pub root: B256,
+}
+
+/// Result of one `UbtDatabase::migrate_stem_encoding` call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StemMigrationProgress {
+    /// Rows visited in this call.
+    pub scanned: usize,
+    /// Legacy rows rewritten in the compact encoding.
+    pub rewritten: usize,
+    /// Every row is now compact.
+    pub done: bool,
+}
+
+/// Size of one table, from `mdbx_dbi_stat`.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct TableStats {
+    pub name: String,
+    pub entries: u64,
+    /// Bytes in branch, leaf and overflow pages.
+    #[serde(rename = "sizeBytes")]
+    pub size_bytes: u64,
+    pub depth: u32,
+    #[serde(rename = "overflowPages")]
+    pub overflow_pages: u64,
+}
+
+/// Table sizes and map usage, from `UbtDatabase::stats`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct DbStats {
+    #[serde(rename = "pageSize")]
+    pub page_size: u32,
+    /// Bytes of the map in use, including free pages not yet reclaimed.
+    #[serde(rename = "usedBytes")]
+    pub used_bytes: u64,
+    /// Current size of the data file.
+    #[serde(rename = "mapSizeBytes")]
+    pub map_size_bytes: u64,
+    /// Upper bound of the map (`UBT_MDBX_MAX_SIZE`).
+    #[serde(rename = "mapUpperBytes")]
+    pub map_upper_bytes: u64,
+    /// `used_bytes / map_upper_bytes`.
+    #[serde(rename = "mapUtilization")]
+    pub map_utilization: f64,
+    pub tables: Vec<TableStats>,
+}
+
+/// Mutations of one flush, applied to MDBX in a single write transaction by
+/// `UbtDatabase::write_batch`.
+#[derive(Debug, Default)]
+pub struct WriteBatch {
+    stem_addresses: BTreeMap<Stem, Address>,
+    /// Deltas to write per block; `None` deletes the block's deltas.
+    block_deltas: BTreeMap<u64, Option<Vec<(Stem, u8, B256)>>>,
+    /// Overflowing balances by address and block; `None` clears the account's record.
+    balance_overflows: BTreeMap<(Address, u64), Option<U256>>,
+    /// Delete stored balance overflow entries of blocks after this one before
+    /// writing `balance_overflows`.
+    balance_overflows_after: Option<u64>,
+    /// Delete stored deltas of blocks before this one.
+    deltas_before: Option<u64>,
+    stems: Vec<(Stem, StemNode)>,
+    /// Rewrite up to this many legacy stems in the compact encoding after writing
+    /// `stems`.
+    stem_migration: Option<usize>,
+    /// Internal node writes matching `stems`. Without them, writing stems marks
+    /// the stored internal nodes invalid.
+    internal_nodes: Option<NodeUpdates>,
+    /// Serialized `ubt_meta` values; `None` deletes the key.
+    meta: BTreeMap<&'static [u8], Option<Vec<u8>>>,
+    /// Head to record in `ubt_roots`.
+    root: Option<UbtHead>,
+    /// Delete root history records of blocks before this one.
+    roots_before: Option<u64>,
+}
+
+impl WriteBatch {
+    pub fn is_empty(&self) -> bool {
+        self.stem_addresses.is_empty()
+            && self.block_deltas.is_empty()
+            && self.balance_overflows.is_empty()
+            && self.balance_overflows_after.is_none()
+            && self.deltas_before.is_none()
+            && self.stems.is_empty()
+            && self.stem_migration.is_none()
+            && self.internal_nodes.is_none()
+            && self.meta.is_empty()
+            && self.root.is_none()
+            && self.roots_before.is_none()
+    }
+
+    /// Queue stem-to-address mappings. The first address queued for a stem wins.
+    pub fn save_stem_addresses(&mut self, mappings: impl IntoIterator<Item = (Stem, Address)>) {
+        for (stem, address) in mappings {
+            self.stem_addresses.entry(stem).or_insert(address);
+        }
+    }
+
+    /// Address queued for a stem that has not been written yet.
+    pub fn stem_address(&self, stem: &Stem) -> Option<Address> {
+        self.stem_addresses.get(stem).copied()
+    }
+
+    pub fn save_block_deltas(&mut self, block_number: u64, deltas: Vec<(Stem, u8, B256)>) {
+        self.block_deltas.insert(block_number, Some(deltas));
+    }
+
+    /// Remove and return the deltas queued for a block, or `None` if the batch
+    /// has no entry for it. A block queued for deletion yields no deltas.
+    pub fn take_block_deltas(&mut self, block_number: u64) -> Option<Vec<(Stem, u8, B256)>> {
+        match self.block_deltas.remove(&block_number)? {
+            Some(deltas) => Some(deltas),
+            None => {
+                self.block_deltas.insert(block_number, None);
+                Some(Vec::new())
+            }
+        }
+    }
+
+    pub fn delete_block_deltas(&mut self, block_number: u64) {
+        self.block_deltas.insert(block_number, None);
+    }
+
+    /// Delete the stored deltas of blocks before `block_number`, as retention asks.
+    pub fn prune_deltas_before(&mut self, block_number: u64) {
+        self.deltas_before = Some(
+            self.deltas_before
+                .map_or(block_number, |before| before.max(block_number)),
+        );
+    }
+
+    pub fn save_balance_overflow(&mut self, record: BalanceOverflow) {
+        self.balance_overflows
+            .insert((record.address, record.block_number), Some(record.balance));
+    }
+
+    /// Clear the account's balance overflow record from `block_number` on, as when
+    /// the block wrote a balance that fits. Reverting the block restores it.
+    pub fn clear_balance_overflow(&mut self, address: Address, block_number: u64) {
+        self.balance_overflows.insert((address, block_number), None);
+    }
+
+    /// Drop balance overflow entries of blocks after `block_number`, queued or
+    /// stored, as when those blocks are reverted.
+    pub fn delete_balance_overflows_after(&mut self, block_number: u64) {
+        self.balance_overflows
+            .retain(|(_, block), _| *block <= block_number);
+        self.balance_overflows_after = Some(
+            self.balance_overflows_after
+                .map_or(block_number, |after| after.min(block_number)),
+        );
+    }
+
+    /// Queue stem nodes to write. Stems without any values are deleted.
+    pub fn update_stems(&mut self, updates: impl IntoIterator<Item = (Stem, StemNode)>) {
+        self.stems.extend(updates);
+    }
+
+    /// Continue the stem encoding migration by up to `max_rows` rows (see
+    /// `UbtDatabase::migrate_stem_encoding`).
+    pub fn migrate_stem_encoding(&mut self, max_rows: usize) {
+        self.stem_migration = Some(max_rows);
+    }
+
+    /// Queue the internal node writes computed for this batch's stems by
+    /// `UbtDatabase::compute_root_incremental`.
+    pub fn update_internal_nodes(&mut self, updates: NodeUpdates) {
+        self.internal_nodes
+            .get_or_insert_with(NodeUpdates::new)
+            .extend(updates);
+    }
+
+    /// Mark the stored internal nodes invalid, as when stems are written without them.
+    pub fn invalidate_internal_nodes(&mut self) {
+        self.internal_nodes = None;
+        self.meta.insert(META_KEY_INTERNAL_NODES, None);
+    }
+
+    pub fn save_head(&mut self, head: &UbtHead) -> Result<()> {
+        self.meta.insert(META_KEY_HEAD, Some(bincode::serialize(head)?));
+        Ok(())
+    }
+
+    /// Queue `head` for the root history. Records of later blocks are deleted with
+    /// it, so a head moved back by a revert unwinds the history.
+    pub fn save_root(&mut self, head: &UbtHead) {
+        self.root = Some(head.clone());
+    }
+
+    /// Delete root history records of blocks before `block_number`, as retention
+    /// asks.
+    pub fn prune_roots_before(&mut self, block_number: u64) {
+        self.roots_before = Some(
+            self.roots_before
+                .map_or(block_number, |before| before.max(block_number)),
+        );
+    }
+
+    /// Whether a `PendingRoot` marker is queued, so the queued head's root is not
+    /// known yet.
+    pub fn root_pending(&self) -> bool {
+        matches!(self.meta.get(META_KEY_PENDING_ROOT), Some(Some(_)))
+    }
+
+    pub fn save_pending_root(&mut self, pending: &PendingRoot) -> Result<()> {
+        self.meta
+            .insert(META_KEY_PENDING_ROOT, Some(bincode::serialize(pending)?));
+        Ok(())
+    }
+
+    pub fn save_bootstrap_checkpoint(&mut self, checkpoint: &BootstrapCheckpoint) -> Result<()> {
+        self.meta.insert(META_KEY_BOOTSTRAP, Some(bincode::serialize(checkpoint)?));
+        Ok(())
+    }
+
+    pub fn delete_bootstrap_checkpoint(&mut self) {
+        self.meta.insert(META_KEY_BOOTSTRAP, None);
+    }
+
+    /// Record the highest block a bootstrap has read plain state at.
+    pub fn save_bootstrap_floor(&mut self, block_number: u64) -> Result<()> {
+        self.meta.insert(
+            META_KEY_BOOTSTRAP_FLOOR,
+            Some(bincode::serialize(&block_number)?),
+        );
+        Ok(())
+    }
+}
+
 impl UbtDatabase {
+    /// Open the database read-write, creating it and its tables if needed and
+    /// running schema migrations.
     pub fn open(path: &Path) -> Result<Self> {
+        let max_size = mdbx_max_size_from_env().unwrap_or(1024 * 1024 * 1024 * 1024); // 1TB
+        Self::open_with_max_size(path, max_size as u64)
+    }
+
+    /// `open` with an explicit map upper bound instead of `UBT_MDBX_MAX_SIZE`.
+    pub(crate) fn open_with_max_size(path: &Path, max_size: u64) -> Result<Self> {
         std::fs::create_dir_all(path)?;
 
-        let max_size = mdbx_max_size_from_env().unwrap_or(1024 * 1024 * 1024 * 1024); // 1TB
+        // A map grown past the configured bound at runtime keeps at least its size.
+        let file_size = std::fs::metadata(path.join(DATA_FILE))
+            .map(|meta| meta.len())
+            .unwrap_or(0);
         let geometry = Geometry {
             size_lower: 0,
             size_now: 4096 * 256,
-            size_upper: max_size as i64,
+            size_upper: max_size.max(file_size) as i64,
             growth_step: -1,
             shrink_threshold: -1,
             page_size: 4096,
         };
 
-        let env = Environment::builder()
-            .set_max_dbs(10)
-            .set_geometry(geometry)
-            .open(path)
-            .map_err(|e| {
-                UbtError::Database(DatabaseError::Open {
-                    path: path.display().to_string(),
-                    reason: e.to_string(),
-                })
-            })?;
+        let env = Self::open_env(path, Environment::builder().set_geometry(geometry))?;
+        let db = Self { env };
+        // Refuse a newer schema before any table or meta key is written.
+        db.ensure_schema_supported()?;
 
-        let txn = env
+        let txn = db
+            .env
             .begin_rw_txn()
             .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
-        txn.create_db(Some(STEMS_DB), DatabaseFlags::CREATE)
+        let stems_db = txn
+            .create_db(Some(STEMS_DB), DatabaseFlags::CREATE)
             .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
-        txn.create_db(Some(META_DB), DatabaseFlags::CREATE)
+        let meta_db = txn
+            .create_db(Some(META_DB), DatabaseFlags::CREATE)
             .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
         txn.create_db(Some(DELTAS_DB), DatabaseFlags::CREATE)
             .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
         txn.create_db(Some(STEM_ADDR_DB), DatabaseFlags::default())
             .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        txn.create_db(Some(BALANCE_OVERFLOW_DB), DatabaseFlags::default())
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        txn.create_db(Some(STEM_HASHES_DB), DatabaseFlags::default())
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        txn.create_db(Some(INTERNAL_NODES_DB), DatabaseFlags::default())
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        txn.create_db(Some(ROOTS_DB), DatabaseFlags::default())
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        txn.create_db(Some(ROOT_HASHES_DB), DatabaseFlags::default())
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+        // A database without stems has nothing to migrate and no stem or internal
+        // node hashes, and one without a head or stems is new.
+        let has_stems = txn
+            .cursor(&stems_db)
+            .and_then(|mut cursor| cursor.first::<Vec<u8>, Vec<u8>>())
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+            .is_some();
+        let has_meta = |key: &[u8]| {
+            txn.get::<Vec<u8>>(meta_db, key)
+                .map(|value| value.is_some())
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))
+        };
+        if !has_stems && !has_meta(META_KEY_STEM_ENCODING)? {
+            txn.put(
+                meta_db,
+                META_KEY_STEM_ENCODING,
+                STEM_ENCODING_COMPACT_V1,
+                WriteFlags::DEFAULT,
+            )
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        }
+        for flag in [META_KEY_STEM_HASHES, META_KEY_INTERNAL_NODES] {
+            if !has_stems && !has_meta(flag)? {
+                txn.put(meta_db, flag, META_FLAG_VALID, WriteFlags::DEFAULT)
+                    .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+            }
+        }
+        if !has_stems && !has_meta(META_KEY_HEAD)? && !has_meta(META_KEY_SCHEMA_VERSION)? {
+            txn.put(
+                meta_db,
+                META_KEY_SCHEMA_VERSION,
+                &SCHEMA_VERSION.to_be_bytes(),
+                WriteFlags::DEFAULT,
+            )
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        }
         txn.commit()
             .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
 
-        Ok(Self { env })
+        db.migrate_schema()?;
+        Ok(db)
     }
 
-    pub fn load_head(&self) -> Result<Option<UbtHead>> {
+    /// Open an existing database read-only, for readers alongside the ExEx (RPC)
+    /// and offline inspection.
+    ///
+    /// Never creates the directory, tables or geometry and never migrates; write
+    /// methods fail. A database with a newer schema is refused. An older one is
+    /// read as is, since readers handle every earlier layout.
+    pub fn open_read_only(path: &Path) -> Result<Self> {
+        let env = Self::open_env(path, Environment::builder().read_only())?;
+        let db = Self { env };
+
+        let stored = db.ensure_schema_supported()?;
+        if stored < SCHEMA_VERSION {
+            warn!(
+                stored,
+                current = SCHEMA_VERSION,
+                "Read-only UBT database has not been migrated; open it read-write to upgrade"
+            );
+        }
+        Ok(db)
+    }
+
+    fn open_env(path: &Path, builder: EnvironmentBuilder) -> Result<Environment> {
+        builder.set_max_dbs(10).open(path).map_err(|e| {
+            UbtError::Database(DatabaseError::Open {
+                path: path.display().to_string(),
+                reason: e.to_string(),
+            })
+        })
+    }
+
+    /// Fail with `SchemaTooNew` if a newer version wrote this database, otherwise
+    /// return its schema version. A database without tables yet is new and gets
+    /// `SCHEMA_VERSION`.
+    fn ensure_schema_supported(&self) -> Result<u32> {
+        let txn = self
+            .env
+            .begin_ro_txn()
+            .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
+        match txn.open_db(Some(META_DB)) {
+            Ok(_) => {}
+            Err(e) if e.is_not_found() => return Ok(SCHEMA_VERSION),
+            Err(e) => return Err(UbtError::Database(DatabaseError::Mdbx(e.to_string()))),
+        }
+        drop(txn);
+
+        let stored = self.schema_version()?;
+        if stored > SCHEMA_VERSION {
+            return Err(UbtError::SchemaTooNew {
+                stored,
+                supported: SCHEMA_VERSION,
+            });
+        }
+        Ok(stored)
+    }
+
+    /// Layout version of this database; 1 for databases that predate versioning.
+    pub fn schema_version(&self) -> Result<u32> {
         let txn = self
             .env
             .begin_ro_txn()
@@ -93,522 +778,2790 @@ impl UbtDatabase {
             .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
 
         match txn
-            .get::<Vec<u8>>(meta_db, META_KEY_HEAD)
+            .get::<Vec<u8>>(meta_db, META_KEY_SCHEMA_VERSION)
             .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
         {
             Some(bytes) => {
-                let head: UbtHead = bincode::deserialize(&bytes)?;
-                Ok(Some(head))
+                let bytes: [u8; 4] = bytes.as_slice().try_into().map_err(|_| {
+                    UbtError::Database(DatabaseError::Mdbx(format!(
+                        "Invalid schema version length: expected 4, got {}",
+                        bytes.len()
+                    )))
+                })?;
+                Ok(u32::from_be_bytes(bytes))
             }
-            None => Ok(None),
+            None => Ok(1),
         }
     }
 
-    pub fn save_head(&self, head: &UbtHead) -> Result<()> {
+    fn save_schema_version(&self, version: u32) -> Result<()> {
+        self.with_rw_txn(|txn| {
+            let meta_db = txn
+                .open_db(Some(META_DB))
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+            txn.put(
+                meta_db,
+                META_KEY_SCHEMA_VERSION,
+                &version.to_be_bytes(),
+                WriteFlags::DEFAULT,
+            )
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))
+        })
+    }
+
+    /// Run the upgrades this database has not had yet.
+    fn migrate_schema(&self) -> Result<()> {
+        let stored = self.ensure_schema_supported()?;
+        for migration in MIGRATIONS.iter().filter(|m| m.version > stored) {
+            info!(
+                version = migration.version,
+                description = migration.description,
+                "Migrating UBT database schema"
+            );
+            (migration.run)(self)?;
+            self.save_schema_version(migration.version)?;
+        }
+        Ok(())
+    }
+
+    /// Stems without a stored address, as counted when the database was upgraded
+    /// to schema version 3. Stems written since always have one.
+    pub fn missing_stem_addresses(&self) -> Result<u64> {
         let txn = self
             .env
-            .begin_rw_txn()
+            .begin_ro_txn()
             .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
         let meta_db = txn
             .open_db(Some(META_DB))
             .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
 
-        let bytes = bincode::serialize(head)?;
-        txn.put(meta_db, META_KEY_HEAD, &bytes, WriteFlags::DEFAULT)
-            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
-        txn.commit()
+        Ok(txn
+            .get::<Vec<u8>>(meta_db, META_KEY_MISSING_STEM_ADDRESSES)
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+            .as_deref()
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(u64::from_be_bytes)
+            .unwrap_or(0))
+    }
+
+    /// Apply every mutation in `batch` in one write transaction.
+    pub fn write_batch(&self, batch: &WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        self.with_rw_txn(|txn| {
+            put_stem_addresses(txn, &batch.stem_addresses)?;
+            for (block_number, deltas) in &batch.block_deltas {
+                match deltas {
+                    Some(deltas) => put_block_deltas(txn, *block_number, deltas)?,
+                    None => del_block_deltas(txn, *block_number)?,
+                }
+            }
+            if let Some(block_number) = batch.deltas_before {
+                del_deltas_before(txn, block_number)?;
+            }
+            if let Some(block_number) = batch.balance_overflows_after {
+                del_balance_overflows_after(txn, block_number)?;
+            }
+            for ((address, block_number), balance) in &batch.balance_overflows {
+                put_balance_overflow(txn, *address, *block_number, *balance)?;
+            }
+            put_stems(txn, &batch.stems, batch.root_pending())?;
+            if let Some(max_rows) = batch.stem_migration {
+                migrate_stem_rows(txn, max_rows)?;
+            }
+            match &batch.internal_nodes {
+                Some(updates) => put_internal_nodes(txn, updates)?,
+                None if !batch.stems.is_empty() => invalidate_internal_nodes(txn)?,
+                None => {}
+            }
+
+            let meta_db = txn
+                .open_db(Some(META_DB))
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+            for (key, value) in &batch.meta {
+                match value {
+                    Some(value) => txn.put(meta_db, key, value, WriteFlags::DEFAULT),
+                    None => txn.del(meta_db, key, None),
+                }
+                .map_err(|e| write_error(e, DatabaseError::Mdbx))?;
+            }
+            if let Some(head) = &batch.root {
+                put_root(txn, head)?;
+            }
+            if let Some(block_number) = batch.roots_before {
+                del_roots_before(txn, block_number)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn with_rw_txn<T>(&self, f: impl FnOnce(&RwTransaction<'_>) -> Result<T>) -> Result<T> {
+        let txn = self
+            .env
+            .begin_rw_txn()
             .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
+        let out = f(&txn)?;
+        txn.commit()
+            .map_err(|e| write_error(e, DatabaseError::Transaction))?;
+        Ok(out)
+    }
 
-        Ok(())
+    /// The latest head whose root is known. While the root of the last flush is
+    /// pending, this is the head before it; `load_head_with_pending_root` returns
+    /// the flushed head.
+    pub fn load_head(&self) -> Result<Option<UbtHead>> {
+        Ok(match self.load_head_with_pending_root()? {
+            (Some(_), Some(pending)) => pending.finalized,
+            (head, _) => head,
+        })
     }
 
-    pub fn load_stem(&self, stem: &Stem) -> Result<Option<StemNode>> {
+    pub fn save_head(&self, head: &UbtHead) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        batch.save_head(head)?;
+        self.write_batch(&batch)
+    }
+
+    /// Load the tree hasher recorded for this database, if any.
+    pub fn load_tree_hasher(&self) -> Result<Option<TreeHasher>> {
         let txn = self
             .env
             .begin_ro_txn()
             .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
-        let stems_db = txn
-            .open_db(Some(STEMS_DB))
+        let meta_db = txn
+            .open_db(Some(META_DB))
             .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
 
         match txn
-            .get::<Vec<u8>>(stems_db, stem.as_bytes())
+            .get::<Vec<u8>>(meta_db, META_KEY_HASHER)
             .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
         {
-            Some(bytes) => {
-                let stem_node: StemNode = bincode::deserialize(&bytes)?;
-                Ok(Some(stem_node))
-            }
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
             None => Ok(None),
         }
     }
 
-    /// Load a specific value by TreeKey from MDBX.
-    pub fn load_value(&self, key: &TreeKey) -> Result<Option<B256>> {
-        Ok(self
-            .load_stem(&key.stem)?
-            .and_then(|node| node.get_value(key.subindex)))
-    }
-
-    pub fn batch_update_stems(&self, updates: &[(Stem, StemNode)]) -> Result<()> {
-        if updates.is_empty() {
-            return Ok(());
-        }
-
+    pub fn save_tree_hasher(&self, hasher: TreeHasher) -> Result<()> {
         let txn = self
             .env
             .begin_rw_txn()
             .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
-        let stems_db = txn
-            .open_db(Some(STEMS_DB))
+        let meta_db = txn
+            .open_db(Some(META_DB))
             .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
 
-        for (stem, stem_node) in updates {
-            let key = stem.as_bytes();
-            let value = bincode::serialize(stem_node)?;
-            txn.put(stems_db, key, &value, WriteFlags::DEFAULT)
-                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        // Stem hashes stored under a different hasher no longer match.
+        let bytes = bincode::serialize(&hasher)?;
+        if recorded_tree_hasher(&txn)? != hasher && has_stems(&txn)? {
+            invalidate_stem_hashes(&txn)?;
         }
-
+        txn.put(meta_db, META_KEY_HASHER, &bytes, WriteFlags::DEFAULT)
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
         txn.commit()
             .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
+
         Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn iter_stems(&self) -> Result<Vec<(Stem, StemNode)>> {
+    /// Tree hasher for this database; databases predating the setting used BLAKE3.
+    pub fn tree_hasher(&self) -> Result<TreeHasher> {
+        Ok(self.load_tree_hasher()?.unwrap_or(TreeHasher::Blake3))
+    }
+
+    /// Record `configured` as the hasher, or check it against the recorded one.
+    pub fn ensure_tree_hasher(&self, configured: TreeHasher) -> Result<()> {
+        let stored = match self.load_tree_hasher()? {
+            Some(stored) => stored,
+            // Existing state without a recorded hasher was built with BLAKE3.
+            None if self.load_head()?.is_some() => TreeHasher::Blake3,
+            None => configured,
+        };
+        if stored != configured {
+            return Err(UbtError::HasherMismatch {
+                stored: stored.to_string(),
+                configured: configured.to_string(),
+            });
+        }
+        self.save_tree_hasher(stored)
+    }
+
+    pub fn load_bootstrap_checkpoint(&self) -> Result<Option<BootstrapCheckpoint>> {
         let txn = self
             .env
             .begin_ro_txn()
             .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
-        let stems_db = txn
-            .open_db(Some(STEMS_DB))
+        let meta_db = txn
+            .open_db(Some(META_DB))
             .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
 
-        let mut stems = Vec::new();
-        let mut cursor = txn
-            .cursor(&stems_db)
+        match txn
+            .get::<Vec<u8>>(meta_db, META_KEY_BOOTSTRAP)
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+        {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn save_bootstrap_checkpoint(&self, checkpoint: &BootstrapCheckpoint) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        batch.save_bootstrap_checkpoint(checkpoint)?;
+        self.write_batch(&batch)
+    }
+
+    pub fn delete_bootstrap_checkpoint(&self) -> Result<()> {
+        let mut batch = WriteBatch::default();
+        batch.delete_bootstrap_checkpoint();
+        self.write_batch(&batch)
+    }
+
+    /// Highest block a bootstrap read plain state at. Blocks up to it may already
+    /// be reflected in bootstrapped leaves without deltas, so they cannot be
+    /// reverted.
+    pub fn load_bootstrap_floor(&self) -> Result<Option<u64>> {
+        Ok(self
+            .meta_flag(META_KEY_BOOTSTRAP_FLOOR)?
+            .map(|bytes| bincode::deserialize(&bytes))
+            .transpose()?)
+    }
+
+    pub fn load_pending_commit(&self) -> Result<Option<PendingCommit>> {
+        let txn = self
+            .env
+            .begin_ro_txn()
+            .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
+        let meta_db = txn
+            .open_db(Some(META_DB))
             .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
 
-        while let Some((key, value)) = cursor
-            .next::<Vec<u8>, Vec<u8>>()
+        match txn
+            .get::<Vec<u8>>(meta_db, META_KEY_PENDING_COMMIT)
             .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
         {
-            if key.len() == STEM_LEN {
-                let mut stem_bytes = [0u8; STEM_LEN];
-                stem_bytes.copy_from_slice(&key);
-                let stem = Stem::new(stem_bytes);
-                let stem_node: StemNode = bincode::deserialize(&value)?;
-                stems.push((stem, stem_node));
-            }
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
         }
+    }
 
-        Ok(stems)
+    pub fn save_pending_commit(&self, pending: &PendingCommit) -> Result<()> {
+        self.with_rw_txn(|txn| {
+            let meta_db = txn
+                .open_db(Some(META_DB))
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+            let bytes = bincode::serialize(pending)?;
+            txn.put(meta_db, META_KEY_PENDING_COMMIT, &bytes, WriteFlags::DEFAULT)
+                .map_err(|e| write_error(e, DatabaseError::Mdbx))
+        })
     }
 
-    #[allow(dead_code)]
-    pub fn sync(&self) -> Result<()> {
-        self.env
-            .sync(true)
-            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
-        Ok(())
+    pub fn delete_pending_commit(&self) -> Result<()> {
+        self.with_rw_txn(|txn| {
+            let meta_db = txn
+                .open_db(Some(META_DB))
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+            txn.del(meta_db, META_KEY_PENDING_COMMIT, None)
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))
+        })
     }
 
-    /// Iterate stems and yield (TreeKey, B256) pairs for streaming root computation.
-    /// Entries are yielded in sorted order (by stem, then subindex).
-    pub fn iter_entries_sorted(&self) -> Result<Vec<(TreeKey, B256)>> {
+    pub fn load_pending_root(&self) -> Result<Option<PendingRoot>> {
+        Ok(self.load_head_with_pending_root()?.1)
+    }
+
+    /// The head as flushed and the pending root marker, read in one transaction so
+    /// they describe the same flush. With a marker, the head's root is the root of
+    /// `PendingRoot::finalized`.
+    pub fn load_head_with_pending_root(&self) -> Result<(Option<UbtHead>, Option<PendingRoot>)> {
         let txn = self
             .env
             .begin_ro_txn()
             .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
-        let stems_db = txn
-            .open_db(Some(STEMS_DB))
+        let meta_db = txn
+            .open_db(Some(META_DB))
             .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
 
-        let mut entries = Vec::new();
-        let mut cursor = txn
-            .cursor(&stems_db)
-            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        let load = |key: &[u8]| {
+            txn.get::<Vec<u8>>(meta_db, key)
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))
+        };
+        let head = load(META_KEY_HEAD)?;
+        let pending = load(META_KEY_PENDING_ROOT)?;
+        Ok((
+            head.map(|bytes| bincode::deserialize(&bytes)).transpose()?,
+            pending
+                .map(|bytes| bincode::deserialize(&bytes))
+                .transpose()?,
+        ))
+    }
 
-        while let Some((key, value)) = cursor
-            .next::<Vec<u8>, Vec<u8>>()
-            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
-        {
-            if key.len() == STEM_LEN {
-                let mut stem_bytes = [0u8; STEM_LEN];
-                stem_bytes.copy_from_slice(&key);
-                let stem = Stem::new(stem_bytes);
-                let stem_node: StemNode = bincode::deserialize(&value)?;
-
-                let mut subindices: Vec<_> = stem_node.values.keys().copied().collect();
-                subindices.sort();
-
-                for subindex in subindices {
-                    if let Some(&value) = stem_node.values.get(&subindex) {
-                        let tree_key = TreeKey::new(stem, subindex);
-                        entries.push((tree_key, value));
+    /// Record `root` as the root of the head flushed with `pending`, in the head and
+    /// the root history, and remove the marker, in one transaction.
+    ///
+    /// `stem_hashes` are the hashes of the flushed stems, which the flush left to
+    /// the root computation; without them the stored stem hashes become invalid.
+    /// `nodes` are the internal node writes that match the flushed stems; without
+    /// them the stored internal nodes stay invalid. Fails if the stored marker or
+    /// head is not the one `pending` was written with.
+    pub fn finalize_root(
+        &self,
+        pending: &PendingRoot,
+        root: B256,
+        stem_hashes: Option<&[(Stem, Option<B256>)]>,
+        nodes: Option<&NodeUpdates>,
+    ) -> Result<()> {
+        self.with_rw_txn(|txn| {
+            let meta_db = txn
+                .open_db(Some(META_DB))
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+            let load = |key: &[u8]| {
+                txn.get::<Vec<u8>>(meta_db, key)
+                    .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))
+            };
+            let stored = load(META_KEY_PENDING_ROOT)?
+                .map(|bytes| bincode::deserialize::<PendingRoot>(&bytes))
+                .transpose()?;
+            let head = load(META_KEY_HEAD)?
+                .map(|bytes| bincode::deserialize::<UbtHead>(&bytes))
+                .transpose()?;
+            let mut head = match head {
+                Some(head)
+                    if stored.as_ref() == Some(pending)
+                        && head.block_number == pending.block_number
+                        && head.block_hash == pending.block_hash =>
+                {
+                    head
+                }
+                _ => {
+                    return Err(UbtError::Database(DatabaseError::Mdbx(format!(
+                        "no pending root for block {} ({})",
+                        pending.block_number, pending.block_hash
+                    ))))
+                }
+            };
+
+            head.root = root;
+            put_root(txn, &head)?;
+            txn.put(
+                meta_db,
+                META_KEY_HEAD,
+                &bincode::serialize(&head)?,
+                WriteFlags::DEFAULT,
+            )
+            .map_err(|e| write_error(e, DatabaseError::Mdbx))?;
+            txn.del(meta_db, META_KEY_PENDING_ROOT, None)
+                .map_err(|e| write_error(e, DatabaseError::Mdbx))?;
+            let deferred = txn
+                .get::<Vec<u8>>(meta_db, META_KEY_STEM_HASHES)
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+                .as_deref()
+                == Some(META_FLAG_PENDING);
+            match stem_hashes {
+                Some(hashes) => {
+                    put_stem_hashes(txn, hashes.iter().map(|(stem, hash)| (stem, hash.as_ref())))?;
+                    if deferred {
+                        set_meta_flag(txn, META_KEY_STEM_HASHES)?;
                     }
                 }
+                None if deferred => invalidate_stem_hashes(txn)?,
+                None => {}
             }
-        }
-
-        Ok(entries)
+            match nodes {
+                Some(updates) => {
+                    put_internal_nodes(txn, updates.iter().map(|(key, hash)| (*key, *hash)))?;
+                    set_meta_flag(txn, META_KEY_INTERNAL_NODES)
+                }
+                None => invalidate_internal_nodes(txn),
+            }
+        })
     }
 
-    pub fn save_stem_address(&self, stem: &Stem, address: &Address) -> Result<()> {
+    pub fn load_stem(&self, stem: &Stem) -> Result<Option<StemNode>> {
         let txn = self
             .env
-            .begin_rw_txn()
+            .begin_ro_txn()
             .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
-        let db = txn
-            .open_db(Some(STEM_ADDR_DB))
+        let stems_db = txn
+            .open_db(Some(STEMS_DB))
             .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
 
-        if txn
-            .get::<Vec<u8>>(db, stem.as_bytes())
+        match txn
+            .get::<Vec<u8>>(stems_db, stem.as_bytes())
             .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
-            .is_some()
         {
-            return Err(UbtError::Database(DatabaseError::Mdbx(format!(
-                "Stem address already exists for {:?}",
-                stem
-            ))));
+            Some(bytes) => Ok(Some(decode_stem(*stem, &bytes)?)),
+            None => Ok(None),
         }
+    }
 
-        txn.put(
-            db,
-            stem.as_bytes(),
-            address.as_slice(),
-            WriteFlags::DEFAULT,
-        )
-        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
-        txn.commit()
-            .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
-
-        Ok(())
+    /// Load a specific value by TreeKey from MDBX.
+    pub fn load_value(&self, key: &TreeKey) -> Result<Option<B256>> {
+        Ok(self
+            .load_stem(&key.stem)?
+            .and_then(|node| node.get_value(key.subindex)))
     }
 
-    pub fn batch_save_stem_addresses(&self, mappings: &[(Stem, Address)]) -> Result<()> {
-        if mappings.is_empty() {
+    /// Write a batch of stem nodes. Stems without any values are deleted.
+    ///
+    /// Internal node hashes are not updated, so they are marked invalid.
+    pub fn batch_update_stems(&self, updates: &[(Stem, StemNode)]) -> Result<()> {
+        if updates.is_empty() {
             return Ok(());
         }
+        self.with_rw_txn(|txn| {
+            put_stems(txn, updates, false)?;
+            invalidate_internal_nodes(txn)
+        })
+    }
 
+    /// Whether every stored stem uses the compact encoding.
+    pub fn stem_encoding_migrated(&self) -> Result<bool> {
         let txn = self
             .env
-            .begin_rw_txn()
+            .begin_ro_txn()
             .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
-        let db = txn
-            .open_db(Some(STEM_ADDR_DB))
+        let meta_db = txn
+            .open_db(Some(META_DB))
             .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
 
-        for (stem, address) in mappings {
-            if let Some(existing) = txn
-                .get::<Vec<u8>>(db, stem.as_bytes())
-                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
-            {
-                if existing.as_slice() != address.as_slice() {
-                    return Err(UbtError::Database(DatabaseError::Mdbx(
-                        "Stem address mismatch in MDBX".to_string(),
-                    )));
-                }
-                continue;
+        Ok(txn
+            .get::<Vec<u8>>(meta_db, META_KEY_STEM_ENCODING)
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+            .is_some())
+    }
+
+    /// Rewrite up to `max_rows` stems, continuing after the last call, in the
+    /// compact encoding.
+    ///
+    /// Each call is one write transaction that also records where the next call
+    /// resumes, so the migration can run between blocks and survive restarts;
+    /// flushes run the same step in their own transaction through
+    /// `WriteBatch::migrate_stem_encoding`. Stems written in the meantime are
+    /// already compact and are skipped.
+    pub fn migrate_stem_encoding(&self, max_rows: usize) -> Result<StemMigrationProgress> {
+        self.with_rw_txn(|txn| migrate_stem_rows(txn, max_rows))
+    }
+
+    /// Write stems in the legacy bincode encoding and mark the database as not
+    /// migrated, as databases created before the compact encoding were, and without
+    /// stem hashes. Used to test and benchmark the migration.
+    #[doc(hidden)]
+    pub fn write_legacy_stems(&self, updates: &[(Stem, StemNode)]) -> Result<()> {
+        self.with_rw_txn(|txn| {
+            let stems_db = txn
+                .open_db(Some(STEMS_DB))
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+            let meta_db = txn
+                .open_db(Some(META_DB))
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+            for (stem, node) in updates {
+                let value = encode_stem_legacy(node)?;
+                txn.put(stems_db, stem.as_bytes(), &value, WriteFlags::DEFAULT)
+                    .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
             }
+            txn.del(meta_db, META_KEY_STEM_ENCODING, None)
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+            invalidate_stem_hashes(txn)
+        })
+    }
 
-            txn.put(
-                db,
-                stem.as_bytes(),
-                address.as_slice(),
-                WriteFlags::DEFAULT,
-            )
-            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
-        }
+    /// Lazily iterate all stems in key order within a single read transaction.
+    pub fn iter_stems(&self) -> Result<StemIter<'_>> {
+        self.open_stem_iter(None)
+    }
 
-        txn.commit()
-            .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
+    /// Like `iter_stems`, starting at the first stem greater than or equal to `stem`.
+    pub fn iter_stems_from(&self, stem: &Stem) -> Result<StemIter<'_>> {
+        self.open_stem_iter(Some(*stem))
+    }
 
-        Ok(())
+    fn open_stem_iter(&self, start: Option<Stem>) -> Result<StemIter<'_>> {
+        Ok(self.open_stem_snapshot(start)?.1)
     }
 
-    pub fn load_stem_address(&self, stem: &Stem) -> Result<Option<Address>> {
+    /// A stem iterator together with the encoded head stored in the same snapshot.
+    fn open_stem_snapshot(&self, start: Option<Stem>) -> Result<(Option<Vec<u8>>, StemIter<'_>)> {
         let txn = self
             .env
             .begin_ro_txn()
             .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
-        let db = txn
+        let meta_db = txn
+            .open_db(Some(META_DB))
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        let head = txn
+            .get::<Vec<u8>>(meta_db, META_KEY_HEAD)
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        let stems_db = txn
+            .open_db(Some(STEMS_DB))
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        let stem_addr_db = txn
             .open_db(Some(STEM_ADDR_DB))
             .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        let cursor = txn
+            .into_cursor(&stems_db)
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
 
-        match txn
-            .get::<Vec<u8>>(db, stem.as_bytes())
-            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
-        {
-            Some(bytes) => {
-                if bytes.len() != 20 {
-                    return Err(UbtError::Database(DatabaseError::Mdbx(format!(
-                        "Invalid address length: expected 20, got {}",
-                        bytes.len()
-                    ))));
-                }
-                let mut addr = [0u8; 20];
-                addr.copy_from_slice(&bytes);
-                Ok(Some(Address::from(addr)))
-            }
-            None => Ok(None),
-        }
+        Ok((
+            head,
+            StemIter {
+                cursor,
+                stem_addr_db,
+                start,
+            },
+        ))
     }
 
-    pub fn save_block_deltas(&self, block_number: u64, deltas: &[(Stem, u8, B256)]) -> Result<()> {
-        let txn = self
+    #[allow(dead_code)]
+    pub fn sync(&self) -> Result<()> {
+        self.env
+            .sync(true)
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        Ok(())
+    }
+
+    /// Double the upper bound of the map, so writes that failed with `MapFull` can
+    /// be retried. Returns the new upper bound in bytes.
+    ///
+    /// The raised bound lasts until the database is closed; on reopen the bound is
+    /// `UBT_MDBX_MAX_SIZE` or the data file size, whichever is larger.
+    pub fn grow_map(&self) -> Result<u64> {
+        let info = self
             .env
-            .begin_rw_txn()
-            .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
-        let deltas_db = txn
-            .open_db(Some(DELTAS_DB))
+            .info()
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        let upper = info.geo_upper.saturating_mul(2);
+        if upper <= info.geo_upper {
+            return Err(UbtError::Database(DatabaseError::MapFull));
+        }
+        self.env
+            .set_geometry(&Geometry {
+                size_lower: -1,
+                size_now: -1,
+                size_upper: upper as i64,
+                growth_step: -1,
+                shrink_threshold: -1,
+                page_size: -1,
+            })
             .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        info!(from = info.geo_upper, to = upper, "Raised MDBX map upper bound");
+        Ok(upper)
+    }
 
-        let key = block_number.to_be_bytes();
-        let value = bincode::serialize(deltas)?;
-        txn.put(deltas_db, &key, &value, WriteFlags::DEFAULT)
+    /// Entry counts and sizes of every table, and how full the map is.
+    pub fn stats(&self) -> Result<DbStats> {
+        let info = self
+            .env
+            .info()
             .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
-        txn.commit()
+        let txn = self
+            .env
+            .begin_ro_txn()
             .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
 
-        Ok(())
+        let mut tables = Vec::with_capacity(TABLES.len());
+        for name in TABLES {
+            // Tables added after a read-only database was written may be missing.
+            let db = match txn.open_db(Some(name)) {
+                Ok(db) => db,
+                Err(e) if e.is_not_found() => continue,
+                Err(e) => return Err(UbtError::Database(DatabaseError::Mdbx(e.to_string()))),
+            };
+            let stat = txn
+                .db_stat(db)
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+            tables.push(TableStats {
+                name: name.to_string(),
+                entries: stat.entries,
+                size_bytes: stat.size_bytes(),
+                depth: stat.depth,
+                overflow_pages: stat.overflow_pages,
+            });
+        }
+
+        let used_bytes = info.used_bytes();
+        let map_utilization = if info.geo_upper == 0 {
+            0.0
+        } else {
+            used_bytes as f64 / info.geo_upper as f64
+        };
+        Ok(DbStats {
+            page_size: info.page_size,
+            used_bytes,
+            map_size_bytes: info.geo_current,
+            map_upper_bytes: info.geo_upper,
+            map_utilization,
+            tables,
+        })
     }
 
-    pub fn load_block_deltas(&self, block_number: u64) -> Result<Vec<(Stem, u8, B256)>> {
+    /// Copy the database into `dest_dir` while it stays open, optionally compacted.
+    ///
+    /// The copy is a consistent snapshot of the last committed write transaction and
+    /// can be opened with `UbtDatabase::open`. `dest_dir` is created if needed and
+    /// must not already contain a database.
+    pub fn backup(&self, dest_dir: &Path, compact: bool) -> Result<()> {
+        let dest = dest_dir.join(DATA_FILE);
+        if dest.exists() {
+            return Err(UbtError::Backup {
+                message: format!("destination {} already exists", dest.display()),
+            });
+        }
+        std::fs::create_dir_all(dest_dir)?;
+        self.env
+            .copy(&dest, compact)
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))
+    }
+
+    /// Lazily iterate all (TreeKey, B256) pairs for streaming root computation.
+    /// Entries are yielded in sorted order (by stem, then subindex), one stem
+    /// decoded at a time.
+    pub fn iter_entries_sorted(&self) -> Result<EntryIter<'_>> {
+        self.iter_entries_with_overlay(&[])
+    }
+
+    /// Like `iter_entries_sorted`, with `overlay` stems (sorted by stem) taking the
+    /// place of stored ones. Lets a root be computed before the overlay is written.
+    pub fn iter_entries_with_overlay<'a>(
+        &'a self,
+        overlay: &'a [(Stem, StemNode)],
+    ) -> Result<EntryIter<'a>> {
+        Ok(EntryIter {
+            stems: self.iter_stems()?.fuse(),
+            stored: None,
+            overlay: overlay.iter().peekable(),
+            current: Vec::new().into_iter(),
+        })
+    }
+
+    /// Like `iter_entries_sorted`, with the head stored in the same read snapshot, so
+    /// the entries can be checked against its root while a writer keeps going.
+    pub fn iter_entries_at_head(&self) -> Result<(Option<UbtHead>, EntryIter<'_>)> {
+        let (head, stems) = self.open_stem_snapshot(None)?;
+        let head = head.map(|bytes| bincode::deserialize(&bytes)).transpose()?;
+        Ok((
+            head,
+            EntryIter {
+                stems: stems.fuse(),
+                stored: None,
+                overlay: <&[_]>::default().iter().peekable(),
+                current: Vec::new().into_iter(),
+            },
+        ))
+    }
+
+    /// Rehash the first stem at or after each of `starts` (wrapping around to the
+    /// first stem) and the path from each up to the root, in one read transaction.
+    ///
+    /// Each sampled stem is compared with its stored hash, and every node on the
+    /// sampled paths is recomputed from the stored sibling subtrees and compared
+    /// with `ubt_internal_nodes`, so the root the paths lead to only matches the
+    /// head while the stored nodes along them do.
+    pub fn sample_root_paths(&self, hasher: TreeHasher, starts: &[Stem]) -> Result<RootPathSample> {
         let txn = self
             .env
             .begin_ro_txn()
             .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
-        let deltas_db = txn
-            .open_db(Some(DELTAS_DB))
+        let stems_db = txn
+            .open_db(Some(STEMS_DB))
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        let hashes_db = txn
+            .open_db(Some(STEM_HASHES_DB))
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        let nodes_db = txn
+            .open_db(Some(INTERNAL_NODES_DB))
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        let mut cursor = txn
+            .cursor(&stems_db)
             .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
 
-        let key = block_number.to_be_bytes();
-        match txn
-            .get::<Vec<u8>>(deltas_db, &key)
-            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
-        {
-            Some(bytes) => {
-                let deltas: Vec<(Stem, u8, B256)> = bincode::deserialize(&bytes)?;
-                Ok(deltas)
+        let mut sampled = BTreeMap::new();
+        let mut stale_stems = Vec::new();
+        for start in starts {
+            let entry = match cursor
+                .set_range::<Vec<u8>, Vec<u8>>(start.as_bytes())
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+            {
+                Some(entry) => Some(entry),
+                None => cursor
+                    .first()
+                    .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?,
+            };
+            let Some((key, value)) = entry else {
+                break;
+            };
+            let Ok(bytes) = <[u8; STEM_LEN]>::try_from(key.as_slice()) else {
+                continue;
+            };
+            let stem = Stem::new(bytes);
+            if sampled.contains_key(&stem) {
+                continue;
+            }
+            let expected = hasher.hash_stem(stem, &decode_stem(stem, &value)?);
+            let stored = txn
+                .get::<Vec<u8>>(hashes_db, &key)
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+                .map(|bytes| decode_hash(&bytes))
+                .transpose()?;
+            if stored != Some(expected) {
+                stale_stems.push(stem);
+            }
+            sampled.insert(stem, Some(expected));
+        }
+
+        let overlay: Vec<_> = sampled.into_iter().collect();
+        let stem_hashes = txn
+            .cursor(&hashes_db)
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        let nodes = txn
+            .cursor(&nodes_db)
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        let (root, updates) =
+            IncrementalRoot::new(hasher, &overlay, stem_hashes, nodes).compute()?;
+
+        let mut stale_nodes = Vec::new();
+        for (key, hash) in updates {
+            let stored = txn
+                .get::<Vec<u8>>(nodes_db, &key)
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+                .map(|bytes| decode_hash(&bytes))
+                .transpose()?;
+            if stored != hash {
+                stale_nodes.push(key);
             }
-            None => Ok(Vec::new()),
         }
+        Ok(RootPathSample {
+            stale_stems,
+            stale_nodes,
+            root,
+        })
     }
 
-    pub fn delete_block_deltas(&self, block_number: u64) -> Result<()> {
+    /// Mark the stored stem hashes, and the internal nodes built from them,
+    /// invalid so they are rebuilt, as for a database from before they were kept.
+    pub fn invalidate_stem_hashes(&self) -> Result<()> {
+        self.with_rw_txn(invalidate_stem_hashes)
+    }
+
+    /// Mark the stored internal node hashes invalid so they are rebuilt.
+    pub fn invalidate_internal_nodes(&self) -> Result<()> {
+        self.with_rw_txn(invalidate_internal_nodes)
+    }
+
+    /// Whether `ubt_stem_hashes` matches `ubt_stems`.
+    pub fn stem_hashes_valid(&self) -> Result<bool> {
+        Ok(self.meta_flag(META_KEY_STEM_HASHES)?.as_deref() == Some(META_FLAG_VALID))
+    }
+
+    /// Whether `ubt_stem_hashes` matches `ubt_stems` except for the stems flushed
+    /// with a pending root, whose hashes the root computation holds.
+    pub fn stem_hashes_pending(&self) -> Result<bool> {
+        Ok(self.meta_flag(META_KEY_STEM_HASHES)?.as_deref() == Some(META_FLAG_PENDING))
+    }
+
+    /// Whether `ubt_internal_nodes` matches `ubt_stems`, so roots can be computed
+    /// incrementally.
+    pub fn internal_nodes_valid(&self) -> Result<bool> {
+        Ok(self.meta_flag(META_KEY_INTERNAL_NODES)?.as_deref() == Some(META_FLAG_VALID))
+    }
+
+    fn meta_flag(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         let txn = self
             .env
-            .begin_rw_txn()
+            .begin_ro_txn()
             .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
-        let deltas_db = txn
-            .open_db(Some(DELTAS_DB))
+        let meta_db = txn
+            .open_db(Some(META_DB))
             .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
 
-        let key = block_number.to_be_bytes();
-        txn.del(deltas_db, &key, None)
-            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
-        txn.commit()
-            .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
+        txn.get::<Vec<u8>>(meta_db, key)
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))
+    }
+
+    /// Compute the root of MDBX with `overlay` (stem hashes from
+    /// `TreeHasher::hash_stems`, sorted by stem) applied on top, rehashing only the
+    /// paths above overlay stems and reading every other subtree from
+    /// `ubt_internal_nodes`.
+    ///
+    /// Also returns the node writes that match the overlay, to be queued with it in
+    /// the same `WriteBatch`. Only valid while `internal_nodes_valid` holds, which
+    /// implies valid stem hashes; a table found not to match the stems is an error.
+    pub fn compute_root_incremental(
+        &self,
+        hasher: TreeHasher,
+        overlay: &[(Stem, Option<B256>)],
+    ) -> Result<(B256, NodeUpdates)> {
+        let txn = self
+            .env
+            .begin_ro_txn()
+            .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
+        let hashes_db = txn
+            .open_db(Some(STEM_HASHES_DB))
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        let nodes_db = txn
+            .open_db(Some(INTERNAL_NODES_DB))
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        let stem_hashes = txn
+            .cursor(&hashes_db)
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        let nodes = txn
+            .cursor(&nodes_db)
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+        IncrementalRoot::new(hasher, overlay, stem_hashes, nodes).compute()
+    }
+
+    /// Compute the root of MDBX with `overlay` (stem hashes from
+    /// `TreeHasher::hash_stems`, sorted by stem) applied on top from the stored
+    /// stem hashes.
+    ///
+    /// One pass over `ubt_stem_hashes` in a single read transaction, in memory
+    /// bounded by the stem length. Only valid while `stem_hashes_valid` holds, or
+    /// while `stem_hashes_pending` does and `overlay` holds the pending hashes.
+    pub fn compute_root_from_stem_hashes(
+        &self,
+        hasher: TreeHasher,
+        overlay: &[(Stem, Option<B256>)],
+    ) -> Result<B256> {
+        let txn = self
+            .env
+            .begin_ro_txn()
+            .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
+        let hashes_db = txn
+            .open_db(Some(STEM_HASHES_DB))
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        let mut cursor = txn
+            .cursor(&hashes_db)
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+        let mut builder = StemHashRoot::new(hasher);
+        let mut dirty = overlay.iter().copied().peekable();
+        let mut stored = stem_hash_entry(
+            cursor
+                .first()
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?,
+        )?;
+        loop {
+            let from_overlay = match (&stored, dirty.peek()) {
+                (None, None) => break,
+                (Some(_), None) => false,
+                (None, Some(_)) => true,
+                (Some((stored, _)), Some((stem, _))) => stem <= stored,
+            };
+
+            if from_overlay {
+                let (stem, hash) = dirty.next().expect("peeked");
+                if stored.as_ref().is_some_and(|(stored, _)| *stored == stem) {
+                    stored =
+                        stem_hash_entry(cursor.next().map_err(|e| {
+                            UbtError::Database(DatabaseError::Mdbx(e.to_string()))
+                        })?)?;
+                }
+                if let Some(hash) = hash {
+                    builder.push(stem, hash);
+                }
+            } else {
+                let (stem, hash) = stored.take().expect("checked above");
+                builder.push(stem, hash);
+                stored = stem_hash_entry(
+                    cursor
+                        .next()
+                        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?,
+                )?;
+            }
+        }
+        Ok(builder.finish())
+    }
+
+    /// Hash stored for a stem in `ubt_stem_hashes`.
+    pub fn load_stem_hash(&self, stem: &Stem) -> Result<Option<B256>> {
+        let txn = self
+            .env
+            .begin_ro_txn()
+            .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
+        let hashes_db = txn
+            .open_db(Some(STEM_HASHES_DB))
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+        txn.get::<Vec<u8>>(hashes_db, stem.as_bytes())
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+            .map(|bytes| decode_hash(&bytes))
+            .transpose()
+    }
+
+    /// Rebuild `ubt_stem_hashes` from `ubt_stems` with the recorded tree hasher,
+    /// mark it valid and return the number of stems hashed.
+    ///
+    /// Stems are hashed in parallel one bucket (first stem byte) per write
+    /// transaction. Internal nodes are built from stem hashes, so they are marked
+    /// invalid as well.
+    pub fn rebuild_stem_hashes(&self) -> Result<u64> {
+        let hasher = self.tree_hasher()?;
+        self.with_rw_txn(|txn| {
+            invalidate_stem_hashes(txn)?;
+            let hashes_db = txn
+                .open_db(Some(STEM_HASHES_DB))
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+            txn.clear_db(hashes_db)
+                .map_err(|e| write_error(e, DatabaseError::Mdbx))
+        })?;
+
+        let mut count = 0;
+        for bucket in 0..=u8::MAX {
+            count += self.with_rw_txn(|txn| {
+                let hashes = read_bucket(txn, STEMS_DB, bucket)?
+                    .into_par_iter()
+                    .map(|(stem, bytes)| {
+                        Ok((stem, hasher.hash_stem(stem, &decode_stem(stem, &bytes)?)))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                put_stem_hashes(txn, hashes.iter().map(|(stem, hash)| (stem, Some(hash))))?;
+                Ok(hashes.len() as u64)
+            })?;
+        }
+
+        self.with_rw_txn(|txn| set_meta_flag(txn, META_KEY_STEM_HASHES))?;
+        Ok(count)
+    }
+
+    /// Rebuild `ubt_internal_nodes` from `ubt_stem_hashes`, mark it valid and return
+    /// the root. Invalid stem hashes are rebuilt first.
+    ///
+    /// Stem hashes are read one bucket (first stem byte) at a time, with each
+    /// bucket's nodes written in its own transaction, so memory is bounded by the
+    /// largest bucket. The nodes above the buckets and the valid flag are written
+    /// last; an interrupted rebuild starts over on the next call.
+    pub fn rebuild_internal_nodes(&self, hasher: TreeHasher) -> Result<B256> {
+        if !self.stem_hashes_valid()? {
+            self.rebuild_stem_hashes()?;
+        }
+        self.with_rw_txn(|txn| {
+            invalidate_internal_nodes(txn)?;
+            let nodes_db = txn
+                .open_db(Some(INTERNAL_NODES_DB))
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+            txn.clear_db(nodes_db)
+                .map_err(|e| write_error(e, DatabaseError::Mdbx))
+        })?;
+
+        let mut buckets = Vec::with_capacity(1 << REBUILD_BUCKET_DEPTH);
+        for bucket in 0..=u8::MAX {
+            buckets.push(self.with_rw_txn(|txn| rebuild_bucket(txn, hasher, bucket))?);
+        }
+
+        self.with_rw_txn(|txn| {
+            let mut nodes = Vec::new();
+            let root = build_top(hasher, &[0u8; STEM_LEN], 0, &buckets, &mut nodes);
+            put_internal_nodes(txn, nodes.into_iter().map(|(key, hash)| (key, Some(hash))))?;
+            set_meta_flag(txn, META_KEY_INTERNAL_NODES)?;
+            Ok(root.hash)
+        })
+    }
+
+    pub fn save_stem_address(&self, stem: &Stem, address: &Address) -> Result<()> {
+        let txn = self
+            .env
+            .begin_rw_txn()
+            .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
+        let db = txn
+            .open_db(Some(STEM_ADDR_DB))
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+        if txn
+            .get::<Vec<u8>>(db, stem.as_bytes())
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+            .is_some()
+        {
+            return Err(UbtError::Database(DatabaseError::Mdbx(format!(
+                "Stem address already exists for {:?}",
+                stem
+            ))));
+        }
+
+        txn.put(
+            db,
+            stem.as_bytes(),
+            address.as_slice(),
+            WriteFlags::DEFAULT,
+        )
+        .map_err(|e| write_error(e, DatabaseError::Mdbx))?;
+        txn.commit()
+            .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
+
+        Ok(())
+    }
+
+    pub fn batch_save_stem_addresses(&self, mappings: &[(Stem, Address)]) -> Result<()> {
+        if mappings.is_empty() {
+            return Ok(());
+        }
+        self.with_rw_txn(|txn| {
+            put_stem_addresses(txn, mappings.iter().map(|(stem, address)| (stem, address)))
+        })
+    }
+
+    pub fn load_stem_address(&self, stem: &Stem) -> Result<Option<Address>> {
+        let txn = self
+            .env
+            .begin_ro_txn()
+            .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
+        let db = txn
+            .open_db(Some(STEM_ADDR_DB))
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+        match txn
+            .get::<Vec<u8>>(db, stem.as_bytes())
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+        {
+            Some(bytes) => {
+                if bytes.len() != 20 {
+                    return Err(UbtError::Database(DatabaseError::Mdbx(format!(
+                        "Invalid address length: expected 20, got {}",
+                        bytes.len()
+                    ))));
+                }
+                let mut addr = [0u8; 20];
+                addr.copy_from_slice(&bytes);
+                Ok(Some(Address::from(addr)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Record an account whose balance overflowed the basic-data leaf at a block.
+    pub fn save_balance_overflow(&self, record: &BalanceOverflow) -> Result<()> {
+        self.with_rw_txn(|txn| {
+            put_balance_overflow(
+                txn,
+                record.address,
+                record.block_number,
+                Some(record.balance),
+            )
+        })
+    }
+
+    /// Load the latest balance overflow of every account whose record has not been
+    /// cleared since, ordered by address.
+    pub fn load_balance_overflows(&self) -> Result<Vec<BalanceOverflow>> {
+        let txn = self
+            .env
+            .begin_ro_txn()
+            .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
+        let db = txn
+            .open_db(Some(BALANCE_OVERFLOW_DB))
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+        let mut cursor = txn
+            .cursor(&db)
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+        // Entries of an account are ordered by block, so the last one is current.
+        let mut latest: BTreeMap<Address, (u64, Option<U256>)> = BTreeMap::new();
+        while let Some((key, value)) = cursor
+            .next::<Vec<u8>, Vec<u8>>()
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+        {
+            let (address, block_number) = balance_overflow_key_parts(&key)?;
+            latest.insert(address, (block_number, bincode::deserialize(&value)?));
+        }
+
+        Ok(latest
+            .into_iter()
+            .filter_map(|(address, (block_number, balance))| {
+                Some(BalanceOverflow {
+                    address,
+                    block_number,
+                    balance: balance?,
+                })
+            })
+            .collect())
+    }
+
+    pub fn save_block_deltas(&self, block_number: u64, deltas: &[(Stem, u8, B256)]) -> Result<()> {
+        self.with_rw_txn(|txn| put_block_deltas(txn, block_number, deltas))
+    }
+
+    pub fn load_block_deltas(&self, block_number: u64) -> Result<Vec<(Stem, u8, B256)>> {
+        let txn = self
+            .env
+            .begin_ro_txn()
+            .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
+        let deltas_db = txn
+            .open_db(Some(DELTAS_DB))
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+        let key = block_number.to_be_bytes();
+        match txn
+            .get::<Vec<u8>>(deltas_db, &key)
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+        {
+            Some(bytes) => {
+                let deltas: Vec<(Stem, u8, B256)> = bincode::deserialize(&bytes)?;
+                Ok(deltas)
+            }
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn delete_block_deltas(&self, block_number: u64) -> Result<()> {
+        self.with_rw_txn(|txn| del_block_deltas(txn, block_number))
+    }
+
+    /// Prune deltas for blocks older than the given block number.
+    /// Returns the number of deltas deleted.
+    pub fn prune_deltas_before(&self, block_number: u64) -> Result<usize> {
+        self.with_rw_txn(|txn| del_deltas_before(txn, block_number))
+    }
+
+    /// Delete the deltas of every block after `block_number`.
+    #[allow(dead_code)]
+    pub fn delete_deltas_after(&self, block_number: u64) -> Result<()> {
+        let Some(first) = block_number.checked_add(1) else {
+            return Ok(());
+        };
+        self.with_rw_txn(|txn| {
+            let deltas_db = txn
+                .open_db(Some(DELTAS_DB))
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+            let mut cursor = txn
+                .cursor(&deltas_db)
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+            let mut entry = cursor.set_range::<Vec<u8>, Vec<u8>>(&first.to_be_bytes());
+            while entry
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+                .is_some()
+            {
+                cursor
+                    .del()
+                    .map_err(|e| write_error(e, DatabaseError::Mdbx))?;
+                entry = cursor.next::<Vec<u8>, Vec<u8>>();
+            }
+            Ok(())
+        })
+    }
+
+    /// Stored deltas of blocks `from..=to`, in ascending block order. Blocks
+    /// without stored deltas are omitted.
+    pub fn deltas_in_range(&self, from: u64, to: u64) -> Result<Vec<(u64, Vec<(Stem, u8, B256)>)>> {
+        let mut blocks = Vec::new();
+        if from > to {
+            return Ok(blocks);
+        }
+
+        let txn = self
+            .env
+            .begin_ro_txn()
+            .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
+        let deltas_db = txn
+            .open_db(Some(DELTAS_DB))
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        let mut cursor = txn
+            .cursor(&deltas_db)
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+        let mut entry = cursor.set_range::<Vec<u8>, Vec<u8>>(&from.to_be_bytes());
+        while let Some((key, value)) =
+            entry.map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+        {
+            match block_number_from_key(&key) {
+                Some(block_number) if block_number > to => break,
+                Some(block_number) => blocks.push((block_number, bincode::deserialize(&value)?)),
+                None => {}
+            }
+            entry = cursor.next::<Vec<u8>, Vec<u8>>();
+        }
+        Ok(blocks)
+    }
+
+    /// Root history record of `block_number`, if that block was flushed and its
+    /// record not pruned.
+    pub fn load_root_at(&self, block_number: u64) -> Result<Option<UbtHead>> {
+        self.find_root(|txn, roots_db| {
+            let mut cursor = txn.cursor(&roots_db)?;
+            match cursor.set_range::<Vec<u8>, Vec<u8>>(&block_number.to_be_bytes())? {
+                Some((key, value)) if key.starts_with(&block_number.to_be_bytes()) => {
+                    Ok(Some(value))
+                }
+                _ => Ok(None),
+            }
+        })
+    }
+
+    /// Root history record of the block with `block_hash`, found through
+    /// `ubt_root_hashes`.
+    pub fn load_root_by_hash(&self, block_hash: B256) -> Result<Option<UbtHead>> {
+        self.find_root(|txn, roots_db| {
+            let hashes_db = txn.open_db(Some(ROOT_HASHES_DB))?;
+            let number = txn
+                .get::<Vec<u8>>(hashes_db, block_hash.as_slice())?
+                .as_deref()
+                .and_then(block_number_from_key);
+            match number {
+                Some(number) => txn.get(roots_db, &root_key(number, block_hash)),
+                None => Ok(None),
+            }
+        })
+    }
+
+    /// Run `find` with `ubt_roots` in one read transaction and decode the record it
+    /// returns. A database predating the tables, opened read-only, has no records.
+    fn find_root(
+        &self,
+        find: impl FnOnce(
+            &RoTransaction<'_>,
+            Database,
+        ) -> std::result::Result<Option<Vec<u8>>, MdbxError>,
+    ) -> Result<Option<UbtHead>> {
+        let txn = self
+            .env
+            .begin_ro_txn()
+            .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
+        let roots_db = match txn.open_db(Some(ROOTS_DB)) {
+            Ok(db) => db,
+            Err(e) if e.is_not_found() => return Ok(None),
+            Err(e) => return Err(UbtError::Database(DatabaseError::Mdbx(e.to_string()))),
+        };
+
+        match find(&txn, roots_db) {
+            Ok(Some(value)) => Ok(Some(bincode::deserialize(&value)?)),
+            Ok(None) => Ok(None),
+            Err(e) if e.is_not_found() => Ok(None),
+            Err(e) => Err(UbtError::Database(DatabaseError::Mdbx(e.to_string()))),
+        }
+    }
+
+    /// Delete root history records of blocks before `block_number`. Returns the
+    /// number of records deleted.
+    pub fn prune_roots_before(&self, block_number: u64) -> Result<usize> {
+        self.with_rw_txn(|txn| del_roots_before(txn, block_number))
+    }
+}
+
+/// Schema 8: balance overflow records were keyed by address alone, holding the
+/// latest overflow. Rewrites each as the entry of its block.
+fn migrate_balance_overflow_keys(db: &UbtDatabase) -> Result<()> {
+    db.with_rw_txn(|txn| {
+        let overflows_db = txn
+            .open_db(Some(BALANCE_OVERFLOW_DB))
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        let mut cursor = txn
+            .cursor(&overflows_db)
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+        let mut records = Vec::new();
+        let mut entry = cursor.first::<Vec<u8>, Vec<u8>>();
+        while let Some((key, value)) =
+            entry.map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+        {
+            if key.len() == 20 {
+                records.push(bincode::deserialize::<BalanceOverflow>(&value)?);
+                cursor
+                    .del()
+                    .map_err(|e| write_error(e, DatabaseError::Mdbx))?;
+            }
+            entry = cursor.next::<Vec<u8>, Vec<u8>>();
+        }
+
+        for record in records {
+            put_balance_overflow(
+                txn,
+                record.address,
+                record.block_number,
+                Some(record.balance),
+            )?;
+        }
+        Ok(())
+    })
+}
+
+/// Schema 7: start the root history at the head, unless its root is still pending.
+fn migrate_record_head_root(db: &UbtDatabase) -> Result<()> {
+    match db.load_head_with_pending_root()? {
+        (Some(head), None) => {
+            let mut batch = WriteBatch::default();
+            batch.save_root(&head);
+            db.write_batch(&batch)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Schema 2: state written before the hasher was selectable used BLAKE3.
+fn migrate_record_tree_hasher(db: &UbtDatabase) -> Result<()> {
+    if db.load_tree_hasher()?.is_none() && db.load_head()?.is_some() {
+        db.save_tree_hasher(TreeHasher::Blake3)?;
+    }
+    Ok(())
+}
+
+/// Schema 3: stems written before `ubt_stem_addresses` existed have no address,
+/// which PIR export needs. Counts them in batches, resuming after the last stem
+/// checked.
+fn migrate_audit_stem_addresses(db: &UbtDatabase) -> Result<()> {
+    loop {
+        let (done, missing) = db.with_rw_txn(|txn| {
+            let meta_db = txn
+                .open_db(Some(META_DB))
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+            let stems_db = txn
+                .open_db(Some(STEMS_DB))
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+            let stem_addr_db = txn
+                .open_db(Some(STEM_ADDR_DB))
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+            let resume = txn
+                .get::<Vec<u8>>(meta_db, META_KEY_MIGRATION_CURSOR)
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+            let mut missing = match &resume {
+                Some(_) => txn
+                    .get::<Vec<u8>>(meta_db, META_KEY_MISSING_STEM_ADDRESSES)
+                    .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+                    .as_deref()
+                    .and_then(|bytes| bytes.try_into().ok())
+                    .map(u64::from_be_bytes)
+                    .unwrap_or(0),
+                None => 0,
+            };
+
+            let mut cursor = txn
+                .cursor(&stems_db)
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+            let mut entry = match &resume {
+                Some(key) => cursor.set_range::<Vec<u8>, Vec<u8>>(key),
+                None => cursor.first::<Vec<u8>, Vec<u8>>(),
+            }
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+            if resume.is_some() && entry.as_ref().map(|(key, _)| key) == resume.as_ref() {
+                entry = cursor
+                    .next::<Vec<u8>, Vec<u8>>()
+                    .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+            }
+
+            let mut checked = 0;
+            let mut last_key = None;
+            let done = loop {
+                let Some((key, _)) = entry else {
+                    break true;
+                };
+                if checked == STEM_ADDRESS_AUDIT_BATCH {
+                    break false;
+                }
+                if key.len() == STEM_LEN
+                    && txn
+                        .get::<Vec<u8>>(stem_addr_db, &key)
+                        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+                        .is_none()
+                {
+                    missing += 1;
+                }
+                checked += 1;
+                last_key = Some(key);
+                entry = cursor
+                    .next::<Vec<u8>, Vec<u8>>()
+                    .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+            };
+            drop(cursor);
+
+            txn.put(
+                meta_db,
+                META_KEY_MISSING_STEM_ADDRESSES,
+                &missing.to_be_bytes(),
+                WriteFlags::DEFAULT,
+            )
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+            match (done, last_key) {
+                (false, Some(key)) => {
+                    txn.put(meta_db, META_KEY_MIGRATION_CURSOR, &key, WriteFlags::DEFAULT)
+                }
+                _ => txn.del(meta_db, META_KEY_MIGRATION_CURSOR, None),
+            }
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+            Ok((done, missing))
+        })?;
+
+        if done {
+            if missing > 0 {
+                warn!(
+                    missing,
+                    "Stems without a stored address; PIR export from MDBX needs a fresh UBT build"
+                );
+            }
+            return Ok(());
+        }
+    }
+}
+
+/// Convert an MDBX write error, keeping `MDBX_MAP_FULL` distinguishable so callers
+/// can grow the map and retry.
+fn write_error(e: crate::mdbx::Error, other: fn(String) -> DatabaseError) -> UbtError {
+    if e.is_map_full() {
+        UbtError::Database(DatabaseError::MapFull)
+    } else {
+        UbtError::Database(other(e.to_string()))
+    }
+}
+
+/// Record `head` in `ubt_roots`, keyed by block number then hash, and index its
+/// hash in `ubt_root_hashes`, after deleting the records of its block number and
+/// later.
+fn put_root(txn: &RwTransaction<'_>, head: &UbtHead) -> Result<()> {
+    let roots_db = txn
+        .open_db(Some(ROOTS_DB))
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+    let hashes_db = txn
+        .open_db(Some(ROOT_HASHES_DB))
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+    let mut cursor = txn
+        .cursor(&roots_db)
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+    let mut entry = cursor.set_range::<Vec<u8>, Vec<u8>>(&head.block_number.to_be_bytes());
+    while let Some((key, _)) =
+        entry.map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+    {
+        cursor
+            .del()
+            .map_err(|e| write_error(e, DatabaseError::Mdbx))?;
+        txn.del(hashes_db, &key[8..], None)
+            .map_err(|e| write_error(e, DatabaseError::Mdbx))?;
+        entry = cursor.next::<Vec<u8>, Vec<u8>>();
+    }
+
+    txn.put(
+        roots_db,
+        &root_key(head.block_number, head.block_hash),
+        &bincode::serialize(head)?,
+        WriteFlags::DEFAULT,
+    )
+    .map_err(|e| write_error(e, DatabaseError::Mdbx))?;
+    txn.put(
+        hashes_db,
+        head.block_hash.as_slice(),
+        &head.block_number.to_be_bytes(),
+        WriteFlags::DEFAULT,
+    )
+    .map_err(|e| write_error(e, DatabaseError::Mdbx))
+}
+
+/// Key of a `ubt_roots` record: big-endian block number, then block hash.
+fn root_key(block_number: u64, block_hash: B256) -> [u8; 40] {
+    let mut key = [0u8; 40];
+    key[..8].copy_from_slice(&block_number.to_be_bytes());
+    key[8..].copy_from_slice(block_hash.as_slice());
+    key
+}
+
+fn block_number_from_key(key: &[u8]) -> Option<u64> {
+    key.try_into().ok().map(u64::from_be_bytes)
+}
+
+/// Write stems and their hashes. With `defer_hashes`, as for a flush whose root is
+/// pending, the hashes are left to `UbtDatabase::finalize_root` and valid stem
+/// hashes become pending until then.
+fn put_stems(
+    txn: &RwTransaction<'_>,
+    updates: &[(Stem, StemNode)],
+    defer_hashes: bool,
+) -> Result<()> {
+    if updates.is_empty() {
+        return Ok(());
+    }
+    let stems_db = txn
+        .open_db(Some(STEMS_DB))
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+    for (stem, stem_node) in updates {
+        let key = stem.as_bytes();
+        if stem_node.values.is_empty() {
+            txn.del(stems_db, key, None)
+                .map_err(|e| write_error(e, DatabaseError::Mdbx))?;
+            continue;
+        }
+        txn.put(stems_db, key, &encode_stem(stem_node), WriteFlags::DEFAULT)
+            .map_err(|e| write_error(e, DatabaseError::Mdbx))?;
+    }
+
+    if defer_hashes {
+        return defer_stem_hashes(txn);
+    }
+    let hashes = recorded_tree_hasher(txn)?.hash_stems(updates);
+    put_stem_hashes(txn, hashes.iter().map(|(stem, hash)| (stem, hash.as_ref())))
+}
+
+fn has_stems(txn: &RwTransaction<'_>) -> Result<bool> {
+    let stems_db = txn
+        .open_db(Some(STEMS_DB))
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+    Ok(txn
+        .cursor(&stems_db)
+        .and_then(|mut cursor| cursor.first::<Vec<u8>, Vec<u8>>())
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+        .is_some())
+}
+
+/// Write stem hashes; `None` deletes the stem's hash.
+fn put_stem_hashes<'a>(
+    txn: &RwTransaction<'_>,
+    hashes: impl IntoIterator<Item = (&'a Stem, Option<&'a B256>)>,
+) -> Result<()> {
+    let hashes_db = txn
+        .open_db(Some(STEM_HASHES_DB))
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+    for (stem, hash) in hashes {
+        match hash {
+            Some(hash) => txn.put(
+                hashes_db,
+                stem.as_bytes(),
+                hash.as_slice(),
+                WriteFlags::DEFAULT,
+            ),
+            None => txn.del(hashes_db, stem.as_bytes(), None),
+        }
+        .map_err(|e| write_error(e, DatabaseError::Mdbx))?;
+    }
+    Ok(())
+}
+
+/// Tree hasher recorded in `ubt_meta`, read inside a write transaction;
+/// databases predating the setting used BLAKE3.
+fn recorded_tree_hasher(txn: &RwTransaction<'_>) -> Result<TreeHasher> {
+    let meta_db = txn
+        .open_db(Some(META_DB))
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+    match txn
+        .get::<Vec<u8>>(meta_db, META_KEY_HASHER)
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+    {
+        Some(bytes) => Ok(bincode::deserialize(&bytes)?),
+        None => Ok(TreeHasher::Blake3),
+    }
+}
+
+/// Write internal node hashes; `None` deletes the node.
+fn put_internal_nodes(
+    txn: &RwTransaction<'_>,
+    updates: impl IntoIterator<Item = (NodeKey, Option<B256>)>,
+) -> Result<()> {
+    let nodes_db = txn
+        .open_db(Some(INTERNAL_NODES_DB))
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+    for (key, hash) in updates {
+        match hash {
+            Some(hash) => txn.put(nodes_db, &key, hash.as_slice(), WriteFlags::DEFAULT),
+            None => txn.del(nodes_db, &key, None),
+        }
+        .map_err(|e| write_error(e, DatabaseError::Mdbx))?;
+    }
+    Ok(())
+}
+
+/// Clear the flag marking `ubt_internal_nodes` as matching `ubt_stems`.
+fn invalidate_internal_nodes(txn: &RwTransaction<'_>) -> Result<()> {
+    let meta_db = txn
+        .open_db(Some(META_DB))
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+    txn.del(meta_db, META_KEY_INTERNAL_NODES, None)
+        .map_err(|e| write_error(e, DatabaseError::Mdbx))
+}
+
+/// Clear the flag marking `ubt_stem_hashes` as matching `ubt_stems`, and with it
+/// the internal nodes built from them.
+fn invalidate_stem_hashes(txn: &RwTransaction<'_>) -> Result<()> {
+    let meta_db = txn
+        .open_db(Some(META_DB))
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+    txn.del(meta_db, META_KEY_STEM_HASHES, None)
+        .map_err(|e| write_error(e, DatabaseError::Mdbx))?;
+    invalidate_internal_nodes(txn)
+}
+
+/// Mark valid stem hashes pending, as when stems are written with their hashes
+/// left to `UbtDatabase::finalize_root`.
+fn defer_stem_hashes(txn: &RwTransaction<'_>) -> Result<()> {
+    let meta_db = txn
+        .open_db(Some(META_DB))
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+    let flag = txn
+        .get::<Vec<u8>>(meta_db, META_KEY_STEM_HASHES)
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+    if flag.as_deref() != Some(META_FLAG_VALID) {
+        return Ok(());
+    }
+    txn.put(
+        meta_db,
+        META_KEY_STEM_HASHES,
+        META_FLAG_PENDING,
+        WriteFlags::DEFAULT,
+    )
+    .map_err(|e| write_error(e, DatabaseError::Mdbx))
+}
+
+fn set_meta_flag(txn: &RwTransaction<'_>, key: &[u8]) -> Result<()> {
+    let meta_db = txn
+        .open_db(Some(META_DB))
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+    txn.put(meta_db, key, META_FLAG_VALID, WriteFlags::DEFAULT)
+        .map_err(|e| write_error(e, DatabaseError::Mdbx))
+}
+
+/// Decode an `ubt_stem_hashes` row read from a cursor.
+fn stem_hash_entry(entry: Option<(Vec<u8>, Vec<u8>)>) -> Result<Option<(Stem, B256)>> {
+    let Some((key, value)) = entry else {
+        return Ok(None);
+    };
+    let bytes = <[u8; STEM_LEN]>::try_from(key.as_slice()).map_err(|_| {
+        UbtError::Database(DatabaseError::Mdbx(format!(
+            "Invalid stem hash key length: expected {STEM_LEN}, got {}",
+            key.len()
+        )))
+    })?;
+    Ok(Some((Stem::new(bytes), decode_hash(&value)?)))
+}
+
+/// Rows of `table`, keyed by stem, whose stem starts with the byte `bucket`.
+fn read_bucket(txn: &RwTransaction<'_>, table: &str, bucket: u8) -> Result<Vec<(Stem, Vec<u8>)>> {
+    let db = txn
+        .open_db(Some(table))
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+    let mut cursor = txn
+        .cursor(&db)
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+    let mut rows = Vec::new();
+    let mut entry = cursor
+        .set_range::<Vec<u8>, Vec<u8>>(&[bucket])
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+    while let Some((key, value)) = entry {
+        if key.first() != Some(&bucket) {
+            break;
+        }
+        if let Ok(bytes) = <[u8; STEM_LEN]>::try_from(key.as_slice()) {
+            rows.push((Stem::new(bytes), value));
+        }
+        entry = cursor
+            .next()
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+    }
+    Ok(rows)
+}
+
+/// Build the internal nodes below `REBUILD_BUCKET_DEPTH` on the paths of the stems
+/// whose first byte is `bucket`, from their stored hashes.
+fn rebuild_bucket(txn: &RwTransaction<'_>, hasher: TreeHasher, bucket: u8) -> Result<Subtree> {
+    let stems = read_bucket(txn, STEM_HASHES_DB, bucket)?
+        .into_iter()
+        .map(|(stem, hash)| Ok((stem, decode_hash(&hash)?)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut path = [0u8; STEM_LEN];
+    path[0] = bucket;
+    let mut nodes = Vec::new();
+    let subtree = build_subtree(hasher, &path, REBUILD_BUCKET_DEPTH, &stems, &mut nodes);
+    put_internal_nodes(txn, nodes.into_iter().map(|(key, hash)| (key, Some(hash))))?;
+    Ok(subtree)
+}
+
+/// Write stem addresses that are not stored yet; a different stored address is an error.
+fn put_stem_addresses<'a>(
+    txn: &RwTransaction<'_>,
+    mappings: impl IntoIterator<Item = (&'a Stem, &'a Address)>,
+) -> Result<()> {
+    let db = txn
+        .open_db(Some(STEM_ADDR_DB))
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+    for (stem, address) in mappings {
+        if let Some(existing) = txn
+            .get::<Vec<u8>>(db, stem.as_bytes())
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+        {
+            if existing.as_slice() != address.as_slice() {
+                return Err(UbtError::Database(DatabaseError::Mdbx(
+                    "Stem address mismatch in MDBX".to_string(),
+                )));
+            }
+            continue;
+        }
+
+        txn.put(
+            db,
+            stem.as_bytes(),
+            address.as_slice(),
+            WriteFlags::DEFAULT,
+        )
+        .map_err(|e| write_error(e, DatabaseError::Mdbx))?;
+    }
+    Ok(())
+}
+
+/// Write the balance overflow entry of `address` at `block_number`; `None` marks
+/// the record cleared.
+fn put_balance_overflow(
+    txn: &RwTransaction<'_>,
+    address: Address,
+    block_number: u64,
+    balance: Option<U256>,
+) -> Result<()> {
+    let db = txn
+        .open_db(Some(BALANCE_OVERFLOW_DB))
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+    let value = bincode::serialize(&balance)?;
+    txn.put(
+        db,
+        &balance_overflow_key(address, block_number),
+        &value,
+        WriteFlags::DEFAULT,
+    )
+    .map_err(|e| write_error(e, DatabaseError::Mdbx))
+}
+
+/// Key of a `ubt_balance_overflows` entry: address, then big-endian block number.
+fn balance_overflow_key(address: Address, block_number: u64) -> [u8; 28] {
+    let mut key = [0u8; 28];
+    key[..20].copy_from_slice(address.as_slice());
+    key[20..].copy_from_slice(&block_number.to_be_bytes());
+    key
+}
+
+fn balance_overflow_key_parts(key: &[u8]) -> Result<(Address, u64)> {
+    if key.len() != 28 {
+        return Err(UbtError::Database(DatabaseError::Mdbx(format!(
+            "Invalid balance overflow key length: expected 28, got {}",
+            key.len()
+        ))));
+    }
+    let block_number = block_number_from_key(&key[20..]).expect("8-byte block number");
+    Ok((Address::from_slice(&key[..20]), block_number))
+}
+
+/// Delete the deltas of blocks before `block_number`, returning how many were
+/// deleted.
+fn del_deltas_before(txn: &RwTransaction<'_>, block_number: u64) -> Result<usize> {
+    let deltas_db = txn.open_db(Some(DELTAS_DB)).map_err(|e| {
+        UbtError::Database(DatabaseError::Mdbx(format!(
+            "Failed to open deltas db: {}",
+            e
+        )))
+    })?;
+    let mut cursor = txn.cursor(&deltas_db).map_err(|e| {
+        UbtError::Database(DatabaseError::Mdbx(format!(
+            "Failed to create cursor: {}",
+            e
+        )))
+    })?;
+
+    // Keys are big-endian block numbers, so older blocks come first.
+    let mut count = 0;
+    let mut entry = cursor.first::<Vec<u8>, Vec<u8>>();
+    loop {
+        let key = match entry {
+            Ok(Some((key, _))) => key,
+            Ok(None) => break,
+            Err(e) => {
+                return Err(UbtError::Database(DatabaseError::Mdbx(format!(
+                    "Cursor iteration failed: {}",
+                    e
+                ))))
+            }
+        };
+        match block_number_from_key(&key) {
+            Some(bn) if bn >= block_number => break,
+            Some(_) => {
+                cursor.del().map_err(|e| {
+                    write_error(e, |msg| {
+                        DatabaseError::Mdbx(format!("Failed to delete delta: {msg}"))
+                    })
+                })?;
+                count += 1;
+            }
+            None => {}
+        }
+        entry = cursor.next::<Vec<u8>, Vec<u8>>();
+    }
+    Ok(count)
+}
+
+/// Delete root history records of blocks before `block_number`, returning how
+/// many were deleted.
+fn del_roots_before(txn: &RwTransaction<'_>, block_number: u64) -> Result<usize> {
+    let roots_db = txn
+        .open_db(Some(ROOTS_DB))
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+    let hashes_db = txn
+        .open_db(Some(ROOT_HASHES_DB))
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+    let mut cursor = txn
+        .cursor(&roots_db)
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+    // Keys start with big-endian block numbers, so older blocks come first.
+    let mut count = 0;
+    let mut entry = cursor.first::<Vec<u8>, Vec<u8>>();
+    while let Some((key, _)) =
+        entry.map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+    {
+        if key[..] >= block_number.to_be_bytes()[..] {
+            break;
+        }
+        cursor
+            .del()
+            .map_err(|e| write_error(e, DatabaseError::Mdbx))?;
+        txn.del(hashes_db, &key[8..], None)
+            .map_err(|e| write_error(e, DatabaseError::Mdbx))?;
+        count += 1;
+        entry = cursor.next::<Vec<u8>, Vec<u8>>();
+    }
+    Ok(count)
+}
+
+/// Rewrite up to `max_rows` legacy stems in the compact encoding, resuming where
+/// the last call stopped (see `UbtDatabase::migrate_stem_encoding`).
+fn migrate_stem_rows(txn: &RwTransaction<'_>, max_rows: usize) -> Result<StemMigrationProgress> {
+    let meta_db = txn
+        .open_db(Some(META_DB))
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+    if txn
+        .get::<Vec<u8>>(meta_db, META_KEY_STEM_ENCODING)
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+        .is_some()
+    {
+        return Ok(StemMigrationProgress {
+            done: true,
+            ..Default::default()
+        });
+    }
+    let stems_db = txn
+        .open_db(Some(STEMS_DB))
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+    let resume = txn
+        .get::<Vec<u8>>(meta_db, META_KEY_STEM_MIGRATION)
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+    let mut progress = StemMigrationProgress::default();
+    let mut legacy = Vec::new();
+    let mut last_key = None;
+    {
+        let mut cursor = txn
+            .cursor(&stems_db)
+            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        let mut entry = match &resume {
+            Some(key) => cursor.set_range::<Vec<u8>, Vec<u8>>(key),
+            None => cursor.next::<Vec<u8>, Vec<u8>>(),
+        }
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        if resume.is_some() && entry.as_ref().map(|(key, _)| key) == resume.as_ref() {
+            entry = cursor
+                .next::<Vec<u8>, Vec<u8>>()
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        }
+
+        loop {
+            let Some((key, value)) = entry else {
+                progress.done = true;
+                break;
+            };
+            if progress.scanned == max_rows {
+                break;
+            }
+            progress.scanned += 1;
+            if let Ok(stem) = <[u8; STEM_LEN]>::try_from(key.as_slice()) {
+                if !is_compact(&Stem::new(stem), &value) {
+                    legacy.push((key.clone(), value));
+                }
+            }
+            last_key = Some(key);
+            entry = cursor
+                .next::<Vec<u8>, Vec<u8>>()
+                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        }
+    }
+
+    for (key, value) in &legacy {
+        let mut stem_bytes = [0u8; STEM_LEN];
+        stem_bytes.copy_from_slice(key);
+        let node = decode_stem(Stem::new(stem_bytes), value)?;
+        txn.put(stems_db, key, &encode_stem(&node), WriteFlags::DEFAULT)
+            .map_err(|e| write_error(e, DatabaseError::Mdbx))?;
+    }
+    progress.rewritten = legacy.len();
+
+    if progress.done {
+        txn.put(
+            meta_db,
+            META_KEY_STEM_ENCODING,
+            STEM_ENCODING_COMPACT_V1,
+            WriteFlags::DEFAULT,
+        )
+        .and_then(|()| txn.del(meta_db, META_KEY_STEM_MIGRATION, None))
+        .map_err(|e| write_error(e, DatabaseError::Mdbx))?;
+    } else if let Some(key) = last_key {
+        txn.put(meta_db, META_KEY_STEM_MIGRATION, &key, WriteFlags::DEFAULT)
+            .map_err(|e| write_error(e, DatabaseError::Mdbx))?;
+    }
+    Ok(progress)
+}
+
+fn del_balance_overflows_after(txn: &RwTransaction<'_>, block_number: u64) -> Result<()> {
+    let db = txn
+        .open_db(Some(BALANCE_OVERFLOW_DB))
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+    let mut cursor = txn
+        .cursor(&db)
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+    let mut entry = cursor.first::<Vec<u8>, Vec<u8>>();
+    while let Some((key, _)) =
+        entry.map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+    {
+        if balance_overflow_key_parts(&key)?.1 > block_number {
+            cursor
+                .del()
+                .map_err(|e| write_error(e, DatabaseError::Mdbx))?;
+        }
+        entry = cursor.next::<Vec<u8>, Vec<u8>>();
+    }
+    Ok(())
+}
+
+fn put_block_deltas(
+    txn: &RwTransaction<'_>,
+    block_number: u64,
+    deltas: &[(Stem, u8, B256)],
+) -> Result<()> {
+    let deltas_db = txn
+        .open_db(Some(DELTAS_DB))
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+    let value = bincode::serialize(deltas)?;
+    txn.put(deltas_db, &block_number.to_be_bytes(), &value, WriteFlags::DEFAULT)
+        .map_err(|e| write_error(e, DatabaseError::Mdbx))
+}
+
+fn del_block_deltas(txn: &RwTransaction<'_>, block_number: u64) -> Result<()> {
+    let deltas_db = txn
+        .open_db(Some(DELTAS_DB))
+        .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+
+    txn.del(deltas_db, &block_number.to_be_bytes(), None)
+        .map_err(|e| write_error(e, DatabaseError::Mdbx))
+}
+
+fn mdbx_max_size_from_env() -> Option<usize> {
+    let raw = std::env::var("UBT_MDBX_MAX_SIZE").ok()?;
+    let s = raw.trim().to_ascii_uppercase();
+    let split = s
+        .find(|c: char| c.is_ascii_alphabetic())
+        .unwrap_or(s.len());
+    let (num_str, unit) = s.split_at(split);
+    let number: u64 = match num_str.trim().parse() {
+        Ok(v) => v,
+        Err(_) => {
+            warn!(value = %raw, "Invalid UBT_MDBX_MAX_SIZE, expected integer with optional unit");
+            return None;
+        }
+    };
+
+    let factor: u64 = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KB" => 1024,
+        "M" | "MB" => 1024 * 1024,
+        "G" | "GB" => 1024 * 1024 * 1024,
+        "T" | "TB" => 1024_u64 * 1024 * 1024 * 1024,
+        other => {
+            warn!(value = %raw, unit = %other, "Invalid UBT_MDBX_MAX_SIZE unit");
+            return None;
+        }
+    };
+
+    number.checked_mul(factor).map(|v| v as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn create_test_db() -> (TempDir, UbtDatabase) {
+        let dir = TempDir::new().unwrap();
+        let db = UbtDatabase::open(dir.path()).unwrap();
+        (dir, db)
+    }
+
+    #[test]
+    fn test_open_creates_tables() {
+        let (_dir, _db) = create_test_db();
+    }
+
+    #[test]
+    fn test_save_load_head() {
+        let (_dir, db) = create_test_db();
+
+        let head = UbtHead {
+            block_number: 100,
+            block_hash: B256::repeat_byte(0x42),
+            root: B256::repeat_byte(0xAB),
+            stem_count: 1000,
+        };
+
+        db.save_head(&head).unwrap();
+        let loaded = db.load_head().unwrap().unwrap();
+
+        assert_eq!(loaded.block_number, head.block_number);
+        assert_eq!(loaded.block_hash, head.block_hash);
+        assert_eq!(loaded.root, head.root);
+        assert_eq!(loaded.stem_count, head.stem_count);
+    }
+
+    #[test]
+    fn test_load_head_empty() {
+        let (_dir, db) = create_test_db();
+        assert!(db.load_head().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_batch_update_and_iter_stems() {
+        let (_dir, db) = create_test_db();
+
+        let stem1 = Stem::new([1u8; STEM_LEN]);
+        let stem2 = Stem::new([2u8; STEM_LEN]);
+
+        let mut node1 = StemNode::new(stem1);
+        node1.set_value(0, B256::repeat_byte(0x11));
+        node1.set_value(1, B256::repeat_byte(0x12));
+
+        let mut node2 = StemNode::new(stem2);
+        node2.set_value(0, B256::repeat_byte(0x21));
+
+        db.batch_update_stems(&[(stem1, node1.clone()), (stem2, node2.clone())])
+            .unwrap();
+
+        let stems: Vec<_> = db.iter_stems().unwrap().collect::<Result<_>>().unwrap();
+        assert_eq!(stems.len(), 2);
+        assert_eq!(stems[0].0, stem1);
+        assert_eq!(stems[1].0, stem2);
+    }
+
+    #[test]
+    fn test_iter_entries_sorted_is_ordered() {
+        let (_dir, db) = create_test_db();
+
+        let stem1 = Stem::new([1u8; STEM_LEN]);
+        let stem2 = Stem::new([2u8; STEM_LEN]);
+        let mut node1 = StemNode::new(stem1);
+        for subindex in [200u8, 3, 64] {
+            node1.set_value(subindex, B256::repeat_byte(subindex));
+        }
+        let mut node2 = StemNode::new(stem2);
+        node2.set_value(0, B256::repeat_byte(0xaa));
+        db.batch_update_stems(&[(stem2, node2), (stem1, node1)]).unwrap();
+
+        let keys: Vec<_> = db
+            .iter_entries_sorted()
+            .unwrap()
+            .map(|entry| entry.map(|(key, _)| (key.stem, key.subindex)))
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            keys,
+            vec![(stem1, 3), (stem1, 64), (stem1, 200), (stem2, 0)]
+        );
+    }
+
+    #[test]
+    fn test_iter_entries_with_overlay() {
+        let (_dir, db) = create_test_db();
+
+        let stem1 = Stem::new([1u8; STEM_LEN]);
+        let stem2 = Stem::new([2u8; STEM_LEN]);
+        let stem3 = Stem::new([3u8; STEM_LEN]);
+        let mut node1 = StemNode::new(stem1);
+        node1.set_value(0, B256::repeat_byte(0x11));
+        let mut node2 = StemNode::new(stem2);
+        node2.set_value(0, B256::repeat_byte(0x21));
+        db.batch_update_stems(&[(stem1, node1), (stem2, node2)]).unwrap();
+
+        // stem1 is emptied, stem2 is replaced and stem3 is new.
+        let mut overlay2 = StemNode::new(stem2);
+        overlay2.set_value(1, B256::repeat_byte(0x22));
+        let mut overlay3 = StemNode::new(stem3);
+        overlay3.set_value(0, B256::repeat_byte(0x33));
+        let overlay = vec![
+            (stem1, StemNode::new(stem1)),
+            (stem2, overlay2.clone()),
+            (stem3, overlay3.clone()),
+        ];
+
+        let merged: Vec<_> = db
+            .iter_entries_with_overlay(&overlay)
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(
+            merged,
+            vec![
+                (TreeKey::new(stem2, 1), B256::repeat_byte(0x22)),
+                (TreeKey::new(stem3, 0), B256::repeat_byte(0x33)),
+            ]
+        );
+
+        db.batch_update_stems(&overlay).unwrap();
+        let written: Vec<_> = db
+            .iter_entries_sorted()
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(merged, written);
+    }
+
+    #[test]
+    fn test_write_batch_applies_all_tables() {
+        let (_dir, db) = create_test_db();
+
+        let stem = Stem::new([12u8; STEM_LEN]);
+        let address = Address::repeat_byte(0x12);
+        let mut node = StemNode::new(stem);
+        node.set_value(0, B256::repeat_byte(0x01));
+        db.save_block_deltas(3, &[(stem, 0, B256::ZERO)]).unwrap();
+
+        let head = UbtHead {
+            block_number: 5,
+            block_hash: B256::repeat_byte(0x05),
+            root: B256::repeat_byte(0xAB),
+            stem_count: 1,
+        };
+        let record = BalanceOverflow {
+            address,
+            block_number: 5,
+            balance: U256::MAX,
+        };
+        let mut batch = WriteBatch::default();
+        batch.save_stem_addresses([(stem, address)]);
+        batch.save_block_deltas(4, vec![(stem, 0, B256::ZERO)]);
+        batch.save_block_deltas(5, vec![(stem, 0, B256::repeat_byte(0x02))]);
+        batch.delete_block_deltas(3);
+        batch.save_balance_overflow(record.clone());
+        batch.update_stems([(stem, node)]);
+        batch.save_head(&head).unwrap();
+
+        assert_eq!(batch.stem_address(&stem), Some(address));
+        assert_eq!(batch.take_block_deltas(4).unwrap().len(), 1);
+        assert!(batch.take_block_deltas(3).unwrap().is_empty());
+        assert!(batch.take_block_deltas(6).is_none());
+
+        db.write_batch(&batch).unwrap();
+
+        assert_eq!(db.load_stem_address(&stem).unwrap(), Some(address));
+        assert!(db.load_block_deltas(3).unwrap().is_empty());
+        assert!(db.load_block_deltas(4).unwrap().is_empty());
+        assert_eq!(
+            db.load_block_deltas(5).unwrap(),
+            vec![(stem, 0, B256::repeat_byte(0x02))]
+        );
+        assert_eq!(db.load_balance_overflows().unwrap(), vec![record]);
+        assert_eq!(
+            db.load_value(&TreeKey::new(stem, 0)).unwrap(),
+            Some(B256::repeat_byte(0x01))
+        );
+        assert_eq!(db.load_head().unwrap().unwrap().block_number, 5);
+    }
+
+    fn internal_nodes(db: &UbtDatabase) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let txn = db.env.begin_ro_txn().unwrap();
+        let nodes_db = txn.open_db(Some(INTERNAL_NODES_DB)).unwrap();
+        let mut cursor = txn.cursor(&nodes_db).unwrap();
+        let mut nodes = Vec::new();
+        let mut entry = cursor.first().unwrap();
+        while let Some(node) = entry {
+            nodes.push(node);
+            entry = cursor.next().unwrap();
+        }
+        nodes
+    }
+
+    #[test]
+    fn test_incremental_root_matches_streaming() {
+        let (_dir, db) = create_test_db();
+        let hasher = TreeHasher::Sha256;
+        let streaming = |overlay: &[(Stem, StemNode)]| {
+            hasher
+                .try_root_from_sorted_entries(db.iter_entries_with_overlay(overlay).unwrap())
+                .unwrap()
+        };
+        let stem_at = |first: u8, second: u8| {
+            let mut bytes = [0u8; STEM_LEN];
+            bytes[0] = first;
+            bytes[1] = second;
+            Stem::new(bytes)
+        };
+        let node_with = |stem: Stem, values: &[u8]| {
+            let mut node = StemNode::new(stem);
+            for &value in values {
+                node.set_value(value, B256::repeat_byte(value));
+            }
+            (stem, node)
+        };
+
+        db.ensure_tree_hasher(hasher).unwrap();
+        assert!(db.internal_nodes_valid().unwrap());
+        assert_eq!(db.rebuild_internal_nodes(hasher).unwrap(), B256::ZERO);
+        assert_eq!(db.compute_root_incremental(hasher, &[]).unwrap().0, B256::ZERO);
+
+        let initial: Vec<_> = (0..64u8)
+            .map(|i| node_with(stem_at(i.wrapping_mul(37), i % 3), &[i + 1]))
+            .collect();
+        db.batch_update_stems(&initial).unwrap();
+        assert!(!db.internal_nodes_valid().unwrap());
+        assert_eq!(db.rebuild_internal_nodes(hasher).unwrap(), streaming(&[]));
+        assert!(db.internal_nodes_valid().unwrap());
+
+        for round in 0..4u8 {
+            // Change, delete and add stems, including ones next to existing stems.
+            let mut overlay: Vec<_> = (0..64u8)
+                .filter(|i| i % 4 == round)
+                .map(|i| {
+                    let stem = stem_at(i.wrapping_mul(37), i % 3);
+                    match i % 3 {
+                        0 => node_with(stem, &[]),
+                        _ => node_with(stem, &[i + 1, round + 100]),
+                    }
+                })
+                .chain((0..8u8).map(|i| node_with(stem_at(i.wrapping_mul(37), 0x80 | round), &[i + 1])))
+                .collect();
+            overlay.sort_unstable_by_key(|(stem, _)| *stem);
+
+            let (root, updates) = db
+                .compute_root_incremental(hasher, &hasher.hash_stems(&overlay))
+                .unwrap();
+            assert_eq!(root, streaming(&overlay), "round {round}");
+
+            let mut batch = WriteBatch::default();
+            batch.update_stems(overlay);
+            batch.update_internal_nodes(updates);
+            db.write_batch(&batch).unwrap();
+            assert!(db.internal_nodes_valid().unwrap());
+            assert_eq!(db.compute_root_incremental(hasher, &[]).unwrap().0, root);
+
+            // The maintained table is exactly the one a rebuild produces.
+            let maintained = internal_nodes(&db);
+            assert_eq!(db.rebuild_internal_nodes(hasher).unwrap(), root);
+            assert_eq!(internal_nodes(&db), maintained, "round {round}");
+        }
+    }
+
+    #[test]
+    fn test_stem_hashes_follow_stem_writes() {
+        let (_dir, db) = create_test_db();
+        let hasher = TreeHasher::Blake3;
+        let streaming = |overlay: &[(Stem, StemNode)]| {
+            hasher
+                .try_root_from_sorted_entries(db.iter_entries_with_overlay(overlay).unwrap())
+                .unwrap()
+        };
+        let node_with = |first: u8, values: &[u8]| {
+            let stem = Stem::new([first; STEM_LEN]);
+            let mut node = StemNode::new(stem);
+            for &value in values {
+                node.set_value(value, B256::repeat_byte(value));
+            }
+            (stem, node)
+        };
+
+        assert!(db.stem_hashes_valid().unwrap());
+        assert_eq!(
+            db.compute_root_from_stem_hashes(hasher, &[]).unwrap(),
+            B256::ZERO
+        );
+
+        let initial: Vec<_> = (1..=8u8).map(|i| node_with(i * 16, &[i])).collect();
+        db.batch_update_stems(&initial).unwrap();
+        assert!(db.stem_hashes_valid().unwrap());
+        for (stem, node) in &initial {
+            assert_eq!(
+                db.load_stem_hash(stem).unwrap(),
+                Some(hasher.hash_stem(*stem, node))
+            );
+        }
+        assert_eq!(
+            db.compute_root_from_stem_hashes(hasher, &[]).unwrap(),
+            streaming(&[])
+        );
+
+        // Change, delete and add stems in an overlay, then write it.
+        let overlay = vec![
+            node_with(16, &[1, 2]),
+            node_with(24, &[3]),
+            node_with(32, &[]),
+            node_with(255, &[4]),
+        ];
+        assert_eq!(
+            db.compute_root_from_stem_hashes(hasher, &hasher.hash_stems(&overlay))
+                .unwrap(),
+            streaming(&overlay)
+        );
+        let mut batch = WriteBatch::default();
+        batch.update_stems(overlay.clone());
+        db.write_batch(&batch).unwrap();
+        assert!(db.stem_hashes_valid().unwrap());
+        assert_eq!(db.load_stem_hash(&overlay[2].0).unwrap(), None);
+        assert_eq!(
+            db.load_stem_hash(&overlay[0].0).unwrap(),
+            Some(hasher.hash_stem(overlay[0].0, &overlay[0].1))
+        );
+        let root = streaming(&[]);
+        assert_eq!(db.compute_root_from_stem_hashes(hasher, &[]).unwrap(), root);
+
+        // Stems written without hashes invalidate the cache until it is rebuilt.
+        db.write_legacy_stems(&[node_with(40, &[5])]).unwrap();
+        assert!(!db.stem_hashes_valid().unwrap());
+        assert!(!db.internal_nodes_valid().unwrap());
+        assert_eq!(db.rebuild_stem_hashes().unwrap(), 10);
+        assert!(db.stem_hashes_valid().unwrap());
+        assert_eq!(
+            db.compute_root_from_stem_hashes(hasher, &[]).unwrap(),
+            streaming(&[])
+        );
+
+        // So does recording a different hasher.
+        db.save_tree_hasher(TreeHasher::Sha256).unwrap();
+        assert!(!db.stem_hashes_valid().unwrap());
+        db.rebuild_stem_hashes().unwrap();
+        assert_eq!(
+            db.load_stem_hash(&initial[0].0).unwrap(),
+            Some(TreeHasher::Sha256.hash_stem(overlay[0].0, &overlay[0].1))
+        );
+    }
 
-        Ok(())
+    #[test]
+    fn test_sample_root_paths() {
+        let (_dir, db) = create_test_db();
+        let hasher = TreeHasher::Blake3;
+        let starts: Vec<_> = (0..=255u8).map(|i| Stem::new([i; STEM_LEN])).collect();
+        let sample = db.sample_root_paths(hasher, &starts).unwrap();
+        assert!(sample.stale_stems.is_empty() && sample.stale_nodes.is_empty());
+        assert_eq!(sample.root, B256::ZERO);
+
+        let updates: Vec<_> = (1..=4u8)
+            .map(|i| {
+                let stem = Stem::new([i * 50; STEM_LEN]);
+                let mut node = StemNode::new(stem);
+                node.set_value(0, B256::repeat_byte(i));
+                (stem, node)
+            })
+            .collect();
+        db.batch_update_stems(&updates).unwrap();
+        let root = db.rebuild_internal_nodes(hasher).unwrap();
+        let sample = db.sample_root_paths(hasher, &starts).unwrap();
+        assert!(sample.stale_stems.is_empty() && sample.stale_nodes.is_empty());
+        assert_eq!(sample.root, root);
+
+        // A stale hash is found by samples at or just before its stem, and by
+        // samples past the last stem, which wrap around to the first.
+        db.with_rw_txn(|txn| {
+            put_stem_hashes(txn, [(&updates[0].0, Some(&B256::repeat_byte(0xee)))])
+        })
+        .unwrap();
+        let stale = updates[0].0;
+        for start in [Stem::new([0; STEM_LEN]), Stem::new([0xff; STEM_LEN])] {
+            let sample = db.sample_root_paths(hasher, &[start]).unwrap();
+            assert_eq!(sample.stale_stems, vec![stale]);
+        }
+        assert!(db
+            .sample_root_paths(hasher, &[updates[1].0])
+            .unwrap()
+            .stale_stems
+            .is_empty());
+        db.batch_update_stems(&updates[..1]).unwrap();
+        assert_eq!(db.rebuild_internal_nodes(hasher).unwrap(), root);
+
+        // A stale node below the root is missed by the stored root node, but found
+        // on the paths through it and turns the root of the paths beside it.
+        let below_root = crate::internal_nodes::node_key(&[0u8; STEM_LEN], 1);
+        db.with_rw_txn(|txn| {
+            put_internal_nodes(txn, [(below_root, Some(B256::repeat_byte(0xdd)))])
+        })
+        .unwrap();
+        assert_eq!(db.compute_root_incremental(hasher, &[]).unwrap().0, root);
+        let through = db.sample_root_paths(hasher, &[updates[0].0]).unwrap();
+        assert_eq!(through.stale_nodes, vec![below_root]);
+        assert_eq!(through.root, root);
+        let beside = db.sample_root_paths(hasher, &[updates[2].0]).unwrap();
+        assert_eq!(
+            beside.stale_nodes,
+            vec![crate::internal_nodes::node_key(&[0u8; STEM_LEN], 0)]
+        );
+        assert_ne!(beside.root, root);
     }
 
-    /// Prune deltas for blocks older than the given block number.
-    /// Returns the number of deltas deleted.
-    pub fn prune_deltas_before(&self, block_number: u64) -> Result<usize> {
-        let txn = self.env.begin_rw_txn().map_err(|e| {
-            UbtError::Database(DatabaseError::Transaction(format!(
-                "Failed to begin prune transaction: {}",
-                e
-            )))
-        })?;
-        let deltas_db = txn.open_db(Some(DELTAS_DB)).map_err(|e| {
-            UbtError::Database(DatabaseError::Mdbx(format!(
-                "Failed to open deltas db: {}",
-                e
-            )))
-        })?;
+    #[test]
+    fn test_iter_entries_at_head() {
+        let (_dir, db) = create_test_db();
+        let (head, entries) = db.iter_entries_at_head().unwrap();
+        assert!(head.is_none());
+        assert_eq!(entries.count(), 0);
 
-        let mut cursor = txn.cursor(&deltas_db).map_err(|e| {
-            UbtError::Database(DatabaseError::Mdbx(format!(
-                "Failed to create cursor: {}",
-                e
-            )))
-        })?;
+        let stem = Stem::new([7u8; STEM_LEN]);
+        let mut node = StemNode::new(stem);
+        node.set_value(1, B256::repeat_byte(1));
+        node.set_value(2, B256::repeat_byte(2));
+        let head = UbtHead {
+            block_number: 3,
+            block_hash: B256::repeat_byte(3),
+            root: B256::repeat_byte(4),
+            stem_count: 1,
+        };
+        let mut batch = WriteBatch::default();
+        batch.update_stems(vec![(stem, node)]);
+        batch.save_head(&head).unwrap();
+        db.write_batch(&batch).unwrap();
+
+        let (stored, entries) = db.iter_entries_at_head().unwrap();
+        assert_eq!(stored.unwrap().block_number, 3);
+        assert_eq!(entries.count(), 2);
+    }
 
-        let mut to_delete = Vec::new();
-
-        while let Some((key_bytes, _)) = cursor.next::<Vec<u8>, Vec<u8>>().map_err(|e| {
-            UbtError::Database(DatabaseError::Mdbx(format!(
-                "Cursor iteration failed: {}",
-                e
-            )))
-        })? {
-            if key_bytes.len() == 8 {
-                let mut arr = [0u8; 8];
-                arr.copy_from_slice(&key_bytes);
-                let bn = u64::from_be_bytes(arr);
-                if bn < block_number {
-                    to_delete.push(key_bytes);
-                }
-            }
-        }
-        drop(cursor);
+    #[test]
+    fn test_incremental_root_detects_missing_nodes() {
+        let (_dir, db) = create_test_db();
+        let hasher = TreeHasher::Blake3;
+        let updates: Vec<_> = (1..=4u8)
+            .map(|i| {
+                let stem = Stem::new([i; STEM_LEN]);
+                let mut node = StemNode::new(stem);
+                node.set_value(0, B256::repeat_byte(i));
+                (stem, node)
+            })
+            .collect();
+        db.batch_update_stems(&updates).unwrap();
+        db.rebuild_internal_nodes(hasher).unwrap();
+
+        db.with_rw_txn(|txn| {
+            let nodes_db = txn.open_db(Some(INTERNAL_NODES_DB)).unwrap();
+            txn.clear_db(nodes_db).unwrap();
+            Ok(())
+        })
+        .unwrap();
+        assert!(db.compute_root_incremental(hasher, &[]).is_err());
+    }
 
-        let count = to_delete.len();
-        for key in to_delete {
-            txn.del(deltas_db, &key, None).map_err(|e| {
-                UbtError::Database(DatabaseError::Mdbx(format!(
-                    "Failed to delete delta: {}",
-                    e
-                )))
-            })?;
-        }
-        txn.commit().map_err(|e| {
-            UbtError::Database(DatabaseError::Transaction(format!(
-                "Failed to commit prune: {}",
-                e
-            )))
-        })?;
+    fn raw_stem(db: &UbtDatabase, stem: &Stem) -> Vec<u8> {
+        let txn = db.env.begin_ro_txn().unwrap();
+        let stems_db = txn.open_db(Some(STEMS_DB)).unwrap();
+        txn.get::<Vec<u8>>(stems_db, stem.as_bytes()).unwrap().unwrap()
+    }
 
-        Ok(count)
+    #[test]
+    fn test_new_database_has_current_schema() {
+        let (_dir, db) = create_test_db();
+        assert_eq!(db.schema_version().unwrap(), SCHEMA_VERSION);
+        assert_eq!(db.missing_stem_addresses().unwrap(), 0);
     }
 
-    #[allow(dead_code)]
-    pub fn delete_deltas_after(&self, block_number: u64) -> Result<()> {
-        let txn = self
-            .env
-            .begin_rw_txn()
-            .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
-        let deltas_db = txn
-            .open_db(Some(DELTAS_DB))
-            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+    #[test]
+    fn test_migrates_unversioned_database() {
+        let dir = TempDir::new().unwrap();
+        {
+            let db = UbtDatabase::open(dir.path()).unwrap();
+            let updates: Vec<_> = (1..=3u8)
+                .map(|i| {
+                    let stem = Stem::new([i; STEM_LEN]);
+                    let mut node = StemNode::new(stem);
+                    node.set_value(0, B256::repeat_byte(i));
+                    (stem, node)
+                })
+                .collect();
+            db.write_legacy_stems(&updates).unwrap();
+            db.save_stem_address(&updates[0].0, &Address::repeat_byte(1))
+                .unwrap();
+            db.save_head(&UbtHead {
+                block_number: 7,
+                block_hash: B256::repeat_byte(7),
+                root: B256::repeat_byte(8),
+                stem_count: 3,
+            })
+            .unwrap();
+            db.with_rw_txn(|txn| {
+                let meta_db = txn.open_db(Some(META_DB)).unwrap();
+                txn.del(meta_db, META_KEY_SCHEMA_VERSION, None).unwrap();
+                // Before schema 8, overflow records were keyed by address alone.
+                let overflows_db = txn.open_db(Some(BALANCE_OVERFLOW_DB)).unwrap();
+                let record = BalanceOverflow {
+                    address: Address::repeat_byte(0x77),
+                    block_number: 6,
+                    balance: U256::MAX,
+                };
+                txn.put(
+                    overflows_db,
+                    record.address.as_slice(),
+                    &bincode::serialize(&record).unwrap(),
+                    WriteFlags::DEFAULT,
+                )
+                .unwrap();
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(db.schema_version().unwrap(), 1);
+        }
 
-        let mut cursor = txn
-            .cursor(&deltas_db)
-            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
-        let mut to_delete = Vec::new();
+        let db = UbtDatabase::open(dir.path()).unwrap();
+        assert_eq!(db.schema_version().unwrap(), SCHEMA_VERSION);
+        assert_eq!(db.load_tree_hasher().unwrap(), Some(TreeHasher::Blake3));
+        assert_eq!(db.missing_stem_addresses().unwrap(), 2);
+        assert!(!db.stem_encoding_migrated().unwrap());
+        assert_eq!(db.load_head().unwrap().unwrap().block_number, 7);
+        assert_eq!(
+            db.load_root_at(7).unwrap().unwrap().root,
+            B256::repeat_byte(8)
+        );
+        assert_eq!(
+            db.load_balance_overflows().unwrap(),
+            vec![BalanceOverflow {
+                address: Address::repeat_byte(0x77),
+                block_number: 6,
+                balance: U256::MAX,
+            }]
+        );
+    }
 
-        while let Some((key_bytes, _)) = cursor
-            .next::<Vec<u8>, Vec<u8>>()
-            .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?
+    #[test]
+    fn test_refuses_newer_schema() {
+        let dir = TempDir::new().unwrap();
         {
-            if key_bytes.len() == 8 {
-                let mut arr = [0u8; 8];
-                arr.copy_from_slice(&key_bytes);
-                let bn = u64::from_be_bytes(arr);
-                if bn > block_number {
-                    to_delete.push(key_bytes);
-                }
-            }
+            let db = UbtDatabase::open(dir.path()).unwrap();
+            db.save_schema_version(SCHEMA_VERSION + 1).unwrap();
         }
-        drop(cursor);
 
-        for key in to_delete {
-            txn.del(deltas_db, &key, None)
-                .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+        match UbtDatabase::open(dir.path()) {
+            Err(UbtError::SchemaTooNew { stored, supported }) => {
+                assert_eq!(stored, SCHEMA_VERSION + 1);
+                assert_eq!(supported, SCHEMA_VERSION);
+            }
+            other => panic!("expected SchemaTooNew, got {:?}", other.map(|_| ())),
         }
-        txn.commit()
-            .map_err(|e| UbtError::Database(DatabaseError::Transaction(e.to_string())))?;
 
-        Ok(())
+        // A newer database is refused before tables or meta keys are written.
+        let dir = TempDir::new().unwrap();
+        {
+            let db = UbtDatabase {
+                env: UbtDatabase::open_env(dir.path(), Environment::builder()).unwrap(),
+            };
+            db.with_rw_txn(|txn| {
+                txn.create_db(Some(META_DB), DatabaseFlags::CREATE)
+                    .map_err(|e| UbtError::Database(DatabaseError::Mdbx(e.to_string())))?;
+                Ok(())
+            })
+            .unwrap();
+            db.save_schema_version(SCHEMA_VERSION + 1).unwrap();
+        }
+        assert!(matches!(
+            UbtDatabase::open(dir.path()),
+            Err(UbtError::SchemaTooNew { .. })
+        ));
+        let env = UbtDatabase::open_env(dir.path(), Environment::builder().read_only()).unwrap();
+        let txn = env.begin_ro_txn().unwrap();
+        assert!(txn.open_db(Some(STEMS_DB)).unwrap_err().is_not_found());
+        let meta_db = txn.open_db(Some(META_DB)).unwrap();
+        assert!(txn
+            .get::<Vec<u8>>(meta_db, META_KEY_STEM_ENCODING)
+            .unwrap()
+            .is_none());
     }
-}
 
-fn mdbx_max_size_from_env() -> Option<usize> {
-    let raw = std::env::var("UBT_MDBX_MAX_SIZE").ok()?;
-    let s = raw.trim().to_ascii_uppercase();
-    let split = s
-        .find(|c: char| c.is_ascii_alphabetic())
-        .unwrap_or(s.len());
-    let (num_str, unit) = s.split_at(split);
-    let number: u64 = match num_str.trim().parse() {
-        Ok(v) => v,
-        Err(_) => {
-            warn!(value = %raw, "Invalid UBT_MDBX_MAX_SIZE, expected integer with optional unit");
-            return None;
+    #[test]
+    fn test_open_read_only() {
+        let dir = TempDir::new().unwrap();
+        let stem = Stem::new([1u8; STEM_LEN]);
+        {
+            let db = UbtDatabase::open(dir.path()).unwrap();
+            let mut node = StemNode::new(stem);
+            node.set_value(0, B256::repeat_byte(0x11));
+            db.batch_update_stems(&[(stem, node)]).unwrap();
         }
-    };
 
-    let factor: u64 = match unit.trim() {
-        "" | "B" => 1,
-        "K" | "KB" => 1024,
-        "M" | "MB" => 1024 * 1024,
-        "G" | "GB" => 1024 * 1024 * 1024,
-        "T" | "TB" => 1024_u64 * 1024 * 1024 * 1024,
-        other => {
-            warn!(value = %raw, unit = %other, "Invalid UBT_MDBX_MAX_SIZE unit");
-            return None;
-        }
-    };
+        let db = UbtDatabase::open_read_only(dir.path()).unwrap();
+        assert_eq!(
+            db.load_value(&TreeKey::new(stem, 0)).unwrap(),
+            Some(B256::repeat_byte(0x11))
+        );
+        assert_eq!(db.schema_version().unwrap(), SCHEMA_VERSION);
+        assert!(db.batch_update_stems(&[(stem, StemNode::new(stem))]).is_err());
+    }
 
-    number.checked_mul(factor).map(|v| v as usize)
-}
+    #[test]
+    fn test_open_read_only_never_creates() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("ubt");
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::TempDir;
+        assert!(UbtDatabase::open_read_only(&path).is_err());
+        assert!(!path.exists());
+    }
 
-    fn create_test_db() -> (TempDir, UbtDatabase) {
+    #[test]
+    fn test_map_full_and_grow() {
         let dir = TempDir::new().unwrap();
-        let db = UbtDatabase::open(dir.path()).unwrap();
-        (dir, db)
+        let db = UbtDatabase::open_with_max_size(dir.path(), 1024 * 1024).unwrap();
+        let updates: Vec<_> = (0..20_000u32)
+            .map(|i| {
+                let mut bytes = [0u8; STEM_LEN];
+                bytes[..4].copy_from_slice(&i.to_be_bytes());
+                let stem = Stem::new(bytes);
+                let mut node = StemNode::new(stem);
+                for subindex in 0..4u8 {
+                    node.set_value(subindex, B256::repeat_byte(subindex + 1));
+                }
+                (stem, node)
+            })
+            .collect();
+        let mut batch = WriteBatch::default();
+        batch.update_stems(updates);
+
+        let err = db.write_batch(&batch).unwrap_err();
+        assert!(err.is_map_full());
+        assert_eq!(db.stats().unwrap().tables[0].entries, 0);
+
+        while let Err(e) = db.write_batch(&batch) {
+            assert!(e.is_map_full());
+            db.grow_map().unwrap();
+        }
+        assert_eq!(db.stats().unwrap().tables[0].entries, 20_000);
+        assert!(db.stats().unwrap().map_upper_bytes > 1024 * 1024);
     }
 
     #[test]
-    fn test_open_creates_tables() {
-        let (_dir, _db) = create_test_db();
+    fn test_stats() {
+        let (_dir, db) = create_test_db();
+        let updates: Vec<_> = (1..=3u8)
+            .map(|i| {
+                let stem = Stem::new([i; STEM_LEN]);
+                let mut node = StemNode::new(stem);
+                node.set_value(0, B256::repeat_byte(i));
+                (stem, node)
+            })
+            .collect();
+        db.batch_update_stems(&updates).unwrap();
+
+        let stats = db.stats().unwrap();
+        let names: Vec<_> = stats.tables.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, TABLES);
+        let stems = &stats.tables[0];
+        assert_eq!(stems.entries, 3);
+        assert!(stems.size_bytes > 0);
+        assert_eq!(stats.tables[3].entries, 0);
+        assert!(stats.used_bytes > 0);
+        assert!(stats.map_upper_bytes >= stats.map_size_bytes);
+        assert!(stats.map_utilization > 0.0 && stats.map_utilization < 1.0);
     }
 
     #[test]
-    fn test_save_load_head() {
+    fn test_backup() {
         let (_dir, db) = create_test_db();
-
+        let stem = Stem::new([1u8; STEM_LEN]);
+        let mut node = StemNode::new(stem);
+        node.set_value(0, B256::repeat_byte(0x11));
+        db.batch_update_stems(&[(stem, node)]).unwrap();
         let head = UbtHead {
-            block_number: 100,
-            block_hash: B256::repeat_byte(0x42),
-            root: B256::repeat_byte(0xAB),
-            stem_count: 1000,
+            block_number: 7,
+            block_hash: B256::repeat_byte(0x07),
+            root: B256::repeat_byte(0xaa),
+            stem_count: 1,
         };
-
         db.save_head(&head).unwrap();
-        let loaded = db.load_head().unwrap().unwrap();
 
-        assert_eq!(loaded.block_number, head.block_number);
-        assert_eq!(loaded.block_hash, head.block_hash);
-        assert_eq!(loaded.root, head.root);
-        assert_eq!(loaded.stem_count, head.stem_count);
+        let backup_dir = TempDir::new().unwrap();
+        let dest = backup_dir.path().join("ubt");
+        db.backup(&dest, true).unwrap();
+        assert!(db.backup(&dest, false).is_err());
+
+        let copy = UbtDatabase::open(&dest).unwrap();
+        assert_eq!(copy.load_head().unwrap().unwrap().block_number, 7);
+        assert_eq!(
+            copy.load_value(&TreeKey::new(stem, 0)).unwrap(),
+            Some(B256::repeat_byte(0x11))
+        );
     }
 
     #[test]
-    fn test_load_head_empty() {
+    fn test_new_database_uses_compact_stems() {
         let (_dir, db) = create_test_db();
-        assert!(db.load_head().unwrap().is_none());
+        assert!(db.stem_encoding_migrated().unwrap());
+
+        let stem = Stem::new([1u8; 31]);
+        let mut node = StemNode::new(stem);
+        node.set_value(0, B256::repeat_byte(0x11));
+        db.batch_update_stems(&[(stem, node)]).unwrap();
+        assert!(is_compact(&stem, &raw_stem(&db, &stem)));
     }
 
     #[test]
-    fn test_batch_update_and_iter_stems() {
+    fn test_migrate_stem_encoding() {
         let (_dir, db) = create_test_db();
+        let updates: Vec<_> = (1..=3u8)
+            .map(|i| {
+                let stem = Stem::new([i; 31]);
+                let mut node = StemNode::new(stem);
+                node.set_value(0, B256::repeat_byte(i));
+                node.set_value(i, B256::repeat_byte(0x10 + i));
+                (stem, node)
+            })
+            .collect();
+        db.write_legacy_stems(&updates).unwrap();
+        let entries: Vec<_> = db.iter_entries_sorted().unwrap().map(|e| e.unwrap()).collect();
+
+        assert!(!db.stem_encoding_migrated().unwrap());
+        assert_eq!(
+            db.load_value(&TreeKey::new(updates[1].0, 2)).unwrap(),
+            Some(B256::repeat_byte(0x12))
+        );
+
+        // A compact row written mid-migration is skipped.
+        let mut node = db.load_stem(&updates[2].0).unwrap().unwrap();
+        node.set_value(9, B256::repeat_byte(0x99));
+        db.batch_update_stems(&[(updates[2].0, node)]).unwrap();
+
+        let progress = db.migrate_stem_encoding(2).unwrap();
+        assert_eq!(
+            progress,
+            StemMigrationProgress {
+                scanned: 2,
+                rewritten: 2,
+                done: false
+            }
+        );
+        let progress = db.migrate_stem_encoding(2).unwrap();
+        assert_eq!(
+            progress,
+            StemMigrationProgress {
+                scanned: 1,
+                rewritten: 0,
+                done: true
+            }
+        );
+        assert!(db.stem_encoding_migrated().unwrap());
 
-        let stem1 = Stem::new([1u8; STEM_LEN]);
-        let stem2 = Stem::new([2u8; STEM_LEN]);
-
-        let mut node1 = StemNode::new(stem1);
-        node1.set_value(0, B256::repeat_byte(0x11));
-        node1.set_value(1, B256::repeat_byte(0x12));
-
-        let mut node2 = StemNode::new(stem2);
-        node2.set_value(0, B256::repeat_byte(0x21));
-
-        db.batch_update_stems(&[(stem1, node1.clone()), (stem2, node2.clone())])
-            .unwrap();
+        for (stem, _) in &updates {
+            assert!(is_compact(stem, &raw_stem(&db, stem)));
+        }
+        let migrated: Vec<_> = db.iter_entries_sorted().unwrap().map(|e| e.unwrap()).collect();
+        assert_eq!(migrated.len(), entries.len() + 1);
+        assert!(entries.iter().all(|entry| migrated.contains(entry)));
+    }
 
-        let stems = db.iter_stems().unwrap();
-        assert_eq!(stems.len(), 2);
+    #[test]
+    fn test_migrate_stem_encoding_rewrites_rows_that_look_compact() {
+        let (_dir, db) = create_test_db();
+        let (stem, node) = crate::stem_codec::tests::colliding_legacy_node();
+        db.write_legacy_stems(&[(stem, node.clone())]).unwrap();
+        assert_eq!(db.load_stem(&stem).unwrap().unwrap().values, node.values);
+
+        let progress = db.migrate_stem_encoding(10).unwrap();
+        assert_eq!(progress.rewritten, 1);
+        assert!(progress.done);
+        assert_eq!(raw_stem(&db, &stem), encode_stem(&node));
+        assert_eq!(db.load_stem(&stem).unwrap().unwrap().values, node.values);
     }
 
     #[test]
@@ -625,6 +3578,23 @@ mod tests {
         assert_eq!(loaded.get_value(5), Some(B256::repeat_byte(0x55)));
     }
 
+    #[test]
+    fn test_batch_update_deletes_empty_stems() {
+        let (_dir, db) = create_test_db();
+
+        let stem = Stem::new([11u8; STEM_LEN]);
+        let mut node = StemNode::new(stem);
+        node.set_value(3, B256::repeat_byte(0x33));
+        db.batch_update_stems(&[(stem, node.clone())]).unwrap();
+        assert!(db.load_stem(&stem).unwrap().is_some());
+
+        node.values.remove(&3);
+        db.batch_update_stems(&[(stem, node)]).unwrap();
+
+        assert!(db.load_stem(&stem).unwrap().is_none());
+        assert!(db.iter_stems().unwrap().next().is_none());
+    }
+
     #[test]
     fn test_block_deltas_roundtrip() {
         let (_dir, db) = create_test_db();
@@ -672,6 +3642,116 @@ mod tests {
         assert!(!db.load_block_deltas(150).unwrap().is_empty());
     }
 
+    #[test]
+    fn test_write_batch_prunes_and_migrates_with_the_flush() {
+        let (_dir, db) = create_test_db();
+        let legacy_stem = Stem::new([7u8; STEM_LEN]);
+        let mut legacy = StemNode::new(legacy_stem);
+        legacy.set_value(0, B256::repeat_byte(0x07));
+        db.write_legacy_stems(&[(legacy_stem, legacy)]).unwrap();
+
+        let stem = Stem::new([6u8; STEM_LEN]);
+        let head_at = |block: u64| UbtHead {
+            block_number: block,
+            block_hash: B256::with_last_byte(block as u8),
+            root: B256::repeat_byte(block as u8),
+            stem_count: 1,
+        };
+        for block in [50, 100] {
+            let mut batch = WriteBatch::default();
+            batch.save_block_deltas(block, vec![(stem, 0, B256::ZERO)]);
+            batch.save_root(&head_at(block));
+            db.write_batch(&batch).unwrap();
+        }
+
+        let mut batch = WriteBatch::default();
+        batch.save_block_deltas(150, vec![(stem, 2, B256::ZERO)]);
+        batch.save_root(&head_at(150));
+        batch.prune_deltas_before(100);
+        batch.prune_roots_before(100);
+        batch.migrate_stem_encoding(10);
+        db.write_batch(&batch).unwrap();
+
+        assert!(db.load_block_deltas(50).unwrap().is_empty());
+        assert!(!db.load_block_deltas(100).unwrap().is_empty());
+        assert!(!db.load_block_deltas(150).unwrap().is_empty());
+        assert!(db.load_root_at(50).unwrap().is_none());
+        assert!(db.load_root_at(100).unwrap().is_some());
+        assert!(db.load_root_at(150).unwrap().is_some());
+        assert!(db.stem_encoding_migrated().unwrap());
+        assert!(is_compact(&legacy_stem, &raw_stem(&db, &legacy_stem)));
+    }
+
+    #[test]
+    fn test_delete_deltas_after() {
+        let (_dir, db) = create_test_db();
+
+        let stem = Stem::new([6u8; STEM_LEN]);
+        for block in [50, 100, 150, 200] {
+            db.save_block_deltas(block, &[(stem, 0, B256::ZERO)]).unwrap();
+        }
+
+        db.delete_deltas_after(100).unwrap();
+        let blocks: Vec<_> = db
+            .deltas_in_range(0, u64::MAX)
+            .unwrap()
+            .into_iter()
+            .map(|(block, _)| block)
+            .collect();
+        assert_eq!(blocks, vec![50, 100]);
+
+        db.delete_deltas_after(u64::MAX).unwrap();
+        assert_eq!(db.deltas_in_range(0, u64::MAX).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_deltas_in_range() {
+        let (_dir, db) = create_test_db();
+
+        let stem = Stem::new([6u8; STEM_LEN]);
+        for block in [10u64, 20, 30, 40] {
+            db.save_block_deltas(block, &[(stem, block as u8, B256::repeat_byte(1))])
+                .unwrap();
+        }
+
+        let range = db.deltas_in_range(15, 30).unwrap();
+        assert_eq!(
+            range,
+            vec![
+                (20, vec![(stem, 20, B256::repeat_byte(1))]),
+                (30, vec![(stem, 30, B256::repeat_byte(1))]),
+            ]
+        );
+        assert!(db.deltas_in_range(41, 100).unwrap().is_empty());
+        assert!(db.deltas_in_range(30, 20).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_iter_stems_from() {
+        let (_dir, db) = create_test_db();
+
+        let updates: Vec<_> = [1u8, 3, 5]
+            .into_iter()
+            .map(|i| {
+                let stem = Stem::new([i; STEM_LEN]);
+                let mut node = StemNode::new(stem);
+                node.set_value(0, B256::repeat_byte(i));
+                (stem, node)
+            })
+            .collect();
+        db.batch_update_stems(&updates).unwrap();
+
+        let stems_from = |start: u8| -> Vec<Stem> {
+            db.iter_stems_from(&Stem::new([start; STEM_LEN]))
+                .unwrap()
+                .map(|entry| entry.unwrap().0)
+                .collect()
+        };
+        assert_eq!(stems_from(3), vec![updates[1].0, updates[2].0]);
+        assert_eq!(stems_from(4), vec![updates[2].0]);
+        assert!(stems_from(6).is_empty());
+    }
+
     #[test]
     fn test_delete_block_deltas_idempotent() {
         let (_dir, db) = create_test_db();
@@ -707,6 +3787,268 @@ mod tests {
         assert!(loaded.is_none());
     }
 
+    #[test]
+    fn test_tree_hasher_is_pinned() {
+        let (_dir, db) = create_test_db();
+        assert_eq!(db.load_tree_hasher().unwrap(), None);
+
+        db.ensure_tree_hasher(TreeHasher::Sha256).unwrap();
+        assert_eq!(db.tree_hasher().unwrap(), TreeHasher::Sha256);
+        db.ensure_tree_hasher(TreeHasher::Sha256).unwrap();
+        assert!(matches!(
+            db.ensure_tree_hasher(TreeHasher::Blake3),
+            Err(UbtError::HasherMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_tree_hasher_defaults_to_blake3_for_existing_state() {
+        let (_dir, db) = create_test_db();
+        db.save_head(&UbtHead {
+            block_number: 1,
+            block_hash: B256::ZERO,
+            root: B256::ZERO,
+            stem_count: 0,
+        })
+        .unwrap();
+
+        assert!(matches!(
+            db.ensure_tree_hasher(TreeHasher::Sha256),
+            Err(UbtError::HasherMismatch { .. })
+        ));
+        db.ensure_tree_hasher(TreeHasher::Blake3).unwrap();
+        assert_eq!(db.load_tree_hasher().unwrap(), Some(TreeHasher::Blake3));
+    }
+
+    #[test]
+    fn test_pending_commit_roundtrip() {
+        let (_dir, db) = create_test_db();
+        assert_eq!(db.load_pending_commit().unwrap(), None);
+
+        let pending = PendingCommit::Revert {
+            block_number: 7,
+            block_hash: B256::repeat_byte(0x07),
+        };
+        db.save_pending_commit(&pending).unwrap();
+        assert_eq!(db.load_pending_commit().unwrap(), Some(pending));
+
+        db.delete_pending_commit().unwrap();
+        assert_eq!(db.load_pending_commit().unwrap(), None);
+    }
+
+    #[test]
+    fn test_finalize_pending_root() {
+        let (_dir, db) = create_test_db();
+        let hasher = TreeHasher::Blake3;
+        db.ensure_tree_hasher(hasher).unwrap();
+
+        let mut stem_bytes = [0u8; STEM_LEN];
+        stem_bytes[0] = 0x80;
+        let stem = Stem::new(stem_bytes);
+        let mut node = StemNode::new(stem);
+        node.set_value(0, B256::repeat_byte(0x01));
+        let stem_hashes = hasher.hash_stems(&[(stem, node.clone())]);
+        let (expected, updates) = db.compute_root_incremental(hasher, &stem_hashes).unwrap();
+
+        let finalized = UbtHead {
+            block_number: 1,
+            block_hash: B256::repeat_byte(0x01),
+            root: B256::repeat_byte(0xAA),
+            stem_count: 0,
+        };
+        let pending = PendingRoot {
+            block_number: 2,
+            block_hash: B256::repeat_byte(0x02),
+            finalized: Some(finalized.clone()),
+        };
+        let mut batch = WriteBatch::default();
+        batch.update_stems([(stem, node.clone())]);
+        batch
+            .save_head(&UbtHead {
+                block_number: 2,
+                block_hash: B256::repeat_byte(0x02),
+                root: B256::repeat_byte(0xAA),
+                stem_count: 1,
+            })
+            .unwrap();
+        batch.save_pending_root(&pending).unwrap();
+        db.write_batch(&batch).unwrap();
+        assert!(!db.internal_nodes_valid().unwrap());
+        // The stem's hash is left to the root computation.
+        assert!(db.stem_hashes_pending().unwrap());
+        assert!(!db.stem_hashes_valid().unwrap());
+        assert_eq!(db.load_stem_hash(&stem).unwrap(), None);
+
+        let (head, stored) = db.load_head_with_pending_root().unwrap();
+        assert_eq!(head.unwrap().root, B256::repeat_byte(0xAA));
+        assert_eq!(stored, Some(pending.clone()));
+        assert_eq!(db.load_head().unwrap(), Some(finalized));
+        assert!(db.load_root_at(2).unwrap().is_none());
+
+        // Only the marker written with the head can be finalized.
+        let other = PendingRoot {
+            block_number: 3,
+            ..pending.clone()
+        };
+        assert!(db.finalize_root(&other, expected, None, None).is_err());
+
+        db.finalize_root(&pending, expected, Some(&stem_hashes), Some(&updates))
+            .unwrap();
+        assert_eq!(db.load_pending_root().unwrap(), None);
+        let head = db.load_head().unwrap().unwrap();
+        assert_eq!((head.block_number, head.root), (2, expected));
+        assert_eq!(db.load_root_at(2).unwrap().unwrap().root, expected);
+        assert!(db.stem_hashes_valid().unwrap());
+        assert_eq!(
+            db.load_stem_hash(&stem).unwrap(),
+            Some(hasher.hash_stem(stem, &node))
+        );
+        assert!(db.internal_nodes_valid().unwrap());
+        assert_eq!(
+            db.compute_root_incremental(hasher, &[]).unwrap().0,
+            expected
+        );
+        assert!(db.finalize_root(&pending, expected, None, None).is_err());
+    }
+
+    #[test]
+    fn test_root_history() {
+        let (_dir, db) = create_test_db();
+        let head_at = |block: u8, fork: u8| UbtHead {
+            block_number: block as u64,
+            block_hash: B256::repeat_byte(block ^ fork),
+            root: B256::repeat_byte(0x80 | (block ^ fork)),
+            stem_count: block as usize,
+        };
+        let save = |head: &UbtHead| {
+            let mut batch = WriteBatch::default();
+            batch.save_root(head);
+            db.write_batch(&batch).unwrap();
+        };
+
+        for block in 1..=5 {
+            save(&head_at(block, 0));
+        }
+        let record = db.load_root_at(3).unwrap().unwrap();
+        assert_eq!(record.block_hash, B256::repeat_byte(3));
+        assert_eq!(record.root, B256::repeat_byte(0x83));
+        assert_eq!(record.stem_count, 3);
+        assert_eq!(
+            db.load_root_by_hash(B256::repeat_byte(4))
+                .unwrap()
+                .unwrap()
+                .block_number,
+            4
+        );
+        assert!(db.load_root_at(6).unwrap().is_none());
+
+        // Moving the head back drops the later records; the new fork replaces them.
+        save(&head_at(2, 0));
+        assert!(db.load_root_at(3).unwrap().is_none());
+        assert!(db
+            .load_root_by_hash(B256::repeat_byte(5))
+            .unwrap()
+            .is_none());
+        save(&head_at(3, 0x40));
+        assert_eq!(
+            db.load_root_at(3).unwrap().unwrap().root,
+            head_at(3, 0x40).root
+        );
+        assert_eq!(
+            db.load_root_by_hash(B256::repeat_byte(3 ^ 0x40)).unwrap(),
+            Some(head_at(3, 0x40))
+        );
+        assert!(db
+            .load_root_by_hash(B256::repeat_byte(3))
+            .unwrap()
+            .is_none());
+
+        assert_eq!(db.prune_roots_before(2).unwrap(), 1);
+        assert!(db.load_root_at(1).unwrap().is_none());
+        assert!(db
+            .load_root_by_hash(B256::repeat_byte(1))
+            .unwrap()
+            .is_none());
+        assert!(db.load_root_at(2).unwrap().is_some());
+        assert_eq!(db.prune_roots_before(2).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_balance_overflow_roundtrip() {
+        let (_dir, db) = create_test_db();
+        assert!(db.load_balance_overflows().unwrap().is_empty());
+
+        let record = BalanceOverflow {
+            address: Address::repeat_byte(0x77),
+            block_number: 12,
+            balance: U256::MAX,
+        };
+        db.save_balance_overflow(&record).unwrap();
+
+        let updated = BalanceOverflow {
+            block_number: 13,
+            ..record.clone()
+        };
+        db.save_balance_overflow(&updated).unwrap();
+
+        assert_eq!(db.load_balance_overflows().unwrap(), vec![updated.clone()]);
+
+        // A revert to block 12 drops records of later blocks, stored or queued.
+        let reverted = BalanceOverflow {
+            address: Address::repeat_byte(0x78),
+            block_number: 14,
+            balance: U256::MAX,
+        };
+        let kept = BalanceOverflow {
+            address: Address::repeat_byte(0x79),
+            block_number: 11,
+            balance: U256::MAX,
+        };
+        let mut batch = WriteBatch::default();
+        batch.save_balance_overflow(reverted);
+        batch.save_balance_overflow(kept.clone());
+        batch.delete_balance_overflows_after(12);
+        db.write_batch(&batch).unwrap();
+
+        assert_eq!(db.load_balance_overflows().unwrap(), vec![kept]);
+    }
+
+    #[test]
+    fn test_balance_overflow_revert_restores_earlier_overflow() {
+        let (_dir, db) = create_test_db();
+        let address = Address::repeat_byte(0x77);
+        let overflow_at = |block_number: u64| BalanceOverflow {
+            address,
+            block_number,
+            balance: U256::MAX - U256::from(block_number),
+        };
+
+        let mut batch = WriteBatch::default();
+        batch.save_balance_overflow(overflow_at(5));
+        db.write_batch(&batch).unwrap();
+        let mut batch = WriteBatch::default();
+        batch.save_balance_overflow(overflow_at(8));
+        db.write_batch(&batch).unwrap();
+        assert_eq!(db.load_balance_overflows().unwrap(), vec![overflow_at(8)]);
+
+        // Reverting to block 6 brings back the overflow of block 5.
+        let mut batch = WriteBatch::default();
+        batch.delete_balance_overflows_after(6);
+        db.write_batch(&batch).unwrap();
+        assert_eq!(db.load_balance_overflows().unwrap(), vec![overflow_at(5)]);
+
+        // A balance that fits clears the record until that block is reverted.
+        let mut batch = WriteBatch::default();
+        batch.clear_balance_overflow(address, 7);
+        db.write_batch(&batch).unwrap();
+        assert!(db.load_balance_overflows().unwrap().is_empty());
+
+        let mut batch = WriteBatch::default();
+        batch.delete_balance_overflows_after(6);
+        db.write_batch(&batch).unwrap();
+        assert_eq!(db.load_balance_overflows().unwrap(), vec![overflow_at(5)]);
+    }
+
     #[test]
     fn test_batch_save_stem_addresses() {
         let (_dir, db) = create_test_db();
@@ -31,8 +31,6 @@
 //! ```
 
 use alloy_primitives::{Address, B256};
-use nomt::{Nomt, Options as NomtOptions};
-use nomt::hasher::Blake3Hasher as NomtBlake3Hasher;
 use nomt::trie::KeyPath;
 use std::fs::File;
 use std::io::{BufWriter, Seek, SeekFrom, Write};
@@ -41,8 +39,9 @@ use tracing::info;
 use ubt::Stem;
 
 use crate::error::{Result, UbtError};
+use crate::hasher::NomtDb;
 use crate::key_index::KeyIndex;
-use crate::persistence::UbtDatabase;
+use crate::persistence::{UbtDatabase, UbtHead};
 
 pub const STATE_MAGIC: [u8; 4] = *b"PIR2";
 pub const STATE_HEADER_SIZE: usize = 64;
@@ -127,11 +126,8 @@ pub fn export_full_state_from_mdbx(
     output_dir: &Path,
     chain_id: u64,
 ) -> Result<ExportResult> {
-    let head = db.load_head()?.ok_or_else(|| {
-        UbtError::Database(crate::error::DatabaseError::Mdbx(
-            "No canonical state yet".to_string(),
-        ))
-    })?;
+    let head = mdbx_head(db)?;
+    ensure_stem_addresses(db)?;
 
     info!(
         block = head.block_number,
@@ -155,8 +151,10 @@ pub fn export_full_state_from_mdbx(
     let mut stem_index_entries: Vec<(Stem, u64)> = Vec::new();
     let mut missing_addresses: Vec<Stem> = Vec::new();
 
-    for (stem, stem_node) in db.iter_stems()? {
-        let address = match db.load_stem_address(&stem)? {
+    let mut stems = db.iter_stems()?;
+    while let Some(item) = stems.next() {
+        let (stem, stem_node) = item?;
+        let address = match stems.stem_address(&stem)? {
             Some(addr) => addr,
             None => {
                 missing_addresses.push(stem);
@@ -240,20 +238,20 @@ pub fn export_full_state_from_mdbx(
 }
 
 pub fn export_full_state_from_nomt(
-    _db: &UbtDatabase,
+    db: &UbtDatabase,
     nomt_dir: &Path,
     key_index_path: &Path,
     output_dir: &Path,
     chain_id: u64,
 ) -> Result<ExportResult> {
     let key_index = KeyIndex::open(key_index_path)?;
-    let nomt = open_nomt(nomt_dir)?;
+    let nomt = NomtDb::open(nomt_dir, db.tree_hasher()?)?;
     export_full_state_from_nomt_with(&key_index, &nomt, output_dir, chain_id)
 }
 
 fn export_full_state_from_nomt_with(
     key_index: &KeyIndex,
-    nomt: &Nomt<NomtBlake3Hasher>,
+    nomt: &NomtDb,
     output_dir: &Path,
     chain_id: u64,
 ) -> Result<ExportResult> {
@@ -371,11 +369,7 @@ pub fn export_contract_state_from_mdbx(
     output_dir: &Path,
     chain_id: u64,
 ) -> Result<ExportResult> {
-    let head = db.load_head()?.ok_or_else(|| {
-        UbtError::Database(crate::error::DatabaseError::Mdbx(
-            "No canonical state yet".to_string(),
-        ))
-    })?;
+    let head = mdbx_head(db)?;
 
     info!(
         block = head.block_number,
@@ -398,8 +392,10 @@ pub fn export_contract_state_from_mdbx(
     let mut stem_index_entries: Vec<(Stem, u64)> = Vec::new();
     let mut missing_addresses: Vec<Stem> = Vec::new();
 
-    for (stem, stem_node) in db.iter_stems()? {
-        let address = match db.load_stem_address(&stem)? {
+    let mut stems = db.iter_stems()?;
+    while let Some(item) = stems.next() {
+        let (stem, stem_node) = item?;
+        let address = match stems.stem_address(&stem)? {
             Some(addr) => addr,
             None => {
                 missing_addresses.push(stem);
@@ -486,7 +482,7 @@ pub fn export_contract_state_from_mdbx(
 }
 
 pub fn export_contract_state_from_nomt(
-    _db: &UbtDatabase,
+    db: &UbtDatabase,
     nomt_dir: &Path,
     contract: Address,
     key_index_path: &Path,
@@ -517,7 +513,7 @@ pub fn export_contract_state_from_nomt(
     let placeholder_header = StateHeader::new(0, head.block_number, chain_id, head.block_hash);
     state_writer.write_all(&placeholder_header.to_bytes())?;
 
-    let nomt = open_nomt(nomt_dir)?;
+    let nomt = NomtDb::open(nomt_dir, db.tree_hasher()?)?;
 
     let mut entry_offset: u64 = 0;
     let mut stem_index_entries: Vec<(Stem, u64)> = Vec::new();
@@ -657,8 +653,7 @@ pub fn get_state_delta(
     let mut touched_keys_set: HashSet<TreeKey> = HashSet::new();
     let mut missing_addresses: Vec<Stem> = Vec::new();
 
-    for block in from_block..=to_block {
-        let deltas = db.load_block_deltas(block)?;
+    for (_, deltas) in db.deltas_in_range(from_block, to_block)? {
         for (stem, subindex, _old_value) in deltas {
             let key = TreeKey::new(stem, subindex);
             touched_keys_set.insert(key);
@@ -738,6 +733,39 @@ pub fn get_state_delta(
     })
 }
 
+/// Head of the state stored in MDBX. Fails while the root of the last flush is
+/// still being computed, since the flushed stems do not match the head's root.
+fn mdbx_head(db: &UbtDatabase) -> Result<UbtHead> {
+    let (head, pending) = db.load_head_with_pending_root()?;
+    if let Some(pending) = pending {
+        return Err(UbtError::Database(crate::error::DatabaseError::Mdbx(
+            format!(
+                "Root of block {} is still being computed",
+                pending.block_number
+            ),
+        )));
+    }
+    head.ok_or_else(|| {
+        UbtError::Database(crate::error::DatabaseError::Mdbx(
+            "No canonical state yet".to_string(),
+        ))
+    })
+}
+
+/// Fail before writing anything if some stems are known to have no stored address.
+fn ensure_stem_addresses(db: &UbtDatabase) -> Result<()> {
+    let missing = db.missing_stem_addresses()?;
+    if missing > 0 {
+        return Err(UbtError::Database(crate::error::DatabaseError::Mdbx(
+            format!(
+                "Missing stem->address mappings for {} stems. PIR export requires a fresh UBT build.",
+                missing
+            ),
+        )));
+    }
+    Ok(())
+}
+
 fn tree_index_from_key(stem: &Stem, subindex: u8) -> [u8; 32] {
     let mut tree_index = [0u8; 32];
     tree_index[..31].copy_from_slice(stem.as_bytes());
@@ -757,20 +785,9 @@ fn iter_bitmap_subindices(bitmap: &[u8; 32]) -> impl Iterator<Item = u8> + '_ {
     })
 }
 
-fn open_nomt(nomt_dir: &Path) -> Result<Nomt<NomtBlake3Hasher>> {
-    let mut opts = NomtOptions::new();
-    opts.path(nomt_dir);
-    opts.rollback(true);
-    opts.commit_concurrency(1);
-    Nomt::open(opts)
-        .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))
-}
-
-fn read_nomt_value(nomt: &Nomt<NomtBlake3Hasher>, tree_index: [u8; 32]) -> Result<B256> {
+fn read_nomt_value(nomt: &NomtDb, tree_index: [u8; 32]) -> Result<B256> {
     let key: KeyPath = tree_index;
-    let value = nomt
-        .read(key)
-        .map_err(|e| UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+    let value = nomt.read(key)?;
     match value {
         Some(bytes) => {
             let arr: [u8; 32] = bytes.as_slice().try_into().map_err(|_| {
@@ -787,6 +804,7 @@ fn read_nomt_value(nomt: &Nomt<NomtBlake3Hasher>, tree_index: [u8; 32]) -> Resul
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::hasher::TreeHasher;
     use nomt::KeyReadWrite;
     use tempfile::tempdir;
 
@@ -934,11 +952,7 @@ mod tests {
             expected_entries.push((addr_b, tree_index, value));
         }
 
-        let mut opts = NomtOptions::new();
-        opts.path(&nomt_dir);
-        opts.rollback(true);
-        opts.commit_concurrency(1);
-        let nomt = Nomt::<NomtBlake3Hasher>::open(opts)?;
+        let nomt = NomtDb::open(&nomt_dir, TreeHasher::Blake3)?;
 
         let mut updates: Vec<(KeyPath, KeyReadWrite)> = expected_entries
             .iter()
@@ -950,12 +964,7 @@ mod tests {
         ));
         updates.sort_by(|a, b| a.0.cmp(&b.0));
 
-        let session = nomt.begin_session(Default::default());
-        for (path, _) in &updates {
-            session.warm_up(*path);
-        }
-        let finished = session.finish(updates)?;
-        finished.commit(&nomt)?;
+        nomt.write(updates)?;
 
         let result = export_full_state_from_nomt_with(&key_index, &nomt, &output_dir, 11155111)?;
 
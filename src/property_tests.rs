@@ -151,35 +151,31 @@ proptest! {
     }
 
     #[test]
-    fn prop_stem_count_monotone(blocks in arb_blocks()) {
+    fn prop_stem_count_matches_model(blocks in arb_blocks()) {
         let mut harness = TestHarness::new();
-        let mut seen_stems: HashSet<ubt::Stem> = HashSet::new();
-        let mut prev_stem_count = 0usize;
+        let mut model: HashMap<TreeKey, B256> = HashMap::new();
 
         for (i, block) in blocks.iter().enumerate() {
             let entries = to_tree_entries(block);
 
-            for (key, _) in &entries {
-                seen_stems.insert(key.stem);
+            for (key, value) in &entries {
+                model.insert(*key, *value);
             }
 
             harness.apply_entries_block((i + 1) as u64, make_block_hash((i + 1) as u64), entries);
 
-            let current_stem_count = harness.exex.stem_count();
+            // Zero values delete leaves, so only stems with a non-zero leaf count.
+            let live_stems: HashSet<ubt::Stem> = model
+                .iter()
+                .filter(|(_, v)| **v != B256::ZERO)
+                .map(|(k, _)| k.stem)
+                .collect();
 
-            prop_assert!(
-                current_stem_count >= prev_stem_count,
-                "stem_count should be monotonically increasing: {} < {}",
-                current_stem_count,
-                prev_stem_count
-            );
             prop_assert_eq!(
-                current_stem_count,
-                seen_stems.len(),
-                "stem_count should equal seen_stems.len()"
+                harness.exex.stem_count(),
+                live_stems.len(),
+                "stem_count should equal the number of stems with live leaves"
             );
-
-            prev_stem_count = current_stem_count;
         }
     }
 
@@ -7,19 +7,30 @@
 //! - `ubt_exportState`: Export full UBT state to PIR2 format
 //! - `ubt_exportContract`: Export single contract state
 //! - `ubt_getStateDelta`: Get state changes for block range
-//! - `ubt_getRoot`: Get current UBT root hash and block info
+//! - `ubt_getRoot`: Get current UBT root hash and block info, and whether the root
+//!   of the last flush is still pending
+//! - `ubt_getRootAt`: Get the root recorded for an earlier flushed block
+//! - `ubt_getBalanceOverflows`: List accounts whose balance was saturated to u128
+//! - `ubt_dbStats`: MDBX table sizes and map usage
+//!
+//! # Admin Endpoints
+//!
+//! Served over IPC only.
+//!
+//! - `ubtAdmin_backup`: Hot backup of MDBX, NOMT and the key index at one head
 
-use alloy_primitives::{Address, B256};
+use alloy_primitives::{Address, B256, U256, U64};
 use jsonrpsee::{core::RpcResult, proc_macros::rpc};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot};
 
-use crate::persistence::UbtDatabase;
+use crate::backup::{BackupManifest, BackupRequest};
+use crate::persistence::{DbStats, UbtDatabase};
 use crate::key_index::KeyIndex;
-use nomt::{Nomt, Options as NomtOptions};
+use crate::hasher::NomtDb;
 use nomt::trie::KeyPath;
-use nomt::hasher::Blake3Hasher as NomtBlake3Hasher;
 use crate::pir_export;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -77,15 +88,65 @@ pub struct StateDeltaResult {
     pub delta_file: String,
 }
 
+/// Whether the root of the flushed head has been computed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RootStatus {
+    Finalized,
+    /// The head was flushed and its root is still being computed.
+    Pending,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct GetRootResult {
     #[serde(rename = "blockNumber")]
     pub block_number: u64,
     #[serde(rename = "blockHash")]
     pub block_hash: B256,
+    /// Root of `finalizedBlockNumber`, which is `blockNumber` unless `status` is
+    /// `pending`.
     pub root: B256,
     #[serde(rename = "stemCount")]
     pub stem_count: usize,
+    pub status: RootStatus,
+    #[serde(rename = "finalizedBlockNumber")]
+    pub finalized_block_number: u64,
+    #[serde(rename = "finalizedBlockHash")]
+    pub finalized_block_hash: B256,
+}
+
+/// A block named by number or by hash.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum BlockNumberOrHash {
+    Hash(B256),
+    Number(U64),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RootAtResult {
+    #[serde(rename = "blockNumber")]
+    pub block_number: u64,
+    #[serde(rename = "blockHash")]
+    pub block_hash: B256,
+    pub root: B256,
+    #[serde(rename = "stemCount")]
+    pub stem_count: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BalanceOverflowResult {
+    pub address: Address,
+    #[serde(rename = "blockNumber")]
+    pub block_number: u64,
+    pub balance: U256,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupParams {
+    pub output_path: String,
+    #[serde(default)]
+    pub compact: bool,
 }
 
 #[rpc(server, namespace = "ubt")]
@@ -101,6 +162,23 @@ pub trait UbtApi {
 
     #[method(name = "getRoot")]
     async fn get_root(&self) -> RpcResult<GetRootResult>;
+
+    /// Root recorded for a flushed block, or `null` if the block was not flushed,
+    /// was pruned or was reverted.
+    #[method(name = "getRootAt")]
+    async fn get_root_at(&self, block: BlockNumberOrHash) -> RpcResult<Option<RootAtResult>>;
+
+    #[method(name = "getBalanceOverflows")]
+    async fn get_balance_overflows(&self) -> RpcResult<Vec<BalanceOverflowResult>>;
+
+    #[method(name = "dbStats")]
+    async fn db_stats(&self) -> RpcResult<DbStats>;
+}
+
+#[rpc(server, namespace = "ubtAdmin")]
+pub trait UbtAdminApi {
+    #[method(name = "backup")]
+    async fn backup(&self, params: BackupParams) -> RpcResult<BackupManifest>;
 }
 
 #[derive(Clone)]
@@ -110,6 +188,7 @@ pub struct UbtRpc {
     delta_retention: u64,
     nomt_dir: PathBuf,
     key_index_path: PathBuf,
+    backup_tx: Option<mpsc::Sender<BackupRequest>>,
 }
 
 impl UbtRpc {
@@ -126,9 +205,18 @@ impl UbtRpc {
             delta_retention,
             nomt_dir,
             key_index_path,
+            backup_tx: None,
         }
     }
 
+    /// Route `ubtAdmin_backup` to the task owning the stores.
+    pub fn with_backup_sender(mut self, backup_tx: mpsc::Sender<BackupRequest>) -> Self {
+        self.backup_tx = Some(backup_tx);
+        self
+    }
+
+    /// Open the stores at the given paths. MDBX is opened read-only, since the
+    /// ExEx owns all writes to it.
     pub fn from_paths(
         ubt_dir: PathBuf,
         nomt_dir: PathBuf,
@@ -136,7 +224,7 @@ impl UbtRpc {
         default_chain_id: u64,
         delta_retention: u64,
     ) -> Result<Self, crate::error::UbtError> {
-        let db = UbtDatabase::open(&ubt_dir)?;
+        let db = UbtDatabase::open_read_only(&ubt_dir)?;
         Ok(Self::new(
             db,
             default_chain_id,
@@ -146,16 +234,22 @@ impl UbtRpc {
         ))
     }
 
+    /// Fail unless NOMT and the key index are at the same head and the root of
+    /// that head is finalized, so exports are labelled with the root of their state.
     fn ensure_nomt_synced(&self) -> Result<(), crate::error::UbtError> {
         const NOMT_HEAD_KEY: KeyPath = [0xff; 32];
 
-        let mut opts = NomtOptions::new();
-        opts.path(&self.nomt_dir);
-        opts.rollback(true);
-        opts.commit_concurrency(1);
+        let (head, pending) = self.db.load_head_with_pending_root()?;
+        if let Some(pending) = pending {
+            return Err(crate::error::UbtError::Database(
+                crate::error::DatabaseError::Mdbx(format!(
+                    "Root of block {} is still being computed",
+                    pending.block_number
+                )),
+            ));
+        }
 
-        let nomt = Nomt::<NomtBlake3Hasher>::open(opts)
-            .map_err(|e| crate::error::UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+        let nomt = NomtDb::open(&self.nomt_dir, self.db.tree_hasher()?)?;
 
         let nomt_head = match nomt.read(NOMT_HEAD_KEY) {
             Ok(Some(val)) => {
@@ -171,14 +265,25 @@ impl UbtRpc {
         };
 
         let key_index = KeyIndex::open(&self.key_index_path)?;
-        let key_index_head = key_index
-            .load_head()?
-            .ok_or_else(|| {
-                crate::error::UbtError::Database(crate::error::DatabaseError::Mdbx(
-                    "Missing key index head metadata".to_string(),
-                ))
-            })?
-            .block_number;
+        let key_index_head = key_index.load_head()?.ok_or_else(|| {
+            crate::error::UbtError::Database(crate::error::DatabaseError::Mdbx(
+                "Missing key index head metadata".to_string(),
+            ))
+        })?;
+
+        // The key index head takes a root computed after the flush only once the
+        // ExEx picks it up.
+        if head.is_some_and(|head| {
+            head.block_number == key_index_head.block_number && head.root != key_index_head.root
+        }) {
+            return Err(crate::error::UbtError::Database(
+                crate::error::DatabaseError::Mdbx(format!(
+                    "Root of block {} is still being recorded",
+                    key_index_head.block_number
+                )),
+            ));
+        }
+        let key_index_head = key_index_head.block_number;
 
         if nomt_head != key_index_head {
             return Err(crate::error::UbtError::Database(
@@ -283,27 +388,110 @@ impl UbtApiServer for UbtRpc {
     }
 
     async fn get_root(&self) -> RpcResult<GetRootResult> {
-        let key_index = KeyIndex::open(&self.key_index_path).map_err(|e| {
+        let (head, pending) = self.db.load_head_with_pending_root().map_err(|e| {
             jsonrpsee::types::ErrorObjectOwned::owned(-32000, e.to_string(), None::<()>)
         })?;
-        let head = key_index
-            .load_head()
-            .map_err(|e| {
-                jsonrpsee::types::ErrorObjectOwned::owned(-32000, e.to_string(), None::<()>)
-            })?
-            .ok_or_else(|| {
-                jsonrpsee::types::ErrorObjectOwned::owned(
-                    -32000,
-                    "No canonical state yet",
-                    None::<()>,
-                )
-            })?;
+        let head = head.ok_or_else(|| {
+            jsonrpsee::types::ErrorObjectOwned::owned(-32000, "No canonical state yet", None::<()>)
+        })?;
 
+        let (status, finalized_block_number, finalized_block_hash) = match pending {
+            Some(pending) => match pending.finalized {
+                Some(finalized) => (
+                    RootStatus::Pending,
+                    finalized.block_number,
+                    finalized.block_hash,
+                ),
+                // No root is finalized yet, and `root` is zero.
+                None => (RootStatus::Pending, 0, B256::ZERO),
+            },
+            None => (RootStatus::Finalized, head.block_number, head.block_hash),
+        };
         Ok(GetRootResult {
             block_number: head.block_number,
             block_hash: head.block_hash,
             root: head.root,
-            stem_count: head.stem_count as usize,
+            stem_count: head.stem_count,
+            status,
+            finalized_block_number,
+            finalized_block_hash,
         })
     }
+
+    async fn get_root_at(&self, block: BlockNumberOrHash) -> RpcResult<Option<RootAtResult>> {
+        let head = match block {
+            BlockNumberOrHash::Number(number) => self.db.load_root_at(number.to()),
+            BlockNumberOrHash::Hash(hash) => self.db.load_root_by_hash(hash),
+        }
+        .map_err(|e| {
+            jsonrpsee::types::ErrorObjectOwned::owned(-32000, e.to_string(), None::<()>)
+        })?;
+
+        Ok(head.map(|head| RootAtResult {
+            block_number: head.block_number,
+            block_hash: head.block_hash,
+            root: head.root,
+            stem_count: head.stem_count,
+        }))
+    }
+
+    async fn get_balance_overflows(&self) -> RpcResult<Vec<BalanceOverflowResult>> {
+        let records = self.db.load_balance_overflows().map_err(|e| {
+            jsonrpsee::types::ErrorObjectOwned::owned(-32000, e.to_string(), None::<()>)
+        })?;
+
+        Ok(records
+            .into_iter()
+            .map(|r| BalanceOverflowResult {
+                address: r.address,
+                block_number: r.block_number,
+                balance: r.balance,
+            })
+            .collect())
+    }
+
+    async fn db_stats(&self) -> RpcResult<DbStats> {
+        self.db.stats().map_err(|e| {
+            jsonrpsee::types::ErrorObjectOwned::owned(-32000, e.to_string(), None::<()>)
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl UbtAdminApiServer for UbtRpc {
+    async fn backup(&self, params: BackupParams) -> RpcResult<BackupManifest> {
+        let backup_tx = self.backup_tx.as_ref().ok_or_else(|| {
+            jsonrpsee::types::ErrorObjectOwned::owned(
+                -32000,
+                "Backups are not available",
+                None::<()>,
+            )
+        })?;
+        let (reply, response) = oneshot::channel();
+        let request = BackupRequest {
+            dest: PathBuf::from(&params.output_path),
+            compact: params.compact,
+            reply,
+        };
+        backup_tx.send(request).await.map_err(|_| {
+            jsonrpsee::types::ErrorObjectOwned::owned(
+                -32000,
+                "UBT ExEx is not running",
+                None::<()>,
+            )
+        })?;
+
+        response
+            .await
+            .map_err(|_| {
+                jsonrpsee::types::ErrorObjectOwned::owned(
+                    -32000,
+                    "UBT ExEx is not running",
+                    None::<()>,
+                )
+            })?
+            .map_err(|e| {
+                jsonrpsee::types::ErrorObjectOwned::owned(-32000, e.to_string(), None::<()>)
+            })
+    }
 }
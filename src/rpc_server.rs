@@ -9,7 +9,7 @@ use reth_tasks::TaskExecutor;
 use tokio::net::UnixListener;
 use tracing::{info, warn};
 
-use crate::rpc::{UbtApiServer, UbtRpc};
+use crate::rpc::{UbtAdminApiServer, UbtApiServer, UbtRpc};
 
 #[derive(Debug, Clone)]
 pub struct RpcServerConfig {
@@ -24,7 +24,9 @@ pub async fn start_rpc_servers(
 ) -> Result<()> {
     if let Some(ipc_path) = config.ipc_path {
         info!(path = %ipc_path.display(), "UBT IPC RPC enabled");
-        let methods = rpc.clone().into_rpc().into();
+        // Admin methods are only reachable over IPC, which is guarded by file permissions.
+        let mut methods: Methods = UbtApiServer::into_rpc(rpc.clone()).into();
+        methods.merge(UbtAdminApiServer::into_rpc(rpc.clone()))?;
         let executor = executor.clone();
         let ipc_path = ipc_path.clone();
         executor.spawn_critical("ubt-rpc-ipc", async move {
@@ -38,7 +40,7 @@ pub async fn start_rpc_servers(
 
     if let Some(http_addr) = config.http_addr {
         info!(addr = %http_addr, "UBT HTTP RPC enabled");
-        let methods = rpc.into_rpc().into();
+        let methods = UbtApiServer::into_rpc(rpc).into();
         let executor = executor.clone();
         let http_addr = http_addr.clone();
         executor.spawn_critical("ubt-rpc-http", async move {
@@ -0,0 +1,197 @@
+//! Stem node encoding for the `ubt_stems` table.
+//!
+//! Rows were originally `bincode::serialize(StemNode)`, which repeats the stem
+//! (already the row key) and stores the values as a length-prefixed map with a
+//! subindex and a length prefix per entry. The compact format is:
+//!
+//! | Offset | Size     | Field                                                  |
+//! |--------|----------|--------------------------------------------------------|
+//! | 0      | 1        | Format tag (`STEM_FORMAT_COMPACT_V1`)                  |
+//! | 1      | 32       | Presence bitmap; bit `i % 8` of byte `i / 8` = subindex `i` |
+//! | 33     | 32 * n   | Values of the `n` present subindices, ascending        |
+//!
+//! `decode_stem` reads both formats: a row is compact when it starts with the tag
+//! and its length matches its bitmap, otherwise it is decoded as legacy bincode.
+//! Legacy rows start with their stem, so a row for a stem beginning with the tag
+//! byte can pass both checks; a row that starts with its own stem is legacy.
+//! Legacy rows are rewritten by `UbtDatabase::migrate_stem_encoding`.
+
+use alloy_primitives::B256;
+use ubt::{Stem, StemNode};
+
+use crate::error::{DatabaseError, Result, UbtError};
+
+/// Format tag of the first compact stem encoding.
+pub const STEM_FORMAT_COMPACT_V1: u8 = 1;
+
+const BITMAP_LEN: usize = 32;
+const HEADER_LEN: usize = 1 + BITMAP_LEN;
+const VALUE_LEN: usize = 32;
+
+/// Encode a stem node in the compact format.
+pub fn encode_stem(node: &StemNode) -> Vec<u8> {
+    let mut bitmap = [0u8; BITMAP_LEN];
+    for &subindex in node.values.keys() {
+        bitmap[(subindex / 8) as usize] |= 1u8 << (subindex % 8);
+    }
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN + VALUE_LEN * node.values.len());
+    bytes.push(STEM_FORMAT_COMPACT_V1);
+    bytes.extend_from_slice(&bitmap);
+    for subindex in subindices(&bitmap) {
+        bytes.extend_from_slice(node.values[&subindex].as_slice());
+    }
+    bytes
+}
+
+/// Encode a stem node in the legacy bincode format, as written before the
+/// compact format existed.
+pub fn encode_stem_legacy(node: &StemNode) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(node)?)
+}
+
+/// Decode a stored stem row in either format.
+pub fn decode_stem(stem: Stem, bytes: &[u8]) -> Result<StemNode> {
+    if !is_compact(&stem, bytes) {
+        return Ok(bincode::deserialize(bytes)?);
+    }
+
+    let mut bitmap = [0u8; BITMAP_LEN];
+    bitmap.copy_from_slice(&bytes[1..HEADER_LEN]);
+    let mut node = StemNode::new(stem);
+    for (subindex, value) in subindices(&bitmap).zip(bytes[HEADER_LEN..].chunks_exact(VALUE_LEN)) {
+        let value = B256::from_slice(value);
+        if value == B256::ZERO {
+            return Err(UbtError::Database(DatabaseError::Mdbx(format!(
+                "Zero value stored for subindex {subindex} of stem {stem:?}"
+            ))));
+        }
+        node.set_value(subindex, value);
+    }
+    Ok(node)
+}
+
+/// Whether the stored row of `stem` uses the compact format.
+pub fn is_compact(stem: &Stem, bytes: &[u8]) -> bool {
+    if bytes.len() < HEADER_LEN || bytes[0] != STEM_FORMAT_COMPACT_V1 {
+        return false;
+    }
+    if bytes.starts_with(stem.as_bytes()) {
+        return false;
+    }
+    bytes.len() == HEADER_LEN + VALUE_LEN * bit_count(&bytes[1..HEADER_LEN])
+}
+
+fn bit_count(bytes: &[u8]) -> usize {
+    bytes.iter().map(|byte| byte.count_ones() as usize).sum()
+}
+
+fn subindices(bitmap: &[u8; BITMAP_LEN]) -> impl Iterator<Item = u8> + '_ {
+    (0u16..256).filter_map(move |idx| {
+        if bitmap[(idx / 8) as usize] & (1u8 << (idx % 8)) != 0 {
+            Some(idx as u8)
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A stem and node whose legacy row starts with the format tag and is as long
+    /// as a compact row with the same leading bytes.
+    pub(crate) fn colliding_legacy_node() -> (Stem, StemNode) {
+        for count in 1..=64u8 {
+            let subindices: Vec<u8> = (0..count).collect();
+            let mut stem = [0u8; 31];
+            stem[0] = STEM_FORMAT_COMPACT_V1;
+            let legacy = encode_stem_legacy(&node_with(Stem::new(stem), &subindices)).unwrap();
+            if (legacy.len() - HEADER_LEN) % VALUE_LEN != 0 {
+                continue;
+            }
+            // Bitmap bits past the stem come from the encoding; set the rest in the stem.
+            let bits = (legacy.len() - HEADER_LEN) / VALUE_LEN;
+            let tail = bit_count(&legacy[31..HEADER_LEN]);
+            if bits < tail || bits - tail > 30 * 8 {
+                continue;
+            }
+            for bit in 0..bits - tail {
+                stem[1 + bit / 8] |= 1 << (bit % 8);
+            }
+            let stem = Stem::new(stem);
+            return (stem, node_with(stem, &subindices));
+        }
+        unreachable!("no legacy row length matches a compact bitmap")
+    }
+
+    fn node_with(stem: Stem, subindices: &[u8]) -> StemNode {
+        let mut node = StemNode::new(stem);
+        for &subindex in subindices {
+            node.set_value(subindex, B256::repeat_byte(subindex.wrapping_add(1)));
+        }
+        node
+    }
+
+    #[test]
+    fn test_compact_roundtrip() {
+        let stem = Stem::new([7u8; 31]);
+        let node = node_with(stem, &[0, 1, 64, 128, 255]);
+
+        let bytes = encode_stem(&node);
+        assert_eq!(bytes.len(), 33 + 5 * 32);
+        assert!(is_compact(&stem, &bytes));
+
+        let decoded = decode_stem(stem, &bytes).unwrap();
+        assert_eq!(decoded.values, node.values);
+    }
+
+    #[test]
+    fn test_compact_is_smaller_than_legacy() {
+        let stem = Stem::new([7u8; 31]);
+        let node = node_with(stem, &[0, 1, 2, 3]);
+
+        let compact = encode_stem(&node);
+        let legacy = encode_stem_legacy(&node).unwrap();
+        assert!(compact.len() < legacy.len());
+    }
+
+    #[test]
+    fn test_decodes_legacy_rows() {
+        let stem = Stem::new([7u8; 31]);
+        let node = node_with(stem, &[3, 200]);
+
+        let legacy = encode_stem_legacy(&node).unwrap();
+        assert!(!is_compact(&stem, &legacy));
+        let decoded = decode_stem(stem, &legacy).unwrap();
+        assert_eq!(decoded.values, node.values);
+    }
+
+    #[test]
+    fn test_decodes_legacy_rows_that_look_compact() {
+        let (stem, node) = colliding_legacy_node();
+        let legacy = encode_stem_legacy(&node).unwrap();
+        assert_eq!(legacy[0], STEM_FORMAT_COMPACT_V1);
+        assert_eq!(
+            legacy.len(),
+            HEADER_LEN + VALUE_LEN * bit_count(&legacy[1..HEADER_LEN])
+        );
+
+        assert!(!is_compact(&stem, &legacy));
+        let decoded = decode_stem(stem, &legacy).unwrap();
+        assert_eq!(decoded.values, node.values);
+    }
+
+    #[test]
+    fn test_rejects_zero_values() {
+        let stem = Stem::new([7u8; 31]);
+        let mut bytes = vec![STEM_FORMAT_COMPACT_V1];
+        bytes.extend_from_slice(&[1u8; 1]);
+        bytes.extend_from_slice(&[0u8; 31]);
+        bytes.extend_from_slice(&[0u8; 32]);
+
+        assert!(is_compact(&stem, &bytes));
+        assert!(decode_stem(stem, &bytes).is_err());
+    }
+}
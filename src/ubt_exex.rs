@@ -27,56 +27,178 @@
 //! Deltas are pruned after `delta_retention` blocks to bound storage growth.
 
 use alloy_eips::BlockNumHash;
-use alloy_primitives::{Address, B256, U256};
+use alloy_genesis::GenesisAccount;
+use alloy_primitives::{keccak256, Address, Bytes, B256, U256};
 use futures::TryStreamExt;
+use nomt::trie::KeyPath;
+use nomt::KeyReadWrite;
 use reth_chainspec::EthChainSpec;
 use reth_ethereum::exex::{ExExContext, ExExEvent, ExExHead, ExExNotification};
+use reth_ethereum::provider::{BytecodeReader, StateProviderFactory};
 use reth_execution_types::Chain;
 use reth_exex::ExExNotificationsStream;
 use reth_node_api::FullNodeComponents;
 use reth_primitives_traits::{AlloyBlockHeader as _, NodePrimitives};
-use std::{collections::HashMap, time::Instant};
-use tracing::{debug, info, warn};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread::JoinHandle,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::{debug, error, info, warn};
 use ubt::{
     chunkify_code, get_basic_data_key, get_code_chunk_key, get_code_hash_key, get_storage_slot_key,
-    BasicDataLeaf, Blake3Hasher, Stem, StemNode, StreamingTreeBuilder, TreeKey,
+    BasicDataLeaf, Stem, StemNode, TreeKey, STEM_LEN,
 };
-use nomt::{Nomt, Options as NomtOptions, KeyReadWrite};
-use nomt::trie::KeyPath;
-use nomt::hasher::Blake3Hasher as NomtBlake3Hasher;
 
-use crate::config::UbtConfig;
-use crate::error::Result;
+use crate::backup::{self, BackupManifest, BackupRequest};
+use crate::bootstrap::{PlainStateSource, ProviderStateSource, DEFAULT_BOOTSTRAP_BATCH};
+use crate::config::{BalanceOverflowPolicy, MapFullPolicy, RootComputation, UbtConfig, VerifyMode};
+use crate::error::{Result, UbtError};
+use crate::hasher::{NomtDb, TreeHasher, NOMT_HEAD_KEY};
 use crate::key_index::{KeyIndex, KEY_INDEX_FILE};
-use crate::persistence::{UbtDatabase, UbtHead};
+use crate::persistence::{
+    BalanceOverflow, BootstrapCheckpoint, BootstrapPosition, PendingCommit, PendingRoot,
+    UbtDatabase, UbtHead, WriteBatch,
+};
 use crate::rpc::UbtRpc;
 use crate::rpc_server::{start_rpc_servers, RpcServerConfig};
 
-const UBT_DATA_DIR: &str = "ubt";
-const NOMT_DATA_DIR: &str = "nomt";
-const NOMT_HEAD_KEY: KeyPath = [0xff; 32];
+/// Directory of the MDBX database under the data directory.
+pub const UBT_DATA_DIR: &str = "ubt";
+/// Directory of the NOMT database under the data directory.
+pub const NOMT_DATA_DIR: &str = "nomt";
+/// Legacy stem rows rewritten in the compact encoding after each flush.
+const STEM_MIGRATION_BATCH: usize = 10_000;
+/// Stems rehashed, with their paths up to the root, by `VerifyMode::Sampled`.
+const VERIFY_SAMPLE_STEMS: usize = 1024;
 
+/// A pending leaf write for the current block.
+///
+/// `value: None` deletes the leaf.
 #[derive(Debug, Clone)]
 pub struct PendingEntry {
     pub key: TreeKey,
-    pub value: B256,
+    pub value: Option<B256>,
+    pub address: Address,
+}
+
+/// Post-block account state relevant to the UBT.
+#[derive(Debug, Clone)]
+pub struct AccountState {
+    pub nonce: u64,
+    pub balance: U256,
+    pub code_hash: B256,
+    /// Original (unanalyzed) bytecode, from the account info or the bundle's
+    /// `contracts` map. `None` if neither has it loaded.
+    pub code: Option<Bytes>,
+}
+
+/// Changes to a single account within one block.
+#[derive(Debug, Clone)]
+pub struct AccountChange {
     pub address: Address,
+    /// Account state after the block, `None` if the account does not exist.
+    pub info: Option<AccountState>,
+    /// The account was destroyed or had its storage wiped in this block, so every
+    /// leaf it owned before the block must be cleared.
+    pub storage_wiped: bool,
+    /// Storage slots changed in the block with their post-block values.
+    pub storage: Vec<(U256, U256)>,
+}
+
+/// State changes attributed to a single block of a committed chain.
+#[derive(Debug, Clone)]
+pub struct BlockChanges {
+    pub block_number: u64,
+    pub block_hash: B256,
+    pub accounts: Vec<AccountChange>,
+}
+
+/// Kinds of leaf written per account, used to label write/skip counters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LeafKind {
+    BasicData = 0,
+    CodeHash = 1,
+    CodeChunk = 2,
+    Storage = 3,
+}
+
+impl LeafKind {
+    const ALL: [LeafKind; 4] = [
+        LeafKind::BasicData,
+        LeafKind::CodeHash,
+        LeafKind::CodeChunk,
+        LeafKind::Storage,
+    ];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            LeafKind::BasicData => "basic_data",
+            LeafKind::CodeHash => "code_hash",
+            LeafKind::CodeChunk => "code_chunk",
+            LeafKind::Storage => "storage",
+        }
+    }
+}
+
+/// Leaves written and skipped in one block, indexed by `LeafKind`.
+#[derive(Debug, Default)]
+struct LeafStats {
+    written: [u64; 4],
+    skipped: [u64; 4],
+}
+
+/// Looks up contract bytecode by code hash in one state snapshot.
+pub type CodeLookup = Box<dyn Fn(B256) -> Result<Option<Bytes>>>;
+
+/// Opens a `CodeLookup` at most once per block, for accounts whose `BundleState`
+/// info does not carry the code (typically unchanged contracts).
+pub type CodeResolver = Box<dyn Fn() -> Result<CodeLookup> + Send + Sync>;
+
+/// Root of a flush being computed on the `ubt-root` thread.
+struct RootTask {
+    pending: PendingRoot,
+    stem_count: usize,
+    handle: JoinHandle<Result<B256>>,
 }
 
 pub struct UbtExEx {
-    pub(crate) db: UbtDatabase,
+    /// Shared with the `ubt-root` thread under `RootComputation::Async`.
+    pub(crate) db: Arc<UbtDatabase>,
+    data_dir: PathBuf,
     last_block: u64,
     last_hash: B256,
     last_root: B256,
     pub(crate) pending_entries: Vec<PendingEntry>,
     pub(crate) dirty_stems: HashMap<Stem, StemNode>,
-    pub(crate) nomt: Nomt<NomtBlake3Hasher>,
+    /// MDBX writes (stem addresses, deltas, overflow records) queued since the
+    /// last flush, applied together with the dirty stems and head.
+    pending_writes: WriteBatch,
+    /// A `PendingCommit` marker is stored in MDBX and not yet cleared.
+    commit_pending: bool,
+    /// Every stored stem uses the compact encoding; until then each flush migrates
+    /// a batch of legacy rows.
+    stem_encoding_migrated: bool,
+    pub(crate) nomt: NomtDb,
+    hasher: TreeHasher,
     pub(crate) key_index: KeyIndex,
     flush_interval: u64,
     delta_retention: u64,
+    root_retention: u64,
     last_persisted_block: u64,
     last_persisted_hash: B256,
     stem_count: usize,
+    code_resolver: Option<CodeResolver>,
+    pub(crate) balance_overflow_policy: BalanceOverflowPolicy,
+    pub(crate) map_full_policy: MapFullPolicy,
+    /// Root verification started by `VerifyMode::Background`; yields whether the
+    /// root matched.
+    background_verification: Option<JoinHandle<Result<bool>>>,
+    root_computation: RootComputation,
+    /// Root of the last flush, while it is computed after the flush.
+    root_task: Option<RootTask>,
 }
 
 impl UbtExEx {
@@ -84,126 +206,346 @@ impl UbtExEx {
     ///
     /// Note: Does NOT load the full tree into memory. State is read from MDBX
     /// on demand with a dirty overlay for pending changes.
+    ///
+    /// If the previous run stopped with a pending commit, or NOMT and the key
+    /// index disagree with the MDBX head, the stores are first brought back to a
+    /// common block (see `recover_stores`).
     pub fn new(config: &UbtConfig) -> Result<Self> {
         let data_dir = config.get_data_dir();
         let ubt_dir = data_dir.join(UBT_DATA_DIR);
+        let nomt_dir = data_dir.join(NOMT_DATA_DIR);
+        let key_index_path = data_dir.join(KEY_INDEX_FILE);
         let db = UbtDatabase::open(&ubt_dir)?;
+        let hasher = config.get_tree_hasher();
+        db.ensure_tree_hasher(hasher)?;
         let flush_interval = config.get_flush_interval();
         let delta_retention = config.get_delta_retention();
-        let key_index = KeyIndex::open(data_dir.join(KEY_INDEX_FILE))?;
-
-        let (
-            last_block,
-            last_hash,
-            last_root,
-            mut last_persisted_block,
-            last_persisted_hash,
-            stem_count,
-        ) = if let Some(head) = db.load_head()? {
+        let root_retention = config.get_root_retention();
+        let balance_overflow_policy = config.get_balance_overflow_policy();
+        let map_full_policy = config.get_map_full_policy();
+        let verify_mode = config.get_verify_mode();
+        let root_computation = config.get_root_computation();
+
+        // The flushed head, whose root `recover_pending_root` records if it is pending.
+        let head = db.load_head_with_pending_root()?.0;
+        let mut pending_commit = db.load_pending_commit()?;
+        let bootstrapping = db.load_bootstrap_checkpoint()?.is_some();
+        let stem_encoding_migrated = db.stem_encoding_migrated()?;
+        if !stem_encoding_migrated {
             info!(
-                block = head.block_number,
-                root = %head.root,
-                stems = head.stem_count,
-                "Resuming UBT state from MDBX (not loading full tree)"
+                batch = STEM_MIGRATION_BATCH,
+                "Legacy stem encoding found; migrating to the compact encoding with each flush"
             );
+        }
 
-            info!("Verifying UBT root via streaming; this may take a while on large state");
-            let computed = Self::compute_root_from_db(&db)?;
-            if computed == head.root {
-                info!("Streaming root verification passed");
-            } else {
-                return Err(crate::error::UbtError::RootVerificationFailed {
-                    expected: format!("{}", head.root),
-                    computed: format!("{}", computed),
-                });
+        if head.is_none() && !bootstrapping && pending_commit.is_some() {
+            // MDBX holds no state, so whatever NOMT and the key index hold came
+            // from a first commit that never completed.
+            warn!("Interrupted first UBT commit; resetting NOMT and the key index");
+            if nomt_dir.exists() {
+                std::fs::remove_dir_all(&nomt_dir)?;
+            }
+            if key_index_path.exists() {
+                std::fs::remove_file(&key_index_path)?;
             }
+            db.delete_pending_commit()?;
+            pending_commit = None;
+        }
 
-            (
+        let key_index = KeyIndex::open(&key_index_path)?;
+        let nomt = NomtDb::open(&nomt_dir, hasher)?;
+
+        let (block_number, block_hash, root, stem_count) = match &head {
+            Some(head) => (
                 head.block_number,
                 head.block_hash,
                 head.root,
-                head.block_number,
-                head.block_hash,
                 head.stem_count,
-            )
-        } else {
-            info!("Starting fresh UBT state");
-            (0, B256::ZERO, B256::ZERO, 0, B256::ZERO, 0)
+            ),
+            None => (0, B256::ZERO, B256::ZERO, 0),
         };
 
-        // Initialize NOMT
-        let nomt_dir = data_dir.join(NOMT_DATA_DIR);
-        let mut nomt_opts = NomtOptions::new();
-        nomt_opts.path(nomt_dir);
-        nomt_opts.rollback(true);
-        // Adjust these based on requirements
-        nomt_opts.commit_concurrency(1);
-
-        let nomt = Nomt::open(nomt_opts).map_err(|e| {
-            crate::error::UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string()))
-        })?;
+        let mut exex = Self {
+            db: Arc::new(db),
+            data_dir,
+            last_block: block_number,
+            last_hash: block_hash,
+            last_root: root,
+            pending_entries: Vec::new(),
+            dirty_stems: HashMap::new(),
+            pending_writes: WriteBatch::default(),
+            commit_pending: pending_commit.is_some(),
+            stem_encoding_migrated,
+            nomt,
+            hasher,
+            key_index,
+            flush_interval,
+            delta_retention,
+            root_retention,
+            last_persisted_block: block_number,
+            last_persisted_hash: block_hash,
+            stem_count,
+            code_resolver: None,
+            balance_overflow_policy,
+            map_full_policy,
+            background_verification: None,
+            root_computation,
+            root_task: None,
+        };
+
+        crate::metrics::record_healthy(true);
+        crate::metrics::record_root_pending(false);
+        if head.is_some() && !bootstrapping {
+            exex.recover_pending_root()?;
+            exex.recover_stores(pending_commit)?;
+
+            info!(
+                block = exex.last_persisted_block,
+                root = %exex.last_root,
+                stems = exex.stem_count,
+                "Resuming UBT state from MDBX (not loading full tree)"
+            );
 
-        // Sync logic: Check NOMT head
-        let nomt_head_block = if let Ok(Some(val)) = nomt.read(NOMT_HEAD_KEY) {
-            u64::from_be_bytes(val.try_into().unwrap_or([0; 8]))
+            exex.verify_startup_root(verify_mode)?;
         } else {
-            0
-        };
+            info!("Starting fresh UBT state");
+        }
 
         info!(
-            nomt_head = nomt_head_block,
-            mdbx_head = last_persisted_block,
-            "Checking NOMT/MDBX sync"
+            flush_interval = flush_interval,
+            hasher = %hasher,
+            delta_retention = delta_retention,
+            root_retention = root_retention,
+            balance_overflow = ?balance_overflow_policy,
+            map_full = ?map_full_policy,
+            verify_mode = verify_mode.as_str(),
+            root_computation = ?root_computation,
+            effective_head = exex.last_persisted_block,
+            "UBT flush interval configured"
         );
+        exex.publish_db_stats();
 
-        if nomt_head_block > last_persisted_block {
-            let diff = nomt_head_block - last_persisted_block;
-            warn!(
-                diff,
-                nomt_head = nomt_head_block,
-                mdbx_head = last_persisted_block,
-                "NOMT is ahead of MDBX, rolling back"
-            );
-            if let Err(e) = nomt.rollback(diff as usize) {
-                warn!(
-                    "NOMT rollback failed (possibly not enough history): {}. Proceeding with potential overwrite.",
-                    e
-                );
-            } else {
-                info!("NOMT rollback successful");
+        Ok(exex)
+    }
+
+    /// Record the root of a flush whose root computation was cut short by the last
+    /// shutdown (see `RootComputation::Async`), so the head holds its own root again.
+    ///
+    /// The root is computed from the flushed state, and the stem and internal node
+    /// hashes left to it stay invalid until `verify_startup_root` rebuilds them.
+    fn recover_pending_root(&mut self) -> Result<()> {
+        let Some(pending) = self.db.load_pending_root()? else {
+            return Ok(());
+        };
+        warn!(
+            block = pending.block_number,
+            finalized_block = ?pending.finalized.as_ref().map(|head| head.block_number),
+            "Root of the last flush was not recorded; computing it"
+        );
+
+        let root = self.finalize_pending_root(&pending)?;
+        self.last_root = root;
+        let index_at_pending = self.key_index.load_head()?.is_some_and(|head| {
+            head.block_number == pending.block_number && head.block_hash == pending.block_hash
+        });
+        if index_at_pending {
+            self.key_index.save_head(
+                pending.block_number,
+                pending.block_hash,
+                root,
+                self.stem_count as u64,
+            )?;
+        }
+        info!(block = pending.block_number, root = %root, "Recorded the root of the last flush");
+        Ok(())
+    }
+
+    /// Bring NOMT and the key index to the MDBX head after an unclean shutdown.
+    ///
+    /// Every commit writes a `PendingCommit` marker before touching NOMT or the key
+    /// index and clears it once the MDBX and key index heads are written, so a
+    /// marker (or a NOMT or key index head that differs from MDBX) means the
+    /// stores may disagree. Recovery is the same whatever the point of the crash:
+    ///
+    /// 1. An interrupted revert is finished in MDBX by applying stored deltas
+    ///    down to the revert target.
+    /// 2. NOMT is rolled back, one commit per block, to the MDBX head.
+    /// 3. The key index is rebuilt from MDBX and its head rewritten.
+    ///
+    /// Fails with `InconsistentStores` when NOMT is behind MDBX or cannot be
+    /// rolled back far enough, or when the deltas needed to finish a revert may
+    /// have been pruned.
+    fn recover_stores(&mut self, pending: Option<PendingCommit>) -> Result<()> {
+        let nomt_head = self.nomt.head()?;
+        let index_head = self.key_index.load_head()?;
+        let index_in_sync = index_head.as_ref().is_some_and(|head| {
+            head.block_number == self.last_persisted_block
+                && head.block_hash == self.last_persisted_hash
+        });
+        if pending.is_none() && nomt_head == Some(self.last_persisted_block) && index_in_sync {
+            return Ok(());
+        }
+
+        warn!(
+            mdbx_head = self.last_persisted_block,
+            nomt_head = ?nomt_head,
+            key_index_head = ?index_head.as_ref().map(|head| head.block_number),
+            pending = ?pending,
+            "UBT stores are not at a common block; recovering"
+        );
+
+        if let Some(PendingCommit::Revert {
+            block_number,
+            block_hash,
+        }) = pending
+        {
+            if block_number < self.last_persisted_block {
+                self.rollback_mdbx(block_number, block_hash)?;
             }
-        } else if nomt_head_block < last_persisted_block {
+        }
+        let target = self.last_persisted_block;
+
+        match nomt_head {
+            Some(head) if head > target => {
+                info!(from = head, to = target, "Rolling back NOMT");
+                self.nomt.rollback((head - target) as usize).map_err(|e| {
+                    UbtError::InconsistentStores {
+                        message: format!("NOMT rollback from block {head} to {target} failed: {e}"),
+                    }
+                })?;
+            }
+            Some(head) if head == target => {}
+            _ => {
+                return Err(UbtError::InconsistentStores {
+                    message: format!(
+                        "NOMT head {nomt_head:?} is behind MDBX head {target}; rebuild the NOMT directory"
+                    ),
+                });
+            }
+        }
+        let nomt_head = self.nomt.head()?;
+        if nomt_head != Some(target) {
+            return Err(UbtError::InconsistentStores {
+                message: format!(
+                    "NOMT is at block {nomt_head:?} after rollback, expected {target}"
+                ),
+            });
+        }
+
+        let stem_count = self.rebuild_key_index()?;
+        if stem_count as usize != self.stem_count {
             warn!(
-                nomt_head = nomt_head_block,
-                mdbx_head = last_persisted_block,
-                "NOMT is behind MDBX. Will re-process blocks to catch up."
+                head = self.stem_count,
+                key_index = stem_count,
+                "Stem count differs from the MDBX head; using the rebuilt key index"
             );
-            // Force re-processing from NOMT head
-            last_persisted_block = nomt_head_block;
+            self.stem_count = stem_count as usize;
         }
+        self.key_index
+            .save_head(target, self.last_persisted_hash, self.last_root, stem_count)?;
+        self.db.delete_pending_commit()?;
+        self.commit_pending = false;
 
         info!(
-            flush_interval = flush_interval,
-            delta_retention = delta_retention,
-            effective_head = last_persisted_block,
-            "UBT flush interval configured"
+            block = target,
+            stems = stem_count,
+            "UBT stores recovered to a common block"
         );
+        Ok(())
+    }
 
-        Ok(Self {
-            db,
-            last_block,
-            last_hash,
-            last_root,
-            pending_entries: Vec::new(),
-            dirty_stems: HashMap::new(),
-            nomt,
-            key_index,
-            flush_interval,
-            delta_retention,
-            last_persisted_block,
-            last_persisted_hash,
-            stem_count,
-        })
+    /// Roll MDBX back from its head to `block_number` using stored deltas.
+    fn rollback_mdbx(&mut self, block_number: u64, block_hash: B256) -> Result<()> {
+        let from = self.last_persisted_block;
+        if from - block_number > self.delta_retention {
+            return Err(UbtError::InconsistentStores {
+                message: format!(
+                    "interrupted revert from block {from} to {block_number} exceeds delta retention {}",
+                    self.delta_retention
+                ),
+            });
+        }
+
+        info!(
+            from,
+            to = block_number,
+            "Finishing interrupted revert in MDBX"
+        );
+        for (_, deltas) in self
+            .db
+            .deltas_in_range(block_number + 1, from)?
+            .into_iter()
+            .rev()
+        {
+            self.apply_deltas_reverse(&deltas)?;
+        }
+        self.pending_writes
+            .delete_balance_overflows_after(block_number);
+
+        let dirty = self.take_dirty_stems();
+        let root = self.compute_root_with_overlay(&dirty)?;
+        let head = UbtHead {
+            block_number,
+            block_hash,
+            root,
+            stem_count: self.stem_count,
+        };
+        self.flush(dirty, &head)?;
+
+        self.last_block = block_number;
+        self.last_hash = block_hash;
+        self.last_persisted_block = block_number;
+        self.last_persisted_hash = block_hash;
+        self.last_root = root;
+        Ok(())
+    }
+
+    /// Replace the key index with the stems and addresses stored in MDBX.
+    fn rebuild_key_index(&self) -> Result<u64> {
+        info!("Rebuilding key index from MDBX; this may take a while on large state");
+        let mut stems = self.db.iter_stems()?;
+        let records = std::iter::from_fn(|| loop {
+            let (stem, node) = match stems.next()? {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e)),
+            };
+            match stems.stem_address(&stem) {
+                Ok(Some(address)) => {
+                    return Some(Ok((stem, address, node.values.keys().copied().collect())))
+                }
+                Ok(None) => {
+                    warn!(stem = ?stem, "Missing stem address while rebuilding key index");
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        });
+        self.key_index.rebuild(records)
+    }
+
+    /// Record that NOMT and the key index are about to move past the MDBX head.
+    ///
+    /// The commit marker is written once per flush window; a revert always
+    /// writes its target.
+    fn mark_pending(&mut self, pending: PendingCommit) -> Result<()> {
+        if self.commit_pending && pending == PendingCommit::Commit {
+            return Ok(());
+        }
+        self.with_map_growth(|db| db.save_pending_commit(&pending))?;
+        self.commit_pending = true;
+        Ok(())
+    }
+
+    /// Publish MDBX table sizes and map usage as metrics.
+    fn publish_db_stats(&self) {
+        match self.db.stats() {
+            Ok(stats) => crate::metrics::record_db_stats(&stats),
+            Err(e) => warn!(error = %e, "Failed to read MDBX stats"),
+        }
+    }
+
+    /// Set the fallback used to load bytecode missing from a block's `BundleState`.
+    pub fn set_code_resolver(&mut self, resolver: CodeResolver) {
+        self.code_resolver = Some(resolver);
     }
 
     /// Get the current stem count (used by tests).
@@ -214,11 +556,10 @@ impl UbtExEx {
 
     /// Get the last persisted head for ExEx resumption.
     ///
-    /// Returns `None` if no blocks have been persisted yet (fresh start).
-    /// Note: Block 0 (genesis) is treated as "no head" - this assumes genesis
-    /// is not persisted directly but rather processed via backfill.
+    /// Returns `None` if no blocks have been persisted yet (fresh start). Block 0
+    /// is a valid head once the genesis allocation has been seeded.
     pub fn get_head(&self) -> Option<ExExHead> {
-        if self.last_persisted_block == 0 {
+        if self.last_persisted_hash == B256::ZERO {
             None
         } else {
             Some(ExExHead::new(BlockNumHash::new(
@@ -228,166 +569,507 @@ impl UbtExEx {
         }
     }
 
-    pub fn process_chain<N: NodePrimitives>(&mut self, chain: &Chain<N>) -> Result<()> {
-        let execution_outcome = chain.execution_outcome();
-        let bundle = execution_outcome.state();
+    /// Import the genesis allocation as block 0 on a fresh start.
+    ///
+    /// Genesis accounts are never part of a `BundleState`, so backfill alone leaves
+    /// them out of the tree. Their balances, code and storage go through the normal
+    /// block path and are committed to MDBX, NOMT and the key index, which records
+    /// the genesis root in `ubt_meta`. Does nothing if a head is already persisted.
+    pub fn seed_genesis(
+        &mut self,
+        genesis_hash: B256,
+        alloc: &BTreeMap<Address, GenesisAccount>,
+    ) -> Result<B256> {
+        if self.get_head().is_some() {
+            return Ok(self.last_root);
+        }
 
-        for (address, account) in bundle.state() {
-            let address = *address;
+        let changes = genesis_block_changes(genesis_hash, alloc);
+        self.process_block(&changes)?;
+        let root = self.commit(changes.block_number, changes.block_hash)?;
+        info!(
+            accounts = alloc.len(),
+            hash = %genesis_hash,
+            root = %root,
+            "Seeded UBT state from genesis allocation"
+        );
+        Ok(root)
+    }
 
-            if let Some(info) = &account.info {
-                let code_size = info.code.as_ref().map(|c| c.len()).unwrap_or(0) as u32;
+    /// Whether a bootstrap from plain state was started and has not finished.
+    pub fn bootstrap_in_progress(&self) -> Result<bool> {
+        Ok(self.db.load_bootstrap_checkpoint()?.is_some())
+    }
 
-                let basic_data =
-                    BasicDataLeaf::new(info.nonce, balance_to_u128(info.balance), code_size);
-                let key = get_basic_data_key(&address);
-                self.pending_entries.push(PendingEntry {
-                    key,
-                    value: basic_data.encode(),
+    /// Build the tree from plain state, resuming from the stored checkpoint.
+    ///
+    /// Batches of at most `batch_size` leaves are written to NOMT, MDBX and the key
+    /// index without deltas, and the checkpoint in `ubt_meta` is advanced in the
+    /// same MDBX transaction as each batch's stems. NOMT's head key is only written
+    /// at the end, so an interrupted bootstrap is not mistaken for a synced tree on
+    /// restart. Finally the head is persisted, and the checkpoint removed, at the
+    /// block the bootstrap started from, and backfill continues from there.
+    ///
+    /// Every batch records the highest block `source` has reflected as the
+    /// bootstrap floor, below which `revert` refuses to go (see `crate::bootstrap`).
+    pub fn bootstrap_from_state(
+        &mut self,
+        source: &impl PlainStateSource,
+        batch_size: usize,
+    ) -> Result<B256> {
+        let block = source.state_block()?;
+        let mut checkpoint = match self.db.load_bootstrap_checkpoint()? {
+            Some(checkpoint) => {
+                info!(
+                    block = checkpoint.block_number,
+                    state_block = block.number,
+                    accounts = checkpoint.accounts,
+                    address = %checkpoint.next.address,
+                    "Resuming UBT bootstrap from plain state"
+                );
+                checkpoint
+            }
+            None => {
+                info!(block = block.number, "Bootstrapping UBT from plain state");
+                let checkpoint = BootstrapCheckpoint {
+                    block_number: block.number,
+                    block_hash: block.hash,
+                    next: BootstrapPosition::default(),
+                    accounts: 0,
+                };
+                self.with_map_growth(|db| db.save_bootstrap_checkpoint(&checkpoint))?;
+                checkpoint
+            }
+        };
+        let floor = self
+            .db
+            .load_bootstrap_floor()?
+            .unwrap_or(checkpoint.block_number)
+            .max(block.number);
+
+        loop {
+            let batch = source.read_batch(&checkpoint.next, batch_size)?;
+            let accounts = batch.changes.iter().filter(|c| c.info.is_some()).count() as u64;
+
+            self.process_block(&BlockChanges {
+                block_number: checkpoint.block_number,
+                block_hash: checkpoint.block_hash,
+                accounts: batch.changes,
+            })?;
+            self.apply_pending(None)?;
+            let dirty = self.take_dirty_stems();
+            let mut writes = std::mem::take(&mut self.pending_writes);
+            writes.update_stems(dirty);
+            writes.save_bootstrap_floor(floor)?;
+
+            checkpoint.accounts += accounts;
+            let Some(next) = batch.next else {
+                self.with_map_growth(|db| db.write_batch(&writes))?;
+                break;
+            };
+            checkpoint.next = next;
+            writes.save_bootstrap_checkpoint(&checkpoint)?;
+            self.with_map_growth(|db| db.write_batch(&writes))?;
+            debug!(
+                accounts = checkpoint.accounts,
+                address = %next.address,
+                "UBT bootstrap batch written"
+            );
+        }
+
+        self.apply_pending(Some(checkpoint.block_number))?;
+        self.stem_count = self.key_index.stem_count()? as usize;
+        // Bootstrap batches are written without internal nodes; building them
+        // yields the root.
+        let root = self.rebuild_internal_nodes()?;
+
+        let head = UbtHead {
+            block_number: checkpoint.block_number,
+            block_hash: checkpoint.block_hash,
+            root,
+            stem_count: self.stem_count,
+        };
+        self.pending_writes.delete_bootstrap_checkpoint();
+        self.persist(Vec::new(), &head)?;
+
+        self.last_block = checkpoint.block_number;
+        self.last_hash = checkpoint.block_hash;
+        self.last_persisted_block = checkpoint.block_number;
+        self.last_persisted_hash = checkpoint.block_hash;
+        self.last_root = root;
+
+        info!(
+            block = checkpoint.block_number,
+            accounts = checkpoint.accounts,
+            stems = self.stem_count,
+            root = %root,
+            "UBT bootstrap from plain state complete"
+        );
+        Ok(root)
+    }
+
+    /// Process a committed chain block by block.
+    ///
+    /// Each block's state changes are extracted separately and committed under
+    /// that block's own number and hash, so deltas, NOMT commits and the head
+    /// line up with the blocks that `revert` will later be asked to undo.
+    ///
+    /// Returns the root after the last committed block (see `commit`).
+    pub fn process_chain<N: NodePrimitives>(&mut self, chain: &Chain<N>) -> Result<B256> {
+        let mut root = self.last_root;
+        for changes in chain_block_changes(chain) {
+            self.process_block(&changes)?;
+            root = self.commit(changes.block_number, changes.block_hash)?;
+        }
+        Ok(root)
+    }
+
+    /// Convert the state changes of a single block into pending entries.
+    ///
+    /// Leaves that already hold the value being written are skipped, so an account
+    /// that only changed one storage slot emits only that slot. Wiped accounts are
+    /// written in full, since their existing leaves are queued for deletion.
+    pub fn process_block(&mut self, changes: &BlockChanges) -> Result<()> {
+        let mut stats = LeafStats::default();
+        let mut code_lookup = None;
+
+        for change in &changes.accounts {
+            let address = change.address;
+            let force = change.storage_wiped;
+            let basic_data_key = get_basic_data_key(&address);
+            let old_basic_data = self.get_value(&basic_data_key)?;
+
+            // A saturated balance that now fits (or a destroyed account) ends the
+            // account's overflow record from this block on.
+            let balance_fits = change
+                .info
+                .as_ref()
+                .is_none_or(|info| u128::try_from(info.balance).is_ok());
+            if balance_fits && old_basic_data.map(basic_data_balance) == Some(u128::MAX) {
+                self.pending_writes
+                    .clear_balance_overflow(address, changes.block_number);
+            }
+
+            if change.storage_wiped {
+                self.push_account_wipe(address)?;
+            }
+
+            if let Some(info) = &change.info {
+                let has_code = info.code_hash != B256::ZERO && info.code_hash != KECCAK_EMPTY;
+                let code = match &info.code {
+                    Some(code) => Some(code.clone()),
+                    None if has_code => self.resolve_code(&mut code_lookup, info.code_hash)?,
+                    None => None,
+                };
+
+                let code_size = match &code {
+                    Some(code) => code.len() as u32,
+                    None if has_code => {
+                        // Keep the previously recorded size rather than writing 0.
+                        warn!(
+                            %address,
+                            code_hash = %info.code_hash,
+                            "Bytecode not found; keeping previous code size"
+                        );
+                        old_basic_data.map(basic_data_code_size).unwrap_or(0)
+                    }
+                    None => 0,
+                };
+
+                if !change.storage_wiped {
+                    let new_code_len = if has_code {
+                        code.as_ref().map(|c| c.len())
+                    } else {
+                        Some(0)
+                    };
+                    self.push_stale_code_deletions(address, new_code_len)?;
+                }
+
+                let balance = match u128::try_from(info.balance) {
+                    Ok(balance) => balance,
+                    Err(_) => {
+                        self.handle_balance_overflow(changes.block_number, address, info.balance)?
+                    }
+                };
+                let old_code_size = old_basic_data.map(basic_data_code_size);
+                let basic_data = BasicDataLeaf::new(info.nonce, balance, code_size);
+                self.push_leaf(
+                    LeafKind::BasicData,
+                    basic_data_key,
+                    Some(basic_data.encode()),
                     address,
-                });
+                    force,
+                    &mut stats,
+                )?;
 
-                if info.code_hash != B256::ZERO && info.code_hash != KECCAK_EMPTY {
+                if has_code {
                     let code_hash_key = get_code_hash_key(&address);
-                    self.pending_entries.push(PendingEntry {
-                        key: code_hash_key,
-                        value: info.code_hash,
+                    let code_unchanged = !force
+                        && old_code_size == Some(code_size)
+                        && self.get_value(&code_hash_key)? == Some(info.code_hash);
+                    self.push_leaf(
+                        LeafKind::CodeHash,
+                        code_hash_key,
+                        Some(info.code_hash),
                         address,
-                    });
-
-                    if let Some(code) = &info.code {
-                        let chunks = chunkify_code(code.original_byte_slice());
-                        for (i, chunk) in chunks.iter().enumerate() {
-                            let chunk_key = get_code_chunk_key(&address, i as u64);
-                            self.pending_entries.push(PendingEntry {
-                                key: chunk_key,
-                                value: chunk.encode(),
-                                address,
-                            });
+                        force,
+                        &mut stats,
+                    )?;
+
+                    if let Some(code) = &code {
+                        if code_unchanged {
+                            // Same hash and size: every chunk is already in place.
+                            stats.skipped[LeafKind::CodeChunk as usize] +=
+                                code_chunk_count(code.len());
+                        } else {
+                            let chunks = chunkify_code(code);
+                            for (i, chunk) in chunks.iter().enumerate() {
+                                let chunk_key = get_code_chunk_key(&address, i as u64);
+                                self.push_leaf(
+                                    LeafKind::CodeChunk,
+                                    chunk_key,
+                                    Some(chunk.encode()),
+                                    address,
+                                    true,
+                                    &mut stats,
+                                )?;
+                            }
                         }
                     }
                 }
             }
 
-            for (slot, value) in &account.storage {
+            for (slot, value) in &change.storage {
                 let slot_bytes = u256_to_b256(*slot);
-                let value_bytes = u256_to_b256(value.present_value);
                 let storage_key = get_storage_slot_key(&address, &slot_bytes.0);
+                // EIP-7864: a zeroed slot deletes its leaf rather than storing zero.
+                self.push_leaf(
+                    LeafKind::Storage,
+                    storage_key,
+                    (!value.is_zero()).then(|| u256_to_b256(*value)),
+                    address,
+                    force,
+                    &mut stats,
+                )?;
+            }
+        }
+
+        for kind in LeafKind::ALL {
+            crate::metrics::record_leaf_writes(
+                kind.as_str(),
+                stats.written[kind as usize],
+                stats.skipped[kind as usize],
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Queue a leaf write unless the leaf already holds `value` (or `force` is set).
+    fn push_leaf(
+        &mut self,
+        kind: LeafKind,
+        key: TreeKey,
+        value: Option<B256>,
+        address: Address,
+        force: bool,
+        stats: &mut LeafStats,
+    ) -> Result<()> {
+        if !force && self.get_value(&key)? == value {
+            stats.skipped[kind as usize] += 1;
+            return Ok(());
+        }
+        stats.written[kind as usize] += 1;
+        self.pending_entries.push(PendingEntry {
+            key,
+            value,
+            address,
+        });
+        Ok(())
+    }
+
+    /// Queue deletions for every leaf owned by `address`.
+    ///
+    /// The key index lists the stems owned by each account (basic data, code and
+    /// storage stems alike), so this covers leaves written in any earlier block.
+    fn push_account_wipe(&mut self, address: Address) -> Result<()> {
+        for (stem, record) in self.key_index.stems_for_address(address)? {
+            for subindex in record.subindices() {
                 self.pending_entries.push(PendingEntry {
-                    key: storage_key,
-                    value: value_bytes,
+                    key: TreeKey::new(stem, subindex),
+                    value: None,
+                    address,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Look up bytecode by hash through the configured code resolver, opening
+    /// `lookup` on the block's first call.
+    fn resolve_code(
+        &self,
+        lookup: &mut Option<CodeLookup>,
+        code_hash: B256,
+    ) -> Result<Option<Bytes>> {
+        let Some(resolver) = &self.code_resolver else {
+            return Ok(None);
+        };
+        if lookup.is_none() {
+            *lookup = Some(resolver()?);
+        }
+        lookup.as_ref().map_or(Ok(None), |lookup| lookup(code_hash))
+    }
+
+    /// Apply the configured policy to a balance that does not fit in 16 bytes.
+    ///
+    /// Returns the saturated balance to write when the policy is `Record`.
+    fn handle_balance_overflow(
+        &mut self,
+        block_number: u64,
+        address: Address,
+        balance: U256,
+    ) -> Result<u128> {
+        crate::metrics::record_balance_overflow();
+        match self.balance_overflow_policy {
+            BalanceOverflowPolicy::Error => Err(UbtError::BalanceOverflow {
+                address: format!("{}", address),
+                block_number,
+                balance: format!("{}", balance),
+            }),
+            BalanceOverflowPolicy::Record => {
+                warn!(
+                    %address,
+                    block = block_number,
+                    %balance,
+                    "Balance exceeds u128; writing saturated value"
+                );
+                self.pending_writes.save_balance_overflow(BalanceOverflow {
                     address,
+                    block_number,
+                    balance,
                 });
+                Ok(u128::MAX)
             }
         }
+    }
+
+    /// Queue deletions for code leaves left behind by the account's previous code.
+    ///
+    /// The previous code size comes from the current basic-data leaf. Chunks at or
+    /// beyond the new chunk count are deleted, as is the code-hash leaf when the
+    /// account no longer has code. `new_code_len` is `None` when the account has
+    /// code that is not loaded, in which case nothing can be compared.
+    fn push_stale_code_deletions(
+        &mut self,
+        address: Address,
+        new_code_len: Option<usize>,
+    ) -> Result<()> {
+        let Some(new_code_len) = new_code_len else {
+            return Ok(());
+        };
+        let Some(old_basic_data) = self.get_value(&get_basic_data_key(&address))? else {
+            return Ok(());
+        };
+        let old_code_len = basic_data_code_size(old_basic_data) as usize;
+
+        if old_code_len > 0 && new_code_len == 0 {
+            self.pending_entries.push(PendingEntry {
+                key: get_code_hash_key(&address),
+                value: None,
+                address,
+            });
+        }
+
+        for i in code_chunk_count(new_code_len)..code_chunk_count(old_code_len) {
+            self.pending_entries.push(PendingEntry {
+                key: get_code_chunk_key(&address, i),
+                value: None,
+                address,
+            });
+        }
 
         Ok(())
     }
 
     /// Commit pending entries to the UBT state for the given block.
     ///
+    /// Entries with a `None` or zero value delete their leaf (EIP-7864 treats an
+    /// absent leaf and a zero leaf as the same thing). Stems left without values
+    /// are removed from MDBX on flush and from the key index immediately, and
+    /// `stem_count` is decremented accordingly.
+    ///
+    /// The block's deltas and new stem addresses are queued with the dirty stems
+    /// and written to MDBX, together with the head, in one transaction on flush.
+    ///
     /// Returns the UBT root hash. Note: the returned root is only updated on flush
     /// (every `flush_interval` blocks). Between flushes, returns the last persisted root.
     /// This is a performance optimization - the true tip root could be computed on demand
     /// but would require merging dirty overlay with MDBX for every block.
+    ///
+    /// Under `RootComputation::Async` the root of a flush is computed after it, so
+    /// the root returned is the last one finalized, which may be older.
     pub fn commit(&mut self, block_number: u64, block_hash: B256) -> Result<B256> {
-        let entries = std::mem::take(&mut self.pending_entries);
-        let entry_count = entries.len();
-
-        // Update NOMT
+        if self
+            .root_task
+            .as_ref()
+            .is_some_and(|task| task.handle.is_finished())
         {
-            let mut nomt_updates: Vec<(KeyPath, KeyReadWrite)> = Vec::with_capacity(entry_count + 1);
-            for entry in &entries {
-                let key_path = tree_index_from_key(&entry.key.stem, entry.key.subindex);
-                nomt_updates.push((key_path, KeyReadWrite::Write(Some(entry.value.0.to_vec()))));
-            }
-            nomt_updates.push((NOMT_HEAD_KEY, KeyReadWrite::Write(Some(block_number.to_be_bytes().to_vec()))));
-            nomt_updates.sort_by(|a, b| a.0.cmp(&b.0));
+            self.finish_root()?;
+        }
+        let (entry_count, deltas) = self.apply_pending(Some(block_number))?;
 
-            let session = self.nomt.begin_session(Default::default());
-            for (path, _) in &nomt_updates {
-                session.warm_up(*path);
-            }
-            let finished = session.finish(nomt_updates).map_err(|e| crate::error::UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
-            finished.commit(&self.nomt).map_err(|e| crate::error::UbtError::Database(crate::error::DatabaseError::Mdbx(e.to_string())))?;
+        if !deltas.is_empty() {
+            self.pending_writes.save_block_deltas(block_number, deltas);
         }
 
-        let mut deltas: Vec<(Stem, u8, B256)> = Vec::new();
-        let mut new_stem_addresses: Vec<(Stem, Address)> = Vec::new();
-
-        for PendingEntry {
-            key,
-            value,
-            address,
-        } in &entries
-        {
-            if !self.dirty_stems.contains_key(&key.stem) {
-                if let Some(existing) = self.db.load_stem(&key.stem)? {
-                    self.dirty_stems.insert(key.stem, existing);
-                } else {
-                    self.dirty_stems.insert(key.stem, StemNode::new(key.stem));
-                    new_stem_addresses.push((key.stem, *address));
-                }
-            }
-
-            let stem_node = self.dirty_stems.get_mut(&key.stem).expect("just inserted");
-            let old_value = stem_node.get_value(key.subindex).unwrap_or(B256::ZERO);
-
-            if old_value != *value {
-                deltas.push((key.stem, key.subindex, old_value));
-            }
-
-            stem_node.set_value(key.subindex, *value);
-        }
-
-        if !new_stem_addresses.is_empty() {
-            self.db.batch_save_stem_addresses(&new_stem_addresses)?;
-        }
-
-        let new_stems = self
-            .key_index
-            .apply_updates(entries.iter().map(|entry| {
-                (entry.key.stem, entry.key.subindex, entry.address)
-            }))?;
-        self.stem_count += new_stems;
-
-        if !deltas.is_empty() {
-            self.db.save_block_deltas(block_number, &deltas)?;
-        }
-
-        self.last_block = block_number;
-        self.last_hash = block_hash;
+        self.last_block = block_number;
+        self.last_hash = block_hash;
 
         let should_flush = block_number <= self.last_persisted_block
             || (block_number - self.last_persisted_block) >= self.flush_interval;
 
         if should_flush {
+            if self.root_task.is_some() {
+                let wait_start = Instant::now();
+                self.finish_root()?;
+                crate::metrics::record_root_wait(wait_start.elapsed().as_secs_f64());
+            }
+
             let persist_start = Instant::now();
-            let dirty: Vec<_> = self.dirty_stems.drain().collect();
+            let dirty = self.take_dirty_stems();
             let dirty_count = dirty.len();
-            if !dirty.is_empty() {
-                self.db.batch_update_stems(&dirty)?;
+            self.queue_maintenance(block_number);
+
+            // A background startup verification checks its snapshot against the
+            // head's root, so flushes keep their root until it is done.
+            let verifying = self
+                .background_verification
+                .as_ref()
+                .is_some_and(|handle| !handle.is_finished());
+            let root_pending = self.root_computation == RootComputation::Async && !verifying;
+            if root_pending {
+                self.persist_pending_root(dirty, block_number, block_hash)?;
+            } else {
+                let root_start = Instant::now();
+                let root = self.compute_root_with_overlay(&dirty)?;
+                crate::metrics::record_root_computation(root_start.elapsed().as_secs_f64());
+
+                let head = UbtHead {
+                    block_number,
+                    block_hash,
+                    root,
+                    stem_count: self.stem_count,
+                };
+                self.persist(dirty, &head)?;
+                self.last_root = root;
             }
-
-            let root_start = Instant::now();
-            let root = self.compute_root_streaming()?;
-            crate::metrics::record_root_computation(root_start.elapsed().as_secs_f64());
-
-            let head = UbtHead {
-                block_number,
-                block_hash,
-                root,
-                stem_count: self.stem_count,
-            };
-            self.db.save_head(&head)?;
-            self.key_index
-                .save_head(block_number, block_hash, root, self.stem_count as u64)?;
             crate::metrics::record_persistence(persist_start.elapsed().as_secs_f64(), dirty_count);
             crate::metrics::record_dirty_stems(0);
 
             self.last_persisted_block = block_number;
             self.last_persisted_hash = block_hash;
-            self.last_root = root;
+            let root = self.last_root;
 
             info!(
                 block = block_number,
@@ -395,26 +1077,17 @@ impl UbtExEx {
                 stems = self.stem_count,
                 dirty_stems = dirty_count,
                 root = %root,
+                root_pending,
                 "UBT updated and flushed to MDBX"
             );
 
-            if block_number > self.delta_retention {
-                let prune_before = block_number - self.delta_retention;
-                match self.db.prune_deltas_before(prune_before) {
-                    Ok(count) if count > 0 => {
-                        debug!(
-                            pruned = count,
-                            before_block = prune_before,
-                            "Pruned old deltas"
-                        );
-                    }
-                    Err(e) => {
-                        warn!(error = %e, "Failed to prune old deltas");
-                    }
-                    _ => {}
-                }
+            if !self.stem_encoding_migrated && self.db.stem_encoding_migrated()? {
+                self.stem_encoding_migrated = true;
+                info!("Stem encoding migration complete");
             }
 
+            self.publish_db_stats();
+
             crate::metrics::record_block_processed(block_number, entry_count, self.stem_count);
             Ok(root)
         } else {
@@ -433,33 +1106,140 @@ impl UbtExEx {
         }
     }
 
-    /// Revert the UBT state for the given chain of blocks.
+    /// Apply pending entries to NOMT, the dirty overlay and the key index.
     ///
-    /// Applies stored deltas in reverse order to restore previous values.
+    /// `nomt_head` is written to NOMT's head key alongside the entries. Returns the
+    /// number of entries applied and the old values of the leaves they changed.
+    fn apply_pending(&mut self, nomt_head: Option<u64>) -> Result<(usize, Vec<(Stem, u8, B256)>)> {
+        let entries = std::mem::take(&mut self.pending_entries);
+        let entry_count = entries.len();
+        self.mark_pending(PendingCommit::Commit)?;
+
+        // Update NOMT. Later entries for the same key win (e.g. a wipe followed by
+        // a re-deploy in the same block), and the BTreeMap keeps keys sorted.
+        {
+            let mut nomt_writes: BTreeMap<KeyPath, Option<Vec<u8>>> = BTreeMap::new();
+            for entry in &entries {
+                let key_path = tree_index_from_key(&entry.key.stem, entry.key.subindex);
+                let value = entry.value.filter(|v| *v != B256::ZERO);
+                nomt_writes.insert(key_path, value.map(|v| v.0.to_vec()));
+            }
+            if let Some(head) = nomt_head {
+                nomt_writes.insert(NOMT_HEAD_KEY, Some(head.to_be_bytes().to_vec()));
+            }
+            let nomt_updates: Vec<(KeyPath, KeyReadWrite)> = nomt_writes
+                .into_iter()
+                .map(|(path, value)| (path, KeyReadWrite::Write(value)))
+                .collect();
+
+            self.nomt.write(nomt_updates)?;
+        }
+
+        let mut deltas: Vec<(Stem, u8, B256)> = Vec::new();
+        let mut new_stem_addresses: Vec<(Stem, Address)> = Vec::new();
+        let mut index_state: HashMap<TreeKey, (bool, Address)> = HashMap::new();
+
+        for PendingEntry {
+            key,
+            value,
+            address,
+        } in &entries
+        {
+            let value = value.filter(|v| *v != B256::ZERO);
+            if !self.dirty_stems.contains_key(&key.stem) {
+                if let Some(existing) = self.db.load_stem(&key.stem)? {
+                    self.dirty_stems.insert(key.stem, existing);
+                } else if value.is_none() {
+                    // Deleting from a stem that does not exist is a no-op.
+                    continue;
+                } else {
+                    self.dirty_stems.insert(key.stem, StemNode::new(key.stem));
+                    new_stem_addresses.push((key.stem, *address));
+                }
+            }
+
+            let stem_node = self.dirty_stems.get_mut(&key.stem).expect("just inserted");
+            let old_value = stem_node.get_value(key.subindex).unwrap_or(B256::ZERO);
+
+            if old_value != value.unwrap_or(B256::ZERO) {
+                deltas.push((key.stem, key.subindex, old_value));
+            }
+
+            match value {
+                Some(value) => stem_node.set_value(key.subindex, value),
+                None => {
+                    stem_node.values.remove(&key.subindex);
+                }
+            }
+            index_state.insert(*key, (value.is_some(), *address));
+        }
+
+        self.pending_writes.save_stem_addresses(new_stem_addresses);
+
+        let new_stems = self.key_index.apply_updates(
+            index_state
+                .iter()
+                .filter(|(_, (present, _))| *present)
+                .map(|(key, (_, address))| (key.stem, key.subindex, *address)),
+        )?;
+        let removed_stems = self.key_index.clear_entries(
+            index_state
+                .iter()
+                .filter(|(_, (present, _))| !*present)
+                .map(|(key, _)| (key.stem, key.subindex)),
+        )?;
+        self.stem_count = (self.stem_count + new_stems).saturating_sub(removed_stems);
+
+        Ok((entry_count, deltas))
+    }
+
+    /// Revert the UBT state for the given chain of blocks.
     ///
-    /// Note: `stem_count` is not decremented during reverts, so it may be slightly
-    /// inflated after reorgs. This is a known limitation - accurate stem counting
-    /// would require scanning MDBX which is expensive. The count is reset on restart
-    /// from the persisted head.
+    /// Applies stored deltas in reverse order to restore previous values; deltas of
+    /// blocks not flushed yet are taken from the pending write batch. `stem_count`
+    /// follows the key index, so stems created by the reverted blocks are
+    /// subtracted and stems they emptied are added back. Balance overflow records
+    /// of the reverted blocks are dropped. Fails with `RevertBelowBootstrap` if the
+    /// target is below the bootstrap floor.
     pub fn revert(&mut self, chain: &Chain<impl NodePrimitives>) -> Result<()> {
+        self.finish_root()?;
         let blocks = chain.blocks();
         let mut block_numbers: Vec<u64> = blocks.keys().copied().collect();
         block_numbers.sort();
         block_numbers.reverse();
 
+        let target = blocks.iter().min_by_key(|(num, _)| *num).map(
+            |(&first_reverted_num, first_reverted_block)| {
+                if first_reverted_num > 0 {
+                    (
+                        first_reverted_num - 1,
+                        first_reverted_block.header().parent_hash(),
+                    )
+                } else {
+                    (0, B256::ZERO)
+                }
+            },
+        );
+
         // NOMT Rollback
-        if !block_numbers.is_empty() {
-             info!(count = block_numbers.len(), "Rolling back NOMT state");
-             if let Err(e) = self.nomt.rollback(block_numbers.len()) {
-                 warn!(error = %e, "Failed to rollback NOMT");
-             }
+        if let Some((block_number, block_hash)) = target {
+            self.check_revert_target(block_number)?;
+            self.mark_pending(PendingCommit::Revert {
+                block_number,
+                block_hash,
+            })?;
+            info!(count = block_numbers.len(), "Rolling back NOMT state");
+            self.nomt.rollback(block_numbers.len())?;
         }
 
         let mut total_reverted = 0usize;
         let mut reverted_persisted = false;
 
         for block_number in &block_numbers {
-            let deltas = self.db.load_block_deltas(*block_number)?;
+            let deltas = match self.pending_writes.take_block_deltas(*block_number) {
+                Some(deltas) => deltas,
+                None => self.db.load_block_deltas(*block_number)?,
+            };
 
             if deltas.is_empty() {
                 warn!(
@@ -473,37 +1253,28 @@ impl UbtExEx {
             total_reverted += deltas.len();
 
             if *block_number > self.last_persisted_block {
-                self.db.delete_block_deltas(*block_number)?;
+                self.pending_writes.delete_block_deltas(*block_number);
             }
         }
 
-        if let Some((&first_reverted_num, first_reverted_block)) =
-            blocks.iter().min_by_key(|(num, _)| *num)
-        {
-            if first_reverted_num > 0 {
-                self.last_block = first_reverted_num - 1;
-                self.last_hash = first_reverted_block.header().parent_hash();
-            } else {
-                self.last_block = 0;
-                self.last_hash = B256::ZERO;
-            }
+        if let Some((block_number, block_hash)) = target {
+            self.pending_writes
+                .delete_balance_overflows_after(block_number);
+            self.last_block = block_number;
+            self.last_hash = block_hash;
             reverted_persisted = blocks.keys().any(|&b| b <= self.last_persisted_block);
         }
 
         if reverted_persisted {
-            let dirty: Vec<_> = self.dirty_stems.drain().collect();
-            if !dirty.is_empty() {
-                self.db.batch_update_stems(&dirty)?;
-            }
-
-            let root = self.compute_root_streaming()?;
+            let dirty = self.take_dirty_stems();
+            let root = self.compute_root_with_overlay(&dirty)?;
             let head = UbtHead {
                 block_number: self.last_block,
                 block_hash: self.last_hash,
                 root,
                 stem_count: self.stem_count,
             };
-            self.db.save_head(&head)?;
+            self.persist(dirty, &head)?;
 
             self.last_persisted_block = self.last_block;
             self.last_persisted_hash = self.last_hash;
@@ -523,6 +1294,15 @@ impl UbtExEx {
         Ok(())
     }
 
+    /// Refuse a revert to `target` below the bootstrap floor, whose blocks are
+    /// reflected in bootstrapped leaves without deltas.
+    fn check_revert_target(&self, target: u64) -> Result<()> {
+        match self.db.load_bootstrap_floor()? {
+            Some(floor) if target < floor => Err(UbtError::RevertBelowBootstrap { target, floor }),
+            _ => Ok(()),
+        }
+    }
+
     /// Get a stem node, checking dirty overlay first, then MDBX.
     /// Used for proof generation without requiring full tree in memory.
     ///
@@ -537,12 +1317,12 @@ impl UbtExEx {
     }
 
     /// Get a specific value by TreeKey, checking overlay then MDBX.
-    #[allow(dead_code)]
+    ///
+    /// An overlay stem is seeded from MDBX before mutation, so a subindex missing
+    /// from it has been deleted and must not fall back to the stale MDBX value.
     pub fn get_value(&self, key: &TreeKey) -> Result<Option<B256>> {
         if let Some(node) = self.dirty_stems.get(&key.stem) {
-            if let Some(value) = node.get_value(key.subindex) {
-                return Ok(Some(value));
-            }
+            return Ok(node.get_value(key.subindex));
         }
         self.db.load_value(key)
     }
@@ -551,46 +1331,536 @@ impl UbtExEx {
     pub fn shutdown(&mut self) -> Result<()> {
         info!("UBT ExEx shutting down, flushing pending state...");
 
-        let dirty: Vec<_> = self.dirty_stems.drain().collect();
+        let head = self.flush_pending()?;
+
+        info!(
+            block = head.block_number,
+            root = %head.root,
+            "UBT ExEx shutdown complete"
+        );
+
+        Ok(())
+    }
+
+    /// Persist every block applied since the last flush, so MDBX, NOMT and the key
+    /// index all stand at `last_block`. Returns the persisted head.
+    fn flush_pending(&mut self) -> Result<UbtHead> {
+        self.finish_root()?;
+        if !self.commit_pending
+            && self.dirty_stems.is_empty()
+            && self.last_block == self.last_persisted_block
+        {
+            return Ok(UbtHead {
+                block_number: self.last_persisted_block,
+                block_hash: self.last_persisted_hash,
+                root: self.last_root,
+                stem_count: self.stem_count,
+            });
+        }
+
+        let dirty = self.take_dirty_stems();
         if !dirty.is_empty() {
             info!(stems = dirty.len(), "Flushing dirty stems");
-            self.db.batch_update_stems(&dirty)?;
         }
 
-        let root = self.compute_root_streaming()?;
+        let root = self.compute_root_with_overlay(&dirty)?;
         let head = UbtHead {
             block_number: self.last_block,
             block_hash: self.last_hash,
             root,
             stem_count: self.stem_count,
         };
-        self.db.save_head(&head)?;
+        self.persist(dirty, &head)?;
+
+        self.last_persisted_block = self.last_block;
+        self.last_persisted_hash = self.last_hash;
+        self.last_root = root;
+        Ok(head)
+    }
+
+    /// Back up MDBX, NOMT and the key index into `dest` while running.
+    ///
+    /// Pending blocks are flushed first, so all three stores stand at the same head,
+    /// and nothing is written to them until the copies finish since the ExEx task
+    /// runs the backup between notifications. NOMT has no snapshot, so it is closed
+    /// while its directory is copied and reopened after. The MDBX copy is optionally
+    /// compacted. The manifest is written once the heads read back from the copies
+    /// agree. `dest` must be missing or empty; see `backup` for the layout.
+    pub fn backup(&mut self, dest: &Path, compact: bool) -> Result<BackupManifest> {
+        backup::ensure_empty_dest(dest)?;
+        self.flush_pending()?;
+        if self.get_head().is_none() {
+            return Err(UbtError::Backup {
+                message: "no persisted state to back up".to_string(),
+            });
+        }
+
+        let start = Instant::now();
+        std::fs::create_dir_all(dest)?;
+        self.db.backup(&dest.join(UBT_DATA_DIR), compact)?;
+        self.key_index.backup(&dest.join(KEY_INDEX_FILE))?;
+        let nomt_dir = self.data_dir.join(NOMT_DATA_DIR);
+        self.nomt.close();
+        let copied = backup::copy_dir(&nomt_dir, &dest.join(NOMT_DATA_DIR));
+        self.nomt.reopen(&nomt_dir)?;
+        copied?;
+
+        let (head, nomt_head, key_index_head) = read_backup_heads(dest, self.hasher)?;
+        let manifest = BackupManifest {
+            block_number: head.block_number,
+            block_hash: head.block_hash,
+            root: head.root,
+            stem_count: head.stem_count,
+            nomt_block_number: nomt_head,
+            key_index_block_number: key_index_head,
+            hasher: self.hasher,
+            schema_version: self.db.schema_version()?,
+            compacted: compact,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default(),
+        };
+        backup::write_manifest(dest, &manifest)?;
+
+        info!(
+            path = %dest.display(),
+            block = manifest.block_number,
+            root = %manifest.root,
+            compacted = compact,
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            "UBT backup complete"
+        );
+        Ok(manifest)
+    }
+
+    /// Compute root hash from MDBX entries using streaming builder with parallel hashing.
+    ///
+    /// Entries are streamed from an MDBX read transaction one stem at a time, so
+    /// neither the tree nor the entry list is held in memory. Uses rayon for
+    /// parallel stem hashing. Every stem is rehashed, so this does not depend on
+    /// the stored stem or internal node hashes and is used to verify them.
+    pub(crate) fn compute_root_streaming(&self) -> Result<B256> {
+        Self::compute_root_from_db(&self.db, self.hasher, &[])
+    }
+
+    /// Compute the root of MDBX with `overlay` (sorted by stem) applied on top, so
+    /// the head can be written in the same transaction as the overlay.
+    ///
+    /// While the stored internal node hashes are valid, only the paths above the
+    /// overlay are rehashed and the node writes are queued in `pending_writes`.
+    /// Otherwise, or if the stored nodes turn out not to match the stems, the root
+    /// is built from the stored stem hashes, or streamed from every entry when
+    /// those are invalid too, until the next startup rebuilds them.
+    fn compute_root_with_overlay(&mut self, overlay: &[(Stem, StemNode)]) -> Result<B256> {
+        let stem_hashes = self.hasher.hash_stems(overlay);
+        if self.db.internal_nodes_valid()? {
+            match self.db.compute_root_incremental(self.hasher, &stem_hashes) {
+                Ok((root, updates)) => {
+                    self.pending_writes.update_internal_nodes(updates);
+                    crate::metrics::record_root_mode("incremental");
+                    return Ok(root);
+                }
+                Err(e) => {
+                    warn!(error = %e, "Incremental root computation failed; falling back to streaming");
+                    self.pending_writes.invalidate_internal_nodes();
+                }
+            }
+        }
+        if self.db.stem_hashes_valid()? {
+            crate::metrics::record_root_mode("stem_hashes");
+            return self
+                .db
+                .compute_root_from_stem_hashes(self.hasher, &stem_hashes);
+        }
+        crate::metrics::record_root_mode("streaming");
+        Self::compute_root_from_db(&self.db, self.hasher, overlay)
+    }
+
+    /// Verify the root of the resumed head as `mode` asks, recording the mode and
+    /// result in logs and metrics.
+    ///
+    /// Every mode leaves valid stem and internal node hashes behind, rebuilding
+    /// them if they are missing; a rebuild recomputes the root, which is verified.
+    fn verify_startup_root(&mut self, mode: VerifyMode) -> Result<()> {
+        info!(mode = mode.as_str(), "Verifying UBT root at startup");
+        let start = Instant::now();
+        let result = match mode {
+            VerifyMode::Full => self.verify_root_full().map(|()| "passed"),
+            VerifyMode::Sampled => self.verify_root_sampled(),
+            VerifyMode::Background => self.spawn_root_verification().map(|()| "started"),
+            VerifyMode::Skip => self.ensure_root_hashes().map(|()| "skipped"),
+        };
+        let elapsed = start.elapsed();
+        crate::metrics::record_startup_verification(
+            mode.as_str(),
+            *result.as_ref().unwrap_or(&"failed"),
+            elapsed.as_secs_f64(),
+        );
+
+        let result = result?;
+        info!(
+            mode = mode.as_str(),
+            result,
+            elapsed_ms = elapsed.as_millis() as u64,
+            "Startup root verification finished"
+        );
+        if mode == VerifyMode::Skip {
+            warn!("Startup root verification skipped; the stored root is trusted");
+        }
+        Ok(())
+    }
+
+    /// Recompute the root from every entry, then check the stem and internal node
+    /// hashes against it.
+    fn verify_root_full(&self) -> Result<()> {
+        info!("Verifying UBT root via streaming; this may take a while on large state");
+        let computed = self.compute_root_streaming()?;
+        self.verify_root(computed)?;
+        info!("Streaming root verification passed");
+
+        self.ensure_stem_hashes(computed)?;
+        self.ensure_internal_nodes(computed)
+    }
+
+    /// Rehash `VERIFY_SAMPLE_STEMS` random stems and their paths up to the root
+    /// against the stored stem and internal node hashes, falling back to
+    /// `verify_root_full` on a mismatch.
+    ///
+    /// The fallback rebuilds the hashes the sample found stale, which a full
+    /// verification alone would keep while the stored root node matches.
+    fn verify_root_sampled(&self) -> Result<&'static str> {
+        self.ensure_root_hashes()?;
+
+        let sample = self
+            .db
+            .sample_root_paths(self.hasher, &random_stems(VERIFY_SAMPLE_STEMS));
+        let stale_stems = match &sample {
+            Ok(sample)
+                if sample.stale_stems.is_empty()
+                    && sample.stale_nodes.is_empty()
+                    && sample.root == self.last_root =>
+            {
+                info!(
+                    samples = VERIFY_SAMPLE_STEMS,
+                    "Sampled root verification passed"
+                );
+                return Ok("passed");
+            }
+            Ok(sample) => {
+                warn!(
+                    samples = VERIFY_SAMPLE_STEMS,
+                    stale_stems = sample.stale_stems.len(),
+                    stale_nodes = sample.stale_nodes.len(),
+                    root = %sample.root,
+                    "Sampled root verification failed; falling back to full verification"
+                );
+                !sample.stale_stems.is_empty()
+            }
+            Err(e) => {
+                warn!(
+                    samples = VERIFY_SAMPLE_STEMS,
+                    error = %e,
+                    "Sampled root verification failed; falling back to full verification"
+                );
+                true
+            }
+        };
+
+        if stale_stems {
+            self.with_map_growth(|db| db.invalidate_stem_hashes())?;
+        } else {
+            self.with_map_growth(|db| db.invalidate_internal_nodes())?;
+        }
+        self.verify_root_full()?;
+        Ok("fallback")
+    }
+
+    /// Recompute the root from every entry on a separate thread, over a read-only
+    /// handle and one read snapshot, while blocks are processed.
+    ///
+    /// A mismatch is logged as an error and marks the node unhealthy
+    /// (`ubt_exex_healthy`); processing continues.
+    fn spawn_root_verification(&mut self) -> Result<()> {
+        self.ensure_root_hashes()?;
+
+        let ubt_dir = self.data_dir.join(UBT_DATA_DIR);
+        let hasher = self.hasher;
+        let handle = std::thread::Builder::new()
+            .name("ubt-verify".to_string())
+            .spawn(move || verify_root_in_background(&ubt_dir, hasher))?;
+        self.background_verification = Some(handle);
+        info!("Root verification running in the background");
+        Ok(())
+    }
+
+    /// Make sure the stem and internal node hashes are valid without verifying the
+    /// root otherwise, rebuilding them if either is missing.
+    ///
+    /// Nodes are rebuilt from stem hashes that are still marked valid; if those
+    /// give the wrong root, the stems are rehashed and the nodes rebuilt again.
+    fn ensure_root_hashes(&self) -> Result<()> {
+        let stem_hashes_valid = self.db.stem_hashes_valid()?;
+        if stem_hashes_valid && self.db.internal_nodes_valid()? {
+            return Ok(());
+        }
+
+        info!("Stem or internal node hashes missing; rebuilding, which also verifies the root");
+        let mut root = self.rebuild_internal_nodes()?;
+        if root != self.last_root && stem_hashes_valid {
+            warn!("Stored stem hashes do not match the stems; rebuilding");
+            self.with_map_growth(|db| db.rebuild_stem_hashes())?;
+            root = self.rebuild_internal_nodes()?;
+        }
+        self.verify_root(root)?;
+        info!("Root verification passed");
+        Ok(())
+    }
+
+    /// Check the stored stem hashes against `computed`, the verified root, and
+    /// rebuild them if they are missing or do not match.
+    fn ensure_stem_hashes(&self, computed: B256) -> Result<()> {
+        if self.db.stem_hashes_valid()? {
+            match self.db.compute_root_from_stem_hashes(self.hasher, &[]) {
+                Ok(stored) if stored == computed => return Ok(()),
+                result => warn!(
+                    error = ?result.err(),
+                    "Stored stem hashes do not match the stems; rebuilding"
+                ),
+            }
+        }
+
+        info!("Hashing stems from MDBX into the stem hash cache");
+        let start = Instant::now();
+        let stems = self.with_map_growth(|db| db.rebuild_stem_hashes())?;
+        let rebuilt = self.db.compute_root_from_stem_hashes(self.hasher, &[])?;
+        info!(
+            stems,
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            "Stem hashes rebuilt"
+        );
+        self.verify_root(rebuilt)
+    }
+
+    /// Check the stored internal node hashes against `computed`, the verified root,
+    /// and rebuild them if they are missing or do not match.
+    fn ensure_internal_nodes(&self, computed: B256) -> Result<()> {
+        if self.db.internal_nodes_valid()? {
+            match self.db.compute_root_incremental(self.hasher, &[]) {
+                Ok((stored, _)) if stored == computed => return Ok(()),
+                result => warn!(
+                    error = ?result.err(),
+                    "Stored internal node hashes do not match the stems; rebuilding"
+                ),
+            }
+        }
 
+        info!("Building internal node hashes from the stem hashes");
+        let rebuilt = self.rebuild_internal_nodes()?;
+        self.verify_root(rebuilt)?;
+        info!("Root verification passed");
+        Ok(())
+    }
+
+    /// Rebuild the internal node hashes from MDBX and return the root.
+    fn rebuild_internal_nodes(&self) -> Result<B256> {
+        let start = Instant::now();
+        let hasher = self.hasher;
+        let root = self.with_map_growth(|db| db.rebuild_internal_nodes(hasher))?;
         info!(
-            block = self.last_block,
             root = %root,
-            "UBT ExEx shutdown complete"
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            "Internal node hashes rebuilt"
         );
+        Ok(root)
+    }
+
+    /// Fail with `RootVerificationFailed` unless `computed` is the persisted root.
+    fn verify_root(&self, computed: B256) -> Result<()> {
+        if computed == self.last_root {
+            return Ok(());
+        }
+        Err(crate::error::UbtError::RootVerificationFailed {
+            expected: format!("{}", self.last_root),
+            computed: format!("{}", computed),
+        })
+    }
+
+    /// Static helper to compute root hash from every entry in a database reference.
+    fn compute_root_from_db(
+        db: &UbtDatabase,
+        hasher: TreeHasher,
+        overlay: &[(Stem, StemNode)],
+    ) -> Result<B256> {
+        hasher.try_root_from_sorted_entries(db.iter_entries_with_overlay(overlay)?)
+    }
+
+    /// Drain the dirty overlay, sorted by stem.
+    fn take_dirty_stems(&mut self) -> Vec<(Stem, StemNode)> {
+        let mut dirty: Vec<_> = self.dirty_stems.drain().collect();
+        dirty.sort_unstable_by_key(|(stem, _)| *stem);
+        dirty
+    }
+
+    /// Write `dirty`, `head` and all queued writes to MDBX in one transaction.
+    ///
+    /// The head is recorded in the root history unless its root is pending; the
+    /// `ubt-root` thread records it then.
+    fn flush(&mut self, dirty: Vec<(Stem, StemNode)>, head: &UbtHead) -> Result<()> {
+        let mut writes = std::mem::take(&mut self.pending_writes);
+        writes.update_stems(dirty);
+        writes.save_head(head)?;
+        if !writes.root_pending() {
+            writes.save_root(head);
+        }
+        self.with_map_growth(|db| db.write_batch(&writes))
+    }
+
+    /// Run an MDBX write, raising the map upper bound and retrying whenever it fails
+    /// with `MapFull` under `MapFullPolicy::Grow`. Under `Pause` the error is
+    /// returned for the ExEx task to stop on.
+    fn with_map_growth<T>(&self, mut write: impl FnMut(&UbtDatabase) -> Result<T>) -> Result<T> {
+        loop {
+            match write(&self.db) {
+                Err(e) if e.is_map_full() => {
+                    crate::metrics::record_map_full();
+                    if self.map_full_policy == MapFullPolicy::Pause {
+                        return Err(e);
+                    }
+                    let upper = self.db.grow_map()?;
+                    warn!(
+                        upper,
+                        "MDBX map full; raised the upper bound and retrying the write"
+                    );
+                }
+                result => return result,
+            }
+        }
+    }
 
+    /// Queue the upkeep that rides along with the flush of `block_number`: pruning
+    /// deltas and root history past their retention, and the next step of the
+    /// stem encoding migration.
+    fn queue_maintenance(&mut self, block_number: u64) {
+        if block_number > self.delta_retention {
+            self.pending_writes
+                .prune_deltas_before(block_number - self.delta_retention);
+        }
+        if self.root_retention > 0 && block_number > self.root_retention {
+            self.pending_writes
+                .prune_roots_before(block_number - self.root_retention);
+        }
+        if !self.stem_encoding_migrated {
+            self.pending_writes
+                .migrate_stem_encoding(STEM_MIGRATION_BATCH);
+        }
+    }
+
+    /// `flush`, then move the key index head to `head` and clear the pending-commit
+    /// marker, completing the commit protocol (see `recover_stores`).
+    fn persist(&mut self, dirty: Vec<(Stem, StemNode)>, head: &UbtHead) -> Result<()> {
+        self.flush(dirty, head)?;
+        self.key_index.save_head(
+            head.block_number,
+            head.block_hash,
+            head.root,
+            head.stem_count as u64,
+        )?;
+        if self.commit_pending {
+            self.db.delete_pending_commit()?;
+            self.commit_pending = false;
+        }
         Ok(())
     }
 
-    /// Compute root hash from MDBX entries using streaming builder with parallel hashing.
+    /// `persist` `dirty` at `block_number` with a `PendingRoot` marker, the head
+    /// still holding the last finalized root, and compute the new root on the
+    /// `ubt-root` thread from the flushed state.
     ///
-    /// This reads all entries from MDBX and computes the root without
-    /// keeping the full tree in memory. Uses rayon for parallel stem hashing.
-    /// Note: still creates a Vec of all entries, so memory spikes during computation.
-    pub(crate) fn compute_root_streaming(&self) -> Result<B256> {
-        Self::compute_root_from_db(&self.db)
+    /// The thread records the root in the head and removes the marker, in one
+    /// transaction; `finish_root` then takes it as the persisted root. The flush
+    /// leaves the hashes of `dirty` to the thread, which writes them with the root;
+    /// while the stored internal nodes were valid before the flush, they are
+    /// updated along the paths above `dirty` as in a synchronous flush.
+    fn persist_pending_root(
+        &mut self,
+        dirty: Vec<(Stem, StemNode)>,
+        block_number: u64,
+        block_hash: B256,
+    ) -> Result<()> {
+        let pending = PendingRoot {
+            block_number,
+            block_hash,
+            finalized: self.db.load_head()?,
+        };
+        let incremental = self.db.internal_nodes_valid()?;
+        let flushed = dirty.clone();
+        let head = UbtHead {
+            block_number,
+            block_hash,
+            root: self.last_root,
+            stem_count: self.stem_count,
+        };
+        self.pending_writes.save_pending_root(&pending)?;
+        self.persist(dirty, &head)?;
+
+        let db = Arc::clone(&self.db);
+        let hasher = self.hasher;
+        let task_pending = pending.clone();
+        let handle = std::thread::Builder::new()
+            .name("ubt-root".to_string())
+            .spawn(move || {
+                compute_pending_root(&db, hasher, &task_pending, &flushed, incremental)
+            })?;
+        self.root_task = Some(RootTask {
+            pending,
+            stem_count: self.stem_count,
+            handle,
+        });
+        crate::metrics::record_root_pending(true);
+        Ok(())
     }
 
-    /// Static helper to compute root hash from a database reference.
+    /// Wait for the root of the last flush if it is still being computed, and take
+    /// it as the persisted root, moving the key index head to it.
     ///
-    /// Used both during initialization (before `self` exists) and via
-    /// `compute_root_streaming` for instance calls.
-    fn compute_root_from_db(db: &UbtDatabase) -> Result<B256> {
-        let entries = db.iter_entries_sorted()?;
-        let root = StreamingTreeBuilder::<Blake3Hasher>::new().build_root_hash_parallel(entries);
+    /// If the `ubt-root` thread failed, the root is computed and recorded here.
+    fn finish_root(&mut self) -> Result<()> {
+        let Some(task) = self.root_task.take() else {
+            return Ok(());
+        };
+        let block = task.pending.block_number;
+        let root = match task.handle.join() {
+            Ok(Ok(root)) => root,
+            Ok(Err(e)) => {
+                warn!(block, error = %e, "Background root computation failed; computing it here");
+                self.finalize_pending_root(&task.pending)?
+            }
+            Err(_) => {
+                warn!(
+                    block,
+                    "Background root computation panicked; computing it here"
+                );
+                self.finalize_pending_root(&task.pending)?
+            }
+        };
+
+        self.key_index
+            .save_head(block, task.pending.block_hash, root, task.stem_count as u64)?;
+        self.last_root = root;
+        crate::metrics::record_root_pending(false);
+        debug!(block, root = %root, "Root of the flush recorded");
+        Ok(())
+    }
+
+    /// Compute the root of the state flushed with `pending` and record it in the
+    /// head, leaving the stem hashes it deferred and the internal node hashes
+    /// invalid.
+    fn finalize_pending_root(&self, pending: &PendingRoot) -> Result<B256> {
+        let start = Instant::now();
+        let root = compute_flushed_root(&self.db, self.hasher, None)?;
+        crate::metrics::record_root_computation(start.elapsed().as_secs_f64());
+        self.with_map_growth(|db| db.finalize_root(pending, root, None, None))?;
         Ok(root)
     }
 
@@ -599,7 +1869,13 @@ impl UbtExEx {
     /// This is the core logic shared by revert operations. Given a list of deltas
     /// (stem, subindex, old_value), applies them in reverse order to restore
     /// previous values.
+    ///
+    /// A zero old value means the leaf did not exist before the block, so it is
+    /// deleted. The key index is brought in line with the restored leaves, so later
+    /// wipes and NOMT exports see the same set of keys as MDBX.
     pub(crate) fn apply_deltas_reverse(&mut self, deltas: &[(Stem, u8, B256)]) -> Result<()> {
+        let mut index_state: HashMap<TreeKey, bool> = HashMap::new();
+
         for (stem, subindex, old_value) in deltas.iter().rev() {
             if !self.dirty_stems.contains_key(stem) {
                 if let Some(existing) = self.db.load_stem(stem)? {
@@ -609,8 +1885,43 @@ impl UbtExEx {
                 }
             }
             let stem_node = self.dirty_stems.get_mut(stem).expect("just inserted");
-            stem_node.set_value(*subindex, *old_value);
+            if *old_value == B256::ZERO {
+                stem_node.values.remove(subindex);
+            } else {
+                stem_node.set_value(*subindex, *old_value);
+            }
+            index_state.insert(TreeKey::new(*stem, *subindex), *old_value != B256::ZERO);
+        }
+
+        let mut index_updates = Vec::new();
+        let mut index_deletions = Vec::new();
+        let mut addresses: HashMap<Stem, Option<Address>> = HashMap::new();
+        for (key, present) in index_state {
+            if !present {
+                index_deletions.push((key.stem, key.subindex));
+                continue;
+            }
+            let address = match addresses.get(&key.stem) {
+                Some(address) => *address,
+                None => {
+                    let address = match self.pending_writes.stem_address(&key.stem) {
+                        Some(address) => Some(address),
+                        None => self.db.load_stem_address(&key.stem)?,
+                    };
+                    addresses.insert(key.stem, address);
+                    address
+                }
+            };
+            match address {
+                Some(address) => index_updates.push((key.stem, key.subindex, address)),
+                None => {
+                    warn!(stem = ?key.stem, "Missing stem address while restoring key index entries");
+                }
+            }
         }
+        let new_stems = self.key_index.apply_updates(index_updates)?;
+        let removed_stems = self.key_index.clear_entries(index_deletions)?;
+        self.stem_count = (self.stem_count + new_stems).saturating_sub(removed_stems);
 
         Ok(())
     }
@@ -621,18 +1932,293 @@ const KECCAK_EMPTY: B256 = B256::new([
     0xe5, 0x00, 0xb6, 0x53, 0xca, 0x82, 0x27, 0x3b, 0x7b, 0xfa, 0xd8, 0x04, 0x5d, 0x85, 0xa4, 0x70,
 ]);
 
-fn balance_to_u128(balance: U256) -> u128 {
-    if balance > U256::from(u128::MAX) {
-        u128::MAX
-    } else {
-        balance.to::<u128>()
+/// State changes that create the genesis allocation, as block 0.
+pub fn genesis_block_changes(
+    genesis_hash: B256,
+    alloc: &BTreeMap<Address, GenesisAccount>,
+) -> BlockChanges {
+    let accounts = alloc
+        .iter()
+        .map(|(address, account)| {
+            let code = account.code.clone().filter(|code| !code.is_empty());
+            let code_hash = code.as_ref().map(keccak256).unwrap_or(KECCAK_EMPTY);
+            let storage = account
+                .storage
+                .iter()
+                .flatten()
+                .map(|(slot, value)| (U256::from_be_bytes(slot.0), U256::from_be_bytes(value.0)))
+                .collect();
+
+            AccountChange {
+                address: *address,
+                info: Some(AccountState {
+                    nonce: account.nonce.unwrap_or_default(),
+                    balance: account.balance,
+                    code_hash,
+                    code,
+                }),
+                storage_wiped: false,
+                storage,
+            }
+        })
+        .collect();
+
+    BlockChanges {
+        block_number: 0,
+        block_hash: genesis_hash,
+        accounts,
     }
 }
 
-fn u256_to_b256(value: U256) -> B256 {
+/// Split a committed chain into per-block state changes, in ascending block order.
+///
+/// The chain's `ExecutionOutcome` holds the bundle state at the tip plus one revert
+/// set per block. Walking back from the tip with `revert_to`, the last remaining
+/// revert set names exactly the accounts and slots changed by that block, and the
+/// bundle state at that point holds their post-block values. The outcome is cloned
+/// once and reverted in place, so the walk is linear in the size of the chain.
+pub fn chain_block_changes<N: NodePrimitives>(chain: &Chain<N>) -> Vec<BlockChanges> {
+    let mut outcome = chain.execution_outcome().clone();
+    let mut per_block = Vec::with_capacity(chain.blocks().len());
+
+    for (&block_number, block) in chain.blocks().iter().rev() {
+        if !outcome.revert_to(block_number) {
+            warn!(
+                block = block_number,
+                "Block missing from chain execution outcome; skipping state extraction"
+            );
+            continue;
+        }
+
+        let bundle = outcome.state();
+        let mut accounts = Vec::new();
+
+        if let Some(block_reverts) = bundle.reverts.last() {
+            for (address, revert) in block_reverts {
+                let Some(account) = bundle.state.get(address) else {
+                    continue;
+                };
+
+                let info = account.info.as_ref().map(|info| AccountState {
+                    nonce: info.nonce,
+                    balance: info.balance,
+                    code_hash: info.code_hash,
+                    code: info
+                        .code
+                        .as_ref()
+                        .or_else(|| bundle.contracts.get(&info.code_hash))
+                        .map(|c| c.original_bytes()),
+                });
+
+                let mut storage: Vec<(U256, U256)> = revert
+                    .storage
+                    .keys()
+                    .map(|slot| {
+                        let value = account
+                            .storage
+                            .get(slot)
+                            .map(|s| s.present_value)
+                            .unwrap_or_default();
+                        (*slot, value)
+                    })
+                    .collect();
+                storage.sort_unstable_by_key(|(slot, _)| *slot);
+
+                accounts.push(AccountChange {
+                    address: *address,
+                    storage_wiped: revert.wipe_storage || info.is_none(),
+                    info,
+                    storage,
+                });
+            }
+        }
+
+        accounts.sort_unstable_by_key(|change| change.address);
+        per_block.push(BlockChanges {
+            block_number,
+            block_hash: block.hash(),
+            accounts,
+        });
+    }
+
+    per_block.reverse();
+    per_block
+}
+
+/// Code size stored in an encoded basic-data leaf.
+///
+/// EIP-7864 layout: version (1 byte), reserved (4 bytes), code size (3 bytes,
+/// big-endian), nonce (8 bytes), balance (16 bytes).
+fn basic_data_code_size(value: B256) -> u32 {
+    u32::from_be_bytes([0, value[5], value[6], value[7]])
+}
+
+/// Balance stored in an encoded basic-data leaf (see `basic_data_code_size`).
+fn basic_data_balance(value: B256) -> u128 {
+    u128::from_be_bytes(value[16..].try_into().expect("16-byte balance"))
+}
+
+/// Number of 31-byte code chunks needed for code of the given length.
+fn code_chunk_count(code_len: usize) -> u64 {
+    (code_len as u64).div_ceil(31)
+}
+
+fn u256_to_b256(value: U256) -> B256 {
     B256::from(value.to_be_bytes::<32>())
 }
 
+/// `count` stems spread at random over the key space.
+fn random_stems(count: usize) -> Vec<Stem> {
+    use std::hash::{BuildHasher, Hasher};
+
+    let state = std::collections::hash_map::RandomState::new();
+    (0..count)
+        .map(|i| {
+            let mut bytes = [0u8; STEM_LEN];
+            for (j, chunk) in bytes.chunks_mut(8).enumerate() {
+                let mut hasher = state.build_hasher();
+                hasher.write_usize(i);
+                hasher.write_usize(j);
+                chunk.copy_from_slice(&hasher.finish().to_be_bytes()[..chunk.len()]);
+            }
+            Stem::new(bytes)
+        })
+        .collect()
+}
+
+/// Read the heads of the MDBX, NOMT and key index copies in the backup at `dest`,
+/// returning the MDBX head and the NOMT and key index blocks once they agree.
+fn read_backup_heads(dest: &Path, hasher: TreeHasher) -> Result<(UbtHead, u64, u64)> {
+    let head = UbtDatabase::open_read_only(&dest.join(UBT_DATA_DIR))?
+        .load_head()?
+        .ok_or_else(|| UbtError::Backup {
+            message: "MDBX copy has no head".to_string(),
+        })?;
+    let nomt_head = NomtDb::open(&dest.join(NOMT_DATA_DIR), hasher)?.head()?;
+    let key_index_head = KeyIndex::open(dest.join(KEY_INDEX_FILE))?
+        .load_head()?
+        .map(|head| head.block_number);
+
+    match (nomt_head, key_index_head) {
+        (Some(nomt_head), Some(key_index_head))
+            if nomt_head == head.block_number && key_index_head == head.block_number =>
+        {
+            Ok((head, nomt_head, key_index_head))
+        }
+        _ => Err(UbtError::Backup {
+            message: format!(
+                "copies disagree: MDBX at block {}, NOMT at {nomt_head:?}, key index at {key_index_head:?}",
+                head.block_number
+            ),
+        }),
+    }
+}
+
+/// Recompute the root from every entry in one read snapshot of a read-only handle
+/// on `ubt_dir`, and check it against the head stored in that snapshot.
+///
+/// Logs and records the outcome; a mismatch marks the node unhealthy.
+fn verify_root_in_background(ubt_dir: &Path, hasher: TreeHasher) -> Result<bool> {
+    let start = Instant::now();
+    let result = UbtDatabase::open_read_only(ubt_dir).and_then(|db| {
+        let (head, entries) = db.iter_entries_at_head()?;
+        Ok((head, hasher.try_root_from_sorted_entries(entries)?))
+    });
+    let elapsed = start.elapsed();
+    let (mode, secs) = (VerifyMode::Background.as_str(), elapsed.as_secs_f64());
+
+    let (head, computed) = match result {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            error!(error = %e, "Background root verification could not complete");
+            crate::metrics::record_startup_verification(mode, "error", secs);
+            return Err(e);
+        }
+    };
+    let expected = head.as_ref().map_or(B256::ZERO, |head| head.root);
+    let block = head.as_ref().map(|head| head.block_number);
+    if computed == expected {
+        info!(
+            block,
+            root = %computed,
+            elapsed_ms = elapsed.as_millis() as u64,
+            "Background root verification passed"
+        );
+        crate::metrics::record_startup_verification(mode, "passed", secs);
+        return Ok(true);
+    }
+
+    error!(
+        block,
+        expected = %expected,
+        computed = %computed,
+        "Background root verification failed; UBT state does not match its root"
+    );
+    crate::metrics::record_startup_verification(mode, "failed", secs);
+    crate::metrics::record_healthy(false);
+    Ok(false)
+}
+
+/// Compute the root of the state flushed with `pending` and record it in the head,
+/// on the `ubt-root` thread.
+///
+/// The hashes of `flushed`, the stems of the flush, are computed here and
+/// recorded with the root. With `incremental`, the stored internal nodes are only
+/// rehashed along the paths above them and their updates recorded too.
+fn compute_pending_root(
+    db: &UbtDatabase,
+    hasher: TreeHasher,
+    pending: &PendingRoot,
+    flushed: &[(Stem, StemNode)],
+    incremental: bool,
+) -> Result<B256> {
+    let start = Instant::now();
+    let stem_hashes = hasher.hash_stems(flushed);
+    if incremental {
+        match db.compute_root_incremental(hasher, &stem_hashes) {
+            Ok((root, updates)) => {
+                crate::metrics::record_root_computation(start.elapsed().as_secs_f64());
+                crate::metrics::record_root_mode("incremental");
+                db.finalize_root(pending, root, Some(&stem_hashes), Some(&updates))?;
+                return Ok(root);
+            }
+            Err(e) => {
+                warn!(error = %e, "Incremental root computation failed; falling back to streaming");
+            }
+        }
+    }
+
+    let root = compute_flushed_root(db, hasher, Some(&stem_hashes))?;
+    crate::metrics::record_root_computation(start.elapsed().as_secs_f64());
+    db.finalize_root(pending, root, Some(&stem_hashes), None)?;
+    Ok(root)
+}
+
+/// Root of the state stored in MDBX, from the stored stem hashes or, while those
+/// are invalid, from every entry.
+///
+/// `deferred` are the hashes of the flushed stems when the flush left them
+/// pending; without them, pending stem hashes are not used.
+fn compute_flushed_root(
+    db: &UbtDatabase,
+    hasher: TreeHasher,
+    deferred: Option<&[(Stem, Option<B256>)]>,
+) -> Result<B256> {
+    let overlay = if db.stem_hashes_valid()? {
+        Some(&[][..])
+    } else if db.stem_hashes_pending()? {
+        deferred
+    } else {
+        None
+    };
+    if let Some(overlay) = overlay {
+        crate::metrics::record_root_mode("stem_hashes");
+        return db.compute_root_from_stem_hashes(hasher, overlay);
+    }
+    crate::metrics::record_root_mode("streaming");
+    UbtExEx::compute_root_from_db(db, hasher, &[])
+}
+
 /// Main entry point for the UBT ExEx.
 ///
 /// Configuration precedence: environment variables > defaults.
@@ -652,8 +2238,39 @@ pub async fn ubt_exex<Node: FullNodeComponents>(mut ctx: ExExContext<Node>) -> e
 
     let mut ubt = UbtExEx::new(&config)?;
 
+    let provider = ctx.provider().clone();
+    ubt.set_code_resolver(Box::new(move || {
+        let state = provider.latest().map_err(|e| UbtError::StateExtraction {
+            message: format!("failed to open latest state provider: {e}"),
+        })?;
+        let lookup: CodeLookup = Box::new(move |code_hash| {
+            state
+                .bytecode_by_hash(&code_hash)
+                .map(|code| code.map(|code| code.original_bytes()))
+                .map_err(|e| UbtError::StateExtraction {
+                    message: format!("failed to load bytecode {code_hash}: {e}"),
+                })
+        });
+        Ok(lookup)
+    }));
+
+    if ubt.get_head().is_none() {
+        // A node past genesis already has state no notification will replay, so
+        // build the tree from its plain state instead of the genesis allocation.
+        let source = ProviderStateSource::open(ctx.provider())?;
+        if ubt.bootstrap_in_progress()? || source.state_block()?.number > 0 {
+            ubt.bootstrap_from_state(&source, DEFAULT_BOOTSTRAP_BATCH)?;
+        } else {
+            let chain = &ctx.config.chain;
+            ubt.seed_genesis(chain.genesis_hash(), &chain.genesis().alloc)?;
+        }
+    }
+
     info!("UBT ExEx started with MDBX persistence");
 
+    // Backups requested over RPC run on this task, between notifications.
+    let (backup_tx, mut backup_rx) = tokio::sync::mpsc::channel::<BackupRequest>(1);
+
     let rpc_config = RpcServerConfig {
         http_addr: config.get_rpc_http_addr(),
         ipc_path: config.get_rpc_ipc_path(),
@@ -672,7 +2289,10 @@ pub async fn ubt_exex<Node: FullNodeComponents>(mut ctx: ExExContext<Node>) -> e
             config.get_delta_retention(),
         ) {
             Ok(rpc) => {
-                if let Err(err) = start_rpc_servers(ctx.task_executor().clone(), rpc, rpc_config).await {
+                let rpc = rpc.with_backup_sender(backup_tx);
+                if let Err(err) =
+                    start_rpc_servers(ctx.task_executor().clone(), rpc, rpc_config).await
+                {
                     warn!(error = %err, "Failed to start UBT RPC servers");
                 }
             }
@@ -690,40 +2310,62 @@ pub async fn ubt_exex<Node: FullNodeComponents>(mut ctx: ExExContext<Node>) -> e
         );
         ctx.notifications.set_with_head(head);
     } else {
-        info!("No persisted head, starting fresh (will backfill from genesis)");
+        info!("No persisted head, starting fresh");
     }
 
+    // Set when a write hits a full map under `MapFullPolicy::Pause`. The failed
+    // flush has already consumed the in-memory overlay, so nothing more is
+    // processed or flushed; restart recovery resumes from the MDBX head.
+    let mut paused = false;
+    crate::metrics::record_paused(false);
+
     loop {
         tokio::select! {
-            notification = ctx.notifications.try_next() => {
+            notification = ctx.notifications.try_next(), if !paused => {
                 match notification? {
                     Some(notification) => {
-                        match &notification {
+                        let result = match &notification {
                             ExExNotification::ChainCommitted { new } => {
                                 let tip = new.tip();
-                                let tip_number = tip.number();
-                                let tip_hash = tip.hash();
-                                debug!(block = tip_number, hash = %tip_hash, "Processing committed chain");
-
-                                ubt.process_chain(new.as_ref())?;
-                                ubt.commit(tip_number, tip_hash)?;
+                                debug!(
+                                    first = new.first().number(),
+                                    tip = tip.number(),
+                                    hash = %tip.hash(),
+                                    "Processing committed chain"
+                                );
+
+                                ubt.process_chain(new.as_ref()).map(|_| ())
                             }
                             ExExNotification::ChainReorged { old, new } => {
                                 let old_tip = old.tip().number();
                                 let new_tip = new.tip().number();
                                 info!(from = old_tip, to = new_tip, "Handling reorg");
 
-                                ubt.revert(old.as_ref())?;
-                                ubt.process_chain(new.as_ref())?;
-                                ubt.commit(new.tip().number(), new.tip().hash())?;
+                                ubt.revert(old.as_ref())
+                                    .and_then(|()| ubt.process_chain(new.as_ref()).map(|_| ()))
                             }
                             ExExNotification::ChainReverted { old } => {
                                 let old_tip = old.tip().number();
                                 info!(block = old_tip, "Handling revert");
-                                ubt.revert(old.as_ref())?;
+                                ubt.revert(old.as_ref())
                             }
                         };
 
+                        match result {
+                            Ok(()) => {}
+                            Err(err) if err.is_map_full() => {
+                                error!(
+                                    error = %err,
+                                    "UBT processing paused: MDBX map is full. Raise UBT_MDBX_MAX_SIZE \
+                                     and restart the node to resume from the last flushed block"
+                                );
+                                crate::metrics::record_paused(true);
+                                paused = true;
+                                continue;
+                            }
+                            Err(err) => return Err(err.into()),
+                        }
+
                         if let Some(committed_chain) = notification.committed_chain() {
                             ctx.events
                                 .send(ExExEvent::FinishedHeight(committed_chain.tip().num_hash()))?;
@@ -735,14 +2377,33 @@ pub async fn ubt_exex<Node: FullNodeComponents>(mut ctx: ExExContext<Node>) -> e
                     }
                 }
             }
+            Some(request) = backup_rx.recv() => {
+                info!(path = %request.dest.display(), compact = request.compact, "Starting UBT backup");
+                let result = if paused {
+                    Err(UbtError::Backup {
+                        message: "processing is paused on a full MDBX map".to_string(),
+                    })
+                } else {
+                    // Copying the stores blocks; keep other tasks on this worker running.
+                    tokio::task::block_in_place(|| ubt.backup(&request.dest, request.compact))
+                };
+                if let Err(err) = &result {
+                    warn!(path = %request.dest.display(), error = %err, "UBT backup failed");
+                }
+                let _ = request.reply.send(result);
+            }
             _ = tokio::signal::ctrl_c() => {
                 info!("Received shutdown signal (SIGINT)");
-                ubt.shutdown()?;
+                if !paused {
+                    ubt.shutdown()?;
+                }
                 break;
             }
             _ = sigterm_recv() => {
                 info!("Received shutdown signal (SIGTERM)");
-                ubt.shutdown()?;
+                if !paused {
+                    ubt.shutdown()?;
+                }
                 break;
             }
         }
@@ -775,7 +2436,10 @@ fn tree_index_from_key(stem: &Stem, subindex: u8) -> [u8; 32] {
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use crate::bootstrap::tests::PlainState;
     use crate::config::UbtConfig;
+    use reth_provider::{test_utils::MockNodeTypesWithDB, ProviderFactory};
+    use std::sync::atomic::{AtomicUsize, Ordering};
     use tempfile::TempDir;
 
     /// Test harness for property-based testing of UbtExEx.
@@ -815,7 +2479,7 @@ pub mod tests {
                 .pending_entries
                 .extend(entries.into_iter().map(|(key, value)| PendingEntry {
                     key,
-                    value,
+                    value: Some(value),
                     address: Address::ZERO,
                 }));
             self.exex
@@ -830,6 +2494,7 @@ pub mod tests {
             self.exex
                 .db
                 .iter_entries_sorted()
+                .and_then(|entries| entries.collect())
                 .expect("iter_entries_sorted failed")
         }
 
@@ -892,4 +2557,1309 @@ pub mod tests {
         let root_after_revert = harness.snapshot_root();
         assert_eq!(root_after_block1, root_after_revert);
     }
+
+    #[test]
+    fn test_deltas_are_written_with_the_flush() {
+        let mut harness = TestHarness::new();
+        harness.exex.flush_interval = 3;
+
+        let stem = Stem::new([3u8; 31]);
+        let key = TreeKey::new(stem, 0);
+        for n in 1..=2u64 {
+            harness.apply_entries_block(
+                n,
+                B256::repeat_byte(n as u8),
+                vec![(key, B256::repeat_byte(n as u8))],
+            );
+        }
+
+        // Nothing reaches MDBX before the flush, not even deltas or stem addresses.
+        assert!(harness.exex.db.load_head().unwrap().is_none());
+        assert!(harness.exex.db.load_block_deltas(1).unwrap().is_empty());
+        assert!(harness.exex.db.load_stem_address(&stem).unwrap().is_none());
+        assert!(harness.snapshot_entries().is_empty());
+
+        let root =
+            harness.apply_entries_block(3, B256::repeat_byte(3), vec![(key, B256::repeat_byte(3))]);
+
+        let head = harness.exex.db.load_head().unwrap().unwrap();
+        assert_eq!(head.block_number, 3);
+        assert_eq!(head.root, root);
+        assert_eq!(root, harness.snapshot_root());
+        for n in 1..=3u64 {
+            assert_eq!(harness.exex.db.load_block_deltas(n).unwrap().len(), 1);
+        }
+        assert_eq!(
+            harness.exex.db.load_stem_address(&stem).unwrap(),
+            Some(Address::ZERO)
+        );
+    }
+
+    fn open_exex(dir: &TempDir) -> Result<UbtExEx> {
+        UbtExEx::new(&UbtConfig::for_tests(dir.path().to_path_buf()))
+    }
+
+    #[test]
+    fn test_interrupted_commit_rolls_back_to_mdbx_head() {
+        let temp_dir = TempDir::new().unwrap();
+        let key1 = TreeKey::new(Stem::new([1u8; 31]), 0);
+        let key2 = TreeKey::new(Stem::new([2u8; 31]), 0);
+
+        let root1 = {
+            let mut exex = open_exex(&temp_dir).unwrap();
+            exex.pending_entries.push(PendingEntry {
+                key: key1,
+                value: Some(B256::repeat_byte(0x01)),
+                address: Address::ZERO,
+            });
+            let root1 = exex.commit(1, B256::repeat_byte(0x01)).unwrap();
+
+            // Block 2 reaches NOMT and the key index but not MDBX.
+            exex.flush_interval = 10;
+            exex.pending_entries.push(PendingEntry {
+                key: key2,
+                value: Some(B256::repeat_byte(0x02)),
+                address: Address::ZERO,
+            });
+            exex.commit(2, B256::repeat_byte(0x02)).unwrap();
+            assert_eq!(exex.nomt.head().unwrap(), Some(2));
+            assert_eq!(exex.key_index.stem_count().unwrap(), 2);
+            assert_eq!(
+                exex.db.load_pending_commit().unwrap(),
+                Some(PendingCommit::Commit)
+            );
+            root1
+        };
+
+        let exex = open_exex(&temp_dir).unwrap();
+        assert_eq!(exex.get_head().unwrap().block.number, 1);
+        assert_eq!(exex.last_root, root1);
+        assert_eq!(exex.nomt.head().unwrap(), Some(1));
+        assert_eq!(
+            exex.nomt.read(tree_index_from_key(&key2.stem, 0)).unwrap(),
+            None
+        );
+        assert_eq!(exex.key_index.stem_count().unwrap(), 1);
+        assert_eq!(exex.key_index.load_head().unwrap().unwrap().block_number, 1);
+        assert_eq!(exex.db.load_pending_commit().unwrap(), None);
+    }
+
+    #[test]
+    fn test_interrupted_revert_is_finished_in_mdbx() {
+        let temp_dir = TempDir::new().unwrap();
+        let key = TreeKey::new(Stem::new([1u8; 31]), 0);
+
+        let root1 = {
+            let mut exex = open_exex(&temp_dir).unwrap();
+            let mut root1 = B256::ZERO;
+            for n in 1..=2u8 {
+                exex.pending_entries.push(PendingEntry {
+                    key,
+                    value: Some(B256::repeat_byte(n)),
+                    address: Address::ZERO,
+                });
+                let root = exex.commit(n as u64, B256::repeat_byte(n)).unwrap();
+                if n == 1 {
+                    root1 = root;
+                }
+            }
+
+            exex.db
+                .save_balance_overflow(&BalanceOverflow {
+                    address: Address::ZERO,
+                    block_number: 2,
+                    balance: U256::MAX,
+                })
+                .unwrap();
+
+            // The revert to block 1 reached NOMT only.
+            exex.db
+                .save_pending_commit(&PendingCommit::Revert {
+                    block_number: 1,
+                    block_hash: B256::repeat_byte(1),
+                })
+                .unwrap();
+            exex.nomt.rollback(1).unwrap();
+            root1
+        };
+
+        let exex = open_exex(&temp_dir).unwrap();
+        let head = exex.db.load_head().unwrap().unwrap();
+        assert_eq!(head.block_number, 1);
+        assert_eq!(head.block_hash, B256::repeat_byte(1));
+        assert_eq!(head.root, root1);
+        assert_eq!(exex.get_value(&key).unwrap(), Some(B256::repeat_byte(1)));
+        assert_eq!(exex.nomt.head().unwrap(), Some(1));
+        assert_eq!(exex.key_index.load_head().unwrap().unwrap().block_number, 1);
+        assert_eq!(exex.db.load_root_at(1).unwrap().unwrap().root, root1);
+        assert!(exex.db.load_root_at(2).unwrap().is_none());
+        assert!(exex.db.load_balance_overflows().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_root_history_follows_flushes() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut config = UbtConfig::for_tests(temp_dir.path().to_path_buf());
+        config.root_retention = 2;
+        let mut exex = UbtExEx::new(&config).unwrap();
+
+        let mut roots = vec![B256::ZERO];
+        for block in 1..=4u8 {
+            exex.pending_entries.push(PendingEntry {
+                key: TreeKey::new(Stem::new([block; 31]), 0),
+                value: Some(B256::repeat_byte(block)),
+                address: Address::ZERO,
+            });
+            roots.push(exex.commit(block as u64, B256::repeat_byte(block)).unwrap());
+        }
+
+        // Blocks older than the retention window are pruned after each commit.
+        assert!(exex.db.load_root_at(1).unwrap().is_none());
+        for block in 2..=4u8 {
+            let by_number = exex.db.load_root_at(block as u64).unwrap().unwrap();
+            assert_eq!(by_number.block_hash, B256::repeat_byte(block));
+            assert_eq!(by_number.root, roots[block as usize]);
+            assert_eq!(by_number.stem_count, block as usize);
+            let by_hash = exex
+                .db
+                .load_root_by_hash(B256::repeat_byte(block))
+                .unwrap()
+                .unwrap();
+            assert_eq!(by_hash.block_number, block as u64);
+            assert_eq!(by_hash.root, by_number.root);
+        }
+        assert!(exex
+            .db
+            .load_root_by_hash(B256::repeat_byte(1))
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn test_nomt_behind_mdbx_refuses_to_start() {
+        let temp_dir = TempDir::new().unwrap();
+        {
+            let mut exex = open_exex(&temp_dir).unwrap();
+            for n in 1..=2u8 {
+                exex.pending_entries.push(PendingEntry {
+                    key: TreeKey::new(Stem::new([n; 31]), 0),
+                    value: Some(B256::repeat_byte(n)),
+                    address: Address::ZERO,
+                });
+                exex.commit(n as u64, B256::repeat_byte(n)).unwrap();
+            }
+            exex.nomt.rollback(1).unwrap();
+        }
+
+        assert!(matches!(
+            open_exex(&temp_dir),
+            Err(UbtError::InconsistentStores { .. })
+        ));
+    }
+
+    /// Give a fresh ExEx an MDBX map too small for `commit_many_stems`.
+    fn shrink_map(exex: &mut UbtExEx, dir: &TempDir) {
+        exex.db = Arc::new(
+            UbtDatabase::open_with_max_size(&dir.path().join("small"), 1024 * 1024).unwrap(),
+        );
+    }
+
+    fn commit_many_stems(exex: &mut UbtExEx, block_number: u64) -> Result<B256> {
+        for i in 0..20_000u32 {
+            let mut stem = [0u8; 31];
+            stem[..4].copy_from_slice(&i.to_be_bytes());
+            exex.pending_entries.push(PendingEntry {
+                key: TreeKey::new(Stem::new(stem), 0),
+                value: Some(B256::repeat_byte(0x01)),
+                address: Address::ZERO,
+            });
+        }
+        exex.commit(block_number, B256::repeat_byte(block_number as u8))
+    }
+
+    #[test]
+    fn test_map_full_grows_and_retries() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut exex = open_exex(&temp_dir).unwrap();
+        shrink_map(&mut exex, &temp_dir);
+
+        let root = commit_many_stems(&mut exex, 1).unwrap();
+        assert_eq!(exex.db.load_head().unwrap().unwrap().root, root);
+        assert_eq!(exex.db.stats().unwrap().tables[0].entries, 20_000);
+        assert!(exex.db.stats().unwrap().map_upper_bytes > 1024 * 1024);
+        assert_eq!(exex.db.load_pending_commit().unwrap(), None);
+    }
+
+    #[test]
+    fn test_bootstrap_grows_full_map() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut exex = open_exex(&temp_dir).unwrap();
+        shrink_map(&mut exex, &temp_dir);
+
+        let state = (0..20_000u32)
+            .map(|i| {
+                let mut address = [0u8; 20];
+                address[16..].copy_from_slice(&i.to_be_bytes());
+                let info = AccountState {
+                    nonce: 1,
+                    balance: U256::from(i),
+                    code_hash: KECCAK_EMPTY,
+                    code: None,
+                };
+                (Address::from(address), (info, BTreeMap::new()))
+            })
+            .collect();
+        let source = test_state_source(
+            BlockNumHash::new(100, B256::repeat_byte(0x64)),
+            &state,
+            None,
+        );
+
+        let root = exex.bootstrap_from_state(&source, 5_000).unwrap();
+        assert_eq!(exex.db.load_head().unwrap().unwrap().root, root);
+        assert!(exex.db.stats().unwrap().map_upper_bytes > 1024 * 1024);
+    }
+
+    #[test]
+    fn test_map_full_pause_returns_typed_error() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut exex = open_exex(&temp_dir).unwrap();
+        exex.map_full_policy = MapFullPolicy::Pause;
+        shrink_map(&mut exex, &temp_dir);
+
+        let err = commit_many_stems(&mut exex, 1).unwrap_err();
+        assert!(err.is_map_full());
+        assert!(exex.db.load_head().unwrap().is_none());
+        assert_eq!(
+            exex.db.load_pending_commit().unwrap(),
+            Some(PendingCommit::Commit)
+        );
+    }
+
+    #[test]
+    fn test_backup_flushes_pending_blocks() {
+        let temp_dir = TempDir::new().unwrap();
+        let backup_dir = TempDir::new().unwrap();
+        let dest = backup_dir.path().join("backup");
+
+        let manifest = {
+            let mut exex = open_exex(&temp_dir).unwrap();
+            exex.flush_interval = 10;
+            for n in 1..=3u8 {
+                exex.pending_entries.push(PendingEntry {
+                    key: TreeKey::new(Stem::new([n; 31]), 0),
+                    value: Some(B256::repeat_byte(n)),
+                    address: Address::ZERO,
+                });
+                exex.commit(n as u64, B256::repeat_byte(n)).unwrap();
+            }
+            assert_eq!(exex.get_head().unwrap().block.number, 1);
+
+            let manifest = exex.backup(&dest, true).unwrap();
+            assert_eq!(manifest.block_number, 3);
+            assert_eq!(manifest.block_hash, B256::repeat_byte(3));
+            assert_eq!(manifest.root, exex.compute_root_streaming().unwrap());
+            assert_eq!(manifest.stem_count, 3);
+            assert_eq!(manifest.nomt_block_number, 3);
+            assert_eq!(manifest.key_index_block_number, 3);
+            assert!(manifest.compacted);
+            assert_eq!(exex.get_head().unwrap().block.number, 3);
+            assert_eq!(exex.db.load_pending_commit().unwrap(), None);
+
+            assert!(exex.backup(&dest, false).is_err());
+
+            // NOMT is reopened after the copy and keeps taking blocks.
+            assert_eq!(exex.nomt.head().unwrap(), Some(3));
+            exex.pending_entries.push(PendingEntry {
+                key: TreeKey::new(Stem::new([4; 31]), 0),
+                value: Some(B256::repeat_byte(4)),
+                address: Address::ZERO,
+            });
+            exex.commit(4, B256::repeat_byte(4)).unwrap();
+            exex.flush_pending().unwrap();
+            assert_eq!(exex.nomt.head().unwrap(), Some(4));
+            manifest
+        };
+        assert_eq!(crate::backup::read_manifest(&dest).unwrap(), manifest);
+
+        let restored = UbtExEx::new(&UbtConfig::for_tests(dest)).unwrap();
+        assert_eq!(restored.get_head().unwrap().block.number, 3);
+        assert_eq!(restored.last_root, manifest.root);
+        assert_eq!(restored.nomt.head().unwrap(), Some(3));
+        assert_eq!(
+            restored
+                .key_index
+                .load_head()
+                .unwrap()
+                .unwrap()
+                .block_number,
+            3
+        );
+        assert_eq!(restored.key_index.stem_count().unwrap(), 3);
+    }
+
+    #[test]
+    fn test_incremental_roots_match_streaming() {
+        let temp_dir = TempDir::new().unwrap();
+        let stem = |first: u8, second: u8| {
+            let mut bytes = [0u8; 31];
+            bytes[0] = first;
+            bytes[1] = second;
+            Stem::new(bytes)
+        };
+        let blocks: Vec<Vec<(TreeKey, B256)>> = vec![
+            vec![
+                (TreeKey::new(stem(0x10, 0x00), 0), B256::repeat_byte(1)),
+                (TreeKey::new(stem(0x10, 0x01), 0), B256::repeat_byte(2)),
+                (TreeKey::new(stem(0x90, 0x00), 5), B256::repeat_byte(3)),
+            ],
+            // A stem between existing ones and another value on an existing stem.
+            vec![
+                (TreeKey::new(stem(0x10, 0x80), 1), B256::repeat_byte(4)),
+                (TreeKey::new(stem(0x90, 0x00), 6), B256::repeat_byte(5)),
+            ],
+            // Deleting stems collapses their internal nodes.
+            vec![
+                (TreeKey::new(stem(0x10, 0x00), 0), B256::ZERO),
+                (TreeKey::new(stem(0x10, 0x01), 0), B256::ZERO),
+            ],
+            vec![(TreeKey::new(stem(0x10, 0x80), 1), B256::ZERO)],
+        ];
+
+        {
+            let mut exex = open_exex(&temp_dir).unwrap();
+            for (n, entries) in blocks.into_iter().enumerate() {
+                let block = n as u64 + 1;
+                exex.pending_entries
+                    .extend(entries.into_iter().map(|(key, value)| PendingEntry {
+                        key,
+                        value: Some(value),
+                        address: Address::ZERO,
+                    }));
+                let root = exex.commit(block, B256::repeat_byte(block as u8)).unwrap();
+                assert_eq!(
+                    root,
+                    exex.compute_root_streaming().unwrap(),
+                    "block {block}"
+                );
+                assert!(exex.db.internal_nodes_valid().unwrap());
+            }
+
+            // Stems written without their internal nodes fall back to the stem
+            // hashes, which every stem write keeps up to date.
+            let extra = stem(0x10, 0x02);
+            let mut node = StemNode::new(extra);
+            node.set_value(0, B256::repeat_byte(6));
+            exex.db
+                .batch_update_stems(&[(extra, node.clone())])
+                .unwrap();
+            assert!(!exex.db.internal_nodes_valid().unwrap());
+            assert!(exex.db.stem_hashes_valid().unwrap());
+
+            exex.pending_entries.push(PendingEntry {
+                key: TreeKey::new(stem(0x20, 0x00), 0),
+                value: Some(B256::repeat_byte(7)),
+                address: Address::ZERO,
+            });
+            let root = exex.commit(5, B256::repeat_byte(5)).unwrap();
+            assert_eq!(root, exex.compute_root_streaming().unwrap());
+            assert!(!exex.db.internal_nodes_valid().unwrap());
+
+            // Rewriting a stem without its hash leaves no valid stem hashes either.
+            exex.db.write_legacy_stems(&[(extra, node)]).unwrap();
+            assert!(!exex.db.stem_hashes_valid().unwrap());
+        }
+
+        // The next startup rebuilds the stem hashes and nodes from MDBX.
+        let mut exex = open_exex(&temp_dir).unwrap();
+        assert!(exex.db.stem_hashes_valid().unwrap());
+        assert!(exex.db.internal_nodes_valid().unwrap());
+        assert_eq!(
+            exex.db
+                .compute_root_incremental(exex.hasher, &[])
+                .unwrap()
+                .0,
+            exex.last_root
+        );
+        exex.pending_entries.push(PendingEntry {
+            key: TreeKey::new(stem(0x20, 0x00), 1),
+            value: Some(B256::repeat_byte(8)),
+            address: Address::ZERO,
+        });
+        let root = exex.commit(6, B256::repeat_byte(6)).unwrap();
+        assert_eq!(root, exex.compute_root_streaming().unwrap());
+    }
+
+    #[test]
+    fn test_startup_verification_modes() {
+        let temp_dir = TempDir::new().unwrap();
+        let open_with = |mode: VerifyMode| {
+            let mut config = UbtConfig::for_tests(temp_dir.path().to_path_buf());
+            config.verify_mode = mode;
+            UbtExEx::new(&config)
+        };
+        let join = |exex: &mut UbtExEx| {
+            exex.background_verification
+                .take()
+                .expect("verification started")
+                .join()
+                .unwrap()
+                .unwrap()
+        };
+
+        let root = {
+            let mut exex = open_with(VerifyMode::Full).unwrap();
+            for block in 1..=3u8 {
+                exex.pending_entries.push(PendingEntry {
+                    key: TreeKey::new(Stem::new([block; 31]), block),
+                    value: Some(B256::repeat_byte(block)),
+                    address: Address::ZERO,
+                });
+                exex.commit(block as u64, B256::repeat_byte(block)).unwrap();
+            }
+            exex.last_root
+        };
+
+        for mode in [VerifyMode::Full, VerifyMode::Sampled, VerifyMode::Skip] {
+            let exex = open_with(mode).unwrap();
+            assert_eq!(exex.last_root, root, "{mode:?}");
+            assert!(exex.background_verification.is_none());
+        }
+        let mut exex = open_with(VerifyMode::Background).unwrap();
+        assert!(join(&mut exex));
+        drop(exex);
+
+        // Missing hashes are rebuilt, and the root verified, even when skipping.
+        {
+            let db = UbtDatabase::open(&temp_dir.path().join(UBT_DATA_DIR)).unwrap();
+            db.invalidate_stem_hashes().unwrap();
+            assert!(!db.stem_hashes_valid().unwrap());
+        }
+        let exex = open_with(VerifyMode::Skip).unwrap();
+        assert!(exex.db.stem_hashes_valid().unwrap());
+        assert!(exex.db.internal_nodes_valid().unwrap());
+        drop(exex);
+
+        // A stale node below the root is on every sampled path, and rebuilt.
+        let below_root = crate::internal_nodes::node_key(&[0u8; 31], 1);
+        {
+            let db = UbtDatabase::open(&temp_dir.path().join(UBT_DATA_DIR)).unwrap();
+            let mut batch = WriteBatch::default();
+            batch.update_internal_nodes([(below_root, Some(B256::repeat_byte(0xdd)))].into());
+            db.write_batch(&batch).unwrap();
+            assert!(db.internal_nodes_valid().unwrap());
+            assert_eq!(
+                db.compute_root_incremental(TreeHasher::default(), &[])
+                    .unwrap()
+                    .0,
+                root
+            );
+        }
+        let exex = open_with(VerifyMode::Sampled).unwrap();
+        assert!(exex.db.internal_nodes_valid().unwrap());
+        let sample = exex
+            .db
+            .sample_root_paths(exex.hasher, &[Stem::new([1; 31])])
+            .unwrap();
+        assert!(sample.stale_nodes.is_empty());
+        assert_eq!(sample.root, root);
+        drop(exex);
+
+        // A head whose root does not match the state.
+        {
+            let db = UbtDatabase::open(&temp_dir.path().join(UBT_DATA_DIR)).unwrap();
+            let mut head = db.load_head().unwrap().unwrap();
+            head.root = B256::repeat_byte(0xee);
+            db.save_head(&head).unwrap();
+        }
+        for mode in [VerifyMode::Full, VerifyMode::Sampled] {
+            assert!(matches!(
+                open_with(mode),
+                Err(UbtError::RootVerificationFailed { .. })
+            ));
+        }
+        assert!(open_with(VerifyMode::Skip).is_ok());
+        let mut exex = open_with(VerifyMode::Background).unwrap();
+        assert!(!join(&mut exex));
+    }
+
+    #[test]
+    fn test_async_root_computation() {
+        let temp_dir = TempDir::new().unwrap();
+        let open_async = || {
+            let mut config = UbtConfig::for_tests(temp_dir.path().to_path_buf());
+            config.root_computation = RootComputation::Async;
+            UbtExEx::new(&config)
+        };
+
+        let mut roots = vec![B256::ZERO];
+        {
+            let mut exex = open_async().unwrap();
+            for block in 1..=3u8 {
+                exex.pending_entries.push(PendingEntry {
+                    key: TreeKey::new(Stem::new([block; 31]), block),
+                    value: Some(B256::repeat_byte(block)),
+                    address: Address::ZERO,
+                });
+                // A flush waits for the previous root and returns it.
+                let root = exex.commit(block as u64, B256::repeat_byte(block)).unwrap();
+                assert_eq!(root, roots[block as usize - 1], "block {block}");
+                roots.push(exex.compute_root_streaming().unwrap());
+            }
+
+            exex.finish_root().unwrap();
+            assert_eq!(exex.last_root, roots[3]);
+            let (head, pending) = exex.db.load_head_with_pending_root().unwrap();
+            assert_eq!(head.unwrap().root, roots[3]);
+            assert_eq!(pending, None);
+            assert_eq!(exex.key_index.load_head().unwrap().unwrap().root, roots[3]);
+            // The stem hashes left to the root thread were written with the root.
+            assert!(exex.db.stem_hashes_valid().unwrap());
+            assert_eq!(
+                exex.db
+                    .compute_root_from_stem_hashes(exex.hasher, &[])
+                    .unwrap(),
+                roots[3]
+            );
+            assert!(exex.db.internal_nodes_valid().unwrap());
+            for block in 1..=3u8 {
+                let recorded = exex.db.load_root_at(block as u64).unwrap().unwrap();
+                assert_eq!(recorded.root, roots[block as usize], "block {block}");
+            }
+        }
+
+        // A shutdown before the root was recorded leaves the marker and the
+        // previous root in the head.
+        {
+            let db = UbtDatabase::open(&temp_dir.path().join(UBT_DATA_DIR)).unwrap();
+            let mut head = db.load_head().unwrap().unwrap();
+            head.root = roots[2];
+            let finalized = UbtHead {
+                block_number: 2,
+                block_hash: B256::repeat_byte(2),
+                root: roots[2],
+                stem_count: 2,
+            };
+            let mut batch = WriteBatch::default();
+            batch.save_head(&head).unwrap();
+            batch
+                .save_pending_root(&PendingRoot {
+                    block_number: 3,
+                    block_hash: B256::repeat_byte(3),
+                    finalized: Some(finalized.clone()),
+                })
+                .unwrap();
+            batch.invalidate_internal_nodes();
+            db.write_batch(&batch).unwrap();
+            assert_eq!(db.load_head().unwrap(), Some(finalized));
+        }
+        let exex = open_async().unwrap();
+        assert_eq!(exex.last_root, roots[3]);
+        assert_eq!(exex.db.load_pending_root().unwrap(), None);
+        assert_eq!(exex.db.load_head().unwrap().unwrap().root, roots[3]);
+        assert_eq!(exex.key_index.load_head().unwrap().unwrap().root, roots[3]);
+        assert!(exex.db.internal_nodes_valid().unwrap());
+    }
+
+    #[test]
+    fn test_block_changes_record_deltas_per_block() {
+        let mut harness = TestHarness::new();
+        let address = Address::repeat_byte(0x33);
+        let slot = U256::from(7);
+
+        let blocks = [1u64, 2, 3].map(|n| BlockChanges {
+            block_number: n,
+            block_hash: B256::repeat_byte(n as u8),
+            accounts: vec![AccountChange {
+                address,
+                info: Some(AccountState {
+                    nonce: n,
+                    balance: U256::from(1000 * n),
+                    code_hash: KECCAK_EMPTY,
+                    code: None,
+                }),
+                storage_wiped: false,
+                storage: vec![(slot, U256::from(n))],
+            }],
+        });
+
+        for changes in &blocks {
+            harness.exex.process_block(changes).unwrap();
+            harness
+                .exex
+                .commit(changes.block_number, changes.block_hash)
+                .unwrap();
+        }
+
+        let basic_key = get_basic_data_key(&address);
+        let storage_key = get_storage_slot_key(&address, &u256_to_b256(slot).0);
+        for n in [2u64, 3] {
+            let deltas = harness.exex.db.load_block_deltas(n).unwrap();
+            assert_eq!(deltas.len(), 2, "block {n} should record its own deltas");
+            assert!(deltas.contains(&(
+                storage_key.stem,
+                storage_key.subindex,
+                u256_to_b256(U256::from(n - 1))
+            )));
+            assert!(deltas
+                .iter()
+                .any(|(stem, sub, _)| *stem == basic_key.stem && *sub == basic_key.subindex));
+        }
+
+        let head = harness.exex.db.load_head().unwrap().unwrap();
+        assert_eq!(head.block_number, 3);
+        assert_eq!(head.block_hash, B256::repeat_byte(3));
+    }
+
+    #[test]
+    fn test_chain_block_changes_from_execution_outcome() {
+        use reth_ethereum::evm::revm::{
+            bytecode::Bytecode, database::BundleState, state::AccountInfo,
+        };
+        use reth_ethereum::{Block, EthPrimitives};
+        use reth_execution_types::ExecutionOutcome;
+        use reth_primitives_traits::{Header, RecoveredBlock};
+
+        let account = Address::repeat_byte(0x44);
+        let contract = Address::repeat_byte(0x45);
+        let slot = U256::from(9);
+        let code = Bytes::from(vec![0x5b; 40]);
+        let code_hash = keccak256(&code);
+        let info = |nonce: u64, balance: u64| AccountInfo {
+            nonce,
+            balance: U256::from(balance),
+            code_hash: KECCAK_EMPTY,
+            ..Default::default()
+        };
+        let contract_info = |balance: u64| AccountInfo {
+            balance: U256::from(balance),
+            code_hash,
+            ..Default::default()
+        };
+
+        // `account` is created in block 1 and changed again in block 2; `contract`
+        // only changes in block 2, and its code is only in the bundle's contracts.
+        let bundle = BundleState::new(
+            [
+                (
+                    account,
+                    None,
+                    Some(info(2, 20)),
+                    [(slot, (U256::ZERO, U256::from(22)))].into_iter().collect(),
+                ),
+                (
+                    contract,
+                    Some(contract_info(5)),
+                    Some(contract_info(7)),
+                    Default::default(),
+                ),
+            ],
+            [
+                vec![(account, Some(None), vec![(slot, U256::ZERO)])],
+                vec![
+                    (
+                        account,
+                        Some(Some(info(1, 10))),
+                        vec![(slot, U256::from(11))],
+                    ),
+                    (contract, Some(Some(contract_info(5))), vec![]),
+                ],
+            ],
+            [(code_hash, Bytecode::new_raw(code.clone()))],
+        );
+        let outcome = ExecutionOutcome::new(bundle, vec![vec![], vec![]], 1, vec![]);
+        let block = |number| {
+            let block = Block {
+                header: Header {
+                    number,
+                    ..Default::default()
+                },
+                body: Default::default(),
+            };
+            RecoveredBlock::new_unhashed(block, Vec::new())
+        };
+        let chain = Chain::<EthPrimitives>::new([block(1), block(2)], outcome, None);
+
+        let changes = chain_block_changes(&chain);
+        assert_eq!(changes.len(), 2);
+        for (changes, block) in changes.iter().zip(chain.blocks().values()) {
+            assert_eq!(changes.block_number, block.header().number);
+            assert_eq!(changes.block_hash, block.hash());
+        }
+
+        // Block 1 sees the account as it stood after block 1, not at the tip.
+        let [first] = changes[0].accounts.as_slice() else {
+            panic!("block 1 should change one account");
+        };
+        assert_eq!(first.address, account);
+        let first_info = first.info.as_ref().unwrap();
+        assert_eq!((first_info.nonce, first_info.balance), (1, U256::from(10)));
+        assert_eq!(first.storage, vec![(slot, U256::from(11))]);
+        assert!(!first.storage_wiped);
+
+        let [second, changed_contract] = changes[1].accounts.as_slice() else {
+            panic!("block 2 should change two accounts");
+        };
+        assert_eq!(second.address, account);
+        let second_info = second.info.as_ref().unwrap();
+        assert_eq!(
+            (second_info.nonce, second_info.balance),
+            (2, U256::from(20))
+        );
+        assert_eq!(second.storage, vec![(slot, U256::from(22))]);
+        assert_eq!(changed_contract.address, contract);
+        let contract_state = changed_contract.info.as_ref().unwrap();
+        assert_eq!(contract_state.balance, U256::from(7));
+        assert_eq!(contract_state.code.as_ref(), Some(&code));
+        assert!(changed_contract.storage.is_empty());
+
+        // Committed block by block, block 2's delta restores block 1's slot value.
+        let mut harness = TestHarness::new();
+        harness.exex.process_chain(&chain).unwrap();
+        let storage_key = get_storage_slot_key(&account, &u256_to_b256(slot).0);
+        assert!(harness.exex.db.load_block_deltas(2).unwrap().contains(&(
+            storage_key.stem,
+            storage_key.subindex,
+            u256_to_b256(U256::from(11))
+        )));
+        assert_eq!(
+            harness.exex.get_value(&storage_key).unwrap(),
+            Some(u256_to_b256(U256::from(22)))
+        );
+        assert_eq!(
+            harness.exex.db.load_head().unwrap().unwrap().block_number,
+            2
+        );
+    }
+
+    #[test]
+    fn test_destroyed_account_clears_all_leaves() {
+        let mut harness = TestHarness::new();
+        let address = Address::repeat_byte(0x44);
+        let low_slot = U256::from(1);
+        let high_slot = U256::from(1_000_000);
+
+        let created = BlockChanges {
+            block_number: 1,
+            block_hash: B256::repeat_byte(0x01),
+            accounts: vec![AccountChange {
+                address,
+                info: Some(AccountState {
+                    nonce: 1,
+                    balance: U256::from(5),
+                    code_hash: B256::repeat_byte(0xcc),
+                    code: Some(Bytes::from_static(&[0x60, 0x00, 0x60, 0x00, 0xf3])),
+                }),
+                storage_wiped: false,
+                storage: vec![(low_slot, U256::from(11)), (high_slot, U256::from(22))],
+            }],
+        };
+        harness.exex.process_block(&created).unwrap();
+        harness.exex.commit(1, created.block_hash).unwrap();
+
+        let entries_before = harness.snapshot_entries();
+        let root_before = harness.snapshot_root();
+        assert!(entries_before.len() >= 5);
+
+        let destroyed = BlockChanges {
+            block_number: 2,
+            block_hash: B256::repeat_byte(0x02),
+            accounts: vec![AccountChange {
+                address,
+                info: None,
+                storage_wiped: true,
+                storage: Vec::new(),
+            }],
+        };
+        harness.exex.process_block(&destroyed).unwrap();
+        harness.exex.commit(2, destroyed.block_hash).unwrap();
+
+        let entries_after: Vec<_> = harness
+            .snapshot_entries()
+            .into_iter()
+            .filter(|(_, value)| *value != B256::ZERO)
+            .collect();
+        assert!(
+            entries_after.is_empty(),
+            "wiped account left leaves: {entries_after:?}"
+        );
+        for (stem, record) in harness.exex.key_index.stems_for_address(address).unwrap() {
+            assert_eq!(
+                record.subindices().count(),
+                0,
+                "key index bits left for {stem:?}"
+            );
+        }
+
+        let deltas = harness.exex.db.load_block_deltas(2).unwrap();
+        assert_eq!(deltas.len(), entries_before.len());
+        harness.exex.apply_deltas_reverse(&deltas).unwrap();
+        let dirty: Vec<_> = harness.exex.dirty_stems.drain().collect();
+        harness.exex.db.batch_update_stems(&dirty).unwrap();
+
+        assert_eq!(harness.snapshot_entries(), entries_before);
+        assert_eq!(harness.snapshot_root(), root_before);
+        let restored_bits: usize = harness
+            .exex
+            .key_index
+            .stems_for_address(address)
+            .unwrap()
+            .iter()
+            .map(|(_, record)| record.subindices().count())
+            .sum();
+        assert_eq!(restored_bits, entries_before.len());
+    }
+
+    #[test]
+    fn test_zero_value_deletes_leaf_and_stem() {
+        let mut harness = TestHarness::new();
+
+        let stem = Stem::new([5u8; 31]);
+        let key = TreeKey::new(stem, 9);
+        let value = B256::repeat_byte(0x99);
+
+        let root_empty = harness.snapshot_root();
+        harness.apply_entries_block(1, B256::repeat_byte(0x01), vec![(key, value)]);
+        assert_eq!(harness.exex.stem_count(), 1);
+
+        harness.apply_entries_block(2, B256::repeat_byte(0x02), vec![(key, B256::ZERO)]);
+        assert!(harness.snapshot_entries().is_empty());
+        assert!(harness.exex.db.load_stem(&stem).unwrap().is_none());
+        assert_eq!(harness.exex.stem_count(), 0);
+        assert_eq!(harness.snapshot_root(), root_empty);
+
+        let deltas = harness.exex.db.load_block_deltas(2).unwrap();
+        assert_eq!(deltas, vec![(stem, 9, value)]);
+        harness.exex.apply_deltas_reverse(&deltas).unwrap();
+        let dirty: Vec<_> = harness.exex.dirty_stems.drain().collect();
+        harness.exex.db.batch_update_stems(&dirty).unwrap();
+
+        assert_eq!(harness.snapshot_entries(), vec![(key, value)]);
+        assert_eq!(harness.exex.stem_count(), 1);
+    }
+
+    #[test]
+    fn test_code_shrink_deletes_stale_chunks() {
+        let mut harness = TestHarness::new();
+        let address = Address::repeat_byte(0x55);
+
+        let deploy = |n: u64, code: Vec<u8>| BlockChanges {
+            block_number: n,
+            block_hash: B256::repeat_byte(n as u8),
+            accounts: vec![AccountChange {
+                address,
+                info: Some(AccountState {
+                    nonce: 1,
+                    balance: U256::ZERO,
+                    code_hash: B256::repeat_byte(n as u8),
+                    code: Some(Bytes::from(code)),
+                }),
+                storage_wiped: false,
+                storage: Vec::new(),
+            }],
+        };
+
+        let long = deploy(1, vec![0x5b; 100]);
+        harness.exex.process_block(&long).unwrap();
+        harness.exex.commit(1, long.block_hash).unwrap();
+        for i in 0..4 {
+            let key = get_code_chunk_key(&address, i);
+            assert!(
+                harness.exex.get_value(&key).unwrap().is_some(),
+                "chunk {i} missing"
+            );
+        }
+        let entries_before = harness.snapshot_entries();
+
+        let short = deploy(2, vec![0x5b; 10]);
+        harness.exex.process_block(&short).unwrap();
+        harness.exex.commit(2, short.block_hash).unwrap();
+        assert!(harness
+            .exex
+            .get_value(&get_code_chunk_key(&address, 0))
+            .unwrap()
+            .is_some());
+        for i in 1..4 {
+            let key = get_code_chunk_key(&address, i);
+            assert!(
+                harness.exex.get_value(&key).unwrap().is_none(),
+                "chunk {i} left behind"
+            );
+        }
+
+        let deltas = harness.exex.db.load_block_deltas(2).unwrap();
+        harness.exex.apply_deltas_reverse(&deltas).unwrap();
+        let dirty: Vec<_> = harness.exex.dirty_stems.drain().collect();
+        harness.exex.db.batch_update_stems(&dirty).unwrap();
+        assert_eq!(harness.snapshot_entries(), entries_before);
+    }
+
+    #[test]
+    fn test_unloaded_code_is_resolved_by_hash() {
+        let mut harness = TestHarness::new();
+        let address = Address::repeat_byte(0x66);
+        let code_hash = B256::repeat_byte(0xc0);
+        let code = Bytes::from(vec![0x5b; 40]);
+
+        let resolved = code.clone();
+        let opened = Arc::new(AtomicUsize::new(0));
+        let counter = opened.clone();
+        harness.exex.set_code_resolver(Box::new(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+            let resolved = resolved.clone();
+            let lookup: CodeLookup =
+                Box::new(move |hash| Ok((hash == code_hash).then(|| resolved.clone())));
+            Ok(lookup)
+        }));
+
+        let change = |address| AccountChange {
+            address,
+            info: Some(AccountState {
+                nonce: 7,
+                balance: U256::from(1),
+                code_hash,
+                code: None,
+            }),
+            storage_wiped: false,
+            storage: Vec::new(),
+        };
+        let changes = BlockChanges {
+            block_number: 1,
+            block_hash: B256::repeat_byte(0x01),
+            accounts: vec![change(address), change(Address::repeat_byte(0x67))],
+        };
+        harness.exex.process_block(&changes).unwrap();
+        harness.exex.commit(1, changes.block_hash).unwrap();
+        // One lookup is opened for the block, not one per account.
+        assert_eq!(opened.load(Ordering::Relaxed), 1);
+
+        let basic_data = harness
+            .exex
+            .get_value(&get_basic_data_key(&address))
+            .unwrap()
+            .unwrap();
+        assert_eq!(basic_data_code_size(basic_data), 40);
+        for i in 0..2 {
+            let key = get_code_chunk_key(&address, i);
+            assert!(
+                harness.exex.get_value(&key).unwrap().is_some(),
+                "chunk {i} missing"
+            );
+        }
+    }
+
+    #[test]
+    fn test_balance_overflow_policy() {
+        let address = Address::repeat_byte(0x77);
+        let changes = BlockChanges {
+            block_number: 1,
+            block_hash: B256::repeat_byte(0x01),
+            accounts: vec![AccountChange {
+                address,
+                info: Some(AccountState {
+                    nonce: 0,
+                    balance: U256::MAX,
+                    code_hash: KECCAK_EMPTY,
+                    code: None,
+                }),
+                storage_wiped: false,
+                storage: Vec::new(),
+            }],
+        };
+
+        let mut harness = TestHarness::new();
+        harness.exex.process_block(&changes).unwrap();
+        harness.exex.commit(1, changes.block_hash).unwrap();
+        let expected = BasicDataLeaf::new(0, u128::MAX, 0).encode();
+        assert_eq!(
+            harness
+                .exex
+                .get_value(&get_basic_data_key(&address))
+                .unwrap(),
+            Some(expected)
+        );
+        assert_eq!(
+            harness.exex.db.load_balance_overflows().unwrap(),
+            vec![BalanceOverflow {
+                address,
+                block_number: 1,
+                balance: U256::MAX,
+            }]
+        );
+
+        // A later balance that fits clears the record.
+        let mut fits = changes.clone();
+        fits.block_number = 2;
+        fits.block_hash = B256::repeat_byte(0x02);
+        fits.accounts[0].info.as_mut().unwrap().balance = U256::from(1_000);
+        harness.exex.process_block(&fits).unwrap();
+        harness.exex.commit(2, fits.block_hash).unwrap();
+        assert!(harness.exex.db.load_balance_overflows().unwrap().is_empty());
+
+        let mut harness = TestHarness::new();
+        harness.exex.balance_overflow_policy = BalanceOverflowPolicy::Error;
+        let err = harness.exex.process_block(&changes).unwrap_err();
+        assert!(matches!(
+            err,
+            UbtError::BalanceOverflow {
+                block_number: 1,
+                ..
+            }
+        ));
+        assert!(harness.exex.db.load_balance_overflows().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_seed_genesis_as_block_zero() {
+        let mut harness = TestHarness::new();
+        assert!(harness.exex.get_head().is_none());
+
+        let eoa = Address::repeat_byte(0x01);
+        let contract = Address::repeat_byte(0x02);
+        let code = Bytes::from(vec![0x60, 0x00, 0x60, 0x00]);
+        let alloc = BTreeMap::from([
+            (
+                eoa,
+                GenesisAccount {
+                    balance: U256::from(1_000),
+                    ..Default::default()
+                },
+            ),
+            (
+                contract,
+                GenesisAccount {
+                    nonce: Some(1),
+                    code: Some(code.clone()),
+                    storage: Some(BTreeMap::from([(
+                        B256::with_last_byte(1),
+                        B256::with_last_byte(0x2a),
+                    )])),
+                    ..Default::default()
+                },
+            ),
+        ]);
+        let genesis_hash = B256::repeat_byte(0x99);
+
+        let root = harness.exex.seed_genesis(genesis_hash, &alloc).unwrap();
+        assert_ne!(root, B256::ZERO);
+
+        let head = harness.exex.get_head().expect("genesis head");
+        assert_eq!(head.block.number, 0);
+        assert_eq!(head.block.hash, genesis_hash);
+        let persisted = harness.exex.db.load_head().unwrap().unwrap();
+        assert_eq!(persisted.block_number, 0);
+        assert_eq!(persisted.root, root);
+
+        assert_eq!(
+            harness.exex.get_value(&get_basic_data_key(&eoa)).unwrap(),
+            Some(BasicDataLeaf::new(0, 1_000, 0).encode())
+        );
+        assert_eq!(
+            harness
+                .exex
+                .get_value(&get_code_hash_key(&contract))
+                .unwrap(),
+            Some(keccak256(&code))
+        );
+        assert_eq!(
+            harness
+                .exex
+                .get_value(&get_storage_slot_key(&contract, &B256::with_last_byte(1).0))
+                .unwrap(),
+            Some(B256::with_last_byte(0x2a))
+        );
+
+        // A second call is a no-op once the head exists.
+        assert_eq!(
+            harness.exex.seed_genesis(genesis_hash, &alloc).unwrap(),
+            root
+        );
+    }
+
+    #[test]
+    fn test_unchanged_leaves_are_skipped() {
+        let mut harness = TestHarness::new();
+        let address = Address::repeat_byte(0x88);
+        let code = Bytes::from(vec![0x5b; 70]);
+        let block = |number: u64, storage: Vec<(U256, U256)>| BlockChanges {
+            block_number: number,
+            block_hash: B256::with_last_byte(number as u8),
+            accounts: vec![AccountChange {
+                address,
+                info: Some(AccountState {
+                    nonce: 1,
+                    balance: U256::from(10),
+                    code_hash: keccak256(&code),
+                    code: Some(code.clone()),
+                }),
+                storage_wiped: false,
+                storage,
+            }],
+        };
+
+        let first = block(
+            1,
+            vec![
+                (U256::from(1), U256::from(5)),
+                (U256::from(2), U256::from(6)),
+            ],
+        );
+        harness.exex.process_block(&first).unwrap();
+        // Basic data, code hash, 3 chunks and 2 slots.
+        assert_eq!(harness.exex.pending_entries.len(), 7);
+        harness.exex.commit(1, first.block_hash).unwrap();
+
+        // Slot 1 is rewritten with its current value; only slot 2 really changes.
+        let second = block(
+            2,
+            vec![
+                (U256::from(1), U256::from(5)),
+                (U256::from(2), U256::from(9)),
+            ],
+        );
+        harness.exex.process_block(&second).unwrap();
+        let keys: Vec<_> = harness.exex.pending_entries.iter().map(|e| e.key).collect();
+        assert_eq!(
+            keys,
+            vec![get_storage_slot_key(
+                &address,
+                &u256_to_b256(U256::from(2)).0
+            )]
+        );
+        harness.exex.commit(2, second.block_hash).unwrap();
+        assert_eq!(
+            harness
+                .exex
+                .get_value(&get_storage_slot_key(
+                    &address,
+                    &u256_to_b256(U256::from(2)).0
+                ))
+                .unwrap(),
+            Some(u256_to_b256(U256::from(9)))
+        );
+    }
+
+    /// Plain state in a reth test database, read through `ProviderStateSource` as
+    /// of `block`, failing on the `fail_on_call`th batch.
+    struct TestStateSource<S> {
+        block: BlockNumHash,
+        source: S,
+        fail_on_call: Option<usize>,
+        calls: std::cell::Cell<usize>,
+        _factory: ProviderFactory<MockNodeTypesWithDB>,
+    }
+
+    fn test_state_source(
+        block: BlockNumHash,
+        state: &PlainState,
+        fail_on_call: Option<usize>,
+    ) -> TestStateSource<impl PlainStateSource> {
+        let factory = crate::bootstrap::tests::provider_factory_with(state);
+        TestStateSource {
+            block,
+            source: ProviderStateSource::open(&factory).unwrap(),
+            fail_on_call,
+            calls: std::cell::Cell::new(0),
+            _factory: factory,
+        }
+    }
+
+    impl<S: PlainStateSource> PlainStateSource for TestStateSource<S> {
+        fn state_block(&self) -> Result<BlockNumHash> {
+            Ok(self.block)
+        }
+
+        fn read_batch(
+            &self,
+            from: &BootstrapPosition,
+            limit: usize,
+        ) -> Result<crate::bootstrap::BootstrapBatch> {
+            let call = self.calls.get() + 1;
+            self.calls.set(call);
+            if self.fail_on_call == Some(call) {
+                return Err(UbtError::StateExtraction {
+                    message: "interrupted".to_string(),
+                });
+            }
+            self.source.read_batch(from, limit)
+        }
+    }
+
+    fn bootstrap_state() -> PlainState {
+        (1u8..=6)
+            .map(|i| {
+                let code = (i % 2 == 0).then(|| Bytes::from(vec![0x5b; 40 * i as usize]));
+                let info = AccountState {
+                    nonce: i as u64,
+                    balance: U256::from(100 * i as u64),
+                    code_hash: code.as_ref().map(keccak256).unwrap_or(KECCAK_EMPTY),
+                    code,
+                };
+                let storage = (1u8..=i)
+                    .map(|slot| (B256::with_last_byte(slot), U256::from(slot as u64 * 7)))
+                    .collect();
+                (Address::repeat_byte(i), (info, storage))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_bootstrap_from_plain_state_resumes_from_checkpoint() {
+        let block = BlockNumHash::new(500, B256::repeat_byte(0x50));
+        let source = |fail_on_call| test_state_source(block, &bootstrap_state(), fail_on_call);
+
+        let mut reference = TestHarness::new();
+        let expected_root = reference
+            .exex
+            .bootstrap_from_state(&source(None), 1_000)
+            .unwrap();
+        let expected_stems = reference.exex.stem_count();
+        let expected_entries = reference.snapshot_entries();
+
+        let temp_dir = TempDir::new().unwrap();
+        let config = UbtConfig::for_tests(temp_dir.path().to_path_buf());
+        {
+            let mut exex = UbtExEx::new(&config).unwrap();
+            assert!(exex.bootstrap_from_state(&source(Some(4)), 3).is_err());
+            assert!(exex.bootstrap_in_progress().unwrap());
+            assert!(exex.get_head().is_none());
+        }
+
+        let mut exex = UbtExEx::new(&config).unwrap();
+        assert!(exex.get_head().is_none());
+        assert!(exex.bootstrap_in_progress().unwrap());
+        assert_eq!(exex.db.load_bootstrap_floor().unwrap(), Some(500));
+        // The node has moved on by the restart, so the remaining batches are read
+        // at a later block than the head the bootstrap keeps.
+        let later = BlockNumHash::new(510, B256::repeat_byte(0x51));
+        let resumed = test_state_source(later, &bootstrap_state(), None);
+        let root = exex.bootstrap_from_state(&resumed, 3).unwrap();
+
+        assert_eq!(root, expected_root);
+        assert!(!exex.bootstrap_in_progress().unwrap());
+        assert_eq!(exex.stem_count(), expected_stems);
+        let head = exex.get_head().expect("head after bootstrap");
+        assert_eq!(head.block, block);
+        assert_eq!(
+            exex.key_index.load_head().unwrap().unwrap().root,
+            expected_root
+        );
+        assert_eq!(exex.db.load_bootstrap_floor().unwrap(), Some(510));
+        assert!(exex.check_revert_target(510).is_ok());
+        assert!(matches!(
+            exex.check_revert_target(505),
+            Err(UbtError::RevertBelowBootstrap {
+                target: 505,
+                floor: 510
+            })
+        ));
+
+        let entries: Vec<_> = exex
+            .db
+            .iter_entries_sorted()
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap();
+        assert_eq!(entries, expected_entries);
+    }
 }